@@ -3,17 +3,46 @@ use proc_macro2::TokenStream as TokenStream2;
 use quote::quote;
 use syn::punctuated::Punctuated;
 use syn::token::Comma;
-use syn::{parse_macro_input, DeriveInput, Field};
+use syn::{parse_macro_input, DeriveInput, Field, GenericArgument, LitStr, Path, PathArguments};
 use syn::Type;
 
+// `Vec<u8>` is spelled out in full, as it's the only `Vec` with a wire format. Any other
+// `Vec` comes back as plain "Vec", which isn't supported.
 fn get_type_ident(t: &Type) -> String {
-    match t {
-        Type::Path(type_path) => type_path.path.segments.last().unwrap().ident.to_string(),
+    let segment = match t {
+        Type::Path(type_path) => type_path.path.segments.last().unwrap(),
         _ => panic!("Unsupported type"),
+    };
+    let ident = segment.ident.to_string();
+    if let ("Vec", PathArguments::AngleBracketed(args)) = (ident.as_str(), &segment.arguments)
+        && args.args.len() == 1
+        && let Some(GenericArgument::Type(Type::Path(inner))) = args.args.first()
+        && inner.path.is_ident("u8") {
+        return String::from("Vec<u8>");
     }
+    ident
 }
 
-fn get_deserialized_fields(fields: &Punctuated<Field, Comma>) -> impl Iterator<Item = TokenStream2> {
+// Where the trait and its helpers live. That's `::lib` from other crates, while `lib` itself
+// says `#[binser(crate = "crate")]`.
+fn get_crate_path(input: &DeriveInput) -> syn::Result<Path> {
+    let mut path: Path = syn::parse_quote!(::lib);
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("binser")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("crate") {
+                path = meta.value()?.parse::<LitStr>()?.parse()?;
+                Ok(())
+            } else {
+                Err(meta.error("Unsupported binser attribute"))
+            }
+        })?;
+    }
+    Ok(path)
+}
+
+// The generated code goes through the bounds-checked helpers in `lib::traits::serializable`,
+// so a short or malformed buffer comes back as `lib::Error::Decode` instead of a panic.
+fn get_deserialized_fields(fields: &Punctuated<Field, Comma>, krate: &Path) -> impl Iterator<Item = TokenStream2> {
     let mut deserialize_fields: Vec<TokenStream2> = Vec::new();
 
     for f in fields.iter()
@@ -21,51 +50,36 @@ fn get_deserialized_fields(fields: &Punctuated<Field, Comma>) -> impl Iterator<I
         let name = &f.ident;
         let ty = &f.ty;
         let ident = get_type_ident(ty);
-        let token = match ident.as_str() {
-            "u32" => {
-                let tokens = quote! {
-                    #name: (|| {
-                        let end = *byte_offset + 4;
-                        let result = u32::from_le_bytes(data[*byte_offset..end].try_into().unwrap());
-                        *byte_offset = end;
-                        result
-                    })()
-                };
-                tokens
-            },
-            "String" => {                
-                let tokens = quote! {
-                    #name: (|| {
-                        let end = *byte_offset + 4;
-                        let len = u32::from_le_bytes(data[*byte_offset..end].try_into().unwrap()) as usize;
-                        if len == 0
-                        {
-                            return Err(String::from("Failed to deserialize!"));
-                        }
-                        *byte_offset = end;
-                        let str_bytes = &data[*byte_offset..*byte_offset + len];
-                        *byte_offset += len;
-                        String::from_utf8(str_bytes.to_vec()).unwrap()
-                    })()
-                };
-                tokens
-            },
+        let reader = match ident.as_str() {
+            "u32" => quote! { read_u32 },
+            "u64" => quote! { read_u64 },
+            "f64" => quote! { read_f64 },
+            "bool" => quote! { read_bool },
+            "String" => quote! { read_string },
+            "Vec<u8>" => quote! { read_bytes },
             _ => {
-                quote! {
-                    #name: #ty::default(),
-                }
+                deserialize_fields.push(quote! {
+                    #name: <#ty>::default()
+                });
+                continue;
             }
         };
-        deserialize_fields.push(token);
+        deserialize_fields.push(quote! {
+            #name: #krate::traits::serializable::#reader(data, byte_offset)?
+        });
     } 
 
     deserialize_fields.into_iter()   
 }
 
-#[proc_macro_derive(BinarySerializable)]
+#[proc_macro_derive(BinarySerializable, attributes(binser))]
 pub fn binary_serializable_derive(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
     let name = &input.ident;
+    let krate = match get_crate_path(&input) {
+        Ok(path) => path,
+        Err(error) => return error.to_compile_error().into()
+    };
     let fields = if let syn::Data::Struct(syn::DataStruct { fields: syn::Fields::Named(ref fields), .. }) = input.data {
         &fields.named
     } else {
@@ -78,11 +92,16 @@ pub fn binary_serializable_derive(input: TokenStream) -> TokenStream {
         let ident = get_type_ident(ty);
 
         match ident.as_str() {
-            "u32" => {
+            "u32" | "u64" => {
                 quote! {
                     buf.extend(&self.#name.to_le_bytes());
                 }
             },
+            "f64" => {
+                quote! {
+                    buf.extend(&self.#name.to_bits().to_le_bytes());
+                }
+            },
             "bool" => {
                 quote! {
                     buf.push(self.#name as u8);
@@ -90,16 +109,12 @@ pub fn binary_serializable_derive(input: TokenStream) -> TokenStream {
             },
             "Vec<u8>" => {      
                 quote! {
-                    let vec_bytes = &self.#name;
-                    buf.extend((vec_bytes.len() as u32).to_le_bytes());
-                    buf.extend(vec_bytes);
+                    #krate::traits::serializable::write_bytes(&mut buf, &self.#name);
                 }
             },
             "String" => {
                 quote! {
-                    let str_bytes = self.#name.as_bytes();
-                    buf.extend((str_bytes.len() as u32).to_le_bytes());
-                    buf.extend(str_bytes);
+                    #krate::traits::serializable::write_string(&mut buf, &self.#name);
                 }
             },
             _ => {
@@ -110,17 +125,17 @@ pub fn binary_serializable_derive(input: TokenStream) -> TokenStream {
         }
     });
 
-    let deserialize_fields = get_deserialized_fields(fields);
+    let deserialize_fields = get_deserialized_fields(fields, &krate);
 
     let expanded = quote! {
-        impl BinarySerializable for #name {
+        impl #krate::traits::serializable::BinarySerializable for #name {
             fn serialize(&self) -> Vec<u8> {
                 let mut buf = Vec::new();
                 #(#serialized_fields)*
                 buf
             }
 
-            fn deserialize(data: &[u8], byte_offset: &mut usize) -> Result<Self, String> where Self: Sized {
+            fn deserialize(data: &[u8], byte_offset: &mut usize) -> Result<Self, #krate::Error> where Self: Sized {
                 Ok(Self {
                     #(#deserialize_fields),*
                 })
@@ -129,7 +144,5 @@ pub fn binary_serializable_derive(input: TokenStream) -> TokenStream {
         }
     };
 
-    println!("{}", expanded.to_string());
-
     TokenStream::from(expanded)
 }
@@ -0,0 +1,109 @@
+use std::{io::{BufReader, BufWriter, Write}, net::TcpStream};
+
+use lib::{models::{BatchResult, Metric, WriteBatch}, protocol::{Frame, Hello, Opcode, ProtocolError, ReadRequest, Response}, query::Matrix, traits::serializable::BinarySerializable};
+
+use crate::read::ReadDecoder;
+
+const CLIENT_NAME: &str = "metrichouse-client";
+
+/// A persistent connection to the server. Requests are sent one at a time and each waits
+/// for its response.
+pub struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    next_request_id: u32
+}
+
+impl Connection {
+    /// Connects as the default tenant.
+    pub fn connect(address: &str) -> std::io::Result<Self> {
+        Self::connect_as(address, "")
+    }
+
+    /// Connects as `tenant`, whose data is all the connection can read or write.
+    pub fn connect_as(address: &str, tenant: &str) -> std::io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        let mut connection = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            next_request_id: 1
+        };
+
+        let hello = Hello { client: String::from(CLIENT_NAME), tenant: tenant.to_string() };
+        connection.request(Opcode::Hello, hello.serialize())?;
+        Ok(connection)
+    }
+
+    /// Writes many samples in one request. Samples the server turns down are listed in the
+    /// result rather than failing the call.
+    pub fn write_batch(&mut self, metrics: &[Metric]) -> std::io::Result<BatchResult> {
+        let batch = WriteBatch { metrics: metrics.to_vec() };
+        let response = self.request(Opcode::WriteBatch, batch.serialize())?;
+        BatchResult::deserialize(&response.body, &mut 0).map_err(invalid_data)
+    }
+
+    /// Every series stored under `name` with all of its samples.
+    pub fn read(&mut self, name: &str) -> std::io::Result<Matrix> {
+        let request = ReadRequest { name: name.to_string() };
+        let request_id = self.send(Opcode::Read, request.serialize())?;
+
+        let mut decoder = ReadDecoder::new();
+        loop {
+            let frame = self.receive(request_id)?;
+            match frame.header.opcode {
+                Opcode::ReadChunk => decoder.push_chunk(&frame.payload).map_err(invalid_data)?,
+                _ => {
+                    let response = Self::into_response(frame)?;
+                    return decoder.finish(&response.body).map_err(invalid_data);
+                }
+            }
+        }
+    }
+
+    pub fn close(mut self) -> std::io::Result<()> {
+        self.request(Opcode::Goodbye, Vec::new())?;
+        Ok(())
+    }
+
+    /// Sends one request frame and waits for the matching response.
+    fn request(&mut self, opcode: Opcode, payload: Vec<u8>) -> std::io::Result<Response> {
+        let request_id = self.send(opcode, payload)?;
+        let frame = self.receive(request_id)?;
+        Self::into_response(frame)
+    }
+
+    fn send(&mut self, opcode: Opcode, payload: Vec<u8>) -> std::io::Result<u32> {
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+
+        self.writer.write_all(&Frame::new(opcode, request_id, payload).encode())?;
+        self.writer.flush()?;
+        Ok(request_id)
+    }
+
+    fn receive(&mut self, request_id: u32) -> std::io::Result<Frame> {
+        let frame = Frame::read_from(&mut self.reader)?
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Server closed the connection"))?;
+        if frame.header.request_id != request_id {
+            return Err(invalid_data(format!("Expected a frame for request {}, got one for {}", request_id, frame.header.request_id)));
+        }
+        Ok(frame)
+    }
+
+    /// An error frame is turned into an I/O error wrapping the server's `ProtocolError`.
+    fn into_response(frame: Frame) -> std::io::Result<Response> {
+        match frame.header.opcode {
+            Opcode::Response => Response::deserialize(&frame.payload, &mut 0).map_err(invalid_data),
+            Opcode::Error => {
+                let error = ProtocolError::deserialize(&frame.payload, &mut 0).map_err(invalid_data)?;
+                Err(std::io::Error::other(error))
+            },
+            opcode => Err(invalid_data(format!("Expected a response, got {:?}", opcode)))
+        }
+    }
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+}
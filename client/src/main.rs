@@ -1,38 +1,40 @@
-use std::{io::Write, net::TcpStream, time::Instant};
+mod connection;
+mod read;
 
-use lib::{models::Metric, traits::serializable::BinarySerializable};
+use std::time::Instant;
 
+use connection::Connection;
+use lib::models::Metric;
 
-const BIND_ADDRESS: &str = "127.0.0.1:1227";
-
-fn send_trace(trace: &Metric) -> std::io::Result<()> {
-    let mut stream = TcpStream::connect(BIND_ADDRESS)?;
 
-    let serialized_trace = trace.serialize(); 
-    let mut buf= Vec::with_capacity(1 + serialized_trace.len());
-    buf[0] = 1; // Control byte for write operation
-    buf[1..].copy_from_slice(&serialized_trace);
-    stream.write_all(&serialized_trace)?;
-    Ok(())
-}
+const BIND_ADDRESS: &str = "127.0.0.1:1227";
 
 fn main() {
-    println!("Hello, world!");
-
     let test = Metric {
-        timestamp: time::OffsetDateTime::now_utc().unix_timestamp() as u64,
+        timestamp: (time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) as u64,
+        value: 1.0,
         name: "test_metric".to_owned(),
         labels: vec![("test_label".to_owned(), "test_value".to_owned())]
     };
 
+    let mut connection = Connection::connect(BIND_ADDRESS).expect("Failed to connect");
     let start = Instant::now();
 
     let total_metrics = 100_000;
-    for _ in 0..100 {
-        send_trace(&test).expect("Failed to send trace");
+    let batch_size = 1000;
+    let batch = vec![test.clone(); batch_size];
+    for _ in 0..total_metrics / batch_size {
+        let result = connection.write_batch(&batch).expect("Failed to send batch");
+        if result.rejected > 0 {
+            println!("{} samples rejected, first: {:?}", result.rejected, result.errors.first());
+        }
     }
 
     let duration = start.elapsed();
     let seconds = duration.as_secs_f64();
     println!("Sent {} metrics in {:.2} sec -> {:.2} metrics/sec", total_metrics, seconds, total_metrics as f64 / seconds);
+
+    let result = connection.read(&test.name).expect("Failed to read back metrics");
+    println!("Read back {} series with {} samples", result.series.len(), result.point_count());
+    connection.close().expect("Failed to close connection");
 }
@@ -0,0 +1,75 @@
+use lib::{query::Matrix, Error, traits::serializable::BinarySerializable};
+
+/// Rebuilds a read result from the `ReadChunk` frames the server streams back.
+#[derive(Default)]
+pub struct ReadDecoder {
+    matrix: Matrix,
+    chunks: u32
+}
+
+impl ReadDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_chunk(&mut self, payload: &[u8]) -> Result<(), Error> {
+        let mut byte_offset = 0;
+        let chunk = Matrix::deserialize(payload, &mut byte_offset)?;
+        if byte_offset != payload.len() {
+            return Err(Error::Decode(format!("{} trailing bytes after read chunk", payload.len() - byte_offset)));
+        }
+
+        for series in chunk.series {
+            // A series split over several chunks arrives as consecutive pieces.
+            match self.matrix.series.last_mut() {
+                Some(last) if last.name == series.name && last.labels == series.labels => last.points.extend(series.points),
+                _ => self.matrix.series.push(series)
+            }
+        }
+        self.chunks += 1;
+        Ok(())
+    }
+
+    /// `body` is the body of the final ok response, which carries the number of chunks sent.
+    pub fn finish(self, body: &[u8]) -> Result<Matrix, Error> {
+        let expected = body.try_into().map(u32::from_le_bytes)
+            .map_err(|_| Error::Decode(String::from("Read response is missing the chunk count")))?;
+        if expected != self.chunks {
+            return Err(Error::Decode(format!("Expected {} read chunks, received {}", expected, self.chunks)));
+        }
+        Ok(self.matrix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lib::{protocol::read::chunk_matrix, query::Series};
+
+    use super::*;
+
+    #[test]
+    fn reassembles_chunked_results() {
+        let matrix = Matrix { series: vec![
+            Series { name: "a".to_string(), labels: vec![], points: (0..500).map(|t| (t, 1.0)).collect() },
+            Series { name: "a".to_string(), labels: vec![("k".to_string(), "v".to_string())], points: vec![(1, 2.0)] },
+        ]};
+
+        let chunks = chunk_matrix(&matrix, 1024);
+        assert!(chunks.len() > 1);
+
+        let mut decoder = ReadDecoder::new();
+        for chunk in &chunks {
+            decoder.push_chunk(&chunk.serialize()).unwrap();
+        }
+        assert_eq!(decoder.finish(&(chunks.len() as u32).to_le_bytes()).unwrap(), matrix);
+    }
+
+    #[test]
+    fn rejects_missing_chunks() {
+        let mut decoder = ReadDecoder::new();
+        decoder.push_chunk(&Matrix::default().serialize()).unwrap();
+        assert!(decoder.finish(&2u32.to_le_bytes()).is_err());
+
+        assert!(ReadDecoder::new().push_chunk(&[1, 0, 0]).is_err());
+    }
+}
@@ -11,6 +11,12 @@ struct Node<T> {
     next: Link<T>,
 }
 
+impl<T> Default for List<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> List<T> {
     pub fn new() -> Self {
         List { head: None }
@@ -18,7 +24,7 @@ impl<T> List<T> {
 
     pub fn prepend(&self, elem: T) -> List<T> {
         List { head: Some(Rc::new(Node {
-            elem: elem,
+            elem,
             next: self.head.clone(),
         }))}
     }
@@ -25,12 +25,18 @@ impl<T> Node<T> {
     }
 }
 
+impl<T> Default for List<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> List<T> {
     pub fn new() -> Self {
         List { head: None, tail: None }
     }
 
-    pub fn peek_front(&self) -> Option<Ref<T>>
+    pub fn peek_front(&self) -> Option<Ref<'_, T>>
     {
         self.head.as_ref().map(|node|
             Ref::map(node.borrow(), |node| {
@@ -21,6 +21,12 @@ pub struct Iter<'a, T> {
     next: Option<&'a Node<T>>
 }
 
+impl<T: Ord + Copy> Default for SkipList<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T: Ord + Copy> SkipList<T> {
     pub fn new() -> Self {
         let layer_count: usize = 4;
@@ -71,7 +77,7 @@ impl<T: Ord + Copy> SkipList<T> {
                 counter += 1;
             }
 
-            return top;
+            top
         }
     }
 
@@ -125,7 +131,7 @@ impl<T: Ord + Copy> SkipList<T> {
 
             let mut curr_node = node;
             //step 3, we now probabilistically bounce up.
-            for (_layer, &prev_head) in path.iter().rev().skip(1).enumerate()
+            for &prev_head in path.iter().rev().skip(1)
             {
                 if !rng.random_bool(0.5) {
                     break;
@@ -228,10 +234,10 @@ mod tests {
     {
         let slist: SkipList<i32> = SkipList::new();
 
-        assert_eq!(slist.get_layer_head(0).is_null(), false);
-        assert_eq!(slist.get_layer_head(1).is_null(), false);
-        assert_eq!(slist.get_layer_head(2).is_null(), false);
-        assert_eq!(slist.get_layer_head(3).is_null(), false);
+        assert!(!slist.get_layer_head(0).is_null());
+        assert!(!slist.get_layer_head(1).is_null());
+        assert!(!slist.get_layer_head(2).is_null());
+        assert!(!slist.get_layer_head(3).is_null());
 
         let bad_op = panic::catch_unwind(|| {
             slist.get_layer_head(4);
@@ -255,19 +261,19 @@ mod tests {
         slist.add(5);
 
         assert_eq!(slist.len, 1);
-        assert_eq!(slist.contains(&5), true);
+        assert!(slist.contains(&5));
 
 
         slist.add(10);
         slist.add(20);
         slist.add(50);
         slist.add(55);
-        assert_eq!(slist.contains(&5), true);
-        assert_eq!(slist.contains(&10), true);
-        assert_eq!(slist.contains(&20), true);
-        assert_eq!(slist.contains(&50), true);
-        assert_eq!(slist.contains(&55), true);
-        //assert_eq!(slist.contains(&7), false);
+        assert!(slist.contains(&5));
+        assert!(slist.contains(&10));
+        assert!(slist.contains(&20));
+        assert!(slist.contains(&50));
+        assert!(slist.contains(&55));
+        //assert!(!slist.contains(&7));
         assert_eq!(slist.len, 5);
 
         let mut iter = slist.iter();
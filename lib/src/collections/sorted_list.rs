@@ -17,6 +17,12 @@ pub struct Iter<'a, T: Ord> {
     next: Option<&'a Node<T>>
 }
 
+impl<T: Ord> Default for SortedList<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T: Ord> SortedList<T> {
     pub fn new() -> Self {
         SortedList { begin: std::ptr::null_mut() }
@@ -95,7 +101,7 @@ impl<T: Ord> SortedList<T> {
 
 impl<T: Ord> Drop for SortedList<T> {
     fn drop(&mut self) {
-        while let Some(_) = self.pop() {}
+        while self.pop().is_some() {}
     }
 }
 
@@ -10,48 +10,111 @@ impl TrieNode {
     pub fn new() -> Self {
         TrieNode { word: None, children: HashMap::new() }
     }
+
+    fn collect_words(&self, words: &mut Vec<String>) {
+        if let Some(word) = &self.word {
+            words.push(word.clone());
+        }
+
+        for child in self.children.values() {
+            child.collect_words(words);
+        }
+    }
+
+    // Returns whether `value` was removed, and whether this node is now empty and can be pruned.
+    fn remove(&mut self, mut chars: std::str::Chars, value: &str) -> (bool, bool) {
+        let removed = match chars.next() {
+            None => {
+                let removed = self.word.as_deref() == Some(value);
+                if removed {
+                    self.word = None;
+                }
+                removed
+            },
+            Some(c) => {
+                let Some(child) = self.children.get_mut(&c) else { return (false, false) };
+                let (removed, prune) = child.remove(chars, value);
+                if prune {
+                    self.children.remove(&c);
+                }
+                removed
+            }
+        };
+
+        (removed, self.word.is_none() && self.children.is_empty())
+    }
 }
 
-struct Trie {
-    root: Box<TrieNode>
+pub struct Trie {
+    root: Box<TrieNode>,
+    len: usize
+}
+
+impl Default for Trie {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Trie {
     pub fn new() -> Self {
-        Trie { root: Box::new(TrieNode::new()) }
+        Trie { root: Box::new(TrieNode::new()), len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
     }
 
     pub fn insert(&mut self, value: &str) {
         let mut current = &mut self.root;
         for c in value.chars()
         {
-            if current.children.contains_key(&c)
-            {
-                current = current.children.get_mut(&c).unwrap();
-            } else {
-                let new_node = Box::new(TrieNode::new());
-                current.children.insert(c, new_node);
-                current = current.children.get_mut(&c).unwrap();
-            }
+            current = current.children.entry(c).or_insert_with(|| Box::new(TrieNode::new()));
         }
 
+        if current.word.is_none() {
+            self.len += 1;
+        }
         current.word = Some(String::from(value));
     }
 
     pub fn contains(&self, value: &str) -> bool {
+        match self.find(value) {
+            Some(node) => node.word.as_ref().is_some_and(|word| word.eq_ignore_ascii_case(value)),
+            None => false
+        }
+    }
+
+    /// Removes `value`, pruning any branches it leaves empty. Returns false if it wasn't there.
+    pub fn remove(&mut self, value: &str) -> bool {
+        let (removed, _) = self.root.remove(value.chars(), value);
+        if removed {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    /// Every stored word that starts with `prefix`, in sorted order.
+    pub fn starts_with(&self, prefix: &str) -> Vec<String> {
+        let mut words = Vec::new();
+        if let Some(node) = self.find(prefix) {
+            node.collect_words(&mut words);
+        }
+        words.sort();
+        words
+    }
+
+    fn find(&self, prefix: &str) -> Option<&TrieNode> {
         let mut current = &self.root;
-        for c in value.chars()
+        for c in prefix.chars()
         {
-            if current.children.contains_key(&c)
-            {
-                current = current.children.get(&c).unwrap();
-            } else {
-                return false;
-            }
+            current = current.children.get(&c)?;
         }
-
-        if let Some(word) = &current.word { return word.eq_ignore_ascii_case(value); }
-        else { false }
+        Some(current)
     }
 }
 
@@ -67,9 +130,46 @@ mod tests {
         let mut my_trie = Trie::new();
 
         my_trie.insert("test");
-        assert_eq!(my_trie.contains("test"), true);
+        assert!(my_trie.contains("test"));
+        my_trie.insert("tester");
+        assert!(my_trie.contains("test"));
+        assert!(my_trie.contains("tester"));
+        assert!(!my_trie.contains("tes"));
+        assert_eq!(my_trie.len(), 2);
+    }
+
+    #[test]
+    fn trie_prefix_search()
+    {
+        let mut my_trie = Trie::new();
+
+        my_trie.insert("cpu_user");
+        my_trie.insert("cpu_system");
+        my_trie.insert("mem_free");
+        my_trie.insert("cpu");
+
+        assert_eq!(my_trie.starts_with("cpu"), vec!["cpu", "cpu_system", "cpu_user"]);
+        assert_eq!(my_trie.starts_with("cpu_s"), vec!["cpu_system"]);
+        assert_eq!(my_trie.starts_with(""), vec!["cpu", "cpu_system", "cpu_user", "mem_free"]);
+        assert!(my_trie.starts_with("disk").is_empty());
+    }
+
+    #[test]
+    fn trie_remove()
+    {
+        let mut my_trie = Trie::new();
+
+        my_trie.insert("test");
         my_trie.insert("tester");
-        assert_eq!(my_trie.contains("test"), true);
-        assert_eq!(my_trie.contains("tester"), true);
+
+        assert!(!my_trie.remove("tes"));
+        assert!(my_trie.remove("tester"));
+        assert!(!my_trie.remove("tester"));
+        assert!(my_trie.contains("test"));
+        assert_eq!(my_trie.starts_with("te"), vec!["test"]);
+
+        assert!(my_trie.remove("test"));
+        assert!(my_trie.is_empty());
+        assert!(my_trie.root.children.is_empty());
     }
-}
\ No newline at end of file
+}
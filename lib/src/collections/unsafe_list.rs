@@ -13,6 +13,12 @@ struct Node<T> {
     next: Link<T>
 }
 
+impl<T> Default for List<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> List<T> {
     pub fn new() -> Self {
         List { head: std::ptr::null_mut(), tail: std::ptr::null_mut() }
@@ -79,7 +85,7 @@ impl<T> List<T> {
 
 impl<T> Drop for List<T> {
     fn drop(&mut self) {
-        while let Some(_) = self.pop() {}
+        while self.pop().is_some() {}
     }
 }
 
@@ -1,81 +1,550 @@
-use std::{fs::read_dir, io::Read, path::Path};
+use std::{collections::{BTreeSet, HashMap, HashSet}, fs::{read_dir, File}, io::Write, ops::RangeInclusive, path::{Path, PathBuf}, time::Instant};
 
-use crate::{models::Metric, storage::{store::InMemoryStore, wal::{WalWriter, WAL_DIR}}, traits::serializable::BinarySerializable};
+use crate::{error::Error, models::{batch::DecodedBatch, BatchResult, Metric, MetricKind, RejectCode, SampleError, Validation}, query::{range, selector::{Selector, NAME_LABEL}, Matrix, QueryContext, QueryLimits, RangeQuery}, storage::{store::{Cardinality, HeadStats, InMemoryStore}, wal::{WalWriter, WAL_DIR}, StorageOptions}, telemetry::{self, DURATION_BUCKETS}, time::now_ms, traits::serializable::BinarySerializable};
+
+/// Where `snapshot` writes, inside the data directory.
+pub const SNAPSHOT_DIR: &str = "snapshots";
 
 pub struct MetricsDb {
+    data_dir: PathBuf,
     memory_store: InMemoryStore,
-    _wal_writer: WalWriter
+    wal_writer: WalWriter,
+    // WAL files from before this database was opened that `recover` hasn't replayed yet.
+    unreplayed: Vec<PathBuf>,
+    // Not in the WAL: senders that know the kind repeat it with every write.
+    kinds: HashMap<String, MetricKind>,
+    validation: Validation,
+    query_limits: QueryLimits,
+    max_series: Option<usize>
 }
 
 impl MetricsDb {
-    pub fn new() -> Self {
-        let mut db = MetricsDb {
-            memory_store: InMemoryStore::new(),
-            _wal_writer: WalWriter::new()
-        };
-        db.recover();
-        return db
+    pub fn new() -> Result<Self, Error> {
+        Self::open(Path::new(WAL_DIR))
     }
 
-    fn recover(&mut self)
-    {
-        let wal_dir = Path::new(WAL_DIR);
-        if wal_dir.is_dir()
-        {
-            let mut entries = read_dir(WAL_DIR).unwrap()
-                .map(|res| res.map(|e| e.path()))
-                .collect::<Result<Vec<_>, std::io::Error>>().unwrap();
-
-            entries.sort();
-            
-            for entry in &entries
+    /// Replays the WAL files found in `wal_dir` and starts a new one there.
+    pub fn open(wal_dir: &Path) -> Result<Self, Error> {
+        Self::open_in(wal_dir, &StorageOptions::default())
+    }
+
+    /// `open` on the `wals` directory of `options.data_dir`.
+    pub fn open_with(options: &StorageOptions) -> Result<Self, Error> {
+        Self::open_in(&options.data_dir.join(WAL_DIR), options)
+    }
+
+    /// `open_with` without the replay, so it returns straight away. Nothing from before is
+    /// queryable until `recover` is done.
+    pub fn open_unrecovered(options: &StorageOptions) -> Result<Self, Error> {
+        Self::open_unrecovered_in(&options.data_dir.join(WAL_DIR), options)
+    }
+
+    fn open_in(wal_dir: &Path, options: &StorageOptions) -> Result<Self, Error> {
+        let mut db = Self::open_unrecovered_in(wal_dir, options)?;
+        db.recover()?;
+        Ok(db)
+    }
+
+    fn open_unrecovered_in(wal_dir: &Path, options: &StorageOptions) -> Result<Self, Error> {
+        // Listed before the new file is created, which only holds what comes after.
+        let unreplayed = wal_files(wal_dir)?;
+        Ok(MetricsDb {
+            data_dir: options.data_dir.clone(),
+            memory_store: InMemoryStore::with_options(options.data_dir.clone(), options.series_flush_threshold),
+            wal_writer: WalWriter::create_with(wal_dir, options.wal_file_size, options.wal_flush_interval)?,
+            unreplayed,
+            kinds: HashMap::new(),
+            validation: Validation::default(),
+            query_limits: QueryLimits::default(),
+            max_series: None
+        })
+    }
+
+    /// Loads the WAL files that were there when the database was opened, oldest first, and
+    /// deletes each once it's replayed and copied into the current WAL, so the samples outlive
+    /// the next restart too. Each file ends at its zero padding; anything else that fails to
+    /// decode is corruption, and the file is left in place.
+    pub fn recover(&mut self) -> Result<(), Error> {
+        while let Some(entry) = self.unreplayed.first().cloned() {
+            crate::info!(file = entry.display(); "Replaying WAL file");
+            let buffer = std::fs::read(&entry)?;
+
+            let mut byte_offset: usize = 0;
+            let mut end = buffer.len();
+            while byte_offset < buffer.len()
             {
-                println!("{}", entry.as_path().to_string_lossy());
-                let mut entry_file = std::fs::File::open(entry).unwrap();
-                let file_meta = entry.metadata().unwrap();
-                let file_len = file_meta.len() as usize;
-
-                if file_len == 0
-                {
-                    println!("File: {} is empty! Deleting...", entry.to_string_lossy());
-                    continue;
+                let start = byte_offset;
+                match Metric::deserialize(&buffer, &mut byte_offset) {
+                    Ok(metric) => self.memory_store.insert(metric)?,
+                    Err(e) => {
+                        if buffer[start..].iter().any(|b| *b != 0) {
+                            return Err(Error::Corruption { path: entry.clone(), offset: start, detail: e.to_string() });
+                        }
+                        end = start;
+                        break;
+                    }
                 }
+            }
 
-                let mut buffer: Vec<u8> = Vec::with_capacity(file_len);
-                entry_file.read_to_end(&mut buffer).unwrap();
+            if end > 0 {
+                self.wal_writer.write(&buffer[..end])?;
+                self.wal_writer.sync()?;
+            }
+            std::fs::remove_file(&entry)?;
+            self.unreplayed.remove(0);
+        }
+        Ok(())
+    }
 
-                let mut byte_offset: usize = 0;
-                while byte_offset < file_len
-                {
-                    let result = Metric::deserialize(&buffer, &mut byte_offset);
+    /// The checks samples must pass from now on. Replayed WAL data isn't checked again.
+    pub fn set_validation(&mut self, validation: Validation) {
+        self.validation = validation;
+    }
 
-                    match result {
-                        Ok(metric) => self.ingest(metric),
-                        Err(_) => byte_offset = file_len
-                    }
+    pub fn validation(&self) -> &Validation {
+        &self.validation
+    }
+
+    /// The limits queries against this database are meant to run under.
+    pub fn set_query_limits(&mut self, limits: QueryLimits) {
+        self.query_limits = limits;
+    }
+
+    pub fn query_limits(&self) -> QueryLimits {
+        self.query_limits
+    }
+
+    /// How many series `ingest_batch` lets the database hold. Samples of series it already
+    /// holds are taken whatever the limit.
+    pub fn set_max_series(&mut self, max_series: Option<usize>) {
+        self.max_series = max_series;
+    }
+
+    /// Drops the samples held in memory that are older than `cutoff`, returning how many.
+    pub fn delete_before(&mut self, cutoff: u64) -> usize {
+        let started = Instant::now();
+        let removed = self.memory_store.remove_before(cutoff);
+        let registry = telemetry::global();
+        registry.histogram("metrichouse_retention_duration_seconds", "Time taken to drop samples past the retention period.", &[], DURATION_BUCKETS)
+            .observe_duration(started.elapsed());
+        registry.counter("metrichouse_retention_samples_removed_total", "Samples dropped for being past the retention period.", &[]).add(removed as u64);
+        removed
+    }
+
+    /// Syncs the WAL and writes every series to its data file.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.wal_writer.sync()?;
+        self.memory_store.flush_all()
+    }
+
+    /// Rewrites the WAL to hold exactly what's in memory, so samples dropped by retention or
+    /// `delete_series` stop taking space there and aren't replayed. Series files are
+    /// rewritten too, as by `flush`.
+    pub fn compact(&mut self) -> Result<(), Error> {
+        let started = Instant::now();
+        self.wal_writer.rewrite(&self.memory_store.serialize_all())?;
+        self.memory_store.flush_all()?;
+        telemetry::global().histogram("metrichouse_compaction_duration_seconds", "Time taken to rewrite the WAL and series files.", &[], DURATION_BUCKETS)
+            .observe_duration(started.elapsed());
+        Ok(())
+    }
+
+    /// Writes every sample held to `<data_dir>/snapshots/<name>/wal.bin`, returning the name.
+    /// Copying that file into the `wals` directory of an empty data directory restores it.
+    pub fn snapshot(&self) -> Result<String, Error> {
+        let name = now_ms().to_string();
+        let dir = self.data_dir.join(SNAPSHOT_DIR).join(&name);
+        std::fs::create_dir_all(&dir)?;
+        let mut file = File::create(dir.join("wal.bin"))?;
+        file.write_all(&self.memory_store.serialize_all())?;
+        file.sync_all()?;
+        Ok(name)
+    }
+
+    /// Drops the samples of every series matching `selector` within `time_range`, then
+    /// compacts so they stay gone after a restart. Returns how many samples went.
+    pub fn delete_series(&mut self, selector: &Selector, time_range: RangeInclusive<u64>) -> Result<usize, Error> {
+        let removed = self.memory_store.remove_matching(|metric| time_range.contains(&metric.timestamp) && selector.matches(metric));
+        if removed > 0 {
+            self.compact()?;
+        }
+        Ok(removed)
+    }
+
+    /// The series held and the metrics and labels with the most of them, `limit` of each.
+    pub fn cardinality(&self, limit: usize) -> Cardinality {
+        self.memory_store.cardinality(limit)
+    }
+
+    /// What's held in memory. Walks every sample.
+    pub fn head_stats(&self) -> HeadStats {
+        self.memory_store.stats()
+    }
+
+    /// `ingest_batch` for a single sample, which comes back as the error if it's rejected.
+    pub fn ingest(&mut self, metric: Metric) -> Result<(), Error> {
+        match self.ingest_batch(vec![metric]).errors.pop() {
+            Some(error) => Err(Error::Rejected { code: error.code, detail: error.reason }),
+            None => Ok(())
+        }
+    }
+
+    /// Stores every acceptable sample of the batch behind a single WAL append. Samples that
+    /// fail validation are reported by their index in `metrics`. If the append fails, every
+    /// sample is rejected as `RejectCode::WalAppendFailed`, which a retry may well get past.
+    pub fn ingest_batch(&mut self, metrics: Vec<Metric>) -> BatchResult {
+        self.store_batch(metrics, true)
+    }
+
+    /// `ingest_batch` for the server's own metrics, which don't count against `max_series`.
+    /// There are only so many of them, and a tenant at its limit shouldn't lose them.
+    pub fn ingest_own(&mut self, metrics: Vec<Metric>) -> BatchResult {
+        self.store_batch(metrics, false)
+    }
+
+    fn store_batch(&mut self, metrics: Vec<Metric>, capped: bool) -> BatchResult {
+        let mut result = BatchResult::default();
+        let mut accepted = Vec::with_capacity(metrics.len());
+        let mut wal_data = Vec::new();
+
+        let now = now_ms();
+        let mut series = self.max_series.filter(|_| capped).map(|_| self.memory_store.series_count());
+        let mut new_series = HashSet::new();
+        for (index, metric) in metrics.into_iter().enumerate() {
+            match self.validation.check(&metric, now).and_then(|_| self.check_series_limit(&metric, &mut series, &mut new_series)) {
+                Ok(()) => {
+                    wal_data.extend(metric.serialize());
+                    accepted.push((index, metric));
+                },
+                Err((code, reason)) => {
+                    result.rejected += 1;
+                    result.errors.push(SampleError::with_code(index, code, &reason));
                 }
+            }
+        }
 
-                std::fs::remove_file(entry).unwrap();
+        if !accepted.is_empty() && let Err(e) = self.wal_writer.write(&wal_data) {
+            let reason = format!("WAL append failed: {}", e);
+            result.rejected += accepted.len() as u32;
+            result.errors.extend(accepted.iter().map(|(index, _)| SampleError::with_code(*index, RejectCode::WalAppendFailed, &reason)));
+            result.errors.sort_by_key(|e| e.index);
+            record_rejections(&result);
+            return result;
+        }
+
+        result.accepted = accepted.len() as u32;
+        for (_, metric) in accepted {
+            // The samples are already durable in the WAL, so a failed series flush is not a
+            // rejection.
+            if let Err(e) = self.memory_store.insert(metric) {
+                crate::error!(error = e; "Failed to flush series");
             }
-        } else {
-            println!("Path {} not found!", wal_dir.to_string_lossy());
         }
+        record_ingested(result.accepted as u64);
+        record_rejections(&result);
+        result
+    }
+
+    /// Counts a sample that starts a series into `series`, unless that's over the limit.
+    /// `new_series` holds the ones started earlier in the batch.
+    fn check_series_limit(&self, metric: &Metric, series: &mut Option<usize>, new_series: &mut HashSet<(String, Vec<(String, String)>)>) -> Result<(), (RejectCode, String)> {
+        let (Some(max), Some(count)) = (self.max_series, series.as_mut()) else {
+            return Ok(());
+        };
+        let mut labels = metric.labels.clone();
+        labels.sort();
+        if self.memory_store.contains_series(&metric.name, &labels) || new_series.contains(&(metric.name.clone(), labels.clone())) {
+            return Ok(());
+        }
+        if *count >= max {
+            return Err((RejectCode::SeriesLimit, format!("Already holding the limit of {} series", max)));
+        }
+        *count += 1;
+        new_series.insert((metric.name.clone(), labels));
+        Ok(())
+    }
+
+    /// `ingest_batch` for samples decoded from a request, each with its index there. Decode
+    /// errors and rejections both come back by that index.
+    pub fn ingest_decoded(&mut self, (decoded, errors): DecodedBatch) -> BatchResult {
+        let mut result = BatchResult { accepted: 0, rejected: errors.len() as u32, errors };
+        let (indices, metrics): (Vec<usize>, Vec<Metric>) = decoded.into_iter().unzip();
+        result.merge(self.ingest_batch(metrics), &indices);
+        result
+    }
+
+    pub fn set_kind(&mut self, name: &str, kind: MetricKind) {
+        self.kinds.insert(name.to_string(), kind);
+    }
+
+    pub fn kind(&self, name: &str) -> MetricKind {
+        self.kinds.get(name).copied().unwrap_or_default()
+    }
+
+    /// Every metric with a known kind, sorted by name.
+    pub fn kinds(&self) -> Vec<(String, MetricKind)> {
+        let mut kinds: Vec<_> = self.kinds.iter().map(|(name, kind)| (name.clone(), *kind)).collect();
+        kinds.sort_by(|a, b| a.0.cmp(&b.0));
+        kinds
+    }
+
+    /// The most recently stored sample of the series `name` with exactly `labels`, which
+    /// must be sorted.
+    pub fn latest(&self, name: &str, labels: &[(String, String)]) -> Option<&Metric> {
+        self.memory_store.query(name)?.iter().rev().find(|metric| metric.labels == labels)
+    }
+
+    pub fn query(&self, name: &str) -> Result<&Vec<Metric>, Error> {
+        self.memory_store.query(name).ok_or_else(|| Error::NotFound(format!("metric {}", name)))
+    }
+
+    /// Raw samples for `name`, grouped into series.
+    pub fn select(&self, name: &str, ctx: &mut QueryContext) -> Result<Matrix, Error> {
+        match self.memory_store.query(name) {
+            Some(samples) => Matrix::collect(samples, 0..=u64::MAX, ctx),
+            None => Ok(Matrix::default())
+        }
+    }
+
+    pub fn list_metric_names(&self, prefix: &str) -> Vec<String> {
+        self.memory_store.names_with_prefix(prefix)
+    }
+
+    /// Label names used by any series matching `selector`, sorted.
+    pub fn label_names(&self, selector: &Selector) -> Vec<String> {
+        let mut names = BTreeSet::new();
+        for (_, labels) in self.matching_series(selector) {
+            names.extend(labels.iter().map(|(key, _)| key.clone()));
+        }
+        names.into_iter().collect()
     }
 
-    pub fn ingest(&mut self, metric: Metric) {
-        self.memory_store.insert(metric);
+    /// Values `label` takes across the series matching `selector`, sorted.
+    pub fn label_values(&self, label: &str, selector: &Selector) -> Vec<String> {
+        let mut values = BTreeSet::new();
+        for (name, labels) in self.matching_series(selector) {
+            if label == NAME_LABEL {
+                values.insert(name.to_string());
+            } else if let Some((_, value)) = labels.iter().find(|(key, _)| key == label) {
+                values.insert(value.clone());
+            }
+        }
+        values.into_iter().collect()
+    }
+
+    fn matching_names(&self, selector: &Selector) -> Vec<String> {
+        let names = match &selector.name {
+            Some(name) => vec![name.clone()],
+            None => self.memory_store.names_with_prefix("")
+        };
+        names.into_iter().filter(|name| selector.matches_name(name)).collect()
     }
 
-    pub fn query(&self, name: &str) -> &Vec<Metric> {
-        // Implementation for retrieving metrics from the database
-        self.memory_store.query(name).expect(format!("Metric not found: {}", name).as_str())
+    fn matching_series<'a>(&'a self, selector: &'a Selector) -> impl Iterator<Item = (String, &'a Vec<(String, String)>)> {
+        self.matching_names(selector).into_iter()
+            .flat_map(|name| {
+                self.memory_store.label_sets(&name)
+                    .filter(|labels| selector.matches_labels(labels))
+                    .map(|labels| (name.clone(), labels))
+                    .collect::<Vec<_>>()
+            })
+    }
+
+    /// Raw samples of every series matching `selector` within `time_range`, grouped into series.
+    pub fn select_matching(&self, selector: &Selector, time_range: RangeInclusive<u64>, ctx: &mut QueryContext) -> Result<Matrix, Error> {
+        let samples = self.matching_names(selector).into_iter()
+            .filter_map(|name| self.memory_store.query(&name))
+            .flatten()
+            .filter(|metric| selector.matches(metric));
+        Matrix::collect(samples, time_range, ctx)
+    }
+
+    /// One point per matching series: the latest sample at or before `time`, within the lookback.
+    pub fn query_instant(&self, selector: &Selector, time: u64, ctx: &mut QueryContext) -> Result<Matrix, Error> {
+        self.query_range(selector, &RangeQuery::new(time, time, 1)?, ctx)
+    }
+
+    pub fn query_range(&self, selector: &Selector, query: &RangeQuery, ctx: &mut QueryContext) -> Result<Matrix, Error> {
+        let raw = self.select_matching(selector, query.earliest_sample()..=query.end, ctx)?;
+        range::evaluate(&raw, query, ctx)
+    }
+
+    /// Name and sorted labels of every series matching `selector`, ordered by name then labels.
+    pub fn series(&self, selector: &Selector) -> Vec<(String, Vec<(String, String)>)> {
+        let mut series: Vec<_> = self.matching_series(selector)
+            .map(|(name, labels)| (name, labels.clone()))
+            .collect();
+        series.sort();
+        series
+    }
+}
+
+/// The WAL files in `wal_dir`, oldest first. `.tmp` files are rewrites that never finished,
+/// so the file they were replacing still holds everything, and they're deleted.
+fn wal_files(wal_dir: &Path) -> Result<Vec<PathBuf>, Error>
+{
+    if !wal_dir.is_dir()
+    {
+        return Ok(Vec::new());
+    }
+
+    let mut entries = Vec::new();
+    for entry in read_dir(wal_dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "tmp") {
+            std::fs::remove_file(&path)?;
+        } else {
+            entries.push(path);
+        }
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+fn record_ingested(count: u64) {
+    telemetry::global().counter("metrichouse_samples_ingested_total", "Samples stored, whichever protocol they came in by.", &[]).add(count);
+}
+
+/// Counts samples turned down before they reached the database, too.
+pub fn record_rejection(code: RejectCode, count: u64) {
+    telemetry::global().counter("metrichouse_samples_rejected_total", "Samples turned down at ingest, by reason.", &[("reason", code.as_str())]).add(count);
+}
+
+fn record_rejections(result: &BatchResult) {
+    for error in &result.errors {
+        record_rejection(error.code, 1);
     }
 }
 
 impl Drop for MetricsDb
 {
     fn drop(&mut self) {
-        println!("Shutdown!");
-        todo!();
+        crate::info!("Database closed");
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::validation::NanPolicy, testing::TempDir};
+
+    fn metric(timestamp: u64) -> Metric {
+        Metric { timestamp, value: 1.0, name: String::from("up"), labels: vec![] }
+    }
+
+    #[test]
+    fn recovers_up_to_zero_padding() {
+        let dir = TempDir::new("db_recover");
+        let mut data = metric(1).serialize();
+        data.extend(metric(2).serialize());
+        data.extend([0u8; 64]);
+        std::fs::write(dir.join("wal_1.bin"), data).unwrap();
+
+        let db = MetricsDb::open(&dir).unwrap();
+        assert_eq!(db.query("up").unwrap().len(), 2);
+        assert!(matches!(db.query("down"), Err(Error::NotFound(_))));
+    }
+
+    #[test]
+    fn keeps_replayed_samples_across_restarts() {
+        let dir = TempDir::new("db_restarts");
+        let mut db = MetricsDb::open(&dir).unwrap();
+        assert_eq!(db.ingest_batch(vec![metric(1), metric(2)]).accepted, 2);
+        drop(db);
+
+        let mut db = MetricsDb::open(&dir).unwrap();
+        assert_eq!(db.query("up").unwrap().len(), 2);
+        assert_eq!(db.ingest_batch(vec![metric(3)]).accepted, 1);
+        drop(db);
+
+        let db = MetricsDb::open(&dir).unwrap();
+        assert_eq!(db.query("up").unwrap().iter().map(|m| m.timestamp).collect::<Vec<_>>(), vec![1, 2, 3]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn reports_corrupt_wal() {
+        let dir = TempDir::new("db_corrupt");
+        let mut data = metric(1).serialize();
+        data.extend([0u8, 0, 0, 0, 0, 0, 0, 0, 7]);
+        std::fs::write(dir.join("wal_1.bin"), data).unwrap();
+
+        let result = MetricsDb::open(&dir);
+        assert!(matches!(result, Err(Error::Corruption { offset, .. }) if offset == metric(1).serialize().len()));
+        assert!(dir.join("wal_1.bin").exists());
+    }
+
+    #[test]
+    fn compacts_deletes_and_snapshots() {
+        let dir = TempDir::new("db_admin");
+        let options = StorageOptions { data_dir: dir.to_path_buf(), ..StorageOptions::default() };
+        let mut db = MetricsDb::open_with(&options).unwrap();
+        let host = |timestamp, host: &str| Metric { labels: vec![(String::from("host"), host.to_string())], ..metric(timestamp) };
+        db.ingest_batch(vec![host(1, "a"), host(2, "a"), host(1, "b"), Metric { name: String::from("down"), ..metric(1) }]);
+        db.flush().unwrap();
+        assert!(dir.join("up.metricdata").exists());
+
+        let cardinality = db.cardinality(1);
+        assert_eq!(cardinality.series, 3);
+        assert_eq!(cardinality.series_by_metric, vec![(String::from("up"), 2)]);
+        assert_eq!(cardinality.values_by_label, vec![(String::from("host"), 2)]);
+
+        assert_eq!(db.delete_series(&Selector::parse("up{host=\"a\"}").unwrap(), 2..=u64::MAX).unwrap(), 1);
+        assert_eq!(db.delete_series(&Selector::name("down"), 0..=u64::MAX).unwrap(), 1);
+        assert!(!dir.join("down.metricdata").exists());
+        let snapshot = db.snapshot().unwrap();
+        drop(db);
+
+        // What was deleted isn't replayed, and the snapshot holds the same.
+        let mut db = MetricsDb::open_unrecovered(&options).unwrap();
+        assert!(db.query("up").is_err());
+        db.recover().unwrap();
+        assert_eq!(db.query("up").unwrap().len(), 2);
+        assert!(db.query("down").is_err());
+        drop(db);
+        let restored = TempDir::new("db_admin_restored");
+        std::fs::copy(dir.join(SNAPSHOT_DIR).join(snapshot).join("wal.bin"), restored.join("wal.bin")).unwrap();
+        assert_eq!(MetricsDb::open(&restored).unwrap().query("up").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn queries_by_selector() {
+        let dir = TempDir::new("db_selector");
+        let mut db = MetricsDb::open(&dir).unwrap();
+        let sample = |timestamp, host: &str| Metric {
+            timestamp,
+            value: timestamp as f64,
+            name: String::from("cpu"),
+            labels: vec![(String::from("host"), host.to_string())]
+        };
+        let result = db.ingest_batch(vec![sample(1000, "a"), sample(2000, "a"), sample(1500, "b")]);
+        assert_eq!(result.accepted, 3);
+
+        let selector = Selector::parse("cpu{host=\"a\"}").unwrap();
+        let matrix = db.query_instant(&selector, 1800, &mut QueryContext::unlimited()).unwrap();
+        assert_eq!(matrix.series.len(), 1);
+        assert_eq!(matrix.series[0].points, vec![(1800, 1000.0)]);
+
+        let matrix = db.query_instant(&Selector::name("cpu"), 1800, &mut QueryContext::unlimited()).unwrap();
+        assert_eq!(matrix.series.len(), 2);
+        assert_eq!(db.series(&Selector::all()).len(), 2);
+    }
+
+    #[test]
+    fn rejects_invalid_samples_with_codes() {
+        let dir = TempDir::new("db_validation");
+        let mut db = MetricsDb::open(&dir).unwrap();
+        db.set_validation(Validation { nan_policy: NanPolicy::Reject, ..Validation::default() });
+        let nan = Metric { value: f64::NAN, ..metric(1) };
+        let result = db.ingest_batch(vec![metric(1), nan.clone(), Metric { name: String::from("a-b"), ..metric(1) }]);
+        assert_eq!(result.accepted, 1);
+        assert_eq!(result.errors.iter().map(|e| (e.index, e.code)).collect::<Vec<_>>(), vec![(1, RejectCode::NanValue), (2, RejectCode::InvalidName)]);
+        assert!(matches!(db.ingest(nan), Err(Error::Rejected { code: RejectCode::NanValue, .. })));
+
+        // Single samples count against the series limit and are replayed like batches.
+        db.set_max_series(Some(1));
+        assert!(db.ingest(metric(2)).is_ok());
+        let other = Metric { labels: vec![(String::from("host"), String::from("a"))], ..metric(2) };
+        assert!(matches!(db.ingest(other), Err(Error::Rejected { code: RejectCode::SeriesLimit, .. })));
+        drop(db);
+        assert_eq!(MetricsDb::open(&dir).unwrap().query("up").unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,87 @@
+use std::{fmt, path::PathBuf};
+
+use crate::{models::RejectCode, query::limits::Limit};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    /// Stored data that doesn't read back. `offset` is where in `path` it went wrong.
+    Corruption { path: PathBuf, offset: usize, detail: String },
+    /// Bytes from outside (a client, a peer) that don't decode.
+    Decode(String),
+    /// A request that is well formed but makes no sense, like a range query ending before it starts.
+    Invalid(String),
+    /// `max` is the configured value; milliseconds for `Limit::Timeout`.
+    LimitExceeded { limit: Limit, max: u64 },
+    Cancelled,
+    /// A storage `Arena` with no room left for an allocation.
+    ArenaFull { capacity: usize },
+    NotFound(String),
+    /// A sample that failed validation or couldn't be stored.
+    Rejected { code: RejectCode, detail: String }
+}
+
+impl Error {
+    /// Whether retrying the same operation later could succeed. Corrupt data and bad input
+    /// stay bad; interrupted I/O, timeouts and cancellations may not happen again.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Io(e) => matches!(e.kind(),
+                std::io::ErrorKind::Interrupted | std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::StorageFull | std::io::ErrorKind::OutOfMemory),
+            Error::LimitExceeded { limit, .. } => *limit == Limit::Timeout,
+            Error::Cancelled => true,
+            Error::Rejected { code, .. } => code.is_transient(),
+            Error::Corruption { .. } | Error::Decode(_) | Error::Invalid(_) | Error::ArenaFull { .. } | Error::NotFound(_) => false
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Corruption { path, offset, detail } => write!(f, "Corrupt data in {} at offset {}: {}", path.display(), offset, detail),
+            Error::Decode(detail) => write!(f, "Decode error: {}", detail),
+            Error::Invalid(detail) => write!(f, "Invalid request: {}", detail),
+            Error::LimitExceeded { limit, max } => write!(f, "Query exceeded limit {} ({})", limit, max),
+            Error::Cancelled => f.write_str("Query was cancelled"),
+            Error::ArenaFull { capacity } => write!(f, "Arena of {} bytes is full", capacity),
+            Error::NotFound(detail) => write!(f, "Not found: {}", detail),
+            Error::Rejected { code, detail } => write!(f, "Sample rejected ({}): {}", code, detail)
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transient_errors() {
+        assert!(Error::from(std::io::Error::from(std::io::ErrorKind::Interrupted)).is_transient());
+        assert!(!Error::from(std::io::Error::from(std::io::ErrorKind::PermissionDenied)).is_transient());
+        assert!(Error::LimitExceeded { limit: Limit::Timeout, max: 10 }.is_transient());
+        assert!(!Error::LimitExceeded { limit: Limit::Series, max: 10 }.is_transient());
+        assert!(!Error::Corruption { path: PathBuf::from("wal.bin"), offset: 0, detail: String::new() }.is_transient());
+        assert!(!Error::Decode(String::new()).is_transient());
+        assert!(!Error::ArenaFull { capacity: 8 }.is_transient());
+    }
+}
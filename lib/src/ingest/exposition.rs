@@ -0,0 +1,256 @@
+use crate::models::{batch::DecodedBatch, Metric, MetricKind, SampleError};
+
+/// The two formats a `/metrics` endpoint may answer in. They differ in how timestamps are
+/// written, in the types they know, and in OpenMetrics ending with `# EOF`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The Prometheus text format, version 0.0.4. Timestamps are in milliseconds.
+    Text,
+    /// OpenMetrics 1.0. Timestamps are in seconds, exemplars follow a ` # `.
+    OpenMetrics
+}
+
+impl Format {
+    /// The format a response's `Content-Type` says it is in. Anything that isn't OpenMetrics is
+    /// read as the text format, as Prometheus does.
+    pub fn from_content_type(content_type: &str) -> Self {
+        let media_type = content_type.split(';').next().unwrap_or("").trim();
+        if media_type.eq_ignore_ascii_case("application/openmetrics-text") {
+            Format::OpenMetrics
+        } else {
+            Format::Text
+        }
+    }
+}
+
+/// A parsed exposition.
+#[derive(Debug, Default, PartialEq)]
+pub struct Exposition {
+    /// Samples and errors, indexed by their 1-based line number.
+    pub samples: DecodedBatch,
+    /// The kind of each metric family with a `# TYPE` line.
+    pub kinds: Vec<(String, MetricKind)>
+}
+
+/// Parses an exposition. Samples without a timestamp get `default_timestamp`, which should be
+/// when the scrape started.
+pub fn parse(input: &str, format: Format, default_timestamp: u64) -> Exposition {
+    let mut exposition = Exposition::default();
+    let mut eof = false;
+    let mut last_line = 0;
+    for (i, line) in input.lines().enumerate() {
+        last_line = i + 1;
+        if format == Format::Text {
+            if line.trim().is_empty() {
+                continue;
+            }
+        } else if eof {
+            exposition.samples.1.push(SampleError::new(i + 1, "Content after # EOF"));
+            continue;
+        }
+
+        let result = match line.trim_start().strip_prefix('#') {
+            Some(comment) => parse_comment(comment, format, &mut eof).map(|kind| {
+                exposition.kinds.extend(kind);
+                None
+            }),
+            None => parse_sample(line, format, default_timestamp).map(Some)
+        };
+        match result {
+            Ok(Some(metric)) => exposition.samples.0.push((i + 1, metric)),
+            Ok(None) => {},
+            Err(reason) => exposition.samples.1.push(SampleError::new(i + 1, &reason))
+        }
+    }
+    if format == Format::OpenMetrics && !eof {
+        exposition.samples.1.push(SampleError::new(last_line + 1, "Missing # EOF"));
+    }
+    exposition
+}
+
+/// `# TYPE` gives the family's kind; `# HELP`, `# UNIT` and any other comment are skipped.
+fn parse_comment(comment: &str, format: Format, eof: &mut bool) -> Result<Option<(String, MetricKind)>, String> {
+    if format == Format::OpenMetrics && comment == " EOF" {
+        *eof = true;
+        return Ok(None);
+    }
+    let mut words = comment.split_whitespace();
+    if words.next() != Some("TYPE") {
+        return Ok(None);
+    }
+    let (Some(name), Some(kind)) = (words.next(), words.next()) else {
+        return Err(String::from("# TYPE needs a metric name and a type"));
+    };
+    if !valid_name(name) {
+        return Err(format!("Invalid metric name {:?}", name));
+    }
+    let kind = match kind {
+        "counter" => MetricKind::Counter,
+        "gauge" | "info" | "stateset" => MetricKind::Gauge,
+        "histogram" | "gaugehistogram" => MetricKind::Histogram,
+        "summary" => MetricKind::Summary,
+        "untyped" | "unknown" => MetricKind::Unknown,
+        kind => return Err(format!("Unknown metric type {:?}", kind))
+    };
+    Ok(Some((name.to_string(), kind)))
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// `name{label="value",...} value [timestamp]`, with an optional exemplar in OpenMetrics.
+fn parse_sample(line: &str, format: Format, default_timestamp: u64) -> Result<Metric, String> {
+    let line = line.trim_start();
+    let name_end = line.find(|c: char| c == '{' || c.is_ascii_whitespace()).unwrap_or(line.len());
+    let name = &line[..name_end];
+    if !valid_name(name) {
+        return Err(format!("Invalid metric name {:?}", name));
+    }
+
+    let mut rest = &line[name_end..];
+    let mut labels = Vec::new();
+    if let Some(after_brace) = rest.trim_start().strip_prefix('{') {
+        let (parsed, after_labels) = parse_labels(after_brace)?;
+        labels = parsed;
+        rest = after_labels;
+    }
+    // OpenMetrics exemplars aren't stored.
+    if format == Format::OpenMetrics && let Some((sample, _)) = rest.split_once(" # ") {
+        rest = sample;
+    }
+
+    let mut fields = rest.split_whitespace();
+    let value = fields.next().ok_or("Missing value")?;
+    let value: f64 = value.parse().map_err(|_| format!("Invalid value {:?}", value))?;
+    let timestamp = match fields.next() {
+        None => default_timestamp,
+        Some(timestamp) => parse_timestamp(timestamp, format).ok_or_else(|| format!("Invalid timestamp {:?}", timestamp))?
+    };
+    if fields.next().is_some() {
+        return Err(String::from("Unexpected text after the timestamp"));
+    }
+
+    labels.retain(|(_, value)| !value.is_empty());
+    labels.sort();
+    if labels.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+        return Err(String::from("Duplicate label name"));
+    }
+    Ok(Metric { timestamp, value, name: name.to_string(), labels })
+}
+
+fn parse_timestamp(timestamp: &str, format: Format) -> Option<u64> {
+    match format {
+        Format::Text => timestamp.parse::<u64>().ok(),
+        Format::OpenMetrics => timestamp.parse::<f64>().ok()
+            .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+            .map(|seconds| (seconds * 1000.0).round() as u64)
+    }
+}
+
+type Labels = Vec<(String, String)>;
+
+/// The labels after a `{`, and whatever follows the closing `}`.
+fn parse_labels(mut input: &str) -> Result<(Labels, &str), String> {
+    let mut labels = Vec::new();
+    loop {
+        input = input.trim_start();
+        if let Some(rest) = input.strip_prefix('}') {
+            return Ok((labels, rest));
+        }
+        let (key, rest) = input.split_once('=').ok_or("Expected `label=\"value\"`")?;
+        let key = key.trim();
+        if !valid_name(key) || key.contains(':') {
+            return Err(format!("Invalid label name {:?}", key));
+        }
+        let rest = rest.trim_start().strip_prefix('"').ok_or("Label values must be quoted")?;
+        let (value, rest) = unescape_value(rest)?;
+        labels.push((key.to_string(), value));
+
+        input = rest.trim_start();
+        if let Some(rest) = input.strip_prefix(',') {
+            input = rest;
+        } else if !input.starts_with('}') {
+            return Err(String::from("Expected `,` or `}` after a label"));
+        }
+    }
+}
+
+/// A label value up to its closing quote, with `\\`, `\"` and `\n` unescaped.
+fn unescape_value(input: &str) -> Result<(String, &str), String> {
+    let mut value = String::new();
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &input[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, '\\')) => value.push('\\'),
+                Some((_, '"')) => value.push('"'),
+                Some((_, 'n')) => value.push('\n'),
+                _ => return Err(String::from("Invalid escape in a label value"))
+            },
+            c => value.push(c)
+        }
+    }
+    Err(String::from("Unterminated label value"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn parses_the_text_format() {
+        let input = "# HELP http_requests_total Requests.\n\
+                     # TYPE http_requests_total counter\n\
+                     http_requests_total{method=\"post\",code=\"200\"} 1027 1395066363000\n\
+                     http_requests_total{method=\"get\", path=\"a \\\"b\\\"\\\\c\",} 3\n\
+                     \n\
+                     # TYPE rpc_duration_seconds summary\n\
+                     rpc_duration_seconds{quantile=\"0.5\"} NaN\n\
+                     up +Inf\n\
+                     bad{x=\"1\" 2\n\
+                     9lives 1\n";
+        let exposition = parse(input, Format::Text, 42);
+        let (metrics, errors) = exposition.samples;
+        assert_eq!(metrics[0], (3, Metric {
+            timestamp: 1_395_066_363_000,
+            value: 1027.0,
+            name: String::from("http_requests_total"),
+            labels: labels(&[("code", "200"), ("method", "post")])
+        }));
+        assert_eq!(metrics[1].1.labels, labels(&[("method", "get"), ("path", "a \"b\"\\c")]));
+        assert_eq!(metrics[1].1.timestamp, 42);
+        assert!(metrics[2].1.value.is_nan());
+        assert_eq!(metrics[3].1.value, f64::INFINITY);
+        assert_eq!(errors.iter().map(|e| e.index).collect::<Vec<_>>(), vec![9, 10]);
+        assert_eq!(exposition.kinds, vec![
+            (String::from("http_requests_total"), MetricKind::Counter),
+            (String::from("rpc_duration_seconds"), MetricKind::Summary)
+        ]);
+    }
+
+    #[test]
+    fn parses_openmetrics() {
+        let input = "# TYPE jobs counter\n\
+                     # UNIT jobs seconds\n\
+                     jobs_total{queue=\"a\"} 5 1700000000.5 # {trace_id=\"x\"} 1 1700000000\n\
+                     # EOF\n";
+        let (metrics, errors) = parse(input, Format::OpenMetrics, 0).samples;
+        assert!(errors.is_empty());
+        assert_eq!(metrics[0].1.timestamp, 1_700_000_000_500);
+        assert_eq!(metrics[0].1.value, 5.0);
+
+        let (_, errors) = parse("jobs_total 1\n", Format::OpenMetrics, 0).samples;
+        assert_eq!(errors[0].reason, "Missing # EOF");
+        let (_, errors) = parse("# EOF\njobs_total 1\n", Format::OpenMetrics, 0).samples;
+        assert_eq!(errors[0].index, 2);
+        assert_eq!(Format::from_content_type("application/openmetrics-text; version=1.0.0"), Format::OpenMetrics);
+        assert_eq!(Format::from_content_type("text/plain; version=0.0.4"), Format::Text);
+    }
+}
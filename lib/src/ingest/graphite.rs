@@ -0,0 +1,228 @@
+use crate::{error::Error, ingest::{pickle::{self, PickleValue}, sanitize_name}, models::{batch::DecodedBatch, Metric, SampleError}};
+
+/// What one `*` of a template pattern turns into.
+#[derive(Debug, Clone, PartialEq)]
+enum Capture {
+    /// Part of the metric name. Several are joined with `_`.
+    Metric,
+    Label(String),
+    /// `_` drops the segment.
+    Skip
+}
+
+/// Maps dotted paths onto a metric name and labels, like `servers.*.cpu.* -> host, metric`.
+/// The pattern matches paths with the same number of segments, `*` matching any one. Each
+/// `*` is given a meaning by the list after `->`, in order: `metric` makes it part of the
+/// name, `_` drops it, and anything else is the label it becomes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    pattern: Vec<Option<String>>,
+    captures: Vec<Capture>
+}
+
+impl Template {
+    pub fn parse(spec: &str) -> Result<Self, Error> {
+        let invalid = |detail: &str| Error::Invalid(format!("Graphite template {:?}: {}", spec, detail));
+        let (pattern, captures) = spec.split_once("->").ok_or_else(|| invalid("expected `pattern -> names`"))?;
+
+        let pattern: Vec<Option<String>> = pattern.trim().split('.')
+            .map(|segment| (segment != "*").then(|| segment.to_string()))
+            .collect();
+        if pattern.iter().any(|segment| segment.as_ref().is_some_and(|s| s.is_empty() || s.contains('*'))) {
+            return Err(invalid("segments must be a literal or a whole `*`"));
+        }
+
+        let captures: Vec<Capture> = captures.split(',')
+            .map(|name| match name.trim() {
+                "metric" => Ok(Capture::Metric),
+                "_" => Ok(Capture::Skip),
+                "" => Err(invalid("empty name")),
+                label => Ok(Capture::Label(sanitize_name(label)))
+            })
+            .collect::<Result<_, _>>()?;
+        let wildcards = pattern.iter().filter(|segment| segment.is_none()).count();
+        if captures.len() != wildcards {
+            return Err(invalid(&format!("{} wildcards but {} names", wildcards, captures.len())));
+        }
+        if !captures.contains(&Capture::Metric) {
+            return Err(invalid("no wildcard is named `metric`"));
+        }
+        Ok(Template { pattern, captures })
+    }
+
+    fn apply(&self, segments: &[&str]) -> Option<(String, Vec<(String, String)>)> {
+        if segments.len() != self.pattern.len() {
+            return None;
+        }
+        let mut name = Vec::new();
+        let mut labels = Vec::new();
+        let mut captures = self.captures.iter();
+        for (segment, pattern) in segments.iter().zip(&self.pattern) {
+            match pattern {
+                Some(literal) if literal != segment => return None,
+                Some(_) => {},
+                None => match captures.next()? {
+                    Capture::Metric => name.push(*segment),
+                    Capture::Label(label) => labels.push((label.clone(), segment.to_string())),
+                    Capture::Skip => {}
+                }
+            }
+        }
+        Some((sanitize_name(&name.join("_")), labels))
+    }
+}
+
+/// The metric name and labels for `path`, from the first template that matches. Without one
+/// the whole path becomes the name. Graphite 1.1 tags (`path;tag=value`) become labels too.
+fn resolve(path: &str, templates: &[Template]) -> Result<(String, Vec<(String, String)>), String> {
+    let mut parts = path.split(';');
+    let path = parts.next().unwrap_or("");
+    if path.is_empty() || path.split('.').any(str::is_empty) {
+        return Err(format!("Invalid path {:?}", path));
+    }
+
+    let segments: Vec<&str> = path.split('.').collect();
+    let (name, mut labels) = templates.iter()
+        .find_map(|template| template.apply(&segments))
+        .unwrap_or_else(|| (sanitize_name(&segments.join("_")), Vec::new()));
+
+    for tag in parts {
+        let (key, value) = tag.split_once('=').filter(|(k, v)| !k.is_empty() && !v.is_empty())
+            .ok_or_else(|| format!("Invalid tag {:?}", tag))?;
+        labels.push((sanitize_name(key), value.to_string()));
+    }
+    labels.sort();
+    if labels.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+        return Err(String::from("Duplicate label"));
+    }
+    Ok((name, labels))
+}
+
+/// Seconds since the epoch, possibly fractional. `-1` means now, as some senders use it.
+fn timestamp(seconds: f64, now: u64) -> Result<u64, String> {
+    if seconds == -1.0 {
+        return Ok(now);
+    }
+    if !seconds.is_finite() || seconds <= 0.0 {
+        return Err(format!("Invalid timestamp {}", seconds));
+    }
+    Ok((seconds * 1000.0).round() as u64)
+}
+
+/// Parses plaintext `path value timestamp` lines. Samples and errors are indexed by their
+/// 1-based line number. A missing timestamp means now.
+pub fn parse_plaintext(input: &str, templates: &[Template], now: u64) -> DecodedBatch {
+    let mut metrics = Vec::new();
+    let mut errors = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match parse_line(line, templates, now) {
+            Ok(metric) => metrics.push((i + 1, metric)),
+            Err(reason) => errors.push(SampleError::new(i + 1, &reason))
+        }
+    }
+    (metrics, errors)
+}
+
+fn parse_line(line: &str, templates: &[Template], now: u64) -> Result<Metric, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (path, value, seconds) = match fields.as_slice() {
+        [path, value] => (*path, *value, -1.0),
+        [path, value, seconds] => (*path, *value, seconds.parse::<f64>().map_err(|_| format!("Invalid timestamp {:?}", seconds))?),
+        _ => return Err(String::from("Expected `path value timestamp`"))
+    };
+    let value: f64 = value.parse().map_err(|_| format!("Invalid value {:?}", value))?;
+    let (name, labels) = resolve(path, templates)?;
+    Ok(Metric { timestamp: timestamp(seconds, now)?, value, name, labels })
+}
+
+/// Parses the payload of one pickle protocol message: a list of `(path, (timestamp, value))`.
+/// A payload that isn't such a list fails as a whole; bad entries are indexed by position.
+pub fn parse_pickle(payload: &[u8], templates: &[Template], now: u64) -> Result<DecodedBatch, Error> {
+    let value = pickle::load(payload)?;
+    let PickleValue::List(entries) = value else {
+        return Err(Error::Decode(String::from("Pickle payload is not a list")));
+    };
+
+    let mut metrics = Vec::new();
+    let mut errors = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        match pickle_entry(entry, templates, now) {
+            Ok(metric) => metrics.push((index, metric)),
+            Err(reason) => errors.push(SampleError::new(index, &reason))
+        }
+    }
+    Ok((metrics, errors))
+}
+
+fn pickle_entry(entry: &PickleValue, templates: &[Template], now: u64) -> Result<Metric, String> {
+    let [path, point] = entry.as_items().ok_or("Entry is not a tuple")? else {
+        return Err(String::from("Entry is not a (path, (timestamp, value)) pair"));
+    };
+    let [seconds, value] = point.as_items().ok_or("Point is not a tuple")? else {
+        return Err(String::from("Point is not a (timestamp, value) pair"));
+    };
+    let path = path.as_str().ok_or("Path is not a string")?;
+    let seconds = seconds.as_f64().ok_or("Timestamp is not a number")?;
+    let value = value.as_f64().ok_or("Value is not a number")?;
+    let (name, labels) = resolve(path, templates)?;
+    Ok(Metric { timestamp: timestamp(seconds, now)?, value, name, labels })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn templates() -> Vec<Template> {
+        vec![
+            Template::parse("servers.*.cpu.* -> host, metric").unwrap(),
+            Template::parse("apps.*.*.* -> app, _, metric").unwrap()
+        ]
+    }
+
+    fn labels(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn applies_templates() {
+        let input = "servers.web01.cpu.idle 97.5 1700000000\n\
+                     apps.shop.eu.latency 12 1700000000.25\n\
+                     other.thing-1.count 3\n\
+                     disk.used;host=a;dc=eu 40 -1\n\
+                     servers.web01.cpu.idle\n\
+                     bad..path 1 1\n\
+                     x 1 yesterday";
+        let (metrics, errors) = parse_plaintext(input, &templates(), 42);
+        assert_eq!(metrics, vec![
+            (1, Metric { timestamp: 1_700_000_000_000, value: 97.5, name: String::from("idle"), labels: labels(&[("host", "web01")]) }),
+            (2, Metric { timestamp: 1_700_000_000_250, value: 12.0, name: String::from("latency"), labels: labels(&[("app", "shop")]) }),
+            (3, Metric { timestamp: 42, value: 3.0, name: String::from("other_thing_1_count"), labels: Vec::new() }),
+            (4, Metric { timestamp: 42, value: 40.0, name: String::from("disk_used"), labels: labels(&[("dc", "eu"), ("host", "a")]) })
+        ]);
+        assert_eq!(errors.iter().map(|e| e.index).collect::<Vec<_>>(), vec![5, 6, 7]);
+    }
+
+    #[test]
+    fn rejects_bad_templates() {
+        assert!(Template::parse("servers.*.cpu").is_err());
+        assert!(Template::parse("servers.*.cpu.* -> host").is_err());
+        assert!(Template::parse("servers.*.cpu.* -> host, dc").is_err());
+        assert!(Template::parse("servers.web*.cpu -> metric").is_err());
+    }
+
+    #[test]
+    fn parses_pickle_payloads() {
+        // pickle.dumps([("servers.a.cpu.user", (1700000000, 1.5)), ("c", (1700000001, 2))], protocol=2)
+        let payload = b"\x80\x02]q\x00(X\x12\x00\x00\x00servers.a.cpu.userq\x01J\x00\xf1SeG?\xf8\x00\x00\x00\x00\x00\x00\x86q\x02\x86q\x03X\x01\x00\x00\x00cq\x04J\x01\xf1SeK\x02\x86q\x05\x86q\x06e.";
+        let (metrics, errors) = parse_pickle(payload, &templates(), 0).unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(metrics[0].1, Metric { timestamp: 1_700_000_000_000, value: 1.5, name: String::from("user"), labels: labels(&[("host", "a")]) });
+        assert_eq!(metrics[1].1.name, "c");
+
+        assert!(parse_pickle(b"N.", &[], 0).is_err());
+    }
+}
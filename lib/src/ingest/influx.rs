@@ -0,0 +1,213 @@
+use crate::models::{batch::DecodedBatch, Metric, SampleError};
+
+/// The unit of line protocol timestamps. Nanoseconds unless the writer says otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Precision {
+    #[default]
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+    Minutes,
+    Hours
+}
+
+impl Precision {
+    /// Accepts both the InfluxDB 1.x (`n`, `u`) and 2.x (`ns`, `us`) spellings.
+    pub fn parse(value: &str) -> Option<Self> {
+        Some(match value {
+            "n" | "ns" => Precision::Nanoseconds,
+            "u" | "us" | "µ" | "µs" => Precision::Microseconds,
+            "ms" => Precision::Milliseconds,
+            "s" => Precision::Seconds,
+            "m" => Precision::Minutes,
+            "h" => Precision::Hours,
+            _ => return None
+        })
+    }
+
+    fn to_millis(self, timestamp: u64) -> Option<u64> {
+        match self {
+            Precision::Nanoseconds => Some(timestamp / 1_000_000),
+            Precision::Microseconds => Some(timestamp / 1000),
+            Precision::Milliseconds => Some(timestamp),
+            Precision::Seconds => timestamp.checked_mul(1000),
+            Precision::Minutes => timestamp.checked_mul(60 * 1000),
+            Precision::Hours => timestamp.checked_mul(60 * 60 * 1000)
+        }
+    }
+}
+
+/// Parses a block of line protocol. Every numeric or boolean field of a line becomes a
+/// `Metric` named `<measurement>_<field>` with the tags as labels; string fields are
+/// skipped. Samples and errors are indexed by their 1-based line number, so a bad line
+/// doesn't stop the ones after it. Lines without a timestamp are stamped `now`.
+pub fn parse(input: &str, precision: Precision, now: u64) -> DecodedBatch {
+    let mut metrics = Vec::new();
+    let mut errors = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_line(line, precision, now) {
+            Ok(parsed) => metrics.extend(parsed.into_iter().map(|metric| (i + 1, metric))),
+            Err(reason) => errors.push(SampleError::new(i + 1, &reason))
+        }
+    }
+    (metrics, errors)
+}
+
+fn parse_line(line: &str, precision: Precision, now: u64) -> Result<Vec<Metric>, String> {
+    let parts: Vec<&str> = split_unescaped(line, ' ').into_iter().filter(|part| !part.is_empty()).collect();
+    let (series, fields, timestamp) = match parts.as_slice() {
+        [series, fields] => (*series, *fields, None),
+        [series, fields, timestamp] => (*series, *fields, Some(*timestamp)),
+        [_] => return Err(String::from("Line has no fields")),
+        _ => return Err(String::from("Unexpected text after the timestamp"))
+    };
+
+    let mut series = split_unescaped(series, ',').into_iter();
+    let measurement = unescape(series.next().unwrap_or(""));
+    if measurement.is_empty() {
+        return Err(String::from("Missing measurement"));
+    }
+    let mut labels = Vec::new();
+    for tag in series {
+        let (key, value) = split_pair(tag).ok_or_else(|| format!("Invalid tag {:?}", tag))?;
+        labels.push((key, value));
+    }
+    labels.sort();
+    if labels.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+        return Err(String::from("Duplicate tag"));
+    }
+
+    let timestamp = match timestamp {
+        Some(timestamp) => {
+            let raw: u64 = timestamp.parse().map_err(|_| format!("Invalid timestamp {:?}", timestamp))?;
+            precision.to_millis(raw).ok_or_else(|| format!("Timestamp {} is out of range", timestamp))?
+        },
+        None => now
+    };
+
+    let mut metrics = Vec::new();
+    for field in split_unescaped(fields, ',') {
+        let (key, value) = split_pair(field).ok_or_else(|| format!("Invalid field {:?}", field))?;
+        let Some(value) = field_value(&value).ok_or_else(|| format!("Invalid value for field {}", key))? else {
+            continue;
+        };
+        metrics.push(Metric { timestamp, value, name: format!("{}_{}", measurement, key), labels: labels.clone() });
+    }
+    Ok(metrics)
+}
+
+/// `None` for a string field, which has no numeric value to store.
+fn field_value(value: &str) -> Option<Option<f64>> {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        return Some(None);
+    }
+    let number = match value {
+        "t" | "T" | "true" | "True" | "TRUE" => 1.0,
+        "f" | "F" | "false" | "False" | "FALSE" => 0.0,
+        _ if value.ends_with('i') => value[..value.len() - 1].parse::<i64>().ok()? as f64,
+        _ if value.ends_with('u') => value[..value.len() - 1].parse::<u64>().ok()? as f64,
+        // Rust would also take `inf` and `NaN`, which line protocol doesn't allow.
+        _ if value.bytes().all(|b| b.is_ascii_digit() || matches!(b, b'.' | b'-' | b'+' | b'e' | b'E')) => value.parse().ok()?,
+        _ => return None
+    };
+    Some(Some(number))
+}
+
+/// Splits on `separator` where it isn't escaped with a backslash or inside a quoted string.
+fn split_unescaped(input: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut quoted = false;
+    for (i, c) in input.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(&input[start..i]);
+                start = i + 1;
+            },
+            _ => {}
+        }
+    }
+    parts.push(&input[start..]);
+    parts
+}
+
+/// `key=value` with both sides unescaped. Neither side may be empty.
+fn split_pair(input: &str) -> Option<(String, String)> {
+    let mut parts = split_unescaped(input, '=').into_iter();
+    let key = unescape(parts.next()?);
+    let value = parts.collect::<Vec<_>>().join("=");
+    // Field values keep their escapes until they are known not to be strings.
+    let value = if value.starts_with('"') { value } else { unescape(&value) };
+    (!key.is_empty() && !value.is_empty()).then_some((key, value))
+}
+
+fn unescape(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' && let Some(next @ (',' | '=' | ' ' | '"' | '\\')) = chars.peek().copied() {
+            out.push(next);
+            chars.next();
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric(name: &str, labels: &[(&str, &str)], timestamp: u64, value: f64) -> Metric {
+        Metric {
+            timestamp,
+            value,
+            name: name.to_string(),
+            labels: labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        }
+    }
+
+    #[test]
+    fn parses_lines() {
+        let input = "cpu,host=a,region=eu usage=0.5,count=3i,up=true,version=\"1.2, beta\" 1700000000000000000\n\
+                     \n\
+                     # a comment\n\
+                     mem free=10u";
+        let (metrics, errors) = parse(input, Precision::Nanoseconds, 42);
+        assert!(errors.is_empty());
+        let labels = [("host", "a"), ("region", "eu")];
+        assert_eq!(metrics, vec![
+            (1, metric("cpu_usage", &labels, 1_700_000_000_000, 0.5)),
+            (1, metric("cpu_count", &labels, 1_700_000_000_000, 3.0)),
+            (1, metric("cpu_up", &labels, 1_700_000_000_000, 1.0)),
+            (4, metric("mem_free", &[], 42, 10.0))
+        ]);
+    }
+
+    #[test]
+    fn handles_escapes_and_precision() {
+        let (metrics, _) = parse(r#"disk\ io,path=C:\\,dev\=ice=sd\ a bytes\,read=1 1700000000"#, Precision::Seconds, 0);
+        assert_eq!(metrics, vec![(1, metric(r"disk io_bytes,read", &[("dev=ice", "sd a"), ("path", r"C:\")], 1_700_000_000_000, 1.0))]);
+        assert_eq!(Precision::parse("u"), Some(Precision::Microseconds));
+        assert_eq!(Precision::parse("x"), None);
+    }
+
+    #[test]
+    fn reports_errors_per_line() {
+        let input = "cpu\ncpu value=abc\ncpu,host value=1\ncpu value=1 -5\ncpu value=1 1 extra\nok value=1 1000\ncpu value=NaN";
+        let (metrics, errors) = parse(input, Precision::Milliseconds, 0);
+        assert_eq!(metrics, vec![(6, metric("ok_value", &[], 1000, 1.0))]);
+        assert_eq!(errors.iter().map(|e| e.index).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5, 7]);
+        assert_eq!(errors[0].reason, "Line has no fields");
+    }
+}
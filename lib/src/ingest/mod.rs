@@ -0,0 +1,20 @@
+pub mod exposition;
+pub mod graphite;
+pub mod influx;
+pub mod otlp;
+pub mod pickle;
+pub mod pipeline;
+pub mod protobuf;
+pub mod relabel;
+pub mod remote_write;
+pub mod snappy;
+pub mod statsd;
+
+/// Turns a dotted or otherwise free form name, as StatsD and Graphite use, into one PromQL
+/// can select: anything outside `[a-zA-Z0-9_:]` becomes `_`, and so does a leading digit.
+pub fn sanitize_name(name: &str) -> String {
+    name.chars()
+        .enumerate()
+        .map(|(i, c)| if c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit()) { c } else { '_' })
+        .collect()
+}
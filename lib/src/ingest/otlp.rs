@@ -0,0 +1,752 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{db::MetricsDb, error::Error, ingest::{protobuf::{read_varint, write_bytes_field, write_varint_field, Field, Reader}, relabel::Relabeler, sanitize_name}, models::{Metric, MetricKind, SampleError}, query::range::stale_marker};
+
+// Field numbers from opentelemetry-proto's collector/metrics/v1, metrics/v1, common/v1 and
+// resource/v1. Exemplars and start times are not stored, so their fields are skipped.
+const REQUEST_RESOURCE_METRICS: u32 = 1;
+const RESOURCE_METRICS_RESOURCE: u32 = 1;
+const RESOURCE_METRICS_SCOPE_METRICS: u32 = 2;
+const RESOURCE_ATTRIBUTES: u32 = 1;
+const SCOPE_METRICS_SCOPE: u32 = 1;
+const SCOPE_METRICS_METRICS: u32 = 2;
+const SCOPE_NAME: u32 = 1;
+const SCOPE_VERSION: u32 = 2;
+const SCOPE_ATTRIBUTES: u32 = 3;
+const METRIC_NAME: u32 = 1;
+const METRIC_GAUGE: u32 = 5;
+const METRIC_SUM: u32 = 7;
+const METRIC_HISTOGRAM: u32 = 9;
+const METRIC_EXPONENTIAL_HISTOGRAM: u32 = 10;
+const METRIC_SUMMARY: u32 = 11;
+// Gauge, Sum, Histogram, ExponentialHistogram and Summary all keep their points in field 1.
+const DATA_POINTS: u32 = 1;
+const AGGREGATION_TEMPORALITY: u32 = 2;
+const SUM_IS_MONOTONIC: u32 = 3;
+const KEY_VALUE_KEY: u32 = 1;
+const KEY_VALUE_VALUE: u32 = 2;
+const ANY_VALUE_STRING: u32 = 1;
+const ANY_VALUE_BOOL: u32 = 2;
+const ANY_VALUE_INT: u32 = 3;
+const ANY_VALUE_DOUBLE: u32 = 4;
+// Every kind of data point keeps these, where it has them, in the same place.
+const POINT_TIME: u32 = 3;
+const POINT_COUNT: u32 = 4;
+const POINT_SUM: u32 = 5;
+const NUMBER_AS_DOUBLE: u32 = 4;
+const NUMBER_AS_INT: u32 = 6;
+const NUMBER_ATTRIBUTES: u32 = 7;
+const NUMBER_FLAGS: u32 = 8;
+const HISTOGRAM_BUCKET_COUNTS: u32 = 6;
+const HISTOGRAM_EXPLICIT_BOUNDS: u32 = 7;
+const HISTOGRAM_ATTRIBUTES: u32 = 9;
+const HISTOGRAM_FLAGS: u32 = 10;
+const EXPONENTIAL_ATTRIBUTES: u32 = 1;
+const EXPONENTIAL_SCALE: u32 = 6;
+const EXPONENTIAL_ZERO_COUNT: u32 = 7;
+const EXPONENTIAL_POSITIVE: u32 = 8;
+const EXPONENTIAL_NEGATIVE: u32 = 9;
+const EXPONENTIAL_FLAGS: u32 = 10;
+const EXPONENTIAL_ZERO_THRESHOLD: u32 = 14;
+const BUCKETS_OFFSET: u32 = 1;
+const BUCKETS_COUNTS: u32 = 2;
+const SUMMARY_QUANTILE_VALUES: u32 = 6;
+const SUMMARY_ATTRIBUTES: u32 = 7;
+const SUMMARY_FLAGS: u32 = 8;
+const QUANTILE: u32 = 1;
+const QUANTILE_VALUE: u32 = 2;
+const RESPONSE_PARTIAL_SUCCESS: u32 = 1;
+const PARTIAL_SUCCESS_REJECTED: u32 = 1;
+const PARTIAL_SUCCESS_MESSAGE: u32 = 2;
+const STATUS_CODE: u32 = 1;
+const STATUS_MESSAGE: u32 = 2;
+
+const TEMPORALITY_DELTA: u64 = 1;
+/// `DataPointFlags.FLAG_NO_RECORDED_VALUE`: the series went away, which we store as a stale marker.
+const FLAG_NO_RECORDED_VALUE: u64 = 1;
+/// Exponential histograms become one `le` series per bucket, so a point may not have more.
+pub const MAX_EXPONENTIAL_BUCKETS: usize = 1024;
+
+/// One sample made from an OTLP data point.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// Which data point of the request it came from, counting from 0.
+    pub point: usize,
+    pub metric: Metric,
+    /// Its value is the change since the previous point of a delta temporality metric.
+    pub delta: bool
+}
+
+/// A decoded `ExportMetricsServiceRequest`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Export {
+    pub samples: Vec<Sample>,
+    /// Data points that couldn't become samples, indexed like `Sample::point`.
+    pub errors: Vec<SampleError>,
+    /// The kind of every metric in the request, by the name its series start with.
+    pub kinds: Vec<(String, MetricKind)>,
+    pub points: usize
+}
+
+impl Export {
+    /// Runs the samples through `relabeler` before they're made cumulative, so running totals
+    /// are kept per relabeled series.
+    pub fn relabel(&mut self, relabeler: &Relabeler) {
+        self.samples = std::mem::take(&mut self.samples).into_iter()
+            .filter_map(|sample| Some(Sample { metric: relabeler.relabel(sample.metric)?, ..sample }))
+            .collect();
+    }
+}
+
+/// Turns the samples of `metrics` that `deltas` marks into running totals that continue from
+/// the latest value of each series, so every series is stored cumulative. The pipeline does
+/// this on the writer that stores them, which sees every sample of a series in order.
+pub fn accumulate(db: &MetricsDb, metrics: &mut [Metric], deltas: &[bool]) {
+    let mut totals: HashMap<(String, Vec<(String, String)>), f64> = HashMap::new();
+    for (metric, _) in metrics.iter_mut().zip(deltas).filter(|(_, delta)| **delta) {
+        let key = (metric.name.clone(), metric.labels.clone());
+        let total = totals.get(&key).copied()
+            .or_else(|| db.latest(&metric.name, &metric.labels).map(|latest| latest.value).filter(|value| !value.is_nan()))
+            .unwrap_or(0.0);
+        metric.value += total;
+        totals.insert(key, metric.value);
+    }
+}
+
+/// Decodes an uncompressed protobuf `ExportMetricsServiceRequest`. Gauges and sums become a
+/// series each; histograms become `_bucket`, `_sum` and `_count` series like Prometheus', with
+/// exponential buckets turned into `le` bounds; summaries become `quantile` series plus `_sum`
+/// and `_count`. Resource attributes, the scope and point attributes become labels, later ones
+/// winning. A request that doesn't decode fails as a whole.
+pub fn decode(data: &[u8]) -> Result<Export, Error> {
+    let mut export = Export::default();
+    let mut request = Reader::new(data);
+    while let Some((number, field)) = request.next_field()? {
+        if number == REQUEST_RESOURCE_METRICS {
+            read_resource_metrics(field.as_bytes("resource_metrics")?, &mut export)?;
+        }
+    }
+    Ok(export)
+}
+
+type Labels = BTreeMap<String, String>;
+
+fn read_resource_metrics(data: &[u8], export: &mut Export) -> Result<(), Error> {
+    let mut labels = Labels::new();
+    let mut scopes = Vec::new();
+    let mut reader = Reader::new(data);
+    while let Some((number, field)) = reader.next_field()? {
+        match number {
+            RESOURCE_METRICS_RESOURCE => {
+                let mut resource = Reader::new(field.as_bytes("resource")?);
+                while let Some((number, field)) = resource.next_field()? {
+                    if number == RESOURCE_ATTRIBUTES {
+                        read_attribute(field, &mut labels)?;
+                    }
+                }
+            },
+            RESOURCE_METRICS_SCOPE_METRICS => scopes.push(field.as_bytes("scope_metrics")?),
+            _ => {}
+        }
+    }
+    // The resource may come after its scopes, so they wait until it's read.
+    for scope in scopes {
+        read_scope_metrics(scope, labels.clone(), export)?;
+    }
+    Ok(())
+}
+
+fn read_scope_metrics(data: &[u8], mut labels: Labels, export: &mut Export) -> Result<(), Error> {
+    let mut metrics = Vec::new();
+    let mut reader = Reader::new(data);
+    while let Some((number, field)) = reader.next_field()? {
+        match number {
+            SCOPE_METRICS_SCOPE => {
+                let mut scope = Reader::new(field.as_bytes("scope")?);
+                while let Some((number, field)) = scope.next_field()? {
+                    match number {
+                        SCOPE_NAME => set_label(&mut labels, "otel_scope_name", field.as_str("scope name")?),
+                        SCOPE_VERSION => set_label(&mut labels, "otel_scope_version", field.as_str("scope version")?),
+                        SCOPE_ATTRIBUTES => read_attribute(field, &mut labels)?,
+                        _ => {}
+                    }
+                }
+            },
+            SCOPE_METRICS_METRICS => metrics.push(field.as_bytes("metric")?),
+            _ => {}
+        }
+    }
+    for metric in metrics {
+        read_metric(metric, &labels, export)?;
+    }
+    Ok(())
+}
+
+/// Empty values mean the label isn't set, as in Prometheus.
+fn set_label(labels: &mut Labels, key: &str, value: &str) {
+    let key = sanitize_name(key);
+    if value.is_empty() {
+        labels.remove(&key);
+    } else {
+        labels.insert(key, value.to_string());
+    }
+}
+
+/// A `KeyValue`. Values that aren't a string, bool or number are skipped.
+fn read_attribute(field: Field<'_>, labels: &mut Labels) -> Result<(), Error> {
+    let mut key = "";
+    let mut value = None;
+    let mut reader = Reader::new(field.as_bytes("attribute")?);
+    while let Some((number, field)) = reader.next_field()? {
+        match number {
+            KEY_VALUE_KEY => key = field.as_str("attribute key")?,
+            KEY_VALUE_VALUE => {
+                let mut any = Reader::new(field.as_bytes("attribute value")?);
+                while let Some((number, field)) = any.next_field()? {
+                    value = match number {
+                        ANY_VALUE_STRING => Some(field.as_str("string value")?.to_string()),
+                        ANY_VALUE_BOOL => Some((field.as_varint("bool value")? != 0).to_string()),
+                        ANY_VALUE_INT => Some(field.as_int64("int value")?.to_string()),
+                        ANY_VALUE_DOUBLE => Some(field.as_double("double value")?.to_string()),
+                        _ => None
+                    };
+                }
+            },
+            _ => {}
+        }
+    }
+    if let Some(value) = value && !key.is_empty() {
+        set_label(labels, key, &value);
+    }
+    Ok(())
+}
+
+/// A name suffix, an extra label like `le` or `quantile`, and the value.
+type Series = (&'static str, Option<(&'static str, String)>, f64);
+
+/// What a data point turns into before it's given a metric name and labels.
+#[derive(Default)]
+struct Point {
+    attributes: Labels,
+    time_ns: u64,
+    flags: u64,
+    series: Vec<Series>
+}
+
+fn read_metric(data: &[u8], labels: &Labels, export: &mut Export) -> Result<(), Error> {
+    let mut name = "";
+    let mut data_type = None;
+    let mut reader = Reader::new(data);
+    while let Some((number, field)) = reader.next_field()? {
+        match number {
+            METRIC_NAME => name = field.as_str("metric name")?,
+            METRIC_GAUGE | METRIC_SUM | METRIC_HISTOGRAM | METRIC_EXPONENTIAL_HISTOGRAM | METRIC_SUMMARY => {
+                data_type = Some((number, field.as_bytes("metric data")?));
+            },
+            _ => {}
+        }
+    }
+    // A metric without data is allowed, and has nothing to store.
+    let Some((data_type, data)) = data_type else {
+        return Ok(());
+    };
+
+    let mut points = Vec::new();
+    let mut delta = false;
+    let mut monotonic = false;
+    let mut reader = Reader::new(data);
+    while let Some((number, field)) = reader.next_field()? {
+        match number {
+            DATA_POINTS => points.push(field.as_bytes("data point")?),
+            AGGREGATION_TEMPORALITY if data_type != METRIC_GAUGE && data_type != METRIC_SUMMARY => {
+                delta = field.as_varint("aggregation temporality")? == TEMPORALITY_DELTA;
+            },
+            SUM_IS_MONOTONIC if data_type == METRIC_SUM => monotonic = field.as_varint("is_monotonic")? != 0,
+            _ => {}
+        }
+    }
+
+    let name = sanitize_name(name);
+    let kind = match data_type {
+        METRIC_SUM if monotonic => MetricKind::Counter,
+        METRIC_GAUGE | METRIC_SUM => MetricKind::Gauge,
+        METRIC_HISTOGRAM | METRIC_EXPONENTIAL_HISTOGRAM => MetricKind::Histogram,
+        _ => MetricKind::Summary
+    };
+    if !name.is_empty() {
+        export.kinds.push((name.clone(), kind));
+    }
+
+    for data in points {
+        let point = match data_type {
+            METRIC_GAUGE | METRIC_SUM => read_number_point(data)?,
+            METRIC_HISTOGRAM => read_histogram_point(data)?,
+            METRIC_EXPONENTIAL_HISTOGRAM => read_exponential_point(data)?,
+            _ => read_summary_point(data)?
+        };
+        let index = export.points;
+        export.points += 1;
+        match samples(&name, labels, point, delta) {
+            Ok(samples) => export.samples.extend(samples.into_iter().map(|(metric, delta)| Sample { point: index, metric, delta })),
+            Err(reason) => export.errors.push(SampleError::new(index, &reason))
+        }
+    }
+    Ok(())
+}
+
+fn samples(name: &str, labels: &Labels, point: Result<Point, String>, delta: bool) -> Result<Vec<(Metric, bool)>, String> {
+    let point = point?;
+    if name.is_empty() {
+        return Err(String::from("Metric has no name"));
+    }
+    if point.time_ns < 1_000_000 {
+        return Err(String::from("Data point has no time_unix_nano"));
+    }
+    let no_value = point.flags & FLAG_NO_RECORDED_VALUE != 0;
+
+    let mut labels = labels.clone();
+    labels.extend(point.attributes);
+    Ok(point.series.into_iter().map(|(suffix, extra, value)| {
+        let mut labels = labels.clone();
+        if let Some((key, value)) = extra {
+            labels.insert(key.to_string(), value);
+        }
+        let metric = Metric {
+            timestamp: point.time_ns / 1_000_000,
+            value: if no_value { stale_marker() } else { value },
+            name: format!("{}{}", name, suffix),
+            labels: labels.into_iter().collect()
+        };
+        (metric, delta && !no_value)
+    }).collect())
+}
+
+/// Reads a data point, leaving its fields to `read_field`. The outer result is for bytes that
+/// don't decode, the inner one for a point that decodes but can't be stored.
+fn read_point(data: &[u8], attributes: u32, flags: u32, mut read_field: impl FnMut(u32, Field<'_>) -> Result<(), Error>) -> Result<Point, Error> {
+    let mut point = Point::default();
+    let mut reader = Reader::new(data);
+    while let Some((number, field)) = reader.next_field()? {
+        match number {
+            POINT_TIME => point.time_ns = field.as_fixed64("time_unix_nano")?,
+            number if number == attributes => read_attribute(field, &mut point.attributes)?,
+            number if number == flags => point.flags = field.as_varint("flags")?,
+            number => read_field(number, field)?
+        }
+    }
+    Ok(point)
+}
+
+fn read_number_point(data: &[u8]) -> Result<Result<Point, String>, Error> {
+    let mut value = None;
+    let mut point = read_point(data, NUMBER_ATTRIBUTES, NUMBER_FLAGS, |number, field| {
+        match number {
+            NUMBER_AS_DOUBLE => value = Some(field.as_double("as_double")?),
+            NUMBER_AS_INT => value = Some(field.as_fixed64("as_int")? as i64 as f64),
+            _ => {}
+        }
+        Ok(())
+    })?;
+    match value {
+        Some(value) => point.series.push(("", None, value)),
+        None if point.flags & FLAG_NO_RECORDED_VALUE != 0 => point.series.push(("", None, 0.0)),
+        None => return Ok(Err(String::from("Data point has no value")))
+    }
+    Ok(Ok(point))
+}
+
+fn read_histogram_point(data: &[u8]) -> Result<Result<Point, String>, Error> {
+    let (mut count, mut sum) = (0, None);
+    let mut bucket_counts = Vec::new();
+    let mut bounds = Vec::new();
+    let mut point = read_point(data, HISTOGRAM_ATTRIBUTES, HISTOGRAM_FLAGS, |number, field| {
+        match number {
+            POINT_COUNT => count = field.as_fixed64("count")?,
+            POINT_SUM => sum = Some(field.as_double("sum")?),
+            HISTOGRAM_BUCKET_COUNTS => read_packed_fixed64(field, "bucket_counts", &mut bucket_counts)?,
+            HISTOGRAM_EXPLICIT_BOUNDS => {
+                let mut bits = Vec::new();
+                read_packed_fixed64(field, "explicit_bounds", &mut bits)?;
+                bounds.extend(bits.into_iter().map(f64::from_bits));
+            },
+            _ => {}
+        }
+        Ok(())
+    })?;
+    if !bucket_counts.is_empty() && bucket_counts.len() != bounds.len() + 1 {
+        return Ok(Err(format!("{} bucket counts for {} explicit bounds", bucket_counts.len(), bounds.len())));
+    }
+
+    let mut cumulative: u64 = 0;
+    for (bound, bucket) in bounds.iter().zip(&bucket_counts) {
+        let Some(sum) = cumulative.checked_add(*bucket) else {
+            return Ok(Err(String::from("Bucket counts add up to more than 2^64")));
+        };
+        cumulative = sum;
+        point.series.push(("_bucket", Some(("le", bound.to_string())), cumulative as f64));
+    }
+    push_totals(&mut point, count as f64, sum);
+    Ok(Ok(point))
+}
+
+/// Exponential buckets become classic ones whose `le` is each bucket's upper bound: the
+/// negative buckets from the most negative up, then the zero bucket, then the positive ones.
+fn read_exponential_point(data: &[u8]) -> Result<Result<Point, String>, Error> {
+    let (mut count, mut sum, mut scale, mut zero_count, mut zero_threshold) = (0, None, 0, 0, 0.0);
+    let (mut positive, mut negative) = ((0, Vec::new()), (0, Vec::new()));
+    let mut point = read_point(data, EXPONENTIAL_ATTRIBUTES, EXPONENTIAL_FLAGS, |number, field| {
+        match number {
+            POINT_COUNT => count = field.as_fixed64("count")?,
+            POINT_SUM => sum = Some(field.as_double("sum")?),
+            EXPONENTIAL_SCALE => scale = field.as_sint("scale")?,
+            EXPONENTIAL_ZERO_COUNT => zero_count = field.as_fixed64("zero_count")?,
+            EXPONENTIAL_POSITIVE => positive = read_buckets(field.as_bytes("positive")?)?,
+            EXPONENTIAL_NEGATIVE => negative = read_buckets(field.as_bytes("negative")?)?,
+            EXPONENTIAL_ZERO_THRESHOLD => zero_threshold = field.as_double("zero_threshold")?,
+            _ => {}
+        }
+        Ok(())
+    })?;
+    if positive.1.len() + negative.1.len() > MAX_EXPONENTIAL_BUCKETS {
+        return Ok(Err(format!("More than {} exponential buckets", MAX_EXPONENTIAL_BUCKETS)));
+    }
+    if !(-10..=20).contains(&scale) {
+        return Ok(Err(format!("Invalid scale {}", scale)));
+    }
+
+    if let Err(e) = push_exponential_buckets(&mut point, scale, (zero_threshold, zero_count), &positive, &negative) {
+        return Ok(Err(e));
+    }
+    push_totals(&mut point, count as f64, sum);
+    Ok(Ok(point))
+}
+
+/// Bucket `index` holds values in (base^index, base^(index + 1)], base being 2^(2^-scale).
+/// Offsets and counts come from the sender, so they're added up checked.
+fn push_exponential_buckets(point: &mut Point, scale: i64, (zero_threshold, zero_count): (f64, u64), positive: &(i64, Vec<u64>), negative: &(i64, Vec<u64>)) -> Result<(), String> {
+    let bound = |offset: i64, i: usize| offset.checked_add(i as i64)
+        .map(|index| (index as f64 * (-scale as f64).exp2()).exp2())
+        .ok_or_else(|| format!("Bucket offset {} is out of range", offset));
+    let mut cumulative: u64 = 0;
+    let mut push = |point: &mut Point, le: f64, bucket: u64| {
+        cumulative = cumulative.checked_add(bucket).ok_or_else(|| String::from("Bucket counts add up to more than 2^64"))?;
+        if le.is_finite() {
+            point.series.push(("_bucket", Some(("le", le.to_string())), cumulative as f64));
+        }
+        Ok::<_, String>(())
+    };
+    for (i, bucket) in negative.1.iter().enumerate().rev() {
+        push(point, -bound(negative.0, i)?, *bucket)?;
+    }
+    push(point, zero_threshold, zero_count)?;
+    for (i, bucket) in positive.1.iter().enumerate() {
+        push(point, bound(positive.0, i + 1)?, *bucket)?;
+    }
+    Ok(())
+}
+
+/// The `+Inf` bucket, which holds everything, and `_sum` and `_count`. The sum is optional
+/// for histograms, as it makes no sense for some.
+fn push_totals(point: &mut Point, count: f64, sum: Option<f64>) {
+    point.series.push(("_bucket", Some(("le", String::from("+Inf"))), count));
+    if let Some(sum) = sum {
+        point.series.push(("_sum", None, sum));
+    }
+    point.series.push(("_count", None, count));
+}
+
+/// `Buckets`: the index of the first bucket and the bucket counts.
+fn read_buckets(data: &[u8]) -> Result<(i64, Vec<u64>), Error> {
+    let mut offset = 0;
+    let mut counts = Vec::new();
+    let mut reader = Reader::new(data);
+    while let Some((number, field)) = reader.next_field()? {
+        match number {
+            BUCKETS_OFFSET => offset = field.as_sint("bucket offset")?,
+            BUCKETS_COUNTS => match field {
+                Field::Bytes(packed) => {
+                    let mut pos = 0;
+                    while pos < packed.len() {
+                        counts.push(read_varint(packed, &mut pos)?);
+                    }
+                },
+                field => counts.push(field.as_varint("bucket count")?)
+            },
+            _ => {}
+        }
+    }
+    Ok((offset, counts))
+}
+
+fn read_summary_point(data: &[u8]) -> Result<Result<Point, String>, Error> {
+    let (mut count, mut sum) = (0, 0.0);
+    let mut quantiles = Vec::new();
+    let mut point = read_point(data, SUMMARY_ATTRIBUTES, SUMMARY_FLAGS, |number, field| {
+        match number {
+            POINT_COUNT => count = field.as_fixed64("count")?,
+            POINT_SUM => sum = field.as_double("sum")?,
+            SUMMARY_QUANTILE_VALUES => {
+                let (mut quantile, mut value) = (0.0, 0.0);
+                let mut reader = Reader::new(field.as_bytes("quantile_values")?);
+                while let Some((number, field)) = reader.next_field()? {
+                    match number {
+                        QUANTILE => quantile = field.as_double("quantile")?,
+                        QUANTILE_VALUE => value = field.as_double("quantile value")?,
+                        _ => {}
+                    }
+                }
+                quantiles.push((quantile, value));
+            },
+            _ => {}
+        }
+        Ok(())
+    })?;
+    for (quantile, value) in quantiles {
+        point.series.push(("", Some(("quantile", quantile.to_string())), value));
+    }
+    point.series.push(("_sum", None, sum));
+    point.series.push(("_count", None, count as f64));
+    Ok(Ok(point))
+}
+
+/// A repeated `fixed64` or `double`, which senders may pack or not.
+fn read_packed_fixed64(field: Field<'_>, name: &str, out: &mut Vec<u64>) -> Result<(), Error> {
+    match field {
+        Field::Bytes(packed) if packed.len() % 8 == 0 => {
+            out.extend(packed.chunks_exact(8).map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap())));
+            Ok(())
+        },
+        Field::Bytes(_) => Err(Error::Decode(format!("{} is not a whole number of 8 byte values", name))),
+        field => {
+            out.push(field.as_fixed64(name)?);
+            Ok(())
+        }
+    }
+}
+
+/// An `ExportMetricsServiceResponse`, which only says something when points were rejected.
+pub fn encode_response(rejected: usize, message: &str) -> Vec<u8> {
+    let mut response = Vec::new();
+    if rejected > 0 {
+        let mut partial = Vec::new();
+        write_varint_field(&mut partial, PARTIAL_SUCCESS_REJECTED, rejected as u64);
+        write_bytes_field(&mut partial, PARTIAL_SUCCESS_MESSAGE, message.as_bytes());
+        write_bytes_field(&mut response, RESPONSE_PARTIAL_SUCCESS, &partial);
+    }
+    response
+}
+
+/// A `google.rpc.Status`, which OTLP/HTTP sends back with a failed request. `code` is a gRPC
+/// status code.
+pub fn encode_status(code: u64, message: &str) -> Vec<u8> {
+    let mut status = Vec::new();
+    write_varint_field(&mut status, STATUS_CODE, code);
+    write_bytes_field(&mut status, STATUS_MESSAGE, message.as_bytes());
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::{ingest::{pipeline::{Pipeline, PipelineOptions}, protobuf::{write_double_field, write_varint}}, storage::StorageOptions, tenant::{TenantSettings, Tenants}, testing::TempDir};
+
+    const TIME: u64 = 1_700_000_000_000_000_000;
+
+    fn fixed64_field(data: &mut Vec<u8>, number: u32, value: u64) {
+        write_varint(data, ((number as u64) << 3) | 1);
+        data.extend(value.to_le_bytes());
+    }
+
+    fn attribute(key: &str, value: &str) -> Vec<u8> {
+        let mut any = Vec::new();
+        write_bytes_field(&mut any, ANY_VALUE_STRING, value.as_bytes());
+        let mut pair = Vec::new();
+        write_bytes_field(&mut pair, KEY_VALUE_KEY, key.as_bytes());
+        write_bytes_field(&mut pair, KEY_VALUE_VALUE, &any);
+        pair
+    }
+
+    fn number_point(value: f64, host: &str) -> Vec<u8> {
+        let mut point = Vec::new();
+        fixed64_field(&mut point, POINT_TIME, TIME);
+        write_double_field(&mut point, NUMBER_AS_DOUBLE, value);
+        write_bytes_field(&mut point, NUMBER_ATTRIBUTES, &attribute("host", host));
+        point
+    }
+
+    /// A request with one resource (`service.name=shop`), one scope (`meter` 1.0) and `metrics`.
+    fn request(metrics: &[Vec<u8>]) -> Vec<u8> {
+        let mut resource = Vec::new();
+        write_bytes_field(&mut resource, RESOURCE_ATTRIBUTES, &attribute("service.name", "shop"));
+        write_bytes_field(&mut resource, RESOURCE_ATTRIBUTES, &attribute("host", "overridden"));
+        let mut scope = Vec::new();
+        write_bytes_field(&mut scope, SCOPE_NAME, b"meter");
+        write_bytes_field(&mut scope, SCOPE_VERSION, b"1.0");
+        let mut scope_metrics = Vec::new();
+        write_bytes_field(&mut scope_metrics, SCOPE_METRICS_SCOPE, &scope);
+        for metric in metrics {
+            write_bytes_field(&mut scope_metrics, SCOPE_METRICS_METRICS, metric);
+        }
+        let mut resource_metrics = Vec::new();
+        write_bytes_field(&mut resource_metrics, RESOURCE_METRICS_SCOPE_METRICS, &scope_metrics);
+        write_bytes_field(&mut resource_metrics, RESOURCE_METRICS_RESOURCE, &resource);
+        let mut request = Vec::new();
+        write_bytes_field(&mut request, REQUEST_RESOURCE_METRICS, &resource_metrics);
+        request
+    }
+
+    fn metric(name: &str, data_type: u32, data: &[u8]) -> Vec<u8> {
+        let mut metric = Vec::new();
+        write_bytes_field(&mut metric, METRIC_NAME, name.as_bytes());
+        write_bytes_field(&mut metric, data_type, data);
+        metric
+    }
+
+    fn sum(points: &[Vec<u8>], delta: bool) -> Vec<u8> {
+        let mut sum = Vec::new();
+        for point in points {
+            write_bytes_field(&mut sum, DATA_POINTS, point);
+        }
+        write_varint_field(&mut sum, AGGREGATION_TEMPORALITY, if delta { 1 } else { 2 });
+        write_varint_field(&mut sum, SUM_IS_MONOTONIC, 1);
+        sum
+    }
+
+    fn value(export: &Export, name: &str, extra: Option<(&str, &str)>) -> f64 {
+        export.samples.iter()
+            .find(|s| s.metric.name == name && extra.is_none_or(|(k, v)| s.metric.labels.iter().any(|l| l.0 == k && l.1 == v)))
+            .unwrap_or_else(|| panic!("no {} {:?}", name, extra)).metric.value
+    }
+
+    #[test]
+    fn maps_sums_and_labels() {
+        let export = decode(&request(&[metric("http.requests", METRIC_SUM, &sum(&[number_point(3.0, "a")], false))])).unwrap();
+        let label = |k: &str, v: &str| (k.to_string(), v.to_string());
+        assert_eq!(export.samples, vec![Sample {
+            point: 0,
+            metric: Metric {
+                timestamp: TIME / 1_000_000,
+                value: 3.0,
+                name: String::from("http_requests"),
+                labels: vec![label("host", "a"), label("otel_scope_name", "meter"), label("otel_scope_version", "1.0"), label("service_name", "shop")]
+            },
+            delta: false
+        }]);
+        assert_eq!(export.kinds, vec![(String::from("http_requests"), MetricKind::Counter)]);
+    }
+
+    #[test]
+    fn maps_histograms_and_summaries() {
+        let mut histogram_point = Vec::new();
+        fixed64_field(&mut histogram_point, POINT_TIME, TIME);
+        fixed64_field(&mut histogram_point, POINT_COUNT, 6);
+        write_double_field(&mut histogram_point, POINT_SUM, 21.0);
+        write_bytes_field(&mut histogram_point, HISTOGRAM_BUCKET_COUNTS, &[1u64, 2, 3].map(u64::to_le_bytes).concat());
+        write_bytes_field(&mut histogram_point, HISTOGRAM_EXPLICIT_BOUNDS, &[1.0f64, 5.0].map(|b| b.to_bits().to_le_bytes()).concat());
+        let mut histogram = Vec::new();
+        write_bytes_field(&mut histogram, DATA_POINTS, &histogram_point);
+
+        // At scale 0 positive buckets 2 and 3 are (4, 8] and (8, 16], negative bucket 1 is [-4, -2).
+        // Offsets are zigzag encoded.
+        let mut positive = Vec::new();
+        write_varint_field(&mut positive, BUCKETS_OFFSET, 4);
+        write_bytes_field(&mut positive, BUCKETS_COUNTS, &[4, 1]);
+        let mut negative = Vec::new();
+        write_varint_field(&mut negative, BUCKETS_OFFSET, 2);
+        write_bytes_field(&mut negative, BUCKETS_COUNTS, &[2]);
+        let mut exponential_point = Vec::new();
+        fixed64_field(&mut exponential_point, POINT_TIME, TIME);
+        fixed64_field(&mut exponential_point, POINT_COUNT, 10);
+        fixed64_field(&mut exponential_point, EXPONENTIAL_ZERO_COUNT, 3);
+        write_bytes_field(&mut exponential_point, EXPONENTIAL_POSITIVE, &positive);
+        write_bytes_field(&mut exponential_point, EXPONENTIAL_NEGATIVE, &negative);
+        let mut exponential = Vec::new();
+        write_bytes_field(&mut exponential, DATA_POINTS, &exponential_point);
+
+        let mut quantile = Vec::new();
+        write_double_field(&mut quantile, QUANTILE, 0.99);
+        write_double_field(&mut quantile, QUANTILE_VALUE, 120.0);
+        let mut summary_point = Vec::new();
+        fixed64_field(&mut summary_point, POINT_TIME, TIME);
+        fixed64_field(&mut summary_point, POINT_COUNT, 4);
+        write_double_field(&mut summary_point, POINT_SUM, 200.0);
+        write_bytes_field(&mut summary_point, SUMMARY_QUANTILE_VALUES, &quantile);
+        let mut summary = Vec::new();
+        write_bytes_field(&mut summary, DATA_POINTS, &summary_point);
+
+        let export = decode(&request(&[
+            metric("latency", METRIC_HISTOGRAM, &histogram),
+            metric("size", METRIC_EXPONENTIAL_HISTOGRAM, &exponential),
+            metric("rpc", METRIC_SUMMARY, &summary)
+        ])).unwrap();
+        assert!(export.errors.is_empty());
+        assert_eq!(value(&export, "latency_bucket", Some(("le", "1"))), 1.0);
+        assert_eq!(value(&export, "latency_bucket", Some(("le", "5"))), 3.0);
+        assert_eq!(value(&export, "latency_bucket", Some(("le", "+Inf"))), 6.0);
+        assert_eq!(value(&export, "latency_sum", None), 21.0);
+        assert_eq!(value(&export, "size_bucket", Some(("le", "-2"))), 2.0);
+        assert_eq!(value(&export, "size_bucket", Some(("le", "0"))), 5.0);
+        assert_eq!(value(&export, "size_bucket", Some(("le", "8"))), 9.0);
+        assert_eq!(value(&export, "size_bucket", Some(("le", "16"))), 10.0);
+        assert_eq!(value(&export, "size_count", None), 10.0);
+        assert_eq!(value(&export, "rpc", Some(("quantile", "0.99"))), 120.0);
+        assert_eq!(value(&export, "rpc_count", None), 4.0);
+        assert_eq!(export.kinds.iter().map(|(_, kind)| *kind).collect::<Vec<_>>(), vec![MetricKind::Histogram, MetricKind::Histogram, MetricKind::Summary]);
+    }
+
+    #[test]
+    fn rejects_overflowing_buckets() {
+        let exponential = |offset: u64, counts: &[u8], zero_count: u64| {
+            let mut positive = Vec::new();
+            write_varint_field(&mut positive, BUCKETS_OFFSET, offset);
+            write_bytes_field(&mut positive, BUCKETS_COUNTS, counts);
+            let mut point = Vec::new();
+            fixed64_field(&mut point, POINT_TIME, TIME);
+            fixed64_field(&mut point, EXPONENTIAL_ZERO_COUNT, zero_count);
+            write_bytes_field(&mut point, EXPONENTIAL_POSITIVE, &positive);
+            let mut histogram = Vec::new();
+            write_bytes_field(&mut histogram, DATA_POINTS, &point);
+            decode(&request(&[metric("size", METRIC_EXPONENTIAL_HISTOGRAM, &histogram)])).unwrap()
+        };
+        // Zigzag encoded i64::MAX, so the index of the second bucket is past it.
+        assert_eq!(exponential(u64::MAX - 1, &[1, 1], 0).errors.len(), 1);
+        assert_eq!(exponential(0, &[1], u64::MAX).errors.len(), 1);
+        assert!(exponential(0, &[1], 1).errors.is_empty());
+
+        let mut point = Vec::new();
+        fixed64_field(&mut point, POINT_TIME, TIME);
+        write_bytes_field(&mut point, HISTOGRAM_BUCKET_COUNTS, &[u64::MAX, 1, 0].map(u64::to_le_bytes).concat());
+        write_bytes_field(&mut point, HISTOGRAM_EXPLICIT_BOUNDS, &[1.0f64, 5.0].map(|b| b.to_bits().to_le_bytes()).concat());
+        let mut histogram = Vec::new();
+        write_bytes_field(&mut histogram, DATA_POINTS, &point);
+        assert_eq!(decode(&request(&[metric("latency", METRIC_HISTOGRAM, &histogram)])).unwrap().errors.len(), 1);
+    }
+
+    #[test]
+    fn accumulates_deltas() {
+        let dir = TempDir::new("otlp_deltas");
+        let options = StorageOptions { data_dir: dir.to_path_buf(), ..StorageOptions::default() };
+        let tenants = Tenants::new(options.clone(), MetricsDb::open_with(&options).unwrap(), TenantSettings::default());
+        let tenant = tenants.default_tenant();
+        let pipeline = Pipeline::start(Arc::new(Relabeler::default()), PipelineOptions { shards: 2, ..PipelineOptions::default() }).unwrap();
+        let ingest = |body: &[u8]| pipeline.ingest_export(&tenant, decode(body).unwrap());
+
+        let body = request(&[metric("hits", METRIC_SUM, &sum(&[number_point(2.0, "a"), number_point(3.0, "a"), number_point(1.0, "b")], true))]);
+        assert_eq!(ingest(&body).accepted, 3);
+        assert_eq!(ingest(&body).accepted, 3);
+        let db = tenant.db().read().unwrap();
+        let values = |host: &str| -> Vec<f64> {
+            db.query("hits").unwrap().iter().filter(|m| m.labels.iter().any(|l| l.1 == host)).map(|m| m.value).collect()
+        };
+        assert_eq!((values("a"), values("b")), (vec![2.0, 5.0, 7.0, 10.0], vec![1.0, 2.0]));
+        assert_eq!(db.kind("hits"), MetricKind::Counter);
+        drop(db);
+
+        let mut point = Vec::new();
+        write_double_field(&mut point, NUMBER_AS_DOUBLE, 1.0);
+        let export = decode(&request(&[metric("hits", METRIC_SUM, &sum(&[point], true))])).unwrap();
+        assert_eq!(export.errors.len(), 1);
+        assert!(decode(b"\x0a\x05ab").is_err());
+    }
+}
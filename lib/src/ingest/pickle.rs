@@ -0,0 +1,371 @@
+use crate::error::Error;
+
+// A reader for the data-only part of Python's pickle format, protocols 0 to 5, which is what
+// Graphite's pickle protocol sends. Opcodes that import or call anything are refused, so a
+// payload can only ever produce plain values.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PickleValue {
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Bytes(Vec<u8>),
+    List(Vec<PickleValue>),
+    Tuple(Vec<PickleValue>)
+}
+
+impl PickleValue {
+    /// The items of a list or tuple.
+    pub fn as_items(&self) -> Option<&[PickleValue]> {
+        match self {
+            PickleValue::List(items) | PickleValue::Tuple(items) => Some(items),
+            _ => None
+        }
+    }
+
+    /// A string, or bytes that are valid utf-8 as Python 2 senders produce.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            PickleValue::String(s) => Some(s),
+            PickleValue::Bytes(bytes) => std::str::from_utf8(bytes).ok(),
+            _ => None
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            PickleValue::Int(n) => Some(*n as f64),
+            PickleValue::Float(f) => Some(*f),
+            PickleValue::Bool(b) => Some(*b as u8 as f64),
+            _ => None
+        }
+    }
+}
+
+enum Item {
+    Mark,
+    Value(PickleValue)
+}
+
+/// Memo opcodes copy whole values, so a few bytes can repeat a big value until it fills
+/// memory. Copies may add up to this many times the payload's own size before it's refused.
+const MAX_EXPANSION: usize = 4;
+
+struct Loader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    stack: Vec<Item>,
+    memo: Vec<Option<PickleValue>>,
+    /// What's left of the weight that may still be copied in or out of the memo.
+    budget: usize
+}
+
+/// Loads one pickled value. Trailing bytes after the STOP opcode are an error.
+pub fn load(data: &[u8]) -> Result<PickleValue, Error> {
+    let mut loader = Loader { data, pos: 0, stack: Vec::new(), memo: Vec::new(), budget: data.len().saturating_mul(MAX_EXPANSION) };
+    let value = loader.run()?;
+    if loader.pos != data.len() {
+        return Err(Error::Decode(format!("Trailing bytes after pickle at offset {}", loader.pos)));
+    }
+    Ok(value)
+}
+
+impl<'a> Loader<'a> {
+    fn run(&mut self) -> Result<PickleValue, Error> {
+        loop {
+            let opcode = self.take(1)?[0];
+            match opcode {
+                0x80 => { self.take(1)?; }, // PROTO
+                0x95 => { self.take(8)?; }, // FRAME
+                b'.' => return self.pop(),
+                b'(' => self.stack.push(Item::Mark),
+                b']' => self.push(PickleValue::List(Vec::new())),
+                b')' => self.push(PickleValue::Tuple(Vec::new())),
+                b'l' => {
+                    let items = self.pop_mark()?;
+                    self.push(PickleValue::List(items));
+                },
+                b't' => {
+                    let items = self.pop_mark()?;
+                    self.push(PickleValue::Tuple(items));
+                },
+                0x85..=0x87 => {
+                    let count = (opcode - 0x84) as usize;
+                    let mut items = Vec::with_capacity(count);
+                    for _ in 0..count {
+                        items.push(self.pop()?);
+                    }
+                    items.reverse();
+                    self.push(PickleValue::Tuple(items));
+                },
+                b'a' => {
+                    let item = self.pop()?;
+                    self.list()?.push(item);
+                },
+                b'e' => {
+                    let items = self.pop_mark()?;
+                    self.list()?.extend(items);
+                },
+                b'N' => self.push(PickleValue::None),
+                0x88 => self.push(PickleValue::Bool(true)),
+                0x89 => self.push(PickleValue::Bool(false)),
+                b'J' => {
+                    let value = i32::from_le_bytes(self.take(4)?.try_into().unwrap());
+                    self.push(PickleValue::Int(value as i64));
+                },
+                b'K' => {
+                    let value = self.take(1)?[0];
+                    self.push(PickleValue::Int(value as i64));
+                },
+                b'M' => {
+                    let value = u16::from_le_bytes(self.take(2)?.try_into().unwrap());
+                    self.push(PickleValue::Int(value as i64));
+                },
+                0x8a => {
+                    let len = self.take(1)?[0] as usize;
+                    let bytes = self.take(len)?;
+                    if len > 8 {
+                        return Err(Error::Decode(String::from("Pickled integer is too large")));
+                    }
+                    // Little-endian two's complement, sign extended from the last byte.
+                    let fill = if bytes.last().is_some_and(|b| b & 0x80 != 0) { 0xff } else { 0 };
+                    let mut full = [fill; 8];
+                    full[..len].copy_from_slice(bytes);
+                    self.push(PickleValue::Int(i64::from_le_bytes(full)));
+                },
+                b'I' => {
+                    let line = self.line()?;
+                    let value = match line {
+                        "00" => PickleValue::Bool(false),
+                        "01" => PickleValue::Bool(true),
+                        _ => PickleValue::Int(parse_text(line)?)
+                    };
+                    self.push(value);
+                },
+                b'L' => {
+                    let line = self.line()?;
+                    let value = parse_text(line.strip_suffix('L').unwrap_or(line))?;
+                    self.push(PickleValue::Int(value));
+                },
+                b'G' => {
+                    let value = f64::from_be_bytes(self.take(8)?.try_into().unwrap());
+                    self.push(PickleValue::Float(value));
+                },
+                b'F' => {
+                    let value = parse_text(self.line()?)?;
+                    self.push(PickleValue::Float(value));
+                },
+                b'X' | 0x8c | 0x8d => {
+                    let len = match opcode {
+                        b'X' => self.length(4)?,
+                        0x8c => self.length(1)?,
+                        _ => self.length(8)?
+                    };
+                    let value = std::str::from_utf8(self.take(len)?).map_err(|_| Error::Decode(String::from("Pickled string is not valid utf-8")))?;
+                    self.push(PickleValue::String(value.to_string()));
+                },
+                b'U' | b'T' | b'C' | b'B' => {
+                    let len = if matches!(opcode, b'U' | b'C') { self.length(1)? } else { self.length(4)? };
+                    let value = self.take(len)?.to_vec();
+                    self.push(PickleValue::Bytes(value));
+                },
+                b'S' => {
+                    let line = self.line()?;
+                    let quoted = line.len() >= 2 && (line.starts_with('\'') && line.ends_with('\'') || line.starts_with('"') && line.ends_with('"'));
+                    if !quoted {
+                        return Err(Error::Decode(String::from("Pickled string is not quoted")));
+                    }
+                    // Escapes are left as they are; Graphite paths don't need them.
+                    self.push(PickleValue::Bytes(line.as_bytes()[1..line.len() - 1].to_vec()));
+                },
+                b'V' => {
+                    let line = self.line()?.to_string();
+                    self.push(PickleValue::String(line));
+                },
+                b'p' => {
+                    let index = parse_text(self.line()?)?;
+                    self.memoize(index)?;
+                },
+                b'q' => {
+                    let index = self.length(1)?;
+                    self.memoize(index)?;
+                },
+                b'r' => {
+                    let index = self.length(4)?;
+                    self.memoize(index)?;
+                },
+                0x94 => self.memoize(self.memo.len())?,
+                b'g' | b'h' | b'j' => {
+                    let index = match opcode {
+                        b'g' => parse_text(self.line()?)?,
+                        b'h' => self.length(1)?,
+                        _ => self.length(4)?
+                    };
+                    let value = match self.memo.get(index) {
+                        Some(Some(value)) => value,
+                        _ => return Err(Error::Decode(format!("Pickle memo {} is not set", index)))
+                    };
+                    self.budget = spend(self.budget, value)?;
+                    self.push(value.clone());
+                },
+                _ => return Err(Error::Decode(format!("Unsupported pickle opcode 0x{:02x} at offset {}", opcode, self.pos - 1)))
+            }
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.data.len())
+            .ok_or_else(|| Error::Decode(String::from("Truncated pickle")))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn length(&mut self, width: usize) -> Result<usize, Error> {
+        let bytes = self.take(width)?;
+        let value = bytes.iter().rev().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+        usize::try_from(value).map_err(|_| Error::Decode(String::from("Pickle length is too large")))
+    }
+
+    /// Text opcodes are terminated by a newline.
+    fn line(&mut self) -> Result<&'a str, Error> {
+        let rest = &self.data[self.pos..];
+        let len = rest.iter().position(|b| *b == b'\n').ok_or_else(|| Error::Decode(String::from("Truncated pickle")))?;
+        self.pos += len + 1;
+        std::str::from_utf8(&rest[..len]).map_err(|_| Error::Decode(String::from("Pickle text is not valid utf-8")))
+    }
+
+    fn push(&mut self, value: PickleValue) {
+        self.stack.push(Item::Value(value));
+    }
+
+    fn pop(&mut self) -> Result<PickleValue, Error> {
+        match self.stack.pop() {
+            Some(Item::Value(value)) => Ok(value),
+            _ => Err(Error::Decode(String::from("Pickle stack underflow")))
+        }
+    }
+
+    /// Everything pushed since the last mark, in order.
+    fn pop_mark(&mut self) -> Result<Vec<PickleValue>, Error> {
+        let mark = self.stack.iter().rposition(|item| matches!(item, Item::Mark))
+            .ok_or_else(|| Error::Decode(String::from("Pickle mark not found")))?;
+        let items = self.stack.split_off(mark + 1);
+        self.stack.pop();
+        Ok(items.into_iter().map(|item| match item {
+            Item::Value(value) => value,
+            Item::Mark => PickleValue::None
+        }).collect())
+    }
+
+    fn list(&mut self) -> Result<&mut Vec<PickleValue>, Error> {
+        match self.stack.last_mut() {
+            Some(Item::Value(PickleValue::List(items))) => Ok(items),
+            _ => Err(Error::Decode(String::from("Pickle append to something that is not a list")))
+        }
+    }
+
+    fn memoize(&mut self, index: usize) -> Result<(), Error> {
+        let value = match self.stack.last() {
+            Some(Item::Value(value)) => {
+                self.budget = spend(self.budget, value)?;
+                value.clone()
+            },
+            _ => return Err(Error::Decode(String::from("Nothing to memoize")))
+        };
+        // Indices come from the sender, so don't let one allocate a huge table.
+        if index > self.data.len() {
+            return Err(Error::Decode(format!("Pickle memo index {} is out of range", index)));
+        }
+        if self.memo.len() <= index {
+            self.memo.resize(index + 1, None);
+        }
+        self.memo[index] = Some(value);
+        Ok(())
+    }
+}
+
+/// Takes the weight of a value about to be copied out of `budget`.
+fn spend(budget: usize, value: &PickleValue) -> Result<usize, Error> {
+    budget.checked_sub(weight(value)).ok_or_else(|| Error::Decode(String::from("Pickle memo copies are too large for the payload")))
+}
+
+/// Roughly the memory a value takes: one per value plus the length of strings and bytes.
+/// Walked without recursion, since nesting is only bounded by the payload size.
+fn weight(value: &PickleValue) -> usize {
+    let mut weight = 0;
+    let mut pending = vec![value];
+    while let Some(value) = pending.pop() {
+        weight += 1 + match value {
+            PickleValue::String(s) => s.len(),
+            PickleValue::Bytes(bytes) => bytes.len(),
+            PickleValue::List(items) | PickleValue::Tuple(items) => {
+                pending.extend(items);
+                0
+            },
+            _ => 0
+        };
+    }
+    weight
+}
+
+fn parse_text<T: std::str::FromStr>(text: &str) -> Result<T, Error> {
+    text.trim().parse().map_err(|_| Error::Decode(format!("Invalid number {:?} in pickle", text)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric(path: &str, timestamp: PickleValue, value: PickleValue) -> PickleValue {
+        PickleValue::Tuple(vec![PickleValue::String(path.to_string()), PickleValue::Tuple(vec![timestamp, value])])
+    }
+
+    #[test]
+    fn loads_protocol_2_and_4() {
+        // pickle.dumps([("a.b", (1700000000, 1.5)), ("c", (1700000001, 2))], protocol=2)
+        let data = b"\x80\x02]q\x00(X\x03\x00\x00\x00a.bq\x01J\x00\xf1SeG?\xf8\x00\x00\x00\x00\x00\x00\x86q\x02\x86q\x03X\x01\x00\x00\x00cq\x04J\x01\xf1SeK\x02\x86q\x05\x86q\x06e.";
+        assert_eq!(load(data).unwrap(), PickleValue::List(vec![
+            metric("a.b", PickleValue::Int(1_700_000_000), PickleValue::Float(1.5)),
+            metric("c", PickleValue::Int(1_700_000_001), PickleValue::Int(2))
+        ]));
+
+        // pickle.dumps([("a", (1, -1))], protocol=4), with MEMOIZE and FRAME.
+        let data = b"\x80\x04\x95\x13\x00\x00\x00\x00\x00\x00\x00]\x94\x8c\x01a\x94K\x01J\xff\xff\xff\xff\x86\x94\x86\x94a.";
+        assert_eq!(load(data).unwrap(), PickleValue::List(vec![metric("a", PickleValue::Int(1), PickleValue::Int(-1))]));
+    }
+
+    #[test]
+    fn loads_protocol_0() {
+        // pickle.dumps([("a", (1, 2.5))], protocol=0)
+        let data = b"(lp0\n(Va\np1\n(I1\nF2.5\ntp2\ntp3\na.";
+        assert_eq!(load(data).unwrap(), PickleValue::List(vec![metric("a", PickleValue::Int(1), PickleValue::Float(2.5))]));
+        // The same from Python 2, where the path is a byte string.
+        let data = b"(lp0\n(S'a'\np1\n(I1\nF2.5\ntp2\ntp3\na.";
+        assert_eq!(load(data).unwrap().as_items().unwrap()[0].as_items().unwrap()[0], PickleValue::Bytes(b"a".to_vec()));
+    }
+
+    #[test]
+    fn refuses_code_and_garbage() {
+        // GLOBAL 'os system', which would import a callable.
+        assert!(load(b"cos\nsystem\n.").is_err());
+        assert!(load(b"\x80\x02]").is_err());
+        assert!(load(b"a.").is_err());
+        assert!(load(b"N.extra").is_err());
+        assert!(load(b"Nh\x05.").is_err());
+    }
+
+    #[test]
+    fn refuses_memo_blowup() {
+        // Each round builds a list of two copies of memo 0 and memoizes it as 0 again, which
+        // doubles it: 40 rounds would be 2^40 values.
+        let mut data = b"]q\x00".to_vec();
+        for _ in 0..40 {
+            data.extend_from_slice(b"(h\x00h\x00lq\x00");
+        }
+        data.push(b'.');
+        assert!(load(&data).is_err());
+    }
+}
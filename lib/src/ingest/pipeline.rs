@@ -0,0 +1,413 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+
+use crate::{db::{self, MetricsDb}, error::Error, ingest::{otlp::{self, Export}, relabel::Relabeler}, models::{batch::DecodedBatch, BatchResult, Metric, MetricKind, RejectCode, SampleError}, telemetry, tenant::Tenant};
+
+/// Samples in a storage write, from a single sample up to a full batch.
+const BATCH_BUCKETS: &[f64] = &[1.0, 10.0, 100.0, 1000.0, 10_000.0, 100_000.0];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineOptions {
+    /// Storage writers, each with its own queue.
+    pub shards: usize,
+    /// Samples queued across every shard before submissions are turned away.
+    pub capacity: usize,
+    /// Samples a writer stores under one database lock.
+    pub max_batch: usize
+}
+
+impl Default for PipelineOptions {
+    fn default() -> Self {
+        PipelineOptions { shards: 4, capacity: 100_000, max_batch: 10_000 }
+    }
+}
+
+/// Called with the outcome of a submission, on whichever writer finishes it last.
+type Done = Box<dyn FnOnce(BatchResult) + Send>;
+
+/// A submission being stored, which every shard it was split across reports back to.
+struct Submission {
+    state: Mutex<(BatchResult, usize, Option<Done>)>
+}
+
+impl Submission {
+    /// Merges the result of one part, by the indices the part's samples had in the submission.
+    fn finish(&self, result: BatchResult, indices: &[usize]) {
+        let done = {
+            let mut state = match self.state.lock() {
+                Ok(state) => state,
+                Err(poisoned) => poisoned.into_inner()
+            };
+            state.0.merge(result, indices);
+            state.1 -= 1;
+            if state.1 > 0 {
+                return;
+            }
+            state.2.take().map(|done| (done, std::mem::take(&mut state.0)))
+        };
+        if let Some((done, result)) = done {
+            done(result);
+        }
+    }
+}
+
+/// The samples of a submission that belong to one shard.
+struct Part {
+    /// `None` for the markers `wait` sends.
+    tenant: Option<Arc<Tenant>>,
+    metrics: Vec<Metric>,
+    indices: Vec<usize>,
+    /// Which of `metrics` are OTLP deltas, to be made running totals before they're stored.
+    deltas: Vec<bool>,
+    /// Kinds to record for the submission's metrics, sent along with every part.
+    kinds: Vec<(String, MetricKind)>,
+    /// The server's own metrics, stored with `MetricsDb::ingest_own`.
+    own: bool,
+    submission: Arc<Submission>
+}
+
+/// Carries samples from protocol handlers to the databases of their tenants. Handlers relabel
+/// and submit from their own task, and a fixed set of writer threads stores what they're given. Each series
+/// always goes to the same writer, so its samples are stored in the order they were submitted.
+/// A writer stores everything waiting in its queue, up to `max_batch` samples, under one lock
+/// of each tenant's database, so locks are taken once per batch however many connections
+/// there are. When `capacity` samples are waiting, submissions are rejected as
+/// `RejectCode::Overloaded` until the writers catch up, and a tenant over its ingest rate
+/// gets `RejectCode::RateLimited`.
+///
+/// That count of samples is what bounds the queues. Every part sent holds at least one sample
+/// reserved against `capacity`, so no shard's channel holds more than `capacity` parts besides
+/// the markers `wait` sends. A `sync_channel` of that size would allocate every slot up front
+/// for each shard, and a send that found it full would stall the task submitting.
+pub struct Pipeline {
+    relabeler: Arc<Relabeler>,
+    shards: Vec<mpsc::Sender<Part>>,
+    queued: Arc<AtomicUsize>,
+    capacity: usize,
+    writers: Vec<JoinHandle<()>>
+}
+
+impl Pipeline {
+    /// Fails if a writer thread can't be started, stopping any that were.
+    pub fn start(relabeler: Arc<Relabeler>, options: PipelineOptions) -> Result<Self, Error> {
+        let mut pipeline = Pipeline { relabeler, shards: Vec::new(), queued: Arc::new(AtomicUsize::new(0)), capacity: options.capacity, writers: Vec::new() };
+        for shard in 0..options.shards.max(1) {
+            let (sender, receiver) = mpsc::channel();
+            let queued = pipeline.queued.clone();
+            let writer = std::thread::Builder::new()
+                .name(format!("ingest-{}", shard))
+                .spawn(move || write(&receiver, &queued, options.max_batch.max(1)))?;
+            pipeline.shards.push(sender);
+            pipeline.writers.push(writer);
+        }
+        Ok(pipeline)
+    }
+
+    pub fn relabeler(&self) -> &Relabeler {
+        &self.relabeler
+    }
+
+    /// Samples waiting to be stored.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Relabels `batch` and queues what's left for `tenant`. `done` gets the result, with
+    /// errors by the index each sample had in `batch`, once every sample is stored or
+    /// rejected. It's called straight away if the samples can't be taken, or if there are none.
+    pub fn submit(&self, tenant: &Arc<Tenant>, batch: DecodedBatch, done: impl FnOnce(BatchResult) + Send + 'static) {
+        let (decoded, errors) = self.relabeler.apply(batch);
+        let samples = decoded.into_iter().map(|(index, metric)| (index, metric, false)).collect();
+        self.queue(tenant, samples, errors, Vec::new(), false, Box::new(done));
+    }
+
+    /// `submit` for an OTLP export, with errors indexed by data point. Its delta samples are
+    /// made running totals by the writer that stores them, and its metric kinds are recorded.
+    pub fn submit_export(&self, tenant: &Arc<Tenant>, mut export: Export, done: impl FnOnce(BatchResult) + Send + 'static) {
+        export.relabel(&self.relabeler);
+        let samples = export.samples.into_iter().map(|sample| (sample.point, sample.metric, sample.delta)).collect();
+        self.queue(tenant, samples, export.errors, export.kinds, false, Box::new(done));
+    }
+
+    /// Splits relabeled samples, each with its index and whether it's a delta, across the shards.
+    fn queue(&self, tenant: &Arc<Tenant>, samples: Vec<(usize, Metric, bool)>, errors: Vec<SampleError>, kinds: Vec<(String, MetricKind)>, own: bool, done: Done) {
+        let mut result = BatchResult { accepted: 0, rejected: errors.len() as u32, errors };
+        if samples.is_empty() {
+            return done(result);
+        }
+        if !self.reserve(samples.len()) {
+            let reason = format!("{} samples are waiting to be stored, retry later", self.queued());
+            reject_all(&mut result, &samples, RejectCode::Overloaded, &reason);
+            return done(result);
+        }
+        if !own && !tenant.admit(samples.len()) {
+            self.release(samples.len());
+            let reason = format!("Over the ingest rate of {} samples per second, retry later", tenant.limits().ingest_rate.unwrap_or(0));
+            reject_all(&mut result, &samples, RejectCode::RateLimited, &reason);
+            return done(result);
+        }
+
+        let mut parts: Vec<(Vec<Metric>, Vec<usize>, Vec<bool>)> = vec![(Vec::new(), Vec::new(), Vec::new()); self.shards.len()];
+        for (index, metric, delta) in samples {
+            let part = &mut parts[shard(&metric, self.shards.len())];
+            part.0.push(metric);
+            part.1.push(index);
+            part.2.push(delta);
+        }
+        let remaining = parts.iter().filter(|(metrics, _, _)| !metrics.is_empty()).count();
+        let submission = Arc::new(Submission { state: Mutex::new((result, remaining, Some(done))) });
+        for (shard, (metrics, indices, deltas)) in parts.into_iter().enumerate() {
+            if !metrics.is_empty() {
+                // Writers only stop once the pipeline is dropped, so this always arrives.
+                let _ = self.shards[shard].send(Part { tenant: Some(tenant.clone()), metrics, indices, deltas, kinds: kinds.clone(), own, submission: submission.clone() });
+            }
+        }
+    }
+
+    /// `submit`, waiting for the result.
+    pub fn ingest(&self, tenant: &Arc<Tenant>, batch: DecodedBatch) -> BatchResult {
+        let (sender, receiver) = mpsc::channel();
+        self.submit(tenant, batch, move |result| {
+            let _ = sender.send(result);
+        });
+        receiver.recv().unwrap_or_default()
+    }
+
+    /// `submit_export`, waiting for the result.
+    pub fn ingest_export(&self, tenant: &Arc<Tenant>, export: Export) -> BatchResult {
+        let (sender, receiver) = mpsc::channel();
+        self.submit_export(tenant, export, move |result| {
+            let _ = sender.send(result);
+        });
+        receiver.recv().unwrap_or_default()
+    }
+
+    /// `submit` for samples that didn't come out of a request, indexed by their position.
+    pub fn submit_metrics(&self, tenant: &Arc<Tenant>, metrics: Vec<Metric>, done: impl FnOnce(BatchResult) + Send + 'static) {
+        self.submit(tenant, (metrics.into_iter().enumerate().collect(), Vec::new()), done);
+    }
+
+    /// `submit_metrics` for the server's own metrics, recording `kinds` for them. They aren't
+    /// relabeled, and count against neither the tenant's ingest rate nor its series limit.
+    pub fn submit_own(&self, tenant: &Arc<Tenant>, metrics: Vec<Metric>, kinds: Vec<(String, MetricKind)>, done: impl FnOnce(BatchResult) + Send + 'static) {
+        let samples = metrics.into_iter().enumerate().map(|(index, metric)| (index, metric, false)).collect();
+        self.queue(tenant, samples, Vec::new(), kinds, true, Box::new(done));
+    }
+
+    /// Blocks until every sample submitted before is stored or rejected.
+    pub fn wait(&self) {
+        let (sender, receiver) = mpsc::channel();
+        let submission = Arc::new(Submission { state: Mutex::new((BatchResult::default(), self.shards.len(), Some(Box::new(move |_| {
+            let _ = sender.send(());
+        })))) });
+        for shard in &self.shards {
+            let _ = shard.send(Part { tenant: None, metrics: Vec::new(), indices: Vec::new(), deltas: Vec::new(), kinds: Vec::new(), own: false, submission: submission.clone() });
+        }
+        let _ = receiver.recv();
+    }
+
+    /// Counts `samples` as queued unless that would go over capacity. A submission is let in
+    /// whole even when it's bigger than the capacity, as long as nothing else is waiting.
+    fn reserve(&self, samples: usize) -> bool {
+        let reserved = self.queued.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+            (queued == 0 || queued + samples <= self.capacity).then_some(queued + samples)
+        });
+        if reserved.is_ok() {
+            queued_gauge().add(samples as f64);
+        }
+        reserved.is_ok()
+    }
+
+    fn release(&self, samples: usize) {
+        self.queued.fetch_sub(samples, Ordering::Relaxed);
+        queued_gauge().add(-(samples as f64));
+    }
+}
+
+impl Drop for Pipeline {
+    /// Stores what's still queued before returning.
+    fn drop(&mut self) {
+        self.shards.clear();
+        for writer in self.writers.drain(..) {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn reject_all(result: &mut BatchResult, samples: &[(usize, Metric, bool)], code: RejectCode, reason: &str) {
+    result.rejected += samples.len() as u32;
+    result.errors.extend(samples.iter().map(|(index, _, _)| SampleError::with_code(*index, code, reason)));
+    result.errors.sort_by_key(|e| e.index);
+    db::record_rejection(code, samples.len() as u64);
+}
+
+fn queued_gauge() -> Arc<telemetry::Gauge> {
+    telemetry::global().gauge("metrichouse_ingest_queued_samples", "Samples waiting for an ingest writer.", &[])
+}
+
+/// Series hash to the same shard whatever order their labels come in.
+fn shard(metric: &Metric, shards: usize) -> usize {
+    let mut labels: Vec<&(String, String)> = metric.labels.iter().collect();
+    labels.sort();
+    let mut hasher = DefaultHasher::new();
+    metric.name.hash(&mut hasher);
+    labels.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+/// A writer's loop: takes whatever has queued up, stores it as one batch per tenant, and
+/// hands each submission its share of the result.
+fn write(receiver: &mpsc::Receiver<Part>, queued: &AtomicUsize, max_batch: usize) {
+    let batch_size = telemetry::global().histogram("metrichouse_ingest_batch_samples", "Samples stored per ingest writer batch.", &[], BATCH_BUCKETS);
+    while let Ok(part) = receiver.recv() {
+        let mut samples = part.metrics.len();
+        let mut parts = vec![part];
+        while samples < max_batch && let Ok(part) = receiver.try_recv() {
+            samples += part.metrics.len();
+            parts.push(part);
+        }
+
+        let mut results: Vec<BatchResult> = parts.iter().map(|_| BatchResult::default()).collect();
+        let mut tenants: Vec<(Arc<Tenant>, bool)> = Vec::new();
+        for part in &parts {
+            if let Some(tenant) = &part.tenant && !tenants.iter().any(|(seen, own)| Arc::ptr_eq(seen, tenant) && *own == part.own) {
+                tenants.push((tenant.clone(), part.own));
+            }
+        }
+        for (tenant, own) in tenants {
+            let members: Vec<usize> = (0..parts.len())
+                .filter(|i| parts[*i].own == own && parts[*i].tenant.as_ref().is_some_and(|other| Arc::ptr_eq(other, &tenant)))
+                .collect();
+            let metrics: Vec<Metric> = members.iter().flat_map(|i| std::mem::take(&mut parts[*i].metrics)).collect();
+            let deltas: Vec<bool> = members.iter().flat_map(|i| std::mem::take(&mut parts[*i].deltas)).collect();
+            let kinds: Vec<(String, MetricKind)> = members.iter().flat_map(|i| std::mem::take(&mut parts[*i].kinds)).collect();
+            let lengths: Vec<usize> = members.iter().map(|i| parts[*i].indices.len()).collect();
+            for (i, result) in members.into_iter().zip(split(store(tenant.db(), metrics, &deltas, &kinds, own), &lengths)) {
+                results[i] = result;
+            }
+        }
+        queued.fetch_sub(samples, Ordering::Relaxed);
+        queued_gauge().add(-(samples as f64));
+        if samples > 0 {
+            batch_size.observe(samples as f64);
+        }
+
+        for (part, result) in parts.iter().zip(results) {
+            part.submission.finish(result, &part.indices);
+        }
+    }
+}
+
+fn store(db: &RwLock<MetricsDb>, mut metrics: Vec<Metric>, deltas: &[bool], kinds: &[(String, MetricKind)], own: bool) -> BatchResult {
+    match db.write() {
+        Ok(mut db) => {
+            for (name, kind) in kinds {
+                db.set_kind(name, *kind);
+            }
+            otlp::accumulate(&db, &mut metrics, deltas);
+            if own { db.ingest_own(metrics) } else { db.ingest_batch(metrics) }
+        },
+        Err(_) => BatchResult {
+            accepted: 0,
+            rejected: metrics.len() as u32,
+            // Nothing was appended to the WAL, which is the closest there is to say.
+            errors: (0..metrics.len()).map(|index| SampleError::with_code(index, RejectCode::WalAppendFailed, "Database lock is poisoned")).collect()
+        }
+    }
+}
+
+/// Splits a batch's result into one per part, each indexed from zero.
+fn split(result: BatchResult, lengths: &[usize]) -> Vec<BatchResult> {
+    let mut errors = result.errors.into_iter().peekable();
+    let mut start = 0;
+    lengths.iter().map(|length| {
+        let end = start + length;
+        let mut part = BatchResult::default();
+        while let Some(error) = errors.next_if(|error| (error.index as usize) < end) {
+            part.errors.push(SampleError { index: error.index - start as u32, ..error });
+        }
+        part.rejected = part.errors.len() as u32;
+        part.accepted = (*length as u32).saturating_sub(part.rejected);
+        start = end;
+        part
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ingest::relabel::{Action, RelabelConfig}, models::Validation, models::validation::NanPolicy, storage::StorageOptions, tenant::{TenantLimits, TenantSettings, Tenants}, testing::TempDir};
+
+    fn metric(name: &str, host: &str, timestamp: u64) -> Metric {
+        Metric { timestamp, value: timestamp as f64, name: name.to_string(), labels: vec![(String::from("host"), host.to_string())] }
+    }
+
+    fn tenants(test: &str, settings: TenantSettings) -> (TempDir, Tenants) {
+        let dir = TempDir::new(&format!("pipeline_{}", test));
+        let options = StorageOptions { data_dir: dir.to_path_buf(), ..StorageOptions::default() };
+        (dir, Tenants::new(options.clone(), MetricsDb::open_with(&options).unwrap(), settings))
+    }
+
+    #[test]
+    fn stores_in_order_and_reports_by_index() {
+        let (_dir, tenants) = tenants("order", TenantSettings { validation: Validation { nan_policy: NanPolicy::Reject, ..Validation::default() }, ..TenantSettings::default() });
+        let (tenant, other) = (tenants.default_tenant(), tenants.get("other").unwrap());
+        let relabeler = Arc::new(Relabeler::new(vec![RelabelConfig {
+            source_labels: vec![String::from("host")], regex: String::from("dropped"), action: Action::Drop, ..RelabelConfig::default()
+        }]).unwrap());
+        let pipeline = Pipeline::start(relabeler, PipelineOptions { shards: 3, capacity: 1000, max_batch: 7 }).unwrap();
+
+        let nan = Metric { value: f64::NAN, ..metric("cpu", "b", 1) };
+        let batch = vec![(0, metric("cpu", "a", 1)), (1, nan), (2, metric("cpu", "dropped", 1)), (3, metric("mem", "a", 1))];
+        let result = pipeline.ingest(&tenant, (batch, vec![SampleError::new(4, "Bad line")]));
+        assert_eq!((result.accepted, result.rejected), (2, 2));
+        assert_eq!(result.errors.iter().map(|e| (e.index, e.code)).collect::<Vec<_>>(), vec![(1, RejectCode::NanValue), (4, RejectCode::Decode)]);
+
+        for timestamp in 2..=50 {
+            pipeline.submit(&tenant, (vec![(0, metric("cpu", "a", timestamp)), (1, metric("cpu", &format!("h{}", timestamp % 5), timestamp))], Vec::new()), |_| {});
+            pipeline.submit(&other, (vec![(0, metric("disk", "a", timestamp))], Vec::new()), |_| {});
+        }
+        pipeline.wait();
+        assert_eq!(pipeline.queued(), 0);
+        let timestamps: Vec<u64> = tenant.db().read().unwrap().query("cpu").unwrap().iter()
+            .filter(|m| m.labels[0].1 == "a")
+            .map(|m| m.timestamp)
+            .collect();
+        assert_eq!(timestamps, (1..=50).collect::<Vec<_>>());
+        assert_eq!(other.db().read().unwrap().query("disk").unwrap().len(), 49);
+        assert!(other.db().read().unwrap().query("cpu").is_err());
+        assert!(tenant.db().read().unwrap().query("disk").is_err());
+    }
+
+    #[test]
+    fn turns_submissions_away_when_full() {
+        let limits = TenantLimits { ingest_rate: Some(3), ..TenantLimits::default() };
+        let (_dir, tenants) = tenants("full", TenantSettings { overrides: vec![(String::from("slow"), limits)], ..TenantSettings::default() });
+        let tenant = tenants.default_tenant();
+        let pipeline = Pipeline::start(Arc::new(Relabeler::default()), PipelineOptions { shards: 1, capacity: 2, max_batch: 10 }).unwrap();
+
+        // Holding the lock keeps the writer from draining the queue.
+        let guard = tenant.db().write().unwrap();
+        pipeline.submit(&tenant, (vec![(0, metric("up", "a", 1))], Vec::new()), |_| {});
+        pipeline.submit(&tenant, (vec![(0, metric("up", "b", 1))], Vec::new()), |_| {});
+        let result = pipeline.ingest(&tenant, (vec![(0, metric("up", "c", 1)), (1, metric("up", "d", 1))], Vec::new()));
+        assert_eq!(result.rejected, 2);
+        assert!(result.errors.iter().all(|e| e.code == RejectCode::Overloaded && e.code.is_transient()));
+        drop(guard);
+
+        pipeline.wait();
+        assert_eq!(pipeline.ingest(&tenant, (vec![(0, metric("up", "c", 1))], Vec::new())).accepted, 1);
+        assert_eq!(tenant.db().read().unwrap().query("up").unwrap().len(), 3);
+        assert_eq!(split(BatchResult { accepted: 2, rejected: 1, errors: vec![SampleError::new(2, "x")] }, &[2, 1])[1].errors[0].index, 0);
+
+        let slow = tenants.get("slow").unwrap();
+        let batch = || (vec![(0, metric("up", "a", 1)), (1, metric("up", "b", 1))], Vec::new());
+        assert_eq!(pipeline.ingest(&slow, batch()).accepted, 2);
+        let result = pipeline.ingest(&slow, batch());
+        assert_eq!(result.errors.iter().map(|e| e.code).collect::<Vec<_>>(), vec![RejectCode::RateLimited; 2]);
+        assert_eq!(pipeline.queued(), 0);
+    }
+}
@@ -0,0 +1,181 @@
+use crate::error::Error;
+
+// Just enough of the protobuf wire format to read messages whose schema we know, and to
+// write them back in tests. Unknown fields are skipped, as protobuf requires.
+
+pub const VARINT: u8 = 0;
+pub const FIXED64: u8 = 1;
+pub const LENGTH_DELIMITED: u8 = 2;
+pub const FIXED32: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32)
+}
+
+impl<'a> Field<'a> {
+    pub fn as_bytes(&self, name: &str) -> Result<&'a [u8], Error> {
+        match self {
+            Field::Bytes(bytes) => Ok(bytes),
+            _ => Err(Error::Decode(format!("{} must be length delimited", name)))
+        }
+    }
+
+    pub fn as_str(&self, name: &str) -> Result<&'a str, Error> {
+        std::str::from_utf8(self.as_bytes(name)?).map_err(|_| Error::Decode(format!("{} is not valid utf-8", name)))
+    }
+
+    pub fn as_double(&self, name: &str) -> Result<f64, Error> {
+        match self {
+            Field::Fixed64(bits) => Ok(f64::from_bits(*bits)),
+            _ => Err(Error::Decode(format!("{} must be a double", name)))
+        }
+    }
+
+    pub fn as_varint(&self, name: &str) -> Result<u64, Error> {
+        match self {
+            Field::Varint(value) => Ok(*value),
+            _ => Err(Error::Decode(format!("{} must be a varint", name)))
+        }
+    }
+
+    /// A `fixed64`, or an `sfixed64` once cast.
+    pub fn as_fixed64(&self, name: &str) -> Result<u64, Error> {
+        match self {
+            Field::Fixed64(value) => Ok(*value),
+            _ => Err(Error::Decode(format!("{} must be a fixed64", name)))
+        }
+    }
+
+    /// An `sint32` or `sint64`, which are zigzag encoded.
+    pub fn as_sint(&self, name: &str) -> Result<i64, Error> {
+        let value = self.as_varint(name)?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    /// An `int64`, which protobuf encodes as a two's complement varint.
+    pub fn as_int64(&self, name: &str) -> Result<i64, Error> {
+        match self {
+            Field::Varint(value) => Ok(*value as i64),
+            _ => Err(Error::Decode(format!("{} must be a varint", name)))
+        }
+    }
+}
+
+/// Reads the fields of one message in order.
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    /// The next field number and value, or `None` at the end of the message.
+    pub fn next_field(&mut self) -> Result<Option<(u32, Field<'a>)>, Error> {
+        if self.pos == self.data.len() {
+            return Ok(None);
+        }
+        let key = read_varint(self.data, &mut self.pos)?;
+        let number = u32::try_from(key >> 3).ok().filter(|n| *n > 0)
+            .ok_or_else(|| Error::Decode(format!("Invalid field number {}", key >> 3)))?;
+        let field = match (key & 7) as u8 {
+            VARINT => Field::Varint(read_varint(self.data, &mut self.pos)?),
+            FIXED64 => Field::Fixed64(u64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            LENGTH_DELIMITED => {
+                let len = read_varint(self.data, &mut self.pos)?;
+                Field::Bytes(self.take(usize::try_from(len).unwrap_or(usize::MAX))?)
+            },
+            FIXED32 => Field::Fixed32(u32::from_le_bytes(self.take(4)?.try_into().unwrap())),
+            wire_type => return Err(Error::Decode(format!("Unsupported wire type {} for field {}", wire_type, number)))
+        };
+        Ok(Some((number, field)))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.data.len())
+            .ok_or_else(|| Error::Decode(format!("Unexpected end of message at offset {}", self.pos)))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+}
+
+/// A base 128 varint, at most ten bytes long.
+pub fn read_varint(data: &[u8], byte_offset: &mut usize) -> Result<u64, Error> {
+    let mut value: u64 = 0;
+    for (i, byte) in data[(*byte_offset).min(data.len())..].iter().take(10).enumerate() {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            *byte_offset += i + 1;
+            return Ok(value);
+        }
+    }
+    Err(Error::Decode(format!("Truncated or overlong varint at offset {}", byte_offset)))
+}
+
+pub fn write_varint(data: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        data.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+fn write_key(data: &mut Vec<u8>, number: u32, wire_type: u8) {
+    write_varint(data, ((number as u64) << 3) | wire_type as u64);
+}
+
+pub fn write_varint_field(data: &mut Vec<u8>, number: u32, value: u64) {
+    write_key(data, number, VARINT);
+    write_varint(data, value);
+}
+
+pub fn write_double_field(data: &mut Vec<u8>, number: u32, value: f64) {
+    write_key(data, number, FIXED64);
+    data.extend(value.to_bits().to_le_bytes());
+}
+
+pub fn write_bytes_field(data: &mut Vec<u8>, number: u32, value: &[u8]) {
+    write_key(data, number, LENGTH_DELIMITED);
+    write_varint(data, value.len() as u64);
+    data.extend(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_what_it_writes() {
+        let mut data = Vec::new();
+        write_varint_field(&mut data, 1, 300);
+        write_bytes_field(&mut data, 2, b"hi");
+        write_double_field(&mut data, 3, 1.5);
+        write_varint_field(&mut data, 4, -5i64 as u64);
+        data.extend([0x2d, 1, 0, 0, 0]);
+
+        let mut reader = Reader::new(&data);
+        assert_eq!(reader.next_field().unwrap(), Some((1, Field::Varint(300))));
+        assert_eq!(reader.next_field().unwrap().unwrap().1.as_str("name").unwrap(), "hi");
+        assert_eq!(reader.next_field().unwrap().unwrap().1.as_double("value").unwrap(), 1.5);
+        assert_eq!(reader.next_field().unwrap().unwrap().1.as_int64("timestamp").unwrap(), -5);
+        assert_eq!(reader.next_field().unwrap(), Some((5, Field::Fixed32(1))));
+        assert_eq!(reader.next_field().unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        assert!(Reader::new(&[0x0a, 5, b'a']).next_field().is_err());
+        assert!(Reader::new(&[0x08]).next_field().is_err());
+        assert!(Reader::new(&[0x0b]).next_field().is_err());
+        assert!(Reader::new(&[0x00, 0x00]).next_field().is_err());
+        assert!(read_varint(&[0xff; 11], &mut 0).is_err());
+        assert!(Field::Varint(1).as_bytes("labels").is_err());
+    }
+}
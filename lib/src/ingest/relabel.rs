@@ -0,0 +1,360 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use regex::Regex;
+
+use crate::{error::Error, models::{batch::DecodedBatch, Metric}, query::selector::NAME_LABEL};
+
+/// What a rule does with the labels it's given, as in Prometheus' `relabel_config`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Action {
+    /// Sets `target_label` to `replacement` when the source labels match.
+    #[default]
+    Replace,
+    /// Drops the sample unless the source labels match.
+    Keep,
+    /// Drops the sample when the source labels match.
+    Drop,
+    /// Removes every label whose name matches.
+    LabelDrop,
+    /// Removes every label whose name doesn't match.
+    LabelKeep,
+    /// Copies every label whose name matches to the name `replacement` makes of it.
+    LabelMap,
+    /// Sets `target_label` to the hash of the source labels modulo `modulus`.
+    HashMod
+}
+
+impl Action {
+    pub fn parse(action: &str) -> Result<Self, Error> {
+        match action {
+            "replace" => Ok(Action::Replace),
+            "keep" => Ok(Action::Keep),
+            "drop" => Ok(Action::Drop),
+            "labeldrop" => Ok(Action::LabelDrop),
+            "labelkeep" => Ok(Action::LabelKeep),
+            "labelmap" => Ok(Action::LabelMap),
+            "hashmod" => Ok(Action::HashMod),
+            _ => Err(Error::Invalid(format!("Unknown relabel action {:?}", action)))
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Replace => "replace",
+            Action::Keep => "keep",
+            Action::Drop => "drop",
+            Action::LabelDrop => "labeldrop",
+            Action::LabelKeep => "labelkeep",
+            Action::LabelMap => "labelmap",
+            Action::HashMod => "hashmod"
+        }
+    }
+}
+
+/// One rule as configured. The metric name is the `__name__` label.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelabelConfig {
+    /// Their values, joined by `separator`, are what `regex` is matched against.
+    pub source_labels: Vec<String>,
+    pub separator: String,
+    pub target_label: String,
+    /// Anchored at both ends.
+    pub regex: String,
+    pub modulus: u64,
+    /// May refer to `regex`'s capture groups as `$1` or `${name}`.
+    pub replacement: String,
+    pub action: Action
+}
+
+impl Default for RelabelConfig {
+    fn default() -> Self {
+        RelabelConfig {
+            source_labels: Vec::new(),
+            separator: String::from(";"),
+            target_label: String::new(),
+            regex: String::from("(.*)"),
+            modulus: 0,
+            replacement: String::from("$1"),
+            action: Action::Replace
+        }
+    }
+}
+
+/// A compiled rule, which counts how often it applied.
+#[derive(Debug)]
+pub struct Rule {
+    config: RelabelConfig,
+    regex: Regex,
+    hits: AtomicU64
+}
+
+type Labels = BTreeMap<String, String>;
+
+impl Rule {
+    pub fn new(config: RelabelConfig) -> Result<Self, Error> {
+        let invalid = |detail: &str| Error::Invalid(format!("Relabel rule {}: {}", config.action.as_str(), detail));
+        let regex = Regex::new(&format!("^(?:{})$", config.regex)).map_err(|e| invalid(&e.to_string()))?;
+        match config.action {
+            Action::Replace | Action::HashMod if config.target_label.is_empty() => return Err(invalid("target_label is required")),
+            Action::HashMod if config.modulus == 0 => return Err(invalid("modulus must be positive")),
+            Action::Keep | Action::Drop | Action::HashMod if config.source_labels.is_empty() => return Err(invalid("source_labels is required")),
+            _ => {}
+        }
+        Ok(Rule { config, regex, hits: AtomicU64::new(0) })
+    }
+
+    pub fn config(&self) -> &RelabelConfig {
+        &self.config
+    }
+
+    /// How many samples the rule matched: for the label actions, samples with a matching
+    /// label name; for `hashmod`, every sample it hashed.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// `false` if the sample is to be dropped.
+    fn apply(&self, labels: &mut Labels) -> bool {
+        let config = &self.config;
+        let source = || config.source_labels.iter()
+            .map(|name| labels.get(name).map(String::as_str).unwrap_or(""))
+            .collect::<Vec<_>>()
+            .join(&config.separator);
+        let hit = || self.hits.fetch_add(1, Ordering::Relaxed);
+
+        match config.action {
+            Action::Replace => {
+                let value = source();
+                let Some(captures) = self.regex.captures(&value) else {
+                    return true;
+                };
+                hit();
+                let mut target = String::new();
+                captures.expand(&config.target_label, &mut target);
+                let mut replacement = String::new();
+                captures.expand(&config.replacement, &mut replacement);
+                if !valid_label_name(&target) {
+                    return true;
+                }
+                if replacement.is_empty() {
+                    labels.remove(&target);
+                } else {
+                    labels.insert(target, replacement);
+                }
+                true
+            },
+            Action::Keep | Action::Drop => {
+                let matched = self.regex.is_match(&source());
+                if matched {
+                    hit();
+                }
+                matched == (config.action == Action::Keep)
+            },
+            Action::HashMod => {
+                hit();
+                let hash = md5(source().as_bytes());
+                let value = u64::from_be_bytes(hash[8..].try_into().unwrap()) % config.modulus;
+                labels.insert(config.target_label.clone(), value.to_string());
+                true
+            },
+            Action::LabelDrop | Action::LabelKeep => {
+                if labels.keys().any(|name| name != NAME_LABEL && self.regex.is_match(name)) {
+                    hit();
+                }
+                let keep_matching = config.action == Action::LabelKeep;
+                labels.retain(|name, _| name == NAME_LABEL || self.regex.is_match(name) == keep_matching);
+                true
+            },
+            Action::LabelMap => {
+                let mapped: Vec<(String, String)> = labels.iter()
+                    .filter(|(name, _)| self.regex.is_match(name))
+                    .map(|(name, value)| (self.regex.replace(name, config.replacement.as_str()).into_owned(), value.clone()))
+                    .collect();
+                if !mapped.is_empty() {
+                    hit();
+                }
+                labels.extend(mapped.into_iter().filter(|(name, _)| valid_label_name(name)));
+                true
+            }
+        }
+    }
+}
+
+fn valid_label_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Runs rules in order over samples before they're stored. Labels starting with `__`, other
+/// than the name, are scratch space for the rules and are removed once they've all run.
+/// `labeldrop` and `labelkeep` never remove the name.
+#[derive(Debug, Default)]
+pub struct Relabeler {
+    rules: RwLock<Arc<Vec<Rule>>>
+}
+
+impl Relabeler {
+    pub fn new(configs: Vec<RelabelConfig>) -> Result<Self, Error> {
+        Ok(Relabeler { rules: RwLock::new(Arc::new(compile(configs)?)) })
+    }
+
+    pub fn rules(&self) -> Arc<Vec<Rule>> {
+        match self.rules.read() {
+            Ok(rules) => rules.clone(),
+            Err(poisoned) => poisoned.into_inner().clone()
+        }
+    }
+
+    /// Swaps in new rules, which start counting hits from zero. Samples being relabeled finish
+    /// with the old ones. If a rule doesn't compile, the old rules stay.
+    pub fn replace(&self, configs: Vec<RelabelConfig>) -> Result<(), Error> {
+        let rules = Arc::new(compile(configs)?);
+        match self.rules.write() {
+            Ok(mut current) => *current = rules,
+            Err(poisoned) => *poisoned.into_inner() = rules
+        }
+        Ok(())
+    }
+
+    /// The sample as the rules leave it, or `None` if one drops it or it's left without a name.
+    pub fn relabel(&self, metric: Metric) -> Option<Metric> {
+        relabel(&self.rules(), metric)
+    }
+
+    /// Relabels a decoded batch, leaving out the samples that are dropped. Errors pass through.
+    /// The whole batch goes through the same rules.
+    pub fn apply(&self, (metrics, errors): DecodedBatch) -> DecodedBatch {
+        let rules = self.rules();
+        let metrics = metrics.into_iter().filter_map(|(index, metric)| Some((index, relabel(&rules, metric)?))).collect();
+        (metrics, errors)
+    }
+}
+
+fn compile(configs: Vec<RelabelConfig>) -> Result<Vec<Rule>, Error> {
+    configs.into_iter().map(Rule::new).collect()
+}
+
+fn relabel(rules: &[Rule], metric: Metric) -> Option<Metric> {
+    if rules.is_empty() {
+        return Some(metric);
+    }
+    let mut labels: Labels = metric.labels.into_iter().collect();
+    labels.insert(String::from(NAME_LABEL), metric.name);
+    for rule in rules {
+        if !rule.apply(&mut labels) {
+            return None;
+        }
+    }
+    let name = labels.remove(NAME_LABEL)?;
+    let labels = labels.into_iter().filter(|(key, value)| !key.starts_with("__") && !value.is_empty()).collect();
+    Some(Metric { timestamp: metric.timestamp, value: metric.value, name, labels })
+}
+
+/// MD5, which `hashmod` uses so targets shard the same way they would in Prometheus.
+fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 64] = [
+        7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22,
+        5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20,
+        4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23,
+        6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21
+    ];
+    let constants: Vec<u32> = (0..64).map(|i| ((i as f64 + 1.0).sin().abs() * 4_294_967_296.0) as u32).collect();
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend(((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for block in message.chunks_exact(64) {
+        let words: Vec<u32> = block.chunks_exact(4).map(|word| u32::from_le_bytes(word.try_into().unwrap())).collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16)
+            };
+            let rotated = a.wrapping_add(f).wrapping_add(constants[i]).wrapping_add(words[g]).rotate_left(SHIFTS[i]);
+            (a, d, c) = (d, c, b);
+            b = b.wrapping_add(rotated);
+        }
+        for (word, add) in state.iter_mut().zip([a, b, c, d]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+
+    let mut hash = [0; 16];
+    for (chunk, word) in hash.chunks_exact_mut(4).zip(state) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric(name: &str, labels: &[(&str, &str)]) -> Metric {
+        Metric { timestamp: 1, value: 1.0, name: name.to_string(), labels: labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect() }
+    }
+
+    fn sources(labels: &[&str]) -> Vec<String> {
+        labels.iter().map(|label| label.to_string()).collect()
+    }
+
+    #[test]
+    fn hashes_like_md5() {
+        let hex = |hash: [u8; 16]| hash.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(hex(md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(md5(b"abc")), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(hex(md5(&[b'a'; 100])), "36a92cc94a9e0fa21f625f8bfb007adf");
+    }
+
+    #[test]
+    fn applies_rules_in_order() {
+        let relabeler = Relabeler::new(vec![
+            RelabelConfig { source_labels: sources(&["__name__"]), regex: String::from("go_.*"), action: Action::Drop, ..Default::default() },
+            RelabelConfig { source_labels: sources(&["__name__", "env"]), regex: String::from("http_(.*);(.*)"), target_label: String::from("__name__"), replacement: String::from("${2}_$1"), ..Default::default() },
+            RelabelConfig { regex: String::from("pod_(.+)"), replacement: String::from("k8s_$1"), action: Action::LabelMap, ..Default::default() },
+            RelabelConfig { regex: String::from("pod_.*|noisy"), action: Action::LabelDrop, ..Default::default() },
+            RelabelConfig { source_labels: sources(&["instance"]), target_label: String::from("__shard"), modulus: 4, action: Action::HashMod, ..Default::default() },
+            RelabelConfig { source_labels: sources(&["__shard"]), regex: String::from("[0-3]"), action: Action::Keep, ..Default::default() }
+        ]).unwrap();
+
+        assert_eq!(relabeler.relabel(metric("go_goroutines", &[])), None);
+        assert_eq!(
+            relabeler.relabel(metric("http_requests", &[("env", "prod"), ("instance", "a:1"), ("noisy", "x"), ("pod_name", "web-1")])),
+            Some(metric("prod_requests", &[("env", "prod"), ("instance", "a:1"), ("k8s_name", "web-1")]))
+        );
+        assert_eq!(relabeler.rules().iter().map(Rule::hits).collect::<Vec<_>>(), vec![1, 1, 1, 1, 1, 1]);
+
+        let (metrics, _) = relabeler.apply((vec![(3, metric("go_gc", &[])), (4, metric("up", &[]))], Vec::new()));
+        assert_eq!(metrics, vec![(4, metric("up", &[]))]);
+
+        // A rule that doesn't compile leaves the old ones in place.
+        assert!(relabeler.replace(vec![RelabelConfig { regex: String::from("("), target_label: String::from("x"), ..Default::default() }]).is_err());
+        assert_eq!(relabeler.rules().len(), 6);
+        relabeler.replace(Vec::new()).unwrap();
+        assert_eq!(relabeler.relabel(metric("go_gc", &[])), Some(metric("go_gc", &[])));
+    }
+
+    #[test]
+    fn keeps_shards_stable() {
+        let relabeler = Relabeler::new(vec![
+            RelabelConfig { source_labels: sources(&["instance"]), target_label: String::from("shard"), modulus: 1000, action: Action::HashMod, ..Default::default() }
+        ]).unwrap();
+        // The low 8 bytes of md5("localhost:9090"), big endian, modulo 1000, as Prometheus computes it.
+        let relabeled = relabeler.relabel(metric("up", &[("instance", "localhost:9090")])).unwrap();
+        assert_eq!(relabeled.labels[1], (String::from("shard"), String::from("618")));
+
+        assert!(Rule::new(RelabelConfig { action: Action::HashMod, target_label: String::from("shard"), ..Default::default() }).is_err());
+        assert!(Rule::new(RelabelConfig { regex: String::from("("), target_label: String::from("x"), ..Default::default() }).is_err());
+        assert!(Action::parse("relabel").is_err());
+    }
+}
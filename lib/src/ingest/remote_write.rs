@@ -0,0 +1,167 @@
+use crate::{error::Error, ingest::{protobuf::Reader, snappy}, models::{batch::DecodedBatch, Metric, SampleError}, query::selector::NAME_LABEL};
+
+/// Prometheus refuses to send more than this decompressed, so neither do we accept it.
+pub const MAX_DECOMPRESSED_LEN: usize = 32 * 1024 * 1024;
+
+// Field numbers from prometheus/prompb. Exemplars, native histograms and metadata are not
+// stored, so their fields are skipped.
+const WRITE_REQUEST_TIMESERIES: u32 = 1;
+const TIMESERIES_LABELS: u32 = 1;
+const TIMESERIES_SAMPLES: u32 = 2;
+const LABEL_NAME: u32 = 1;
+const LABEL_VALUE: u32 = 2;
+const SAMPLE_VALUE: u32 = 1;
+const SAMPLE_TIMESTAMP: u32 = 2;
+
+/// Decodes a snappy compressed remote write 1.0 `WriteRequest` into samples. A body that
+/// doesn't decode fails as a whole; a series that can't become a `Metric`, like one without
+/// a name, rejects each of its samples, indexed in the order they appear.
+pub fn decode(body: &[u8]) -> Result<DecodedBatch, Error> {
+    decode_write_request(&snappy::decompress(body, MAX_DECOMPRESSED_LEN)?)
+}
+
+pub fn decode_write_request(data: &[u8]) -> Result<DecodedBatch, Error> {
+    let mut metrics = Vec::new();
+    let mut errors = Vec::new();
+    let mut index = 0;
+
+    let mut request = Reader::new(data);
+    while let Some((number, field)) = request.next_field()? {
+        if number != WRITE_REQUEST_TIMESERIES {
+            continue;
+        }
+        let (labels, samples) = read_timeseries(field.as_bytes("timeseries")?)?;
+        let series = series_labels(labels);
+        for (timestamp, value) in samples {
+            let checked = series.as_ref().map_err(|reason| reason.to_string()).and_then(|(name, labels)| {
+                let timestamp = u64::try_from(timestamp).map_err(|_| format!("Negative timestamp {}", timestamp))?;
+                Ok(Metric { timestamp, value, name: name.clone(), labels: labels.clone() })
+            });
+            match checked {
+                Ok(metric) => metrics.push((index, metric)),
+                Err(reason) => errors.push(SampleError::new(index, &reason))
+            }
+            index += 1;
+        }
+    }
+    Ok((metrics, errors))
+}
+
+type Labels<'a> = Vec<(&'a str, &'a str)>;
+/// A metric name and the rest of its labels, sorted.
+type SeriesId = (String, Vec<(String, String)>);
+
+fn read_timeseries(data: &[u8]) -> Result<(Labels<'_>, Vec<(i64, f64)>), Error> {
+    let mut labels = Vec::new();
+    let mut samples = Vec::new();
+    let mut reader = Reader::new(data);
+    while let Some((number, field)) = reader.next_field()? {
+        match number {
+            TIMESERIES_LABELS => labels.push(read_pair(field.as_bytes("label")?)?),
+            TIMESERIES_SAMPLES => {
+                let mut sample = Reader::new(field.as_bytes("sample")?);
+                let (mut timestamp, mut value) = (0, 0.0);
+                while let Some((number, field)) = sample.next_field()? {
+                    match number {
+                        SAMPLE_VALUE => value = field.as_double("sample value")?,
+                        SAMPLE_TIMESTAMP => timestamp = field.as_int64("sample timestamp")?,
+                        _ => {}
+                    }
+                }
+                samples.push((timestamp, value));
+            },
+            _ => {}
+        }
+    }
+    Ok((labels, samples))
+}
+
+fn read_pair(data: &[u8]) -> Result<(&str, &str), Error> {
+    let (mut name, mut value) = ("", "");
+    let mut reader = Reader::new(data);
+    while let Some((number, field)) = reader.next_field()? {
+        match number {
+            LABEL_NAME => name = field.as_str("label name")?,
+            LABEL_VALUE => value = field.as_str("label value")?,
+            _ => {}
+        }
+    }
+    Ok((name, value))
+}
+
+/// Splits `__name__` out of a series' labels. Empty values mean the label isn't set, as in
+/// Prometheus.
+fn series_labels(labels: Labels<'_>) -> Result<SeriesId, &'static str> {
+    let mut name = None;
+    let mut rest: Vec<(String, String)> = Vec::with_capacity(labels.len());
+    for (key, value) in labels {
+        if key.is_empty() {
+            return Err("Empty label name");
+        }
+        if value.is_empty() {
+            continue;
+        }
+        if key == NAME_LABEL {
+            name = Some(value.to_string());
+        } else {
+            rest.push((key.to_string(), value.to_string()));
+        }
+    }
+    rest.sort();
+    if rest.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+        return Err("Duplicate label name");
+    }
+    Ok((name.ok_or("Series has no __name__ label")?, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::protobuf::{write_bytes_field, write_double_field, write_varint_field};
+
+    fn timeseries(labels: &[(&str, &str)], samples: &[(i64, f64)]) -> Vec<u8> {
+        let mut series = Vec::new();
+        for (name, value) in labels {
+            let mut label = Vec::new();
+            write_bytes_field(&mut label, LABEL_NAME, name.as_bytes());
+            write_bytes_field(&mut label, LABEL_VALUE, value.as_bytes());
+            write_bytes_field(&mut series, TIMESERIES_LABELS, &label);
+        }
+        for (timestamp, value) in samples {
+            let mut sample = Vec::new();
+            write_double_field(&mut sample, SAMPLE_VALUE, *value);
+            write_varint_field(&mut sample, SAMPLE_TIMESTAMP, *timestamp as u64);
+            write_bytes_field(&mut series, TIMESERIES_SAMPLES, &sample);
+        }
+        series
+    }
+
+    #[test]
+    fn maps_timeseries_onto_metrics() {
+        let mut request = Vec::new();
+        write_bytes_field(&mut request, WRITE_REQUEST_TIMESERIES, &timeseries(&[("__name__", "up"), ("job", "node"), ("instance", "a"), ("empty", "")], &[(1000, 1.0), (2000, 0.0)]));
+        write_bytes_field(&mut request, WRITE_REQUEST_TIMESERIES, &timeseries(&[("job", "node")], &[(1000, 1.0)]));
+        write_bytes_field(&mut request, WRITE_REQUEST_TIMESERIES, &timeseries(&[("__name__", "x")], &[(-1, 1.0)]));
+        // Metadata, which is skipped.
+        write_bytes_field(&mut request, 3, b"\x08\x01");
+
+        let (metrics, errors) = decode(&snappy::compress(&request)).unwrap();
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics[1], (1, Metric {
+            timestamp: 2000,
+            value: 0.0,
+            name: String::from("up"),
+            labels: vec![(String::from("instance"), String::from("a")), (String::from("job"), String::from("node"))]
+        }));
+        assert_eq!(errors, vec![SampleError::new(2, "Series has no __name__ label"), SampleError::new(3, "Negative timestamp -1")]);
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert!(decode(b"not snappy").is_err());
+        assert!(decode_write_request(&[0x0a, 0x05, 0x0a]).is_err());
+        let mut request = Vec::new();
+        write_bytes_field(&mut request, WRITE_REQUEST_TIMESERIES, &[0x0a, 0x02, 0x0a, 0xff]);
+        assert!(decode_write_request(&request).is_err());
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{error::Error, ingest::protobuf::{read_varint, write_varint}};
+
+// The snappy block format, which is what Prometheus remote write bodies use (not the
+// framed stream format). A block is the uncompressed length as a varint, then a sequence
+// of literals and back references.
+
+/// Decompresses one block. `max_len` bounds the declared size, so a tiny body can't make
+/// us allocate gigabytes.
+pub fn decompress(data: &[u8], max_len: usize) -> Result<Vec<u8>, Error> {
+    let mut pos = 0;
+    let len = read_varint(data, &mut pos)?;
+    let len = usize::try_from(len).ok().filter(|len| *len <= max_len)
+        .ok_or_else(|| Error::Decode(format!("Decompressed size {} is over the limit of {} bytes", len, max_len)))?;
+    let mut out = Vec::with_capacity(len);
+
+    let truncated = || Error::Decode(String::from("Truncated snappy block"));
+    let read_le = |pos: &mut usize, width: usize| -> Result<usize, Error> {
+        let bytes = data.get(*pos..*pos + width).ok_or_else(truncated)?;
+        *pos += width;
+        Ok(bytes.iter().rev().fold(0, |acc, b| (acc << 8) | *b as usize))
+    };
+
+    while pos < data.len() {
+        let tag = data[pos];
+        pos += 1;
+        let (copy_len, offset) = match tag & 3 {
+            0 => {
+                let mut literal_len = (tag >> 2) as usize;
+                if literal_len >= 60 {
+                    literal_len = read_le(&mut pos, literal_len - 59)?;
+                }
+                let literal = data.get(pos..).and_then(|rest| rest.get(..literal_len.checked_add(1)?)).ok_or_else(truncated)?;
+                if out.len() + literal.len() > len {
+                    return Err(Error::Decode(String::from("Snappy block is longer than its declared size")));
+                }
+                out.extend_from_slice(literal);
+                pos += literal.len();
+                continue;
+            },
+            1 => (4 + ((tag >> 2) & 7) as usize, ((tag as usize >> 5) << 8) | read_le(&mut pos, 1)?),
+            2 => ((tag >> 2) as usize + 1, read_le(&mut pos, 2)?),
+            _ => ((tag >> 2) as usize + 1, read_le(&mut pos, 4)?)
+        };
+
+        if offset == 0 || offset > out.len() {
+            return Err(Error::Decode(format!("Invalid snappy back reference {} at output offset {}", offset, out.len())));
+        }
+        if out.len() + copy_len > len {
+            return Err(Error::Decode(String::from("Snappy block is longer than its declared size")));
+        }
+        // The source and destination may overlap, which repeats the last `offset` bytes.
+        let start = out.len() - offset;
+        for i in 0..copy_len {
+            out.push(out[start + i]);
+        }
+    }
+
+    if out.len() != len {
+        return Err(Error::Decode(format!("Snappy block decompressed to {} bytes, expected {}", out.len(), len)));
+    }
+    Ok(out)
+}
+
+/// Compresses into a valid block made only of literals. Nothing is saved, but anything that
+/// reads snappy can read it.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 65_536 * 3 + 13);
+    write_varint(&mut out, data.len() as u64);
+    for chunk in data.chunks(65_536) {
+        let len = chunk.len() - 1;
+        if len < 60 {
+            out.push((len as u8) << 2);
+        } else if len < 256 {
+            out.push(60 << 2);
+            out.push(len as u8);
+        } else {
+            out.push(61 << 2);
+            out.extend((len as u16).to_le_bytes());
+        }
+        out.extend_from_slice(chunk);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decompresses_literals_and_copies() {
+        // "abc", then a one byte offset copy of 9 bytes that overlaps its own output.
+        assert_eq!(decompress(&[12, 0x08, b'a', b'b', b'c', 0x15, 3], 100).unwrap(), b"abcabcabcabc");
+        // A two byte offset copy.
+        assert_eq!(decompress(&[6, 0x04, b'x', b'y', 0x0e, 2, 0], 100).unwrap(), b"xyxyxy");
+
+        let data: Vec<u8> = (0..70_000u32).map(|i| (i % 251) as u8).collect();
+        assert_eq!(decompress(&compress(&data), data.len()).unwrap(), data);
+        assert_eq!(decompress(&compress(b""), 0).unwrap(), b"");
+    }
+
+    #[test]
+    fn rejects_bad_blocks() {
+        assert!(decompress(&compress(b"hello"), 4).is_err());
+        assert!(decompress(&[5, 0x10, b'a'], 100).is_err());
+        assert!(decompress(&[4, 0x05, 1], 100).is_err());
+        assert!(decompress(&[2, 0x00, b'a'], 100).is_err());
+        assert!(decompress(&[1, 0x04, b'a', b'b'], 100).is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{ingest::sanitize_name, models::Metric};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Counter(f64),
+    /// `delta` is set for `+n` and `-n`, which adjust the current value instead of replacing it.
+    Gauge { value: f64, delta: bool },
+    /// Timers, histograms and distributions are all aggregated the same way.
+    Timer(f64),
+    Set(String)
+}
+
+/// One parsed StatsD line, like `api.requests:1|c|@0.5|#region:eu`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    pub value: Value,
+    pub sample_rate: f64,
+    /// DogStatsD tags, sorted. Tags without a value are dropped.
+    pub tags: Vec<(String, String)>
+}
+
+/// Parses a packet, which holds one metric per line. Lines that don't parse come back as
+/// errors with their 1-based line number. DogStatsD events and service checks are skipped.
+pub fn parse(packet: &str) -> (Vec<Sample>, Vec<(usize, String)>) {
+    let mut samples = Vec::new();
+    let mut errors = Vec::new();
+    for (i, line) in packet.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("_e{") || line.starts_with("_sc|") {
+            continue;
+        }
+        match parse_line(line) {
+            Ok(sample) => samples.push(sample),
+            Err(reason) => errors.push((i + 1, reason))
+        }
+    }
+    (samples, errors)
+}
+
+fn parse_line(line: &str) -> Result<Sample, String> {
+    let (name, rest) = line.split_once(':').ok_or("Missing ':' after the metric name")?;
+    if name.is_empty() {
+        return Err(String::from("Empty metric name"));
+    }
+    let mut sections = rest.split('|');
+    let value = sections.next().unwrap_or("");
+    let kind = sections.next().ok_or("Missing '|' and metric type")?;
+
+    let number = || value.parse::<f64>().ok().filter(|v| v.is_finite()).ok_or_else(|| format!("Invalid value {:?}", value));
+    let value = match kind {
+        "c" => Value::Counter(number()?),
+        "g" => Value::Gauge { value: number()?, delta: value.starts_with('+') || value.starts_with('-') },
+        "ms" | "h" | "d" => Value::Timer(number()?),
+        "s" if !value.is_empty() => Value::Set(value.to_string()),
+        "s" => return Err(String::from("Empty set member")),
+        _ => return Err(format!("Unknown metric type {:?}", kind))
+    };
+
+    let mut sample_rate = 1.0;
+    let mut tags = Vec::new();
+    for section in sections {
+        if let Some(rate) = section.strip_prefix('@') {
+            sample_rate = rate.parse().ok().filter(|rate| *rate > 0.0 && *rate <= 1.0)
+                .ok_or_else(|| format!("Invalid sample rate {:?}", rate))?;
+        } else if let Some(list) = section.strip_prefix('#') {
+            for tag in list.split(',') {
+                if let Some((key, value)) = tag.split_once(':') && !key.is_empty() && !value.is_empty() {
+                    tags.push((sanitize_name(key), value.to_string()));
+                }
+            }
+        }
+        // Anything else, like a DogStatsD container id or timestamp, is ignored.
+    }
+    tags.sort();
+    tags.dedup_by(|a, b| a.0 == b.0);
+
+    Ok(Sample { name: sanitize_name(name), value, sample_rate, tags })
+}
+
+type Key = (String, Vec<(String, String)>);
+
+#[derive(Default)]
+struct Timer {
+    values: Vec<f64>,
+    // What the values stand for once sample rates are accounted for.
+    count: f64
+}
+
+/// Collects samples over a flush interval and turns them into series. Counters, timers and
+/// sets start over after every flush; gauges keep their last value, as in StatsD.
+pub struct Aggregator {
+    percentiles: Vec<f64>,
+    counters: HashMap<Key, f64>,
+    gauges: HashMap<Key, f64>,
+    timers: HashMap<Key, Timer>,
+    sets: HashMap<Key, HashSet<String>>
+}
+
+impl Aggregator {
+    /// `percentiles` are between 0 and 100, and are reported for every timer.
+    pub fn new(percentiles: Vec<f64>) -> Self {
+        Aggregator {
+            percentiles,
+            counters: HashMap::new(),
+            gauges: HashMap::new(),
+            timers: HashMap::new(),
+            sets: HashMap::new()
+        }
+    }
+
+    pub fn add(&mut self, sample: Sample) {
+        let key = (sample.name, sample.tags);
+        match sample.value {
+            Value::Counter(value) => *self.counters.entry(key).or_default() += value / sample.sample_rate,
+            Value::Gauge { value, delta: true } => *self.gauges.entry(key).or_default() += value,
+            Value::Gauge { value, delta: false } => {
+                self.gauges.insert(key, value);
+            },
+            Value::Timer(value) => {
+                let timer = self.timers.entry(key).or_default();
+                timer.values.push(value);
+                timer.count += 1.0 / sample.sample_rate;
+            },
+            Value::Set(member) => {
+                self.sets.entry(key).or_default().insert(member);
+            }
+        }
+    }
+
+    /// The series for the interval that ends at `now`, which lasted `interval_ms`:
+    ///
+    /// - counters: `<name>_count` and `<name>_rate`, per second
+    /// - gauges: `<name>`
+    /// - timers: `<name>_count`, `<name>_rate`, `<name>_sum`, `<name>_min`, `<name>_max`,
+    ///   `<name>_mean`, and `<name>` with a `quantile` label per percentile
+    /// - sets: `<name>_cardinality`
+    pub fn flush(&mut self, now: u64, interval_ms: u64) -> Vec<Metric> {
+        let seconds = interval_ms.max(1) as f64 / 1000.0;
+        let mut metrics = Vec::new();
+        let mut push = |name: String, labels: Vec<(String, String)>, value: f64| {
+            metrics.push(Metric { timestamp: now, value, name, labels });
+        };
+
+        for ((name, tags), count) in self.counters.drain() {
+            push(format!("{}_count", name), tags.clone(), count);
+            push(format!("{}_rate", name), tags, count / seconds);
+        }
+        for ((name, tags), value) in &self.gauges {
+            push(name.clone(), tags.clone(), *value);
+        }
+        for ((name, tags), mut timer) in self.timers.drain() {
+            timer.values.sort_by(f64::total_cmp);
+            let sum: f64 = timer.values.iter().sum();
+            push(format!("{}_count", name), tags.clone(), timer.count);
+            push(format!("{}_rate", name), tags.clone(), timer.count / seconds);
+            push(format!("{}_sum", name), tags.clone(), sum);
+            push(format!("{}_min", name), tags.clone(), timer.values[0]);
+            push(format!("{}_max", name), tags.clone(), timer.values[timer.values.len() - 1]);
+            push(format!("{}_mean", name), tags.clone(), sum / timer.values.len() as f64);
+            for percentile in &self.percentiles {
+                let mut labels = tags.clone();
+                labels.push((String::from("quantile"), (percentile / 100.0).to_string()));
+                labels.sort();
+                push(name.clone(), labels, nearest_rank(&timer.values, *percentile));
+            }
+        }
+        for ((name, tags), members) in self.sets.drain() {
+            push(format!("{}_cardinality", name), tags, members.len() as f64);
+        }
+        metrics
+    }
+}
+
+/// The smallest value that at least `percentile` percent of `sorted` is less than or equal to.
+fn nearest_rank(sorted: &[f64], percentile: f64) -> f64 {
+    let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(metrics: &[Metric], name: &str, labels: &[(&str, &str)]) -> f64 {
+        let labels: Vec<(String, String)> = labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        metrics.iter().find(|m| m.name == name && m.labels == labels).unwrap_or_else(|| panic!("no {} {:?}", name, labels)).value
+    }
+
+    #[test]
+    fn parses_lines() {
+        let (samples, errors) = parse("api.requests:2|c|@0.5|#region:eu,host:a,flag\nlatency:12.5|ms\nqueue:-3|g\nusers:bob|s\n_e{1,1}:a|b\nbad\nx:1|q\ny:abc|c\nz:1|c|@2");
+        assert_eq!(samples[0], Sample {
+            name: String::from("api_requests"),
+            value: Value::Counter(2.0),
+            sample_rate: 0.5,
+            tags: vec![(String::from("host"), String::from("a")), (String::from("region"), String::from("eu"))]
+        });
+        assert_eq!(samples[1].value, Value::Timer(12.5));
+        assert_eq!(samples[2].value, Value::Gauge { value: -3.0, delta: true });
+        assert_eq!(samples[3].value, Value::Set(String::from("bob")));
+        assert_eq!(errors.iter().map(|(line, _)| *line).collect::<Vec<_>>(), vec![6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn aggregates_per_interval() {
+        let mut aggregator = Aggregator::new(vec![50.0, 90.0]);
+        let (samples, _) = parse("hits:1|c|@0.1\nhits:2|c\nmem:10|g\nmem:+5|g\nusers:a|s\nusers:b|s\nusers:a|s");
+        samples.into_iter().for_each(|sample| aggregator.add(sample));
+        for latency in 1..=10 {
+            aggregator.add(parse_line(&format!("latency:{}|ms", latency)).unwrap());
+        }
+
+        let metrics = aggregator.flush(5000, 10_000);
+        assert_eq!(value(&metrics, "hits_count", &[]), 12.0);
+        assert_eq!(value(&metrics, "hits_rate", &[]), 1.2);
+        assert_eq!(value(&metrics, "mem", &[]), 15.0);
+        assert_eq!(value(&metrics, "users_cardinality", &[]), 2.0);
+        assert_eq!(value(&metrics, "latency_count", &[]), 10.0);
+        assert_eq!(value(&metrics, "latency_mean", &[]), 5.5);
+        assert_eq!(value(&metrics, "latency", &[("quantile", "0.5")]), 5.0);
+        assert_eq!(value(&metrics, "latency", &[("quantile", "0.9")]), 9.0);
+        assert!(metrics.iter().all(|m| m.timestamp == 5000));
+
+        // Only the gauge carries over.
+        let metrics = aggregator.flush(15_000, 10_000);
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(value(&metrics, "mem", &[]), 15.0);
+    }
+}
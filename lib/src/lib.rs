@@ -1,7 +1,17 @@
-
+pub mod error;
+pub mod log;
 pub mod ingest;
 pub mod storage;
 pub mod traits;
 pub mod models;
 pub mod db;
-pub mod collections;
\ No newline at end of file
+pub mod query;
+pub mod protocol;
+pub mod collections;
+pub mod telemetry;
+pub mod tenant;
+pub mod time;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+pub use error::{Error, Result};
\ No newline at end of file
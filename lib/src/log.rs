@@ -0,0 +1,195 @@
+use std::fmt::{self, Display};
+use std::io::Write;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{error::Error, time::{civil_from_days, now_ms}, traits::json::write_str};
+
+/// How much the server says, from only errors up to every request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Level {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace
+}
+
+impl Level {
+    pub fn parse(level: &str) -> Result<Self, Error> {
+        match level {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            other => Err(Error::Invalid(format!("Unknown log level {:?}, expected error, warn, info, debug or trace", other)))
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace"
+        }
+    }
+}
+
+/// How each line is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// logfmt: `ts=... level=info msg="..." key=value`.
+    #[default]
+    Text,
+    /// One JSON object per line, with `ts`, `level` and `msg` ahead of the fields.
+    Json
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Result<Self, Error> {
+        match format {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            other => Err(Error::Invalid(format!("Unknown log format {:?}, expected text or json", other)))
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Format::Text => "text",
+            Format::Json => "json"
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static FORMAT: AtomicU8 = AtomicU8::new(Format::Text as u8);
+
+/// Applies to every line logged from now on, from any thread.
+pub fn configure(level: Level, format: Format) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Writes one line to stderr. Use the macros, which skip formatting lines that are filtered out.
+pub fn write(level: Level, fields: &[(&str, &dyn Display)], message: fmt::Arguments) {
+    let format = if FORMAT.load(Ordering::Relaxed) == Format::Json as u8 { Format::Json } else { Format::Text };
+    let timestamp = now_ms();
+    let mut line = format_line(format, timestamp, level, fields, &message.to_string());
+    line.push('\n');
+    let _ = std::io::stderr().lock().write_all(line.as_bytes());
+}
+
+/// One line, without the newline. `timestamp` is in milliseconds.
+pub fn format_line(format: Format, timestamp: u64, level: Level, fields: &[(&str, &dyn Display)], message: &str) -> String {
+    let time = format_timestamp(timestamp);
+    let mut out = String::new();
+    match format {
+        Format::Text => {
+            out.push_str(&format!("ts={} level={} msg=", time, level.as_str()));
+            write_text_value(&mut out, message);
+            for (key, value) in fields {
+                out.push_str(&format!(" {}=", key));
+                write_text_value(&mut out, &value.to_string());
+            }
+        },
+        Format::Json => {
+            out.push_str(&format!("{{\"ts\":\"{}\",\"level\":\"{}\",\"msg\":", time, level.as_str()));
+            write_str(&mut out, message);
+            for (key, value) in fields {
+                out.push(',');
+                write_str(&mut out, key);
+                out.push(':');
+                write_str(&mut out, &value.to_string());
+            }
+            out.push('}');
+        }
+    }
+    out
+}
+
+/// Quoted only when it has to be.
+fn write_text_value(out: &mut String, value: &str) {
+    if value.is_empty() || value.contains(|c: char| c == ' ' || c == '=' || c == '"' || c.is_control()) {
+        write_str(out, value);
+    } else {
+        out.push_str(value);
+    }
+}
+
+/// RFC 3339 in UTC, to the millisecond.
+fn format_timestamp(timestamp: u64) -> String {
+    let (days, ms) = (timestamp / 86_400_000, timestamp % 86_400_000);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, ms % 1000)
+}
+
+/// Logs at `level`, with optional `key = value` fields ahead of the message:
+/// `log!(Level::Info, peer = addr; "Connection closed")`. Values are written with `Display`.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        if $crate::log::enabled($level) {
+            $crate::log::write($level, &[$((stringify!($key), &$value as &dyn ::std::fmt::Display)),+], format_args!($($arg)+));
+        }
+    };
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::log::enabled($level) {
+            $crate::log::write($level, &[], format_args!($($arg)+));
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Trace, $($arg)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_lines() {
+        let peer = "127.0.0.1:5000";
+        let fields: [(&str, &dyn Display); 2] = [("peer", &peer), ("error", &"connection reset")];
+        assert_eq!(
+            format_line(Format::Text, 1_700_000_000_123, Level::Warn, &fields, "Connection closed"),
+            r#"ts=2023-11-14T22:13:20.123Z level=warn msg="Connection closed" peer=127.0.0.1:5000 error="connection reset""#
+        );
+        assert_eq!(
+            format_line(Format::Json, 951_782_400_000, Level::Info, &fields[..1], "Leap \"day\""),
+            r#"{"ts":"2000-02-29T00:00:00.000Z","level":"info","msg":"Leap \"day\"","peer":"127.0.0.1:5000"}"#
+        );
+        assert_eq!(Level::parse("debug").unwrap(), Level::Debug);
+        assert!(Level::parse("verbose").is_err());
+        assert!(Level::Error < Level::Trace);
+    }
+}
@@ -0,0 +1,181 @@
+use crate::{error::Error, models::{metric::Metric, validation::RejectCode}, traits::{json::{self, JsonSerializable}, serializable::{read_bytes, read_string, read_u16, read_u32, write_bytes, write_string, BinarySerializable}}};
+
+/// Samples that decoded, with their index in the batch, and the ones that didn't.
+pub type DecodedBatch = (Vec<(usize, Metric)>, Vec<SampleError>);
+
+/// Many samples, possibly across many series, written in one request. Each metric is length
+/// prefixed so one bad sample doesn't make the rest of the batch unreadable.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct WriteBatch {
+    pub metrics: Vec<Metric>
+}
+
+impl WriteBatch {
+    /// Decodes a batch, keeping the samples that decode and recording the ones that don't.
+    /// Only a broken batch envelope is an error.
+    pub fn decode_lenient(data: &[u8], byte_offset: &mut usize) -> Result<DecodedBatch, Error> {
+        let count = read_u32(data, byte_offset)? as usize;
+        let mut metrics = Vec::with_capacity(count.min(data.len() / 4));
+        let mut errors = Vec::new();
+        for index in 0..count {
+            let bytes = read_bytes(data, byte_offset)?;
+            let mut metric_offset = 0;
+            match Metric::deserialize(&bytes, &mut metric_offset) {
+                Ok(_) if metric_offset != bytes.len() => errors.push(SampleError::new(index, "Trailing bytes after sample")),
+                Ok(metric) => metrics.push((index, metric)),
+                Err(e) => errors.push(SampleError::new(index, &e.to_string()))
+            }
+        }
+        Ok((metrics, errors))
+    }
+}
+
+impl BinarySerializable for WriteBatch {
+    fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend((self.metrics.len() as u32).to_le_bytes());
+        for metric in &self.metrics {
+            write_bytes(&mut data, &metric.serialize());
+        }
+        data
+    }
+
+    fn deserialize(data: &[u8], byte_offset: &mut usize) -> Result<Self, Error> where Self: Sized {
+        let (metrics, errors) = Self::decode_lenient(data, byte_offset)?;
+        if let Some(error) = errors.first() {
+            return Err(Error::Decode(format!("Sample {}: {}", error.index, error.reason)));
+        }
+        Ok(WriteBatch { metrics: metrics.into_iter().map(|(_, metric)| metric).collect() })
+    }
+}
+
+/// Why one sample of a batch was not stored. `index` is its position in the batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SampleError {
+    pub index: u32,
+    pub code: RejectCode,
+    pub reason: String
+}
+
+impl SampleError {
+    /// A sample that didn't decode.
+    pub fn new(index: usize, reason: &str) -> Self {
+        Self::with_code(index, RejectCode::Decode, reason)
+    }
+
+    pub fn with_code(index: usize, code: RejectCode, reason: &str) -> Self {
+        SampleError { index: index as u32, code, reason: reason.to_string() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BatchResult {
+    pub accepted: u32,
+    pub rejected: u32,
+    pub errors: Vec<SampleError>
+}
+
+impl BatchResult {
+    /// Folds `other` in; its indices are mapped back through `indices` first.
+    pub fn merge(&mut self, other: BatchResult, indices: &[usize]) {
+        self.accepted += other.accepted;
+        self.rejected += other.rejected;
+        for error in other.errors {
+            let index = indices.get(error.index as usize).copied().unwrap_or(error.index as usize);
+            self.errors.push(SampleError { index: index as u32, ..error });
+        }
+        self.errors.sort_by_key(|e| e.index);
+    }
+}
+
+impl BinarySerializable for BatchResult {
+    fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(self.accepted.to_le_bytes());
+        data.extend(self.rejected.to_le_bytes());
+        data.extend((self.errors.len() as u32).to_le_bytes());
+        for error in &self.errors {
+            data.extend(error.index.to_le_bytes());
+            data.extend(error.code.code().to_le_bytes());
+            write_string(&mut data, &error.reason);
+        }
+        data
+    }
+
+    fn deserialize(data: &[u8], byte_offset: &mut usize) -> Result<Self, Error> where Self: Sized {
+        let accepted = read_u32(data, byte_offset)?;
+        let rejected = read_u32(data, byte_offset)?;
+        let count = read_u32(data, byte_offset)? as usize;
+        let mut errors = Vec::with_capacity(count.min(data.len() / 8));
+        for _ in 0..count {
+            let index = read_u32(data, byte_offset)?;
+            let code = RejectCode::from_code(read_u16(data, byte_offset)?);
+            let reason = read_string(data, byte_offset)?;
+            errors.push(SampleError { index, code, reason });
+        }
+        Ok(BatchResult { accepted, rejected, errors })
+    }
+}
+
+impl JsonSerializable for BatchResult {
+    fn to_json(&self) -> String {
+        let mut out = format!("{{\"accepted\":{},\"rejected\":{},\"errors\":[", self.accepted, self.rejected);
+        for (i, error) in self.errors.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push_str(&format!("{{\"index\":{},\"code\":\"{}\",\"reason\":", error.index, error.code));
+            json::write_str(&mut out, &error.reason);
+            out.push('}');
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric(timestamp: u64) -> Metric {
+        Metric { timestamp, value: 1.0, name: "cpu".to_string(), labels: vec![] }
+    }
+
+    #[test]
+    fn batch_round_trip() {
+        let batch = WriteBatch { metrics: vec![metric(1), metric(2), metric(3)] };
+        let serialized = batch.serialize();
+
+        let mut byte_offset = 0;
+        assert_eq!(WriteBatch::deserialize(&serialized, &mut byte_offset).unwrap(), batch);
+        assert_eq!(byte_offset, serialized.len());
+    }
+
+    #[test]
+    fn bad_samples_are_reported_individually() {
+        // The second sample has the zero timestamp sentinel.
+        let batch = WriteBatch { metrics: vec![metric(1), metric(0), metric(3)] };
+        let serialized = batch.serialize();
+
+        let (metrics, errors) = WriteBatch::decode_lenient(&serialized, &mut 0).unwrap();
+        assert_eq!(metrics.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].index, 1);
+
+        assert!(WriteBatch::deserialize(&serialized, &mut 0).is_err());
+        assert!(WriteBatch::decode_lenient(&serialized[..serialized.len() - 1], &mut 0).is_err());
+    }
+
+    #[test]
+    fn result_round_trip_and_merge() {
+        let mut result = BatchResult { accepted: 0, rejected: 1, errors: vec![SampleError::new(1, "bad")] };
+        result.merge(BatchResult { accepted: 2, rejected: 1, errors: vec![SampleError::with_code(0, RejectCode::EmptyName, "empty name")] }, &[0, 2, 3]);
+        assert_eq!(result.accepted, 2);
+        assert_eq!(result.rejected, 2);
+        assert_eq!(result.errors.iter().map(|e| e.index).collect::<Vec<_>>(), vec![0, 1]);
+
+        let serialized = result.serialize();
+        assert_eq!(BatchResult::deserialize(&serialized, &mut 0).unwrap(), result);
+        assert_eq!(result.to_json(), r#"{"accepted":2,"rejected":2,"errors":[{"index":0,"code":"empty_name","reason":"empty name"},{"index":1,"code":"decode","reason":"bad"}]}"#);
+    }
+}
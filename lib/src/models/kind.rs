@@ -0,0 +1,25 @@
+/// What a metric measures, which decides how its samples may be combined. Named after the
+/// Prometheus metric types so the metadata API can report them as is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+    /// Stored as `<name>_bucket` series with an `le` label, plus `<name>_sum` and `<name>_count`.
+    Histogram,
+    /// Stored as `<name>` series with a `quantile` label, plus `<name>_sum` and `<name>_count`.
+    Summary,
+    #[default]
+    Unknown
+}
+
+impl MetricKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+            MetricKind::Summary => "summary",
+            MetricKind::Unknown => "unknown"
+        }
+    }
+}
@@ -1,8 +1,10 @@
-use crate::traits::serializable::BinarySerializable;
+use crate::{error::Error, traits::serializable::{read_f64, read_labels, read_string, read_u64, write_labels, write_string, BinarySerializable}};
 
-#[derive(Debug)]
+/// A single sample. `timestamp` is in milliseconds since the unix epoch.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Metric {
     pub timestamp: u64,
+    pub value: f64,
     pub name: String,
     pub labels: Vec<(String, String)>
 }
@@ -11,46 +13,26 @@ impl BinarySerializable for Metric {
     fn serialize(&self) -> Vec<u8> {
         let mut data = Vec::new();
         data.extend(&self.timestamp.to_le_bytes());
-        data.extend((self.name.len() as u32).to_le_bytes());
-        data.extend(self.name.as_bytes());
-        for (key, value) in &self.labels {
-            data.extend((key.len() as u32).to_le_bytes());
-            data.extend(key.as_bytes());
-            data.extend((value.len() as u32).to_le_bytes());
-            data.extend(value.as_bytes());
-        }
+        data.extend(&self.value.to_bits().to_le_bytes());
+        write_string(&mut data, &self.name);
+        write_labels(&mut data, &self.labels);
         data
     }
 
-    fn deserialize(_data: &[u8], byte_offset: &mut usize) -> std::result::Result<Self, String> where Self: Sized {
-        let timestamp = u64::from_le_bytes(_data[*byte_offset..*byte_offset + 8].try_into().unwrap());
+    fn deserialize(data: &[u8], byte_offset: &mut usize) -> Result<Self, Error> where Self: Sized {
+        let timestamp = read_u64(data, byte_offset)?;
         if timestamp == 0
         {
-            return Err(String::from("Failed to deserialize metric..."))
+            return Err(Error::Decode(String::from("Failed to deserialize metric...")))
         }
 
-        *byte_offset += 8;
-        let name_len = u32::from_le_bytes(_data[*byte_offset..*byte_offset + 4].try_into().unwrap()) as usize;
-        *byte_offset += 4;
-        let name = String::from_utf8(_data[*byte_offset..*byte_offset + name_len].to_vec()).unwrap();
-        *byte_offset += name_len;
-
-        let mut labels = Vec::new();
-        while *byte_offset < _data.len() {
-            let key_len = u32::from_le_bytes(_data[*byte_offset..*byte_offset + 4].try_into().unwrap()) as usize;
-            *byte_offset += 4;
-            let key = String::from_utf8(_data[*byte_offset..*byte_offset + key_len].to_vec()).unwrap();
-            *byte_offset += key_len;
-            let value_len = u32::from_le_bytes(_data[*byte_offset..*byte_offset + 4].try_into().unwrap()) as usize;
-            *byte_offset += 4;
-            let value = String::from_utf8(_data[*byte_offset..*byte_offset + value_len].to_vec()).unwrap();
-            *byte_offset += value_len;
-
-            labels.push((key, value));
-        }
+        let value = read_f64(data, byte_offset)?;
+        let name = read_string(data, byte_offset)?;
+        let labels = read_labels(data, byte_offset)?;
 
         Ok(Self {
             timestamp,
+            value,
             name,
             labels 
         })
@@ -65,6 +47,7 @@ mod tests {
     fn test_metric_serialization() {
         let metric = Metric {
             timestamp: 1622547800,
+            value: 42.5,
             name: "test_metric".to_string(),
             labels: vec![("label1".to_string(), "value1".to_string()), ("label2".to_string(), "value2".to_string())]
         };
@@ -73,11 +56,27 @@ mod tests {
         let mut byte_offset: usize = 0;
         let deserialized = Metric::deserialize(&serialized, &mut byte_offset).unwrap();
         assert_eq!(deserialized.timestamp, metric.timestamp);
+        assert_eq!(deserialized.value, metric.value);
         assert_eq!(deserialized.name, metric.name);
         assert_eq!(deserialized.labels.len(), metric.labels.len());
         for (i, (key, value)) in deserialized.labels.iter().enumerate() {
             assert_eq!(key, &metric.labels[i].0);
             assert_eq!(value, &metric.labels[i].1);
         }
+        assert_eq!(byte_offset, serialized.len());
+    }
+
+    #[test]
+    fn test_metric_back_to_back() {
+        let first = Metric { timestamp: 1, value: 1.0, name: "a".to_string(), labels: vec![("k".to_string(), "v".to_string())] };
+        let second = Metric { timestamp: 2, value: 2.0, name: "b".to_string(), labels: vec![] };
+
+        let mut serialized = first.serialize();
+        serialized.extend(second.serialize());
+
+        let mut byte_offset: usize = 0;
+        assert_eq!(Metric::deserialize(&serialized, &mut byte_offset).unwrap(), first);
+        assert_eq!(Metric::deserialize(&serialized, &mut byte_offset).unwrap(), second);
+        assert!(Metric::deserialize(&serialized, &mut byte_offset).is_err());
     }
 }
\ No newline at end of file
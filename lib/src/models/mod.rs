@@ -1,5 +1,11 @@
 pub mod metric;
 pub mod chunk;
+pub mod batch;
+pub mod kind;
+pub mod validation;
 
 // Re-exporting the Metric struct for easier access
-pub use metric::Metric;
\ No newline at end of file
+pub use metric::Metric;
+pub use batch::{BatchResult, SampleError, WriteBatch};
+pub use kind::MetricKind;
+pub use validation::{RejectCode, Validation};
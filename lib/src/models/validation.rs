@@ -0,0 +1,275 @@
+use std::fmt;
+use std::time::Duration;
+
+use crate::{models::Metric, query::range::is_stale_marker};
+
+/// Why a sample was turned down. Codes below 10 are for samples that didn't decode, the 10s
+/// for names and labels, the 20s for timestamps, the 30s for values, the 40s for tenant limits
+/// and the 50s for storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RejectCode {
+    #[default]
+    Decode,
+    EmptyName,
+    InvalidName,
+    InvalidLabelName,
+    DuplicateLabel,
+    LabelNameTooLong,
+    LabelValueTooLong,
+    TooManyLabels,
+    /// The metric name is too long to name its series file.
+    NameTooLong,
+    ZeroTimestamp,
+    TooOld,
+    TooFarInFuture,
+    NanValue,
+    /// The tenant holds as many series as it may.
+    SeriesLimit,
+    /// The tenant is sending faster than its ingest rate.
+    RateLimited,
+    /// The tenant is new and the server holds as many tenants as it may.
+    TenantLimit,
+    WalAppendFailed,
+    /// The ingest queue was full.
+    Overloaded,
+    /// A code this build doesn't know about, most likely from a newer peer.
+    Other(u16)
+}
+
+impl RejectCode {
+    pub fn code(&self) -> u16 {
+        match self {
+            RejectCode::Decode => 1,
+            RejectCode::EmptyName => 10,
+            RejectCode::InvalidName => 11,
+            RejectCode::InvalidLabelName => 12,
+            RejectCode::DuplicateLabel => 13,
+            RejectCode::LabelNameTooLong => 14,
+            RejectCode::LabelValueTooLong => 15,
+            RejectCode::TooManyLabels => 16,
+            RejectCode::NameTooLong => 17,
+            RejectCode::ZeroTimestamp => 20,
+            RejectCode::TooOld => 21,
+            RejectCode::TooFarInFuture => 22,
+            RejectCode::NanValue => 30,
+            RejectCode::SeriesLimit => 40,
+            RejectCode::RateLimited => 41,
+            RejectCode::TenantLimit => 42,
+            RejectCode::WalAppendFailed => 50,
+            RejectCode::Overloaded => 51,
+            RejectCode::Other(code) => *code
+        }
+    }
+
+    pub fn from_code(code: u16) -> Self {
+        match code {
+            1 => RejectCode::Decode,
+            10 => RejectCode::EmptyName,
+            11 => RejectCode::InvalidName,
+            12 => RejectCode::InvalidLabelName,
+            13 => RejectCode::DuplicateLabel,
+            14 => RejectCode::LabelNameTooLong,
+            15 => RejectCode::LabelValueTooLong,
+            16 => RejectCode::TooManyLabels,
+            17 => RejectCode::NameTooLong,
+            20 => RejectCode::ZeroTimestamp,
+            21 => RejectCode::TooOld,
+            22 => RejectCode::TooFarInFuture,
+            30 => RejectCode::NanValue,
+            40 => RejectCode::SeriesLimit,
+            41 => RejectCode::RateLimited,
+            42 => RejectCode::TenantLimit,
+            50 => RejectCode::WalAppendFailed,
+            51 => RejectCode::Overloaded,
+            code => RejectCode::Other(code)
+        }
+    }
+
+    /// The name HTTP responses use for the code.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectCode::Decode => "decode",
+            RejectCode::EmptyName => "empty_name",
+            RejectCode::InvalidName => "invalid_name",
+            RejectCode::InvalidLabelName => "invalid_label_name",
+            RejectCode::DuplicateLabel => "duplicate_label",
+            RejectCode::LabelNameTooLong => "label_name_too_long",
+            RejectCode::LabelValueTooLong => "label_value_too_long",
+            RejectCode::TooManyLabels => "too_many_labels",
+            RejectCode::NameTooLong => "name_too_long",
+            RejectCode::ZeroTimestamp => "zero_timestamp",
+            RejectCode::TooOld => "too_old",
+            RejectCode::TooFarInFuture => "too_far_in_future",
+            RejectCode::NanValue => "nan_value",
+            RejectCode::SeriesLimit => "series_limit",
+            RejectCode::RateLimited => "rate_limited",
+            RejectCode::TenantLimit => "tenant_limit",
+            RejectCode::WalAppendFailed => "wal_append_failed",
+            RejectCode::Overloaded => "overloaded",
+            RejectCode::Other(_) => "unknown"
+        }
+    }
+
+    /// Whether the same sample may be accepted on a retry.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, RejectCode::RateLimited | RejectCode::WalAppendFailed | RejectCode::Overloaded)
+    }
+}
+
+impl fmt::Display for RejectCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The longest metric name in bytes. Each metric is flushed to `<name>.metricdata`, which has
+/// to fit the 255 bytes most filesystems allow for a file name.
+pub const MAX_NAME_LEN: usize = 255 - ".metricdata".len();
+
+/// What happens to NaN values. Stale markers are NaN too, with a payload of their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NanPolicy {
+    #[default]
+    Accept,
+    /// Keeps stale markers, so scrapes and remote write can still end series.
+    RejectExceptStale,
+    Reject
+}
+
+/// The checks every sample passes before it's stored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Validation {
+    /// How far behind the current time a sample may be. `None` accepts any age.
+    pub max_age: Option<Duration>,
+    /// How far ahead of the current time a sample may be. `None` accepts any time.
+    pub max_future: Option<Duration>,
+    pub max_label_name_len: usize,
+    pub max_label_value_len: usize,
+    /// Labels besides the name.
+    pub max_labels: usize,
+    pub nan_policy: NanPolicy
+}
+
+impl Default for Validation {
+    /// No time window, and limits no well behaved sender gets near.
+    fn default() -> Self {
+        Validation {
+            max_age: None,
+            max_future: None,
+            max_label_name_len: 1024,
+            max_label_value_len: 4096,
+            max_labels: 64,
+            nan_policy: NanPolicy::Accept
+        }
+    }
+}
+
+impl Validation {
+    /// Checks `metric` as of `now`, in milliseconds. Label values are measured in bytes.
+    pub fn check(&self, metric: &Metric, now: u64) -> Result<(), (RejectCode, String)> {
+        if metric.name.is_empty() {
+            return Err((RejectCode::EmptyName, String::from("Metric name is empty")));
+        }
+        if !valid_name(&metric.name, true) {
+            return Err((RejectCode::InvalidName, format!("Invalid metric name {:?}", metric.name)));
+        }
+        if metric.name.len() > MAX_NAME_LEN {
+            return Err((RejectCode::NameTooLong, format!("Metric name {}... is over the limit of {} bytes", truncate(&metric.name), MAX_NAME_LEN)));
+        }
+
+        if metric.labels.len() > self.max_labels {
+            return Err((RejectCode::TooManyLabels, format!("{} labels is over the limit of {}", metric.labels.len(), self.max_labels)));
+        }
+        for (key, value) in &metric.labels {
+            if !valid_name(key, false) {
+                return Err((RejectCode::InvalidLabelName, format!("Invalid label name {:?}", key)));
+            }
+            if key.len() > self.max_label_name_len {
+                return Err((RejectCode::LabelNameTooLong, format!("Label name {}... is over the limit of {} bytes", truncate(key), self.max_label_name_len)));
+            }
+            if value.len() > self.max_label_value_len {
+                return Err((RejectCode::LabelValueTooLong, format!("Value of label {} is over the limit of {} bytes", key, self.max_label_value_len)));
+            }
+        }
+        let mut keys: Vec<&str> = metric.labels.iter().map(|(key, _)| key.as_str()).collect();
+        keys.sort_unstable();
+        if let Some(pair) = keys.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err((RejectCode::DuplicateLabel, format!("Duplicate label name {}", pair[0])));
+        }
+
+        // A zero timestamp marks the end of the data in a WAL file.
+        if metric.timestamp == 0 {
+            return Err((RejectCode::ZeroTimestamp, String::from("Timestamp must be greater than zero")));
+        }
+        if let Some(max_age) = self.max_age && metric.timestamp < now.saturating_sub(max_age.as_millis() as u64) {
+            return Err((RejectCode::TooOld, format!("Timestamp {} is more than {:?} in the past", metric.timestamp, max_age)));
+        }
+        if let Some(max_future) = self.max_future && metric.timestamp > now.saturating_add(max_future.as_millis() as u64) {
+            return Err((RejectCode::TooFarInFuture, format!("Timestamp {} is more than {:?} in the future", metric.timestamp, max_future)));
+        }
+
+        let rejected_nan = match self.nan_policy {
+            NanPolicy::Accept => false,
+            NanPolicy::RejectExceptStale => metric.value.is_nan() && !is_stale_marker(metric.value),
+            NanPolicy::Reject => metric.value.is_nan()
+        };
+        if rejected_nan {
+            return Err((RejectCode::NanValue, String::from("Value is NaN")));
+        }
+        Ok(())
+    }
+}
+
+/// `[a-zA-Z_][a-zA-Z0-9_]*`, and metric names may also have colons.
+fn valid_name(name: &str, colons: bool) -> bool {
+    !name.is_empty() && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || (colons && c == ':'))
+}
+
+fn truncate(name: &str) -> &str {
+    let end = name.char_indices().nth(32).map_or(name.len(), |(i, _)| i);
+    &name[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::range::stale_marker;
+
+    fn metric(name: &str, labels: &[(&str, &str)], timestamp: u64, value: f64) -> Metric {
+        Metric { timestamp, value, name: name.to_string(), labels: labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect() }
+    }
+
+    #[test]
+    fn rejects_with_codes() {
+        let validation = Validation {
+            max_age: Some(Duration::from_secs(60)),
+            max_future: Some(Duration::from_secs(10)),
+            max_label_value_len: 4,
+            max_labels: 2,
+            nan_policy: NanPolicy::RejectExceptStale,
+            ..Validation::default()
+        };
+        let now = 1_000_000;
+        let code = |metric: Metric| validation.check(&metric, now).err().map(|(code, _)| code);
+
+        assert_eq!(code(metric("job:up", &[("a", "1"), ("b", "2")], now, 1.0)), None);
+        assert_eq!(code(metric("", &[], now, 1.0)), Some(RejectCode::EmptyName));
+        assert_eq!(code(metric("http-requests", &[], now, 1.0)), Some(RejectCode::InvalidName));
+        assert_eq!(code(metric(&"a".repeat(MAX_NAME_LEN), &[], now, 1.0)), None);
+        assert_eq!(code(metric(&"a".repeat(MAX_NAME_LEN + 1), &[], now, 1.0)), Some(RejectCode::NameTooLong));
+        assert_eq!(code(metric("up", &[("a:b", "1")], now, 1.0)), Some(RejectCode::InvalidLabelName));
+        assert_eq!(code(metric("up", &[("a", "1"), ("a", "2")], now, 1.0)), Some(RejectCode::DuplicateLabel));
+        assert_eq!(code(metric("up", &[("a", "12345")], now, 1.0)), Some(RejectCode::LabelValueTooLong));
+        assert_eq!(code(metric("up", &[("a", "1"), ("b", "2"), ("c", "3")], now, 1.0)), Some(RejectCode::TooManyLabels));
+        assert_eq!(code(metric("up", &[], 0, 1.0)), Some(RejectCode::ZeroTimestamp));
+        assert_eq!(code(metric("up", &[], now - 60_001, 1.0)), Some(RejectCode::TooOld));
+        assert_eq!(code(metric("up", &[], now + 10_001, 1.0)), Some(RejectCode::TooFarInFuture));
+        assert_eq!(code(metric("up", &[], now, f64::NAN)), Some(RejectCode::NanValue));
+        assert_eq!(code(metric("up", &[], now, stale_marker())), None);
+
+        for code in [RejectCode::Decode, RejectCode::NameTooLong, RejectCode::TooOld, RejectCode::SeriesLimit, RejectCode::RateLimited, RejectCode::WalAppendFailed, RejectCode::Overloaded, RejectCode::Other(77)] {
+            assert_eq!(RejectCode::from_code(code.code()), code);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::fmt;
+
+use crate::{error::Error, models::RejectCode, protocol::frame::PROTOCOL_VERSION, traits::serializable::{read_string, read_u16, write_string, BinarySerializable}};
+
+/// Everything that can go wrong with a request, as sent back to the client in an `Error`
+/// frame. Codes in the 100s are framing problems that leave the stream in an unknown state,
+/// so the server closes the connection after sending them. Everything else only fails the
+/// one request. A rejected sample is 400 plus its `RejectCode`. Success is `STATUS_OK`, and
+/// is never sent as an error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    MalformedFrame(String),
+    UnsupportedVersion(u8),
+    FrameTooLarge(u32),
+    HandshakeRequired,
+    UnknownOpcode(u8),
+    UnexpectedFrame(String),
+    Decode(String),
+    NotFound(String),
+    LimitExceeded(String),
+    Rejected(RejectCode, String),
+    Internal(String),
+    /// A code this build doesn't know about, most likely from a newer peer.
+    Other(u16, String)
+}
+
+impl ProtocolError {
+    pub fn code(&self) -> u16 {
+        match self {
+            ProtocolError::MalformedFrame(_) => 100,
+            ProtocolError::UnsupportedVersion(_) => 101,
+            ProtocolError::FrameTooLarge(_) => 102,
+            ProtocolError::HandshakeRequired => 103,
+            ProtocolError::UnknownOpcode(_) => 200,
+            ProtocolError::UnexpectedFrame(_) => 201,
+            ProtocolError::Decode(_) => 202,
+            ProtocolError::NotFound(_) => 203,
+            ProtocolError::LimitExceeded(_) => 300,
+            ProtocolError::Rejected(code, _) => 400 + code.code(),
+            ProtocolError::Internal(_) => 500,
+            ProtocolError::Other(code, _) => *code
+        }
+    }
+
+    pub fn is_fatal(&self) -> bool {
+        (100..200).contains(&self.code())
+    }
+
+    fn detail(&self) -> String {
+        match self {
+            ProtocolError::MalformedFrame(detail) | ProtocolError::UnexpectedFrame(detail) | ProtocolError::Decode(detail)
+                | ProtocolError::NotFound(detail) | ProtocolError::LimitExceeded(detail) | ProtocolError::Rejected(_, detail)
+                | ProtocolError::Internal(detail) | ProtocolError::Other(_, detail) => detail.clone(),
+            ProtocolError::UnsupportedVersion(version) => version.to_string(),
+            ProtocolError::FrameTooLarge(length) => length.to_string(),
+            ProtocolError::UnknownOpcode(opcode) => opcode.to_string(),
+            ProtocolError::HandshakeRequired => String::new()
+        }
+    }
+
+    fn from_wire(code: u16, detail: String) -> Self {
+        match code {
+            100 => ProtocolError::MalformedFrame(detail),
+            101 => detail.parse().map(ProtocolError::UnsupportedVersion).unwrap_or(ProtocolError::Other(code, detail)),
+            102 => detail.parse().map(ProtocolError::FrameTooLarge).unwrap_or(ProtocolError::Other(code, detail)),
+            103 => ProtocolError::HandshakeRequired,
+            200 => detail.parse().map(ProtocolError::UnknownOpcode).unwrap_or(ProtocolError::Other(code, detail)),
+            201 => ProtocolError::UnexpectedFrame(detail),
+            202 => ProtocolError::Decode(detail),
+            203 => ProtocolError::NotFound(detail),
+            300 => ProtocolError::LimitExceeded(detail),
+            401..500 => ProtocolError::Rejected(RejectCode::from_code(code - 400), detail),
+            500 => ProtocolError::Internal(detail),
+            _ => ProtocolError::Other(code, detail)
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::MalformedFrame(detail) => write!(f, "Malformed frame: {}", detail),
+            ProtocolError::UnsupportedVersion(version) => write!(f, "Unsupported protocol version {}, expected {}", version, PROTOCOL_VERSION),
+            ProtocolError::FrameTooLarge(length) => write!(f, "Frame payload of {} bytes is too large", length),
+            ProtocolError::HandshakeRequired => f.write_str("The first frame on a connection must be a hello"),
+            ProtocolError::UnknownOpcode(opcode) => write!(f, "Unknown opcode {}", opcode),
+            ProtocolError::UnexpectedFrame(detail) => write!(f, "Unexpected frame: {}", detail),
+            ProtocolError::Decode(detail) => write!(f, "Failed to decode request: {}", detail),
+            ProtocolError::NotFound(detail) => write!(f, "Not found: {}", detail),
+            ProtocolError::LimitExceeded(detail) => write!(f, "Limit exceeded: {}", detail),
+            ProtocolError::Rejected(code, detail) => write!(f, "Sample rejected ({}): {}", code, detail),
+            ProtocolError::Internal(detail) => write!(f, "Internal error: {}", detail),
+            ProtocolError::Other(code, detail) => write!(f, "Error {}: {}", code, detail)
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<Error> for ProtocolError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Decode(detail) | Error::Invalid(detail) => ProtocolError::Decode(detail),
+            Error::NotFound(detail) => ProtocolError::NotFound(detail),
+            Error::LimitExceeded { .. } | Error::Cancelled => ProtocolError::LimitExceeded(e.to_string()),
+            Error::Rejected { code, detail } => ProtocolError::Rejected(code, detail),
+            Error::Io(_) | Error::Corruption { .. } | Error::ArenaFull { .. } => ProtocolError::Internal(e.to_string())
+        }
+    }
+}
+
+impl BinarySerializable for ProtocolError {
+    fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(self.code().to_le_bytes());
+        write_string(&mut data, &self.detail());
+        data
+    }
+
+    fn deserialize(data: &[u8], byte_offset: &mut usize) -> Result<Self, Error> where Self: Sized {
+        let code = read_u16(data, byte_offset)?;
+        let detail = read_string(data, byte_offset)?;
+        Ok(ProtocolError::from_wire(code, detail))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_round_trip() {
+        let errors = vec![
+            ProtocolError::MalformedFrame("bad magic".to_string()),
+            ProtocolError::UnsupportedVersion(9),
+            ProtocolError::FrameTooLarge(u32::MAX),
+            ProtocolError::HandshakeRequired,
+            ProtocolError::UnknownOpcode(77),
+            ProtocolError::Decode("truncated".to_string()),
+            ProtocolError::NotFound("cpu".to_string()),
+            ProtocolError::LimitExceeded("max_series".to_string()),
+            ProtocolError::Rejected(RejectCode::TooOld, "a day old".to_string()),
+            ProtocolError::Other(999, "from the future".to_string()),
+        ];
+
+        for error in errors {
+            let serialized = error.serialize();
+            assert_eq!(ProtocolError::deserialize(&serialized, &mut 0).unwrap(), error);
+        }
+    }
+
+    #[test]
+    fn only_framing_errors_are_fatal() {
+        assert!(ProtocolError::MalformedFrame(String::new()).is_fatal());
+        assert!(ProtocolError::HandshakeRequired.is_fatal());
+        assert!(!ProtocolError::UnknownOpcode(9).is_fatal());
+        assert!(!ProtocolError::Decode(String::new()).is_fatal());
+        assert!(!ProtocolError::Internal(String::new()).is_fatal());
+    }
+}
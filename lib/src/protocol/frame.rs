@@ -0,0 +1,218 @@
+use std::io::Read;
+
+use crate::{protocol::error::ProtocolError, traits::serializable::read_u32};
+
+pub const MAGIC: [u8; 2] = *b"MH";
+pub const PROTOCOL_VERSION: u8 = 2;
+pub const HEADER_LEN: usize = 12;
+pub const MAX_PAYLOAD_LEN: u32 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Opcode {
+    Hello = 1,
+    Read = 2,
+    Write = 3,
+    Response = 4,
+    Goodbye = 5,
+    /// Part of a read result. Any number of these come before the read's `Response`.
+    ReadChunk = 6,
+    WriteBatch = 7,
+    /// Sent instead of a `Response` when a request fails. The payload is a `ProtocolError`.
+    Error = 8
+}
+
+impl TryFrom<u8> for Opcode {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, ProtocolError> {
+        match value {
+            1 => Ok(Opcode::Hello),
+            2 => Ok(Opcode::Read),
+            3 => Ok(Opcode::Write),
+            4 => Ok(Opcode::Response),
+            5 => Ok(Opcode::Goodbye),
+            6 => Ok(Opcode::ReadChunk),
+            7 => Ok(Opcode::WriteBatch),
+            8 => Ok(Opcode::Error),
+            _ => Err(ProtocolError::UnknownOpcode(value))
+        }
+    }
+}
+
+/// Fixed size frame header:
+///
+/// | magic (2) | version (1) | opcode (1) | request id (4) | payload length (4) |
+///
+/// Integers are little-endian like everywhere else on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub version: u8,
+    pub opcode: Opcode,
+    pub request_id: u32,
+    pub length: u32
+}
+
+impl FrameHeader {
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[0..2].copy_from_slice(&MAGIC);
+        bytes[2] = self.version;
+        bytes[3] = self.opcode as u8;
+        bytes[4..8].copy_from_slice(&self.request_id.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.length.to_le_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8; HEADER_LEN]) -> Result<Self, ProtocolError> {
+        if bytes[0..2] != MAGIC {
+            return Err(ProtocolError::MalformedFrame(String::from("Bad frame magic")));
+        }
+
+        let (request_id, length) = Self::peek(bytes);
+        if length > MAX_PAYLOAD_LEN {
+            return Err(ProtocolError::FrameTooLarge(length));
+        }
+
+        Ok(FrameHeader {
+            version: bytes[2],
+            opcode: Opcode::try_from(bytes[3])?,
+            request_id,
+            length
+        })
+    }
+
+    /// Decodes the header bytes a reader got before the stream ended, which is all of them
+    /// unless it ended early. None at all is a clean close between frames, and some but not
+    /// all is a truncated frame.
+    pub fn parse(bytes: &[u8]) -> Result<Option<Self>, ProtocolError> {
+        match bytes.try_into() {
+            Ok(bytes) => Self::decode(bytes).map(Some),
+            Err(_) if bytes.is_empty() => Ok(None),
+            Err(_) => Err(ProtocolError::MalformedFrame(String::from("Connection closed inside a frame header")))
+        }
+    }
+
+    /// Request id and payload length straight from the header bytes, without any checks.
+    /// Lets a reader answer and skip a frame whose header doesn't decode.
+    pub fn peek(bytes: &[u8; HEADER_LEN]) -> (u32, u32) {
+        let mut byte_offset = 4;
+        let request_id = read_u32(bytes, &mut byte_offset).unwrap_or_default();
+        let length = read_u32(bytes, &mut byte_offset).unwrap_or_default();
+        (request_id, length)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub header: FrameHeader,
+    pub payload: Vec<u8>
+}
+
+impl Frame {
+    pub fn new(opcode: Opcode, request_id: u32, payload: Vec<u8>) -> Self {
+        Frame {
+            header: FrameHeader {
+                version: PROTOCOL_VERSION,
+                opcode,
+                request_id,
+                length: payload.len() as u32
+            },
+            payload
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        bytes.extend(self.header.encode());
+        bytes.extend(&self.payload);
+        bytes
+    }
+
+    /// Blocking read of one whole frame. Returns `Ok(None)` on a clean end of stream
+    /// before the first header byte; a stream that ends later than that is an error.
+    pub fn read_from(reader: &mut impl Read) -> std::io::Result<Option<Frame>> {
+        let mut header_bytes = [0u8; HEADER_LEN];
+        let mut filled = 0;
+        while filled < HEADER_LEN {
+            match reader.read(&mut header_bytes[filled..]) {
+                Ok(0) => break,
+                Ok(read) => filled += read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e)
+            }
+        }
+
+        let Some(header) = FrameHeader::parse(&header_bytes[..filled])
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))? else {
+            return Ok(None);
+        };
+        let mut payload = vec![0u8; header.length as usize];
+        reader.read_exact(&mut payload)?;
+
+        Ok(Some(Frame { header, payload }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_round_trip() {
+        let frame = Frame::new(Opcode::Write, 42, vec![1, 2, 3]);
+        let bytes = frame.encode();
+        assert_eq!(bytes.len(), HEADER_LEN + 3);
+
+        let mut reader = bytes.as_slice();
+        assert_eq!(Frame::read_from(&mut reader).unwrap(), Some(frame));
+        assert_eq!(Frame::read_from(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let mut header = Frame::new(Opcode::Read, 1, Vec::new()).header.encode();
+        header[0] = b'X';
+        assert!(matches!(FrameHeader::decode(&header), Err(ProtocolError::MalformedFrame(_))));
+
+        let mut header = Frame::new(Opcode::Read, 1, vec![0; 3]).header.encode();
+        header[3] = 200;
+        assert_eq!(FrameHeader::decode(&header), Err(ProtocolError::UnknownOpcode(200)));
+        assert_eq!(FrameHeader::peek(&header), (1, 3));
+
+        let mut header = Frame::new(Opcode::Read, 1, Vec::new()).header.encode();
+        header[8..12].copy_from_slice(&(MAX_PAYLOAD_LEN + 1).to_le_bytes());
+        assert_eq!(FrameHeader::decode(&header), Err(ProtocolError::FrameTooLarge(MAX_PAYLOAD_LEN + 1)));
+    }
+
+    #[test]
+    fn truncated_header_is_not_a_clean_close() {
+        let bytes = Frame::new(Opcode::Goodbye, 3, Vec::new()).encode();
+        assert_eq!(FrameHeader::parse(&[]), Ok(None));
+        assert!(matches!(FrameHeader::parse(&bytes[..5]), Err(ProtocolError::MalformedFrame(_))));
+
+        let error = Frame::read_from(&mut &bytes[..HEADER_LEN - 1]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn split_frames_are_reassembled() {
+        let bytes = Frame::new(Opcode::Hello, 7, b"client".to_vec()).encode();
+        // A reader that only ever hands out one byte at a time.
+        struct Trickle<'a>(&'a [u8]);
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if self.0.is_empty() || buf.is_empty() {
+                    return Ok(0);
+                }
+                buf[0] = self.0[0];
+                self.0 = &self.0[1..];
+                Ok(1)
+            }
+        }
+
+        let frame = Frame::read_from(&mut Trickle(&bytes)).unwrap().unwrap();
+        assert_eq!(frame.header.request_id, 7);
+        assert_eq!(frame.payload, b"client");
+    }
+}
@@ -0,0 +1,107 @@
+use crate::{error::Error, traits::serializable::{read_bytes, read_string, read_u16, write_bytes, write_string, BinarySerializable}};
+
+/// First frame on every connection. The protocol version travels in the frame header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub client: String,
+    /// Whose data the connection reads and writes. Empty is the default tenant, which is
+    /// also what a client from before there were tenants gets, as it doesn't send one.
+    pub tenant: String
+}
+
+impl BinarySerializable for Hello {
+    fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_string(&mut data, &self.client);
+        write_string(&mut data, &self.tenant);
+        data
+    }
+
+    fn deserialize(data: &[u8], byte_offset: &mut usize) -> Result<Self, Error> where Self: Sized {
+        let client = read_string(data, byte_offset)?;
+        let tenant = if *byte_offset < data.len() { read_string(data, byte_offset)? } else { String::new() };
+        Ok(Hello { client, tenant })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadRequest {
+    pub name: String
+}
+
+impl BinarySerializable for ReadRequest {
+    fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_string(&mut data, &self.name);
+        data
+    }
+
+    fn deserialize(data: &[u8], byte_offset: &mut usize) -> Result<Self, Error> where Self: Sized {
+        Ok(ReadRequest { name: read_string(data, byte_offset)? })
+    }
+}
+
+/// Leads every `Response`, where older clients expect a status. Error codes start at 100 and
+/// travel in `Error` frames, so a response never carries anything else.
+pub const STATUS_OK: u16 = 0;
+
+/// Sent back for every request that succeeds, with the same request id. Failures get an
+/// `Error` frame instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub body: Vec<u8>
+}
+
+impl Response {
+    pub fn ok(body: Vec<u8>) -> Self {
+        Response { body }
+    }
+}
+
+impl BinarySerializable for Response {
+    fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(STATUS_OK.to_le_bytes());
+        write_bytes(&mut data, &self.body);
+        data
+    }
+
+    fn deserialize(data: &[u8], byte_offset: &mut usize) -> Result<Self, Error> where Self: Sized {
+        let status = read_u16(data, byte_offset)?;
+        if status != STATUS_OK {
+            return Err(Error::Decode(format!("Response has status {}, expected {}", status, STATUS_OK)));
+        }
+        Ok(Response { body: read_bytes(data, byte_offset)? })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_without_a_tenant() {
+        let hello = Hello { client: String::from("c"), tenant: String::from("team-a") };
+        assert_eq!(Hello::deserialize(&hello.serialize(), &mut 0).unwrap(), hello);
+
+        let mut old = Vec::new();
+        write_string(&mut old, "c");
+        assert_eq!(Hello::deserialize(&old, &mut 0).unwrap().tenant, "");
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let response = Response::ok(vec![1, 2, 3]);
+        let serialized = response.serialize();
+
+        let mut byte_offset = 0;
+        assert_eq!(Response::deserialize(&serialized, &mut byte_offset).unwrap(), response);
+        assert_eq!(byte_offset, serialized.len());
+
+        assert!(Response::deserialize(&serialized[..5], &mut 0).is_err());
+
+        let mut failed = serialized.clone();
+        failed[0] = 2;
+        assert!(Response::deserialize(&failed, &mut 0).is_err());
+    }
+}
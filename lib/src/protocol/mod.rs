@@ -0,0 +1,50 @@
+pub mod error;
+pub mod frame;
+pub mod message;
+pub mod read;
+
+pub use error::ProtocolError;
+pub use frame::{Frame, FrameHeader, Opcode, HEADER_LEN, PROTOCOL_VERSION};
+pub use message::{Hello, ReadRequest, Response, STATUS_OK};
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+    use crate::{models::{BatchResult, Metric, WriteBatch}, query::Matrix, traits::serializable::BinarySerializable};
+
+    fn decode_all(data: &[u8]) {
+        let _ = Metric::deserialize(data, &mut 0);
+        let _ = WriteBatch::decode_lenient(data, &mut 0);
+        let _ = BatchResult::deserialize(data, &mut 0);
+        let _ = Matrix::deserialize(data, &mut 0);
+        let _ = Hello::deserialize(data, &mut 0);
+        let _ = ReadRequest::deserialize(data, &mut 0);
+        let _ = Response::deserialize(data, &mut 0);
+        let _ = ProtocolError::deserialize(data, &mut 0);
+        if let Ok(header) = data.get(..HEADER_LEN).unwrap_or_default().try_into() {
+            let _ = FrameHeader::decode(header);
+        }
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic() {
+        let mut rng = rand::rng();
+        for _ in 0..2000 {
+            let len = rng.random_range(0..64);
+            let data: Vec<u8> = (0..len).map(|_| rng.random()).collect();
+            decode_all(&data);
+        }
+
+        // Truncations of valid messages hit the paths random bytes rarely reach.
+        let metric = Metric { timestamp: 1, value: 1.0, name: "cpu".to_string(), labels: vec![("host".to_string(), "a".to_string())] };
+        let batch = WriteBatch { metrics: vec![metric.clone(), metric.clone()] }.serialize();
+        let matrix = Matrix::from_samples([&metric]).serialize();
+        for valid in [metric.serialize(), batch, matrix] {
+            for end in 0..valid.len() {
+                decode_all(&valid[..end]);
+            }
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use crate::query::{Matrix, Series};
+
+/// Upper bound on the encoded size of one read result frame.
+pub const MAX_CHUNK_BYTES: usize = 1024 * 1024;
+
+const POINT_BYTES: usize = 16;
+
+fn series_header_bytes(series: &Series) -> usize {
+    let labels: usize = series.labels.iter().map(|(key, value)| 8 + key.len() + value.len()).sum();
+    4 + series.name.len() + 4 + labels + 4
+}
+
+/// Splits a read result into matrices that each encode to roughly `max_bytes` or less. A
+/// series too big for one chunk is split across several, repeating its name and labels;
+/// the receiving side glues consecutive pieces of the same series back together.
+pub fn chunk_matrix(matrix: &Matrix, max_bytes: usize) -> Vec<Matrix> {
+    let mut chunks = Vec::new();
+    let mut current = Matrix::default();
+    let mut current_bytes = 4;
+
+    for series in &matrix.series {
+        let header = series_header_bytes(series);
+        let mut points = series.points.as_slice();
+        loop {
+            // Start a new chunk if this one can't fit the header and at least one point.
+            if current_bytes + header + POINT_BYTES > max_bytes && !current.is_empty() {
+                chunks.push(std::mem::take(&mut current));
+                current_bytes = 4;
+            }
+
+            let room = max_bytes.saturating_sub(current_bytes + header) / POINT_BYTES;
+            let take = room.max(1).min(points.len());
+            current.series.push(Series {
+                name: series.name.clone(),
+                labels: series.labels.clone(),
+                points: points[..take].to_vec()
+            });
+            current_bytes += header + take * POINT_BYTES;
+            points = &points[take..];
+
+            if points.is_empty() {
+                break;
+            }
+        }
+    }
+
+    if !current.is_empty() || chunks.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::serializable::BinarySerializable;
+
+    fn series(name: &str, points: usize) -> Series {
+        Series {
+            name: name.to_string(),
+            labels: vec![("host".to_string(), "a".to_string())],
+            points: (0..points as u64).map(|t| (t, t as f64)).collect()
+        }
+    }
+
+    #[test]
+    fn small_results_are_one_chunk() {
+        let matrix = Matrix { series: vec![series("a", 3), series("b", 3)] };
+        assert_eq!(chunk_matrix(&matrix, MAX_CHUNK_BYTES), vec![matrix]);
+        assert_eq!(chunk_matrix(&Matrix::default(), MAX_CHUNK_BYTES), vec![Matrix::default()]);
+    }
+
+    #[test]
+    fn chunks_stay_under_the_limit() {
+        let matrix = Matrix { series: vec![series("a", 1000), series("b", 10), series("c", 500)] };
+        let max_bytes = 2048;
+
+        let chunks = chunk_matrix(&matrix, max_bytes);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.serialize().len() <= max_bytes);
+        }
+        assert_eq!(chunks.iter().map(|c| c.point_count()).sum::<usize>(), matrix.point_count());
+    }
+}
@@ -0,0 +1,194 @@
+use std::{fmt, sync::{atomic::{AtomicBool, Ordering}, Arc}, time::{Duration, Instant}};
+
+use crate::error::Error;
+
+// The clock and the cancel flag are only looked at every this many samples.
+const CHECK_INTERVAL: usize = 1024;
+
+/// Per query resource limits. A query that goes over any of them is aborted with
+/// `Error::LimitExceeded`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueryLimits {
+    pub max_series: usize,
+    pub max_samples: usize,
+    pub max_points: usize,
+    pub timeout: Duration
+}
+
+impl Default for QueryLimits {
+    fn default() -> Self {
+        QueryLimits {
+            max_series: 10_000,
+            max_samples: 50_000_000,
+            max_points: 1_000_000,
+            timeout: Duration::from_secs(120)
+        }
+    }
+}
+
+impl QueryLimits {
+    pub const UNLIMITED: QueryLimits = QueryLimits {
+        max_series: usize::MAX,
+        max_samples: usize::MAX,
+        max_points: usize::MAX,
+        timeout: Duration::MAX
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Series,
+    Samples,
+    Points,
+    Timeout
+}
+
+impl Limit {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Limit::Series => "max_series",
+            Limit::Samples => "max_samples",
+            Limit::Points => "max_points",
+            Limit::Timeout => "timeout"
+        }
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Shared flag used to stop a running query from another thread or task.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Tracks what a single query has used so far. Query code reports its work here and bails
+/// out on the first error.
+#[derive(Debug)]
+pub struct QueryContext {
+    limits: QueryLimits,
+    deadline: Option<Instant>,
+    token: CancellationToken,
+    series: usize,
+    samples: usize,
+    points: usize
+}
+
+impl QueryContext {
+    pub fn new(limits: QueryLimits) -> Self {
+        Self::with_token(limits, CancellationToken::new())
+    }
+
+    pub fn with_token(limits: QueryLimits, token: CancellationToken) -> Self {
+        QueryContext {
+            limits,
+            deadline: Instant::now().checked_add(limits.timeout),
+            token,
+            series: 0,
+            samples: 0,
+            points: 0
+        }
+    }
+
+    /// A context that never fails unless its token is cancelled.
+    pub fn unlimited() -> Self {
+        Self::new(QueryLimits::UNLIMITED)
+    }
+
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    pub fn samples_scanned(&self) -> usize {
+        self.samples
+    }
+
+    pub fn check(&self) -> Result<(), Error> {
+        if self.token.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(Error::LimitExceeded { limit: Limit::Timeout, max: self.limits.timeout.as_millis() as u64 });
+        }
+        Ok(())
+    }
+
+    pub fn add_series(&mut self, count: usize) -> Result<(), Error> {
+        self.series = self.series.saturating_add(count);
+        if self.series > self.limits.max_series {
+            return Err(Error::LimitExceeded { limit: Limit::Series, max: self.limits.max_series as u64 });
+        }
+        Ok(())
+    }
+
+    pub fn add_samples(&mut self, count: usize) -> Result<(), Error> {
+        let before = self.samples;
+        self.samples = self.samples.saturating_add(count);
+        if self.samples > self.limits.max_samples {
+            return Err(Error::LimitExceeded { limit: Limit::Samples, max: self.limits.max_samples as u64 });
+        }
+        if before / CHECK_INTERVAL != self.samples / CHECK_INTERVAL {
+            self.check()?;
+        }
+        Ok(())
+    }
+
+    pub fn add_points(&mut self, count: usize) -> Result<(), Error> {
+        self.points = self.points.saturating_add(count);
+        if self.points > self.limits.max_points {
+            return Err(Error::LimitExceeded { limit: Limit::Points, max: self.limits.max_points as u64 });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_the_limit_hit() {
+        let limits = QueryLimits { max_series: 2, max_samples: 10, max_points: 5, timeout: Duration::from_secs(60) };
+        let mut ctx = QueryContext::new(limits);
+
+        assert!(ctx.add_series(2).is_ok());
+        assert!(matches!(ctx.add_series(1), Err(Error::LimitExceeded { limit: Limit::Series, max: 2 })));
+        assert!(matches!(ctx.add_samples(11), Err(Error::LimitExceeded { limit: Limit::Samples, max: 10 })));
+        assert!(matches!(ctx.add_points(6), Err(Error::LimitExceeded { limit: Limit::Points, max: 5 })));
+    }
+
+    #[test]
+    fn times_out() {
+        let limits = QueryLimits { timeout: Duration::ZERO, ..QueryLimits::UNLIMITED };
+        let mut ctx = QueryContext::new(limits);
+
+        assert!(matches!(ctx.check(), Err(Error::LimitExceeded { limit: Limit::Timeout, max: 0 })));
+        assert!(ctx.add_samples(CHECK_INTERVAL).is_err());
+    }
+
+    #[test]
+    fn cancels_cooperatively() {
+        let mut ctx = QueryContext::unlimited();
+        let token = ctx.token().clone();
+
+        assert!(ctx.add_samples(CHECK_INTERVAL - 1).is_ok());
+        token.cancel();
+        assert!(matches!(ctx.add_samples(1), Err(Error::Cancelled)));
+    }
+}
@@ -0,0 +1,211 @@
+use std::{collections::HashMap, ops::RangeInclusive};
+
+use crate::{models::Metric, error::Error, query::limits::QueryContext, traits::{json::{self, JsonSerializable}, serializable::{read_f64, read_labels, read_string, read_u32, read_u64, write_labels, write_string, BinarySerializable}}};
+
+/// One series in a query result: the identifying name and labels plus its (timestamp, value) points.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub points: Vec<(u64, f64)>
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Matrix {
+    pub series: Vec<Series>
+}
+
+impl Matrix {
+    /// Groups raw samples into series by label set. Labels are sorted so the same set in a
+    /// different order lands in the same series, and points come out in timestamp order.
+    pub fn from_samples<'a>(samples: impl IntoIterator<Item = &'a Metric>) -> Self {
+        Self::collect(samples, 0..=u64::MAX, &mut QueryContext::unlimited())
+            .expect("An unlimited query can't fail")
+    }
+
+    /// Same as `from_samples`, keeping only samples inside `time_range` and charging every
+    /// sample walked against the query limits.
+    pub fn collect<'a>(samples: impl IntoIterator<Item = &'a Metric>, time_range: RangeInclusive<u64>, ctx: &mut QueryContext) -> Result<Self, Error> {
+        let mut series: Vec<Series> = Vec::new();
+        let mut index: HashMap<(String, Vec<(String, String)>), usize> = HashMap::new();
+        for metric in samples {
+            ctx.add_samples(1)?;
+            if !time_range.contains(&metric.timestamp) {
+                continue;
+            }
+
+            let mut labels = metric.labels.clone();
+            labels.sort();
+
+            let key = (metric.name.clone(), labels);
+            match index.get(&key) {
+                Some(&i) => series[i].points.push((metric.timestamp, metric.value)),
+                None => {
+                    ctx.add_series(1)?;
+                    series.push(Series {
+                        name: key.0.clone(),
+                        labels: key.1.clone(),
+                        points: vec![(metric.timestamp, metric.value)]
+                    });
+                    index.insert(key, series.len() - 1);
+                }
+            }
+        }
+
+        for s in series.iter_mut() {
+            s.points.sort_by_key(|(timestamp, _)| *timestamp);
+        }
+        series.sort_by(|a, b| (&a.name, &a.labels).cmp(&(&b.name, &b.labels)));
+
+        Ok(Matrix { series })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.series.is_empty()
+    }
+
+    pub fn point_count(&self) -> usize {
+        self.series.iter().map(|s| s.points.len()).sum()
+    }
+}
+
+impl BinarySerializable for Series {
+    fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_string(&mut data, &self.name);
+        write_labels(&mut data, &self.labels);
+        data.extend((self.points.len() as u32).to_le_bytes());
+        for (timestamp, value) in &self.points {
+            data.extend(timestamp.to_le_bytes());
+            data.extend(value.to_bits().to_le_bytes());
+        }
+        data
+    }
+
+    fn deserialize(data: &[u8], byte_offset: &mut usize) -> Result<Self, Error> where Self: Sized {
+        let name = read_string(data, byte_offset)?;
+        let labels = read_labels(data, byte_offset)?;
+        let count = read_u32(data, byte_offset)? as usize;
+        let mut points = Vec::with_capacity(count.min(data.len() / 16));
+        for _ in 0..count {
+            let timestamp = read_u64(data, byte_offset)?;
+            let value = read_f64(data, byte_offset)?;
+            points.push((timestamp, value));
+        }
+
+        Ok(Series { name, labels, points })
+    }
+}
+
+impl BinarySerializable for Matrix {
+    fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend((self.series.len() as u32).to_le_bytes());
+        for series in &self.series {
+            data.extend(series.serialize());
+        }
+        data
+    }
+
+    fn deserialize(data: &[u8], byte_offset: &mut usize) -> Result<Self, Error> where Self: Sized {
+        let count = read_u32(data, byte_offset)? as usize;
+        let mut series = Vec::with_capacity(count.min(data.len() / 16));
+        for _ in 0..count {
+            series.push(Series::deserialize(data, byte_offset)?);
+        }
+
+        Ok(Matrix { series })
+    }
+}
+
+impl JsonSerializable for Series {
+    fn to_json(&self) -> String {
+        let mut out = String::from("{\"name\":");
+        json::write_str(&mut out, &self.name);
+        out.push_str(",\"labels\":");
+        json::write_labels(&mut out, &self.labels);
+        out.push_str(",\"points\":[");
+        for (i, (timestamp, value)) in self.points.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push_str(&format!("[{},", timestamp));
+            json::write_str(&mut out, &json::format_value(*value));
+            out.push(']');
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+impl JsonSerializable for Matrix {
+    fn to_json(&self) -> String {
+        let parts: Vec<String> = self.series.iter().map(|s| s.to_json()).collect();
+        format!("[{}]", parts.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric(timestamp: u64, value: f64, labels: &[(&str, &str)]) -> Metric {
+        Metric {
+            timestamp,
+            value,
+            name: "cpu".to_string(),
+            labels: labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        }
+    }
+
+    #[test]
+    fn groups_samples_by_labels() {
+        let samples = vec![
+            metric(20, 2.0, &[("host", "a"), ("dc", "x")]),
+            metric(10, 1.0, &[("dc", "x"), ("host", "a")]),
+            metric(10, 5.0, &[("host", "b")]),
+        ];
+
+        let matrix = Matrix::from_samples(&samples);
+        assert_eq!(matrix.series.len(), 2);
+        assert_eq!(matrix.series[0].labels, vec![("dc".to_string(), "x".to_string()), ("host".to_string(), "a".to_string())]);
+        assert_eq!(matrix.series[0].points, vec![(10, 1.0), (20, 2.0)]);
+        assert_eq!(matrix.series[1].points, vec![(10, 5.0)]);
+        assert_eq!(matrix.point_count(), 3);
+    }
+
+    #[test]
+    fn collect_respects_limits() {
+        use crate::query::limits::{Limit, QueryLimits};
+
+        let samples = vec![metric(10, 1.0, &[("host", "a")]), metric(20, 1.0, &[("host", "b")]), metric(30, 1.0, &[("host", "c")])];
+
+        let limits = QueryLimits { max_series: 1, ..QueryLimits::UNLIMITED };
+        let result = Matrix::collect(&samples, 0..=15, &mut QueryContext::new(limits));
+        assert_eq!(result.unwrap().series.len(), 1);
+
+        let result = Matrix::collect(&samples, 0..=u64::MAX, &mut QueryContext::new(limits));
+        assert!(matches!(result, Err(Error::LimitExceeded { limit: Limit::Series, max: 1 })));
+
+        let limits = QueryLimits { max_samples: 2, ..QueryLimits::UNLIMITED };
+        let result = Matrix::collect(&samples, 0..=15, &mut QueryContext::new(limits));
+        assert!(matches!(result, Err(Error::LimitExceeded { limit: Limit::Samples, max: 2 })));
+    }
+
+    #[test]
+    fn matrix_round_trip() {
+        let matrix = Matrix::from_samples(&[metric(10, 1.0, &[("host", "a")]), metric(10, f64::INFINITY, &[])]);
+
+        let serialized = matrix.serialize();
+        let mut byte_offset: usize = 0;
+        let deserialized = Matrix::deserialize(&serialized, &mut byte_offset).unwrap();
+        assert_eq!(deserialized, matrix);
+        assert_eq!(byte_offset, serialized.len());
+    }
+
+    #[test]
+    fn matrix_to_json() {
+        let matrix = Matrix::from_samples(&[metric(10, 1.5, &[("host", "a")])]);
+        assert_eq!(matrix.to_json(), r#"[{"name":"cpu","labels":{"host":"a"},"points":[[10,"1.5"]]}]"#);
+    }
+}
@@ -0,0 +1,10 @@
+pub mod limits;
+pub mod matrix;
+pub mod promql;
+pub mod range;
+pub mod selector;
+
+pub use limits::{CancellationToken, Limit, QueryContext, QueryLimits};
+pub use matrix::{Matrix, Series};
+pub use range::RangeQuery;
+pub use selector::{MatchOp, Matcher, Selector};
\ No newline at end of file
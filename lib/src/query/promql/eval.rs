@@ -0,0 +1,549 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{db::MetricsDb, error::Error, query::{limits::QueryContext, matrix::{Matrix, Series}, promql::{functions, parser::{AggregateOp, BinaryOp, Expr, Grouping, VectorMatching}}, range::{is_stale_marker, RangeQuery, DEFAULT_LOOKBACK_MS}, selector::NAME_LABEL}};
+
+/// One element of an instant vector. An empty `name` means the metric name was dropped, as
+/// happens after arithmetic, most functions and aggregations.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub value: f64
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Scalar(f64),
+    Vector(Vec<Sample>),
+    /// Only produced by a bare range selector in an instant query.
+    Matrix(Matrix),
+    String(String)
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Scalar(_) => "scalar",
+            Value::Vector(_) => "vector",
+            Value::Matrix(_) => "matrix",
+            Value::String(_) => "string"
+        }
+    }
+}
+
+/// A metric name and its labels, which is what identifies an output series.
+type SeriesKey = (String, Vec<(String, String)>);
+
+/// Evaluates `expr` at `time`, in milliseconds.
+pub fn instant_query(db: &MetricsDb, expr: &Expr, time: u64, ctx: &mut QueryContext) -> Result<Value, Error> {
+    let mut engine = Engine::new(DEFAULT_LOOKBACK_MS);
+    engine.prefetch(db, expr, time, time, ctx)?;
+    let value = engine.eval(expr, time)?;
+    match &value {
+        Value::Vector(samples) => ctx.add_points(samples.len())?,
+        Value::Matrix(matrix) => ctx.add_points(matrix.point_count())?,
+        Value::Scalar(_) | Value::String(_) => ctx.add_points(1)?
+    }
+    Ok(value)
+}
+
+/// Evaluates `expr` at every step of `query`. Only expressions that produce a scalar or an
+/// instant vector can be graphed this way.
+pub fn range_query(db: &MetricsDb, expr: &Expr, query: &RangeQuery, ctx: &mut QueryContext) -> Result<Matrix, Error> {
+    let mut engine = Engine::new(query.lookback);
+    engine.prefetch(db, expr, query.start, query.end, ctx)?;
+
+    let mut series: BTreeMap<SeriesKey, Vec<(u64, f64)>> = BTreeMap::new();
+    for time in query.steps() {
+        ctx.check()?;
+        match engine.eval(expr, time)? {
+            Value::Scalar(value) => {
+                ctx.add_points(1)?;
+                series.entry((String::new(), Vec::new())).or_default().push((time, value));
+            },
+            Value::Vector(samples) => {
+                ctx.add_points(samples.len())?;
+                for sample in samples {
+                    series.entry((sample.name, sample.labels)).or_default().push((time, sample.value));
+                }
+            },
+            other => return Err(Error::Invalid(format!("Range queries need a scalar or instant vector expression, got a {}", other.type_name())))
+        }
+    }
+
+    Ok(Matrix {
+        series: series.into_iter().map(|((name, labels), points)| Series { name, labels, points }).collect()
+    })
+}
+
+struct Engine {
+    lookback: u64,
+    // Raw series for every selector in the expression, keyed by the selector node's address.
+    // The expression is borrowed for the whole evaluation, so the addresses are stable.
+    data: HashMap<usize, Vec<Series>>
+}
+
+fn node_key(expr: &Expr) -> usize {
+    expr as *const Expr as usize
+}
+
+impl Engine {
+    fn new(lookback: u64) -> Self {
+        Engine { lookback, data: HashMap::new() }
+    }
+
+    /// Loads everything the selectors in `expr` can see between `start` and `end`, so the
+    /// per step evaluation never goes back to the database.
+    fn prefetch(&mut self, db: &MetricsDb, expr: &Expr, start: u64, end: u64, ctx: &mut QueryContext) -> Result<(), Error> {
+        match expr {
+            Expr::Number(_) | Expr::String(_) => {},
+            Expr::Selector { selector, range, offset } => {
+                let window = range.unwrap_or(self.lookback);
+                let from = start.saturating_sub(offset.saturating_add(window));
+                let to = end.saturating_sub(*offset);
+                let matrix = db.select_matching(selector, from..=to, ctx)?;
+                self.data.insert(node_key(expr), matrix.series);
+            },
+            Expr::Call { args, .. } => {
+                for arg in args {
+                    self.prefetch(db, arg, start, end, ctx)?;
+                }
+            },
+            Expr::Aggregate { param, expr, .. } => {
+                if let Some(param) = param {
+                    self.prefetch(db, param, start, end, ctx)?;
+                }
+                self.prefetch(db, expr, start, end, ctx)?;
+            },
+            Expr::Binary { lhs, rhs, .. } => {
+                self.prefetch(db, lhs, start, end, ctx)?;
+                self.prefetch(db, rhs, start, end, ctx)?;
+            },
+            Expr::Negate(expr) => self.prefetch(db, expr, start, end, ctx)?
+        }
+        Ok(())
+    }
+
+    fn eval(&self, expr: &Expr, time: u64) -> Result<Value, Error> {
+        match expr {
+            Expr::Number(n) => Ok(Value::Scalar(*n)),
+            Expr::String(s) => Ok(Value::String(s.clone())),
+            Expr::Selector { range: None, offset, .. } => Ok(Value::Vector(self.instant_vector(expr, time.saturating_sub(*offset)))),
+            Expr::Selector { range: Some(range), offset, .. } => {
+                let end = time.saturating_sub(*offset);
+                Ok(Value::Matrix(Matrix { series: self.range_vector(expr, end.saturating_sub(*range), end) }))
+            },
+            Expr::Negate(inner) => match self.eval(inner, time)? {
+                Value::Scalar(v) => Ok(Value::Scalar(-v)),
+                Value::Vector(samples) => Ok(Value::Vector(samples.into_iter().map(|s| Sample { name: String::new(), labels: s.labels, value: -s.value }).collect())),
+                other => Err(Error::Invalid(format!("Can't negate a {}", other.type_name())))
+            },
+            Expr::Call { function, args } => self.call(function, args, time),
+            Expr::Aggregate { op, grouping, param, expr } => {
+                let samples = self.expect_vector(expr, time, "aggregation")?;
+                let param = match param {
+                    Some(param) => Some(self.expect_scalar(param, time, "aggregation parameter")?),
+                    None => None
+                };
+                Ok(Value::Vector(aggregate(*op, grouping, param, samples)))
+            },
+            Expr::Binary { op, lhs, rhs, matching, return_bool } => {
+                let lhs = self.eval(lhs, time)?;
+                let rhs = self.eval(rhs, time)?;
+                binary(*op, lhs, rhs, matching, *return_bool)
+            }
+        }
+    }
+
+    fn series(&self, expr: &Expr) -> &[Series] {
+        self.data.get(&node_key(expr)).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// The latest sample at or before `time` within the lookback window, per series. A stale
+    /// marker hides the series.
+    fn instant_vector(&self, expr: &Expr, time: u64) -> Vec<Sample> {
+        self.series(expr).iter()
+            .filter_map(|series| {
+                let index = series.points.partition_point(|(t, _)| *t <= time);
+                let (sample_time, value) = *series.points.get(index.checked_sub(1)?)?;
+                (time - sample_time < self.lookback && !is_stale_marker(value)).then(|| Sample {
+                    name: series.name.clone(),
+                    labels: series.labels.clone(),
+                    value
+                })
+            })
+            .collect()
+    }
+
+    /// The samples in `(start, end]` per series, without stale markers.
+    fn range_vector(&self, expr: &Expr, start: u64, end: u64) -> Vec<Series> {
+        self.series(expr).iter()
+            .filter_map(|series| {
+                let from = series.points.partition_point(|(t, _)| *t <= start);
+                let to = series.points.partition_point(|(t, _)| *t <= end);
+                let points: Vec<(u64, f64)> = series.points[from..to].iter().copied().filter(|(_, v)| !is_stale_marker(*v)).collect();
+                (!points.is_empty()).then(|| Series { name: series.name.clone(), labels: series.labels.clone(), points })
+            })
+            .collect()
+    }
+
+    fn expect_vector(&self, expr: &Expr, time: u64, what: &str) -> Result<Vec<Sample>, Error> {
+        match self.eval(expr, time)? {
+            Value::Vector(samples) => Ok(samples),
+            other => Err(Error::Invalid(format!("Expected an instant vector for {}, got a {}", what, other.type_name())))
+        }
+    }
+
+    fn expect_scalar(&self, expr: &Expr, time: u64, what: &str) -> Result<f64, Error> {
+        match self.eval(expr, time)? {
+            Value::Scalar(value) => Ok(value),
+            other => Err(Error::Invalid(format!("Expected a scalar for {}, got a {}", what, other.type_name())))
+        }
+    }
+
+    fn call(&self, function: &str, args: &[Expr], time: u64) -> Result<Value, Error> {
+        let arity = |expected: usize| {
+            if args.len() == expected {
+                Ok(())
+            } else {
+                Err(Error::Invalid(format!("{} takes {} argument(s), got {}", function, expected, args.len())))
+            }
+        };
+
+        if functions::is_range_function(function) {
+            arity(1)?;
+            let Expr::Selector { range: Some(range), offset, .. } = &args[0] else {
+                return Err(Error::Invalid(format!("{} needs a range vector selector like metric[5m]", function)));
+            };
+            let end = time.saturating_sub(*offset);
+            let start = end.saturating_sub(*range);
+            // Like Prometheus, only last_over_time keeps the metric name.
+            let keep_name = function == "last_over_time";
+            let samples = self.range_vector(&args[0], start, end).into_iter()
+                .filter_map(|series| {
+                    let value = functions::range_function(function, &series.points, start, end)?;
+                    Some(Sample { name: if keep_name { series.name } else { String::new() }, labels: series.labels, value })
+                })
+                .collect();
+            return Ok(Value::Vector(samples));
+        }
+
+        if let Some(apply) = functions::math_function(function) {
+            arity(1)?;
+            let samples = self.expect_vector(&args[0], time, function)?;
+            return Ok(Value::Vector(map_values(samples, apply)));
+        }
+
+        match function {
+            "time" => {
+                arity(0)?;
+                Ok(Value::Scalar(time as f64 / 1000.0))
+            },
+            "vector" => {
+                arity(1)?;
+                let value = self.expect_scalar(&args[0], time, function)?;
+                Ok(Value::Vector(vec![Sample { name: String::new(), labels: Vec::new(), value }]))
+            },
+            "scalar" => {
+                arity(1)?;
+                let samples = self.expect_vector(&args[0], time, function)?;
+                Ok(Value::Scalar(match samples.as_slice() {
+                    [sample] => sample.value,
+                    _ => f64::NAN
+                }))
+            },
+            "clamp_min" | "clamp_max" => {
+                arity(2)?;
+                let samples = self.expect_vector(&args[0], time, function)?;
+                let bound = self.expect_scalar(&args[1], time, function)?;
+                let clamp_min = function == "clamp_min";
+                Ok(Value::Vector(map_values(samples, |v| if clamp_min { v.max(bound) } else { v.min(bound) })))
+            },
+            "clamp" => {
+                arity(3)?;
+                let samples = self.expect_vector(&args[0], time, function)?;
+                let min = self.expect_scalar(&args[1], time, function)?;
+                let max = self.expect_scalar(&args[2], time, function)?;
+                if max < min {
+                    return Ok(Value::Vector(Vec::new()));
+                }
+                Ok(Value::Vector(map_values(samples, |v| v.max(min).min(max))))
+            },
+            _ => Err(Error::Invalid(format!("Unknown or unsupported function {}", function)))
+        }
+    }
+}
+
+fn map_values(samples: Vec<Sample>, apply: impl Fn(f64) -> f64) -> Vec<Sample> {
+    samples.into_iter().map(|s| Sample { name: String::new(), labels: s.labels, value: apply(s.value) }).collect()
+}
+
+/// The name and labels an aggregation groups `sample` under.
+fn group_key(grouping: &Grouping, sample: &Sample) -> SeriesKey {
+    match grouping {
+        Grouping::All => (String::new(), Vec::new()),
+        Grouping::By(labels) => {
+            let name = if labels.iter().any(|l| l == NAME_LABEL) { sample.name.clone() } else { String::new() };
+            (name, sample.labels.iter().filter(|(key, _)| labels.contains(key)).cloned().collect())
+        },
+        Grouping::Without(labels) => (String::new(), sample.labels.iter().filter(|(key, _)| !labels.contains(key)).cloned().collect())
+    }
+}
+
+fn aggregate(op: AggregateOp, grouping: &Grouping, param: Option<f64>, samples: Vec<Sample>) -> Vec<Sample> {
+    let mut groups: BTreeMap<SeriesKey, Vec<Sample>> = BTreeMap::new();
+    for sample in samples {
+        groups.entry(group_key(grouping, &sample)).or_default().push(sample);
+    }
+
+    let mut result = Vec::new();
+    for ((name, labels), mut members) in groups {
+        let values = || members.iter().map(|s| s.value);
+        let value = match op {
+            AggregateOp::Sum => values().sum(),
+            AggregateOp::Avg => values().sum::<f64>() / members.len() as f64,
+            AggregateOp::Min => values().reduce(|a, b| if b < a || a.is_nan() { b } else { a }).unwrap_or(f64::NAN),
+            AggregateOp::Max => values().reduce(|a, b| if b > a || a.is_nan() { b } else { a }).unwrap_or(f64::NAN),
+            AggregateOp::Count => members.len() as f64,
+            AggregateOp::Group => 1.0,
+            AggregateOp::Stddev => functions::variance(values()).sqrt(),
+            AggregateOp::Stdvar => functions::variance(values()),
+            AggregateOp::Topk | AggregateOp::Bottomk => {
+                // Keeps whole samples rather than producing one per group. NaN sorts last either way.
+                let k = param.unwrap_or(0.0);
+                let k = if k.is_nan() || k < 1.0 { 0 } else { k as usize };
+                let top = op == AggregateOp::Topk;
+                members.sort_by(|a, b| match (a.value.is_nan(), b.value.is_nan()) {
+                    (true, false) => std::cmp::Ordering::Greater,
+                    (false, true) => std::cmp::Ordering::Less,
+                    _ if top => b.value.total_cmp(&a.value),
+                    _ => a.value.total_cmp(&b.value)
+                });
+                result.extend(members.into_iter().take(k));
+                continue;
+            }
+        };
+        result.push(Sample { name, labels, value });
+    }
+    result
+}
+
+fn apply(op: BinaryOp, lhs: f64, rhs: f64) -> f64 {
+    match op {
+        BinaryOp::Add => lhs + rhs,
+        BinaryOp::Sub => lhs - rhs,
+        BinaryOp::Mul => lhs * rhs,
+        BinaryOp::Div => lhs / rhs,
+        BinaryOp::Mod => lhs % rhs,
+        BinaryOp::Pow => lhs.powf(rhs),
+        BinaryOp::Equal => (lhs == rhs) as u8 as f64,
+        BinaryOp::NotEqual => (lhs != rhs) as u8 as f64,
+        BinaryOp::Less => (lhs < rhs) as u8 as f64,
+        BinaryOp::LessEqual => (lhs <= rhs) as u8 as f64,
+        BinaryOp::Greater => (lhs > rhs) as u8 as f64,
+        BinaryOp::GreaterEqual => (lhs >= rhs) as u8 as f64,
+        BinaryOp::And | BinaryOp::Or | BinaryOp::Unless => f64::NAN
+    }
+}
+
+/// What a sample is matched on across the two sides of a vector/vector operation.
+fn signature(matching: &VectorMatching, sample: &Sample) -> SeriesKey {
+    if matching.on {
+        let name = if matching.labels.iter().any(|l| l == NAME_LABEL) { sample.name.clone() } else { String::new() };
+        (name, sample.labels.iter().filter(|(key, _)| matching.labels.contains(key)).cloned().collect())
+    } else {
+        (String::new(), sample.labels.iter().filter(|(key, _)| !matching.labels.contains(key)).cloned().collect())
+    }
+}
+
+fn binary(op: BinaryOp, lhs: Value, rhs: Value, matching: &VectorMatching, return_bool: bool) -> Result<Value, Error> {
+    // Arithmetic and `bool` comparisons produce new values, which lose the metric name.
+    let drops_name = !op.is_comparison() || return_bool;
+    match (lhs, rhs) {
+        (Value::Scalar(l), Value::Scalar(r)) => {
+            if op.is_set() {
+                return Err(Error::Invalid(String::from("Set operators need instant vectors on both sides")));
+            }
+            if op.is_comparison() && !return_bool {
+                return Err(Error::Invalid(String::from("Comparisons between scalars must use bool")));
+            }
+            Ok(Value::Scalar(apply(op, l, r)))
+        },
+        (Value::Vector(_), Value::Scalar(_)) | (Value::Scalar(_), Value::Vector(_)) if op.is_set() => {
+            Err(Error::Invalid(String::from("Set operators need instant vectors on both sides")))
+        },
+        (Value::Vector(samples), Value::Scalar(r)) => Ok(Value::Vector(vector_scalar(op, samples, r, false, return_bool, drops_name))),
+        (Value::Scalar(l), Value::Vector(samples)) => Ok(Value::Vector(vector_scalar(op, samples, l, true, return_bool, drops_name))),
+        (Value::Vector(lhs), Value::Vector(rhs)) => vector_vector(op, lhs, rhs, matching, return_bool, drops_name).map(Value::Vector),
+        (lhs, rhs) => Err(Error::Invalid(format!("Binary operators don't apply to a {} and a {}", lhs.type_name(), rhs.type_name())))
+    }
+}
+
+fn vector_scalar(op: BinaryOp, samples: Vec<Sample>, scalar: f64, scalar_on_left: bool, return_bool: bool, drops_name: bool) -> Vec<Sample> {
+    samples.into_iter()
+        .filter_map(|sample| {
+            let (l, r) = if scalar_on_left { (scalar, sample.value) } else { (sample.value, scalar) };
+            let result = apply(op, l, r);
+            let value = if op.is_comparison() && !return_bool {
+                // A filter keeps the vector's own value whichever side it is on.
+                if result == 0.0 {
+                    return None;
+                }
+                sample.value
+            } else {
+                result
+            };
+            Some(Sample { name: if drops_name { String::new() } else { sample.name }, labels: sample.labels, value })
+        })
+        .collect()
+}
+
+fn vector_vector(op: BinaryOp, lhs: Vec<Sample>, rhs: Vec<Sample>, matching: &VectorMatching, return_bool: bool, drops_name: bool) -> Result<Vec<Sample>, Error> {
+    if op.is_set() {
+        let rhs_signatures: std::collections::HashSet<_> = rhs.iter().map(|s| signature(matching, s)).collect();
+        return Ok(match op {
+            BinaryOp::And => lhs.into_iter().filter(|s| rhs_signatures.contains(&signature(matching, s))).collect(),
+            BinaryOp::Unless => lhs.into_iter().filter(|s| !rhs_signatures.contains(&signature(matching, s))).collect(),
+            _ => {
+                let lhs_signatures: std::collections::HashSet<_> = lhs.iter().map(|s| signature(matching, s)).collect();
+                let mut result = lhs;
+                result.extend(rhs.into_iter().filter(|s| !lhs_signatures.contains(&signature(matching, s))));
+                result
+            }
+        });
+    }
+
+    let mut right: HashMap<_, Sample> = HashMap::new();
+    for sample in rhs {
+        if right.insert(signature(matching, &sample), sample).is_some() {
+            return Err(Error::Invalid(String::from("Many-to-many matching is not allowed: duplicate series on the right hand side")));
+        }
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    let mut result = Vec::new();
+    for sample in lhs {
+        let key = signature(matching, &sample);
+        let Some(other) = right.get(&key) else {
+            continue;
+        };
+        if !seen.insert(key.clone()) {
+            return Err(Error::Invalid(String::from("Many-to-one matching is not supported: duplicate series on the left hand side")));
+        }
+
+        let value = apply(op, sample.value, other.value);
+        if op.is_comparison() && !return_bool && value == 0.0 {
+            continue;
+        }
+        let value = if op.is_comparison() && !return_bool { sample.value } else { value };
+
+        let (name, labels) = if matching.on {
+            let (name, labels) = key;
+            (if drops_name { String::new() } else { name }, labels)
+        } else {
+            let labels = sample.labels.into_iter().filter(|(k, _)| !matching.labels.contains(k)).collect();
+            (if drops_name { String::new() } else { sample.name }, labels)
+        };
+        result.push(Sample { name, labels, value });
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::Metric, query::promql::parse, testing::TempDir};
+
+    /// The directory comes first so that it outlives the database when both are dropped.
+    fn test_db(test: &str) -> (TempDir, MetricsDb) {
+        let dir = TempDir::new(&format!("promql_{}", test));
+        let mut db = MetricsDb::open(&dir).unwrap();
+
+        let mut samples = Vec::new();
+        for i in 1..=10u64 {
+            for (job, instance, step) in [("api", "a", 1.0), ("api", "b", 2.0), ("web", "c", 3.0)] {
+                samples.push(Metric {
+                    timestamp: i * 10_000,
+                    value: i as f64 * step,
+                    name: String::from("requests_total"),
+                    labels: vec![(String::from("job"), job.to_string()), (String::from("instance"), instance.to_string())]
+                });
+            }
+            samples.push(Metric { timestamp: i * 10_000, value: 100.0, name: String::from("limit"), labels: vec![(String::from("job"), String::from("api"))] });
+        }
+        assert_eq!(db.ingest_batch(samples).rejected, 0);
+        (dir, db)
+    }
+
+    fn instant(db: &MetricsDb, query: &str, time: u64) -> Value {
+        instant_query(db, &parse(query).unwrap(), time, &mut QueryContext::unlimited()).unwrap()
+    }
+
+    fn values(value: Value) -> Vec<(String, f64)> {
+        let Value::Vector(samples) = value else { panic!("expected a vector, got {:?}", value) };
+        let mut values: Vec<(String, f64)> = samples.into_iter()
+            .map(|s| (s.labels.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join(","), s.value))
+            .collect();
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        values
+    }
+
+    #[test]
+    fn evaluates_selectors_and_functions() {
+        let (_dir, db) = test_db("functions");
+
+        assert_eq!(values(instant(&db, r#"requests_total{instance=~"a|c"}"#, 100_000)), vec![
+            (String::from("instance=a,job=api"), 10.0),
+            (String::from("instance=c,job=web"), 30.0)
+        ]);
+        assert_eq!(values(instant(&db, "requests_total offset 50s", 100_000))[0].1, 5.0);
+        assert_eq!(values(instant(&db, r#"rate(requests_total{instance="b"}[1m])"#, 100_000)), vec![(String::from("instance=b,job=api"), 0.2)]);
+        assert_eq!(instant(&db, "scalar(sum(limit)) * 2", 100_000), Value::Scalar(200.0));
+        assert_eq!(instant(&db, "time()", 100_000), Value::Scalar(100.0));
+        let Value::Matrix(matrix) = instant(&db, "limit[30s]", 100_000) else { panic!() };
+        assert_eq!(matrix.series[0].points.len(), 3);
+    }
+
+    #[test]
+    fn evaluates_aggregations_and_operators() {
+        let (_dir, db) = test_db("operators");
+
+        assert_eq!(values(instant(&db, "sum by (job) (requests_total)", 100_000)), vec![
+            (String::from("job=api"), 30.0),
+            (String::from("job=web"), 30.0)
+        ]);
+        assert_eq!(values(instant(&db, "topk(1, requests_total)", 100_000)), vec![(String::from("instance=c,job=web"), 30.0)]);
+        assert_eq!(values(instant(&db, "requests_total > 15", 100_000)).len(), 2);
+        assert_eq!(values(instant(&db, "requests_total > bool 15", 100_000)).iter().map(|(_, v)| *v).sum::<f64>(), 2.0);
+        assert_eq!(values(instant(&db, "sum by (job) (requests_total) / on (job) limit", 100_000)), vec![(String::from("job=api"), 0.3)]);
+        assert_eq!(values(instant(&db, "requests_total unless on (job) limit", 100_000)), vec![(String::from("instance=c,job=web"), 30.0)]);
+        assert_eq!(values(instant(&db, "-requests_total{instance=\"a\"} + 1", 100_000)), vec![(String::from("instance=a,job=api"), -9.0)]);
+
+        let result = instant_query(&db, &parse("requests_total / on (job) limit").unwrap(), 100_000, &mut QueryContext::unlimited());
+        assert!(matches!(result, Err(Error::Invalid(_))));
+        let result = instant_query(&db, &parse("1 > 2").unwrap(), 100_000, &mut QueryContext::unlimited());
+        assert!(matches!(result, Err(Error::Invalid(_))));
+    }
+
+    #[test]
+    fn evaluates_range_queries() {
+        let (_dir, db) = test_db("range");
+
+        let query = RangeQuery::new(20_000, 100_000, 20_000).unwrap();
+        let matrix = range_query(&db, &parse("sum(requests_total)").unwrap(), &query, &mut QueryContext::unlimited()).unwrap();
+        assert_eq!(matrix.series.len(), 1);
+        assert_eq!(matrix.series[0].points, vec![(20_000, 12.0), (40_000, 24.0), (60_000, 36.0), (80_000, 48.0), (100_000, 60.0)]);
+
+        let matrix = range_query(&db, &parse("requests_total").unwrap(), &query, &mut QueryContext::unlimited()).unwrap();
+        assert_eq!(matrix.series.len(), 3);
+        assert_eq!(matrix.series[0].name, "requests_total");
+
+        let result = range_query(&db, &parse("limit[1m]").unwrap(), &query, &mut QueryContext::unlimited());
+        assert!(result.is_err());
+
+        // Ranges and offsets this long reach back before any data instead of overflowing.
+        let matrix = range_query(&db, &parse("sum(count_over_time(limit[300000000y] offset 300000000y))").unwrap(), &query, &mut QueryContext::unlimited()).unwrap();
+        assert!(matrix.series.is_empty());
+        assert_eq!(instant(&db, "count_over_time(limit[300000000y])", 100_000), instant(&db, "count_over_time(limit[1000s])", 100_000));
+    }
+}
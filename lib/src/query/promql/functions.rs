@@ -0,0 +1,145 @@
+// Functions over one series at a time. The engine picks the series and the window; these
+// only do the arithmetic. Times are milliseconds, rates are per second as in Prometheus.
+
+/// Functions taking a range vector and returning one value per series.
+pub fn is_range_function(name: &str) -> bool {
+    matches!(name, "rate" | "irate" | "increase" | "delta" | "idelta" | "changes" | "resets"
+        | "avg_over_time" | "min_over_time" | "max_over_time" | "sum_over_time" | "count_over_time"
+        | "last_over_time" | "stddev_over_time" | "stdvar_over_time")
+}
+
+/// Evaluates a range function over the samples of one series that fall in `(start, end]`.
+/// `None` means the series produces no output, for example a rate over a single sample.
+pub fn range_function(name: &str, points: &[(u64, f64)], start: u64, end: u64) -> Option<f64> {
+    let values = || points.iter().map(|(_, v)| *v);
+    let last = points.last()?;
+    match name {
+        "rate" => extrapolated_delta(points, start, end, true, true),
+        "increase" => extrapolated_delta(points, start, end, true, false),
+        "delta" => extrapolated_delta(points, start, end, false, false),
+        "irate" | "idelta" => {
+            let [.., (t1, v1), (t2, v2)] = points else {
+                return None;
+            };
+            // A counter reset restarts from zero.
+            let diff = if name == "irate" && v2 < v1 { *v2 } else { v2 - v1 };
+            if name == "idelta" {
+                return Some(diff);
+            }
+            (t2 > t1).then(|| diff / ((t2 - t1) as f64 / 1000.0))
+        },
+        "changes" => Some(points.windows(2).filter(|w| w[0].1 != w[1].1 && !(w[0].1.is_nan() && w[1].1.is_nan())).count() as f64),
+        "resets" => Some(points.windows(2).filter(|w| w[1].1 < w[0].1).count() as f64),
+        "avg_over_time" => Some(values().sum::<f64>() / points.len() as f64),
+        "min_over_time" => values().reduce(|a, b| if b < a || a.is_nan() { b } else { a }),
+        "max_over_time" => values().reduce(|a, b| if b > a || a.is_nan() { b } else { a }),
+        "sum_over_time" => Some(values().sum()),
+        "count_over_time" => Some(points.len() as f64),
+        "last_over_time" => Some(last.1),
+        "stddev_over_time" => Some(variance(values()).sqrt()),
+        "stdvar_over_time" => Some(variance(values())),
+        _ => None
+    }
+}
+
+pub fn variance(values: impl Iterator<Item = f64> + Clone) -> f64 {
+    let count = values.clone().count() as f64;
+    let mean = values.clone().sum::<f64>() / count;
+    values.map(|v| (v - mean).powi(2)).sum::<f64>() / count
+}
+
+/// The difference between the first and last sample, extrapolated towards the window edges
+/// the same way Prometheus does it, so results line up with what dashboards already show.
+fn extrapolated_delta(points: &[(u64, f64)], start: u64, end: u64, is_counter: bool, is_rate: bool) -> Option<f64> {
+    if points.len() < 2 {
+        return None;
+    }
+    let (first_t, first_v) = points[0];
+    let (last_t, last_v) = points[points.len() - 1];
+
+    let mut result = last_v - first_v;
+    if is_counter {
+        for pair in points.windows(2) {
+            if pair[1].1 < pair[0].1 {
+                result += pair[0].1;
+            }
+        }
+    }
+
+    let seconds = |millis: u64| millis as f64 / 1000.0;
+    let sampled_interval = seconds(last_t - first_t);
+    let average_gap = sampled_interval / (points.len() - 1) as f64;
+    let mut to_start = seconds(first_t.saturating_sub(start));
+    let to_end = seconds(end.saturating_sub(last_t));
+
+    // A counter can't go below zero, so don't extrapolate past where it would have been zero.
+    if is_counter && result > 0.0 && first_v >= 0.0 {
+        let to_zero = sampled_interval * (first_v / result);
+        if to_zero < to_start {
+            to_start = to_zero;
+        }
+    }
+
+    let threshold = average_gap * 1.1;
+    let mut interval = sampled_interval;
+    interval += if to_start < threshold { to_start } else { average_gap / 2.0 };
+    interval += if to_end < threshold { to_end } else { average_gap / 2.0 };
+
+    result *= interval / sampled_interval;
+    if is_rate {
+        result /= seconds(end - start);
+    }
+    Some(result)
+}
+
+/// Functions applied to every sample of an instant vector on their own.
+pub fn math_function(name: &str) -> Option<fn(f64) -> f64> {
+    Some(match name {
+        "abs" => f64::abs,
+        "ceil" => f64::ceil,
+        "floor" => f64::floor,
+        // Prometheus rounds halves up, not away from zero.
+        "round" => |v: f64| (v + 0.5).floor(),
+        "exp" => f64::exp,
+        "ln" => f64::ln,
+        "log2" => f64::log2,
+        "log10" => f64::log10,
+        "sqrt" => f64::sqrt,
+        "sgn" => |v: f64| if v > 0.0 { 1.0 } else if v < 0.0 { -1.0 } else { v },
+        _ => return None
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_extrapolates_like_prometheus() {
+        // A counter going up 1 per 10s, sampled right on the window edges.
+        let points: Vec<(u64, f64)> = (0..=6).map(|i| (i * 10_000, i as f64)).collect();
+        assert_eq!(range_function("increase", &points[1..], 0, 60_000), Some(6.0));
+        assert_eq!(range_function("rate", &points[1..], 0, 60_000), Some(0.1));
+
+        // Resets are compensated.
+        let points = [(10_000, 5.0), (20_000, 1.0), (30_000, 2.0)];
+        assert_eq!(range_function("resets", &points, 0, 30_000), Some(1.0));
+        assert!(range_function("increase", &points, 0, 30_000).unwrap() > 2.0);
+        assert_eq!(range_function("irate", &points, 0, 30_000), Some(0.1));
+
+        assert_eq!(range_function("rate", &points[..1], 0, 30_000), None);
+    }
+
+    #[test]
+    fn over_time_functions() {
+        let points = [(1, 2.0), (2, 4.0), (3, 0.0)];
+        assert_eq!(range_function("avg_over_time", &points, 0, 3), Some(2.0));
+        assert_eq!(range_function("min_over_time", &points, 0, 3), Some(0.0));
+        assert_eq!(range_function("max_over_time", &points, 0, 3), Some(4.0));
+        assert_eq!(range_function("count_over_time", &points, 0, 3), Some(3.0));
+        assert_eq!(range_function("last_over_time", &points, 0, 3), Some(0.0));
+        assert_eq!(range_function("changes", &points, 0, 3), Some(2.0));
+        assert_eq!(range_function("delta", &points, 0, 3), Some(-3.0));
+        assert_eq!(math_function("round").unwrap()(-2.5), -2.0);
+    }
+}
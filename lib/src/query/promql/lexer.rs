@@ -0,0 +1,247 @@
+use crate::error::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Ident(String),
+    Number(f64),
+    /// A duration literal like `5m` or `1h30m`, in milliseconds.
+    Duration(u64),
+    String(String),
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    LeftBracket,
+    RightBracket,
+    Comma,
+    Assign,
+    NotEqual,
+    RegexMatch,
+    RegexNotMatch,
+    Equal,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    At
+}
+
+/// A token and the byte offset it starts at, for error messages.
+pub type Spanned = (Token, usize);
+
+pub fn tokenize(input: &str) -> Result<Vec<Spanned>, Error> {
+    let bytes = input.as_bytes();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        let start = pos;
+        let c = bytes[pos];
+        if c.is_ascii_whitespace() {
+            pos += 1;
+            continue;
+        }
+        if c == b'#' {
+            // Comments run to the end of the line.
+            while pos < bytes.len() && bytes[pos] != b'\n' {
+                pos += 1;
+            }
+            continue;
+        }
+
+        let two = bytes.get(pos..pos + 2);
+        let (token, len) = match (c, two) {
+            (_, Some(b"!=")) => (Token::NotEqual, 2),
+            (_, Some(b"=~")) => (Token::RegexMatch, 2),
+            (_, Some(b"!~")) => (Token::RegexNotMatch, 2),
+            (_, Some(b"==")) => (Token::Equal, 2),
+            (_, Some(b"<=")) => (Token::LessEqual, 2),
+            (_, Some(b">=")) => (Token::GreaterEqual, 2),
+            (b'(', _) => (Token::LeftParen, 1),
+            (b')', _) => (Token::RightParen, 1),
+            (b'{', _) => (Token::LeftBrace, 1),
+            (b'}', _) => (Token::RightBrace, 1),
+            (b'[', _) => (Token::LeftBracket, 1),
+            (b']', _) => (Token::RightBracket, 1),
+            (b',', _) => (Token::Comma, 1),
+            (b'=', _) => (Token::Assign, 1),
+            (b'<', _) => (Token::Less, 1),
+            (b'>', _) => (Token::Greater, 1),
+            (b'+', _) => (Token::Add, 1),
+            (b'-', _) => (Token::Sub, 1),
+            (b'*', _) => (Token::Mul, 1),
+            (b'/', _) => (Token::Div, 1),
+            (b'%', _) => (Token::Mod, 1),
+            (b'^', _) => (Token::Pow, 1),
+            (b'@', _) => (Token::At, 1),
+            (b'"' | b'\'' | b'`', _) => {
+                let (value, len) = read_string(&input[pos..]).map_err(|message| Error::Invalid(format!("{} at offset {}", message, pos)))?;
+                (Token::String(value), len)
+            },
+            (b'0'..=b'9' | b'.', _) => read_number(&input[pos..]).ok_or_else(|| Error::Invalid(format!("Invalid number at offset {}", pos)))?,
+            (c, _) if c.is_ascii_alphabetic() || c == b'_' => {
+                let len = bytes[pos..].iter().take_while(|b| b.is_ascii_alphanumeric() || **b == b'_' || **b == b':').count();
+                let ident = &input[pos..pos + len];
+                match ident.to_ascii_lowercase().as_str() {
+                    "inf" => (Token::Number(f64::INFINITY), ident.len()),
+                    "nan" => (Token::Number(f64::NAN), ident.len()),
+                    _ => (Token::Ident(ident.to_string()), ident.len())
+                }
+            },
+            _ => return Err(Error::Invalid(format!("Unexpected character {:?} at offset {}", input[pos..].chars().next().unwrap_or(' '), pos)))
+        };
+        tokens.push((token, start));
+        pos += len;
+    }
+
+    Ok(tokens)
+}
+
+fn read_string(input: &str) -> Result<(String, usize), &'static str> {
+    let mut chars = input.char_indices();
+    let (_, quote) = chars.next().ok_or("Expected a string")?;
+    let mut value = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            c if c == quote => return Ok((value, i + c.len_utf8())),
+            // Backquoted strings are raw.
+            '\\' if quote != '`' => {
+                let (_, escaped) = chars.next().ok_or("Unterminated string")?;
+                match escaped {
+                    'n' => value.push('\n'),
+                    't' => value.push('\t'),
+                    'r' => value.push('\r'),
+                    '\\' | '"' | '\'' => value.push(escaped),
+                    // Regex escapes like `\d` and `\.` are kept as written.
+                    other => {
+                        value.push('\\');
+                        value.push(other);
+                    }
+                }
+            },
+            '\n' if quote != '`' => return Err("Newline in string"),
+            c => value.push(c)
+        }
+    }
+    Err("Unterminated string")
+}
+
+/// A number, or a duration when the digits are followed by a unit.
+fn read_number(input: &str) -> Option<(Token, usize)> {
+    if let Some((millis, len)) = read_duration(input) {
+        return Some((Token::Duration(millis), len));
+    }
+
+    let bytes = input.as_bytes();
+    if bytes.starts_with(b"0x") || bytes.starts_with(b"0X") {
+        let len = 2 + bytes[2..].iter().take_while(|b| b.is_ascii_hexdigit()).count();
+        return u64::from_str_radix(&input[2..len], 16).ok().map(|n| (Token::Number(n as f64), len));
+    }
+
+    let mut len = bytes.iter().take_while(|b| b.is_ascii_digit() || **b == b'.').count();
+    if let Some(b'e' | b'E') = bytes.get(len) {
+        let mut exponent = len + 1;
+        if let Some(b'+' | b'-') = bytes.get(exponent) {
+            exponent += 1;
+        }
+        let digits = bytes[exponent..].iter().take_while(|b| b.is_ascii_digit()).count();
+        if digits > 0 {
+            len = exponent + digits;
+        }
+    }
+    input[..len].parse().ok().map(|n| (Token::Number(n), len))
+}
+
+// Smallest first, so `ms` is tried before `m`.
+const UNITS: [(&str, u64); 7] = [
+    ("ms", 1),
+    ("s", 1000),
+    ("m", 60 * 1000),
+    ("h", 60 * 60 * 1000),
+    ("d", 24 * 60 * 60 * 1000),
+    ("w", 7 * 24 * 60 * 60 * 1000),
+    ("y", 365 * 24 * 60 * 60 * 1000)
+];
+
+/// Reads `<digits><unit>` groups from the start of `input`. Each unit may appear once, from
+/// the largest down, as in `1h30m`.
+fn read_duration(input: &str) -> Option<(u64, usize)> {
+    let bytes = input.as_bytes();
+    let mut pos = 0;
+    let mut total: u64 = 0;
+    let mut last_unit = usize::MAX;
+
+    loop {
+        let digits = bytes[pos..].iter().take_while(|b| b.is_ascii_digit()).count();
+        if digits == 0 {
+            break;
+        }
+        let rest = &input[pos + digits..];
+        let Some((order, (unit, scale))) = UNITS.iter().enumerate().find(|(_, (unit, _))| rest.starts_with(unit)) else {
+            break;
+        };
+        if order >= last_unit {
+            return None;
+        }
+        // A unit letter glued to more identifier characters isn't a duration.
+        if rest[unit.len()..].starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            return None;
+        }
+        let value: u64 = input[pos..pos + digits].parse().ok()?;
+        total = total.checked_add(value.checked_mul(*scale)?)?;
+        last_unit = order;
+        pos += digits + unit.len();
+    }
+
+    (pos > 0).then_some((total, pos))
+}
+
+/// Parses a standalone duration like `5m` or `1h30m`.
+pub fn parse_duration(input: &str) -> Result<u64, Error> {
+    match read_duration(input) {
+        Some((millis, len)) if len == input.len() => Ok(millis),
+        _ => Err(Error::Invalid(format!("Invalid duration {:?}", input)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(input: &str) -> Vec<Token> {
+        tokenize(input).unwrap().into_iter().map(|(token, _)| token).collect()
+    }
+
+    #[test]
+    fn tokenizes_expressions() {
+        assert_eq!(tokens(r#"sum by (job) (rate(http_requests_total{code=~"5.."}[5m])) > 0.5e1"#), vec![
+            Token::Ident("sum".into()), Token::Ident("by".into()), Token::LeftParen, Token::Ident("job".into()), Token::RightParen,
+            Token::LeftParen, Token::Ident("rate".into()), Token::LeftParen, Token::Ident("http_requests_total".into()),
+            Token::LeftBrace, Token::Ident("code".into()), Token::RegexMatch, Token::String("5..".into()), Token::RightBrace,
+            Token::LeftBracket, Token::Duration(300_000), Token::RightBracket, Token::RightParen, Token::RightParen,
+            Token::Greater, Token::Number(5.0)
+        ]);
+        assert_eq!(tokens("a:b:c offset 1h30m # comment"), vec![
+            Token::Ident("a:b:c".into()), Token::Ident("offset".into()), Token::Duration(5_400_000)
+        ]);
+        assert_eq!(tokens(r#"'a\.b' `c\d`"#), vec![Token::String("a\\.b".into()), Token::String("c\\d".into())]);
+        assert_eq!(tokens("0x1f Inf"), vec![Token::Number(31.0), Token::Number(f64::INFINITY)]);
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("500ms").unwrap(), 500);
+        assert_eq!(parse_duration("1d2h").unwrap(), 93_600_000);
+        assert!(parse_duration("2h1d").is_err());
+        assert!(parse_duration("5").is_err());
+        assert!(parse_duration("5x").is_err());
+        assert!(tokenize(r#"up{job="a"#).is_err());
+        assert!(tokenize("up $").is_err());
+    }
+}
@@ -0,0 +1,8 @@
+mod eval;
+mod functions;
+mod lexer;
+mod parser;
+
+pub use eval::{instant_query, range_query, Sample, Value};
+pub use lexer::parse_duration;
+pub use parser::{parse, AggregateOp, BinaryOp, Expr, Grouping, VectorMatching};
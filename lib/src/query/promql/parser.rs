@@ -0,0 +1,555 @@
+use crate::{error::Error, query::{promql::lexer::{tokenize, Spanned, Token}, selector::{MatchOp, Matcher, Selector, NAME_LABEL}}};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    String(String),
+    /// An instant vector selector, or a range vector selector when `range` is set. Times are
+    /// in milliseconds.
+    Selector { selector: Selector, range: Option<u64>, offset: u64 },
+    Call { function: String, args: Vec<Expr> },
+    Aggregate { op: AggregateOp, grouping: Grouping, param: Option<Box<Expr>>, expr: Box<Expr> },
+    Binary { op: BinaryOp, lhs: Box<Expr>, rhs: Box<Expr>, matching: VectorMatching, return_bool: bool },
+    Negate(Box<Expr>)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateOp {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+    Group,
+    Stddev,
+    Stdvar,
+    Topk,
+    Bottomk
+}
+
+impl AggregateOp {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "sum" => AggregateOp::Sum,
+            "avg" => AggregateOp::Avg,
+            "min" => AggregateOp::Min,
+            "max" => AggregateOp::Max,
+            "count" => AggregateOp::Count,
+            "group" => AggregateOp::Group,
+            "stddev" => AggregateOp::Stddev,
+            "stdvar" => AggregateOp::Stdvar,
+            "topk" => AggregateOp::Topk,
+            "bottomk" => AggregateOp::Bottomk,
+            _ => return None
+        })
+    }
+
+    fn takes_param(&self) -> bool {
+        matches!(self, AggregateOp::Topk | AggregateOp::Bottomk)
+    }
+}
+
+/// Which labels an aggregation groups by. `All` is the plain form without a `by` or
+/// `without` clause, which folds everything into a single group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Grouping {
+    All,
+    By(Vec<String>),
+    Without(Vec<String>)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,
+    Or,
+    Unless
+}
+
+impl BinaryOp {
+    fn precedence(&self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::And | BinaryOp::Unless => 2,
+            BinaryOp::Equal | BinaryOp::NotEqual | BinaryOp::Less | BinaryOp::LessEqual | BinaryOp::Greater | BinaryOp::GreaterEqual => 3,
+            BinaryOp::Add | BinaryOp::Sub => 4,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => 5,
+            BinaryOp::Pow => 6
+        }
+    }
+
+    pub fn is_comparison(&self) -> bool {
+        self.precedence() == 3
+    }
+
+    pub fn is_set(&self) -> bool {
+        matches!(self, BinaryOp::And | BinaryOp::Or | BinaryOp::Unless)
+    }
+}
+
+/// How the two sides of a vector/vector operation are paired up: on exactly `labels` when
+/// `on` is set, otherwise on everything except `labels`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct VectorMatching {
+    pub on: bool,
+    pub labels: Vec<String>
+}
+
+/// Nesting deeper than this is rejected rather than risking the stack. Parentheses, unary
+/// signs, call arguments and each binary operator in a chain all count as a level.
+const MAX_DEPTH: usize = 128;
+
+/// Ranges and offsets are clamped to this, which is far longer than any data goes back and
+/// short enough that an offset and a range can be added without overflowing.
+const MAX_DURATION: u64 = u64::MAX / 4;
+
+/// Parses a PromQL expression. Subqueries, `@` modifiers and `group_left`/`group_right` are
+/// not supported.
+pub fn parse(input: &str) -> Result<Expr, Error> {
+    let mut parser = Parser { tokens: tokenize(input)?, pos: 0, len: input.len(), depth: 0 };
+    let expr = parser.expr(0)?;
+    if let Some((token, offset)) = parser.tokens.get(parser.pos) {
+        return Err(Error::Invalid(format!("Unexpected {:?} at offset {}", token, offset)));
+    }
+    Ok(expr)
+}
+
+struct Parser {
+    tokens: Vec<Spanned>,
+    pos: usize,
+    len: usize,
+    depth: usize
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn peek_ident(&self) -> Option<&str> {
+        match self.peek() {
+            Some(Token::Ident(ident)) => Some(ident),
+            _ => None
+        }
+    }
+
+    fn error(&self, message: &str) -> Error {
+        let offset = self.tokens.get(self.pos).map(|(_, offset)| *offset).unwrap_or(self.len);
+        Error::Invalid(format!("{} at offset {}", message, offset))
+    }
+
+    fn descend(&mut self) -> Result<(), Error> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(self.error("Expression is nested too deeply"));
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|(token, _)| token.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token, what: &str) -> Result<(), Error> {
+        if self.peek() == Some(&expected) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("Expected {}", what)))
+        }
+    }
+
+    fn binary_op(&self) -> Option<BinaryOp> {
+        Some(match self.peek()? {
+            Token::Add => BinaryOp::Add,
+            Token::Sub => BinaryOp::Sub,
+            Token::Mul => BinaryOp::Mul,
+            Token::Div => BinaryOp::Div,
+            Token::Mod => BinaryOp::Mod,
+            Token::Pow => BinaryOp::Pow,
+            Token::Equal => BinaryOp::Equal,
+            Token::NotEqual => BinaryOp::NotEqual,
+            Token::Less => BinaryOp::Less,
+            Token::LessEqual => BinaryOp::LessEqual,
+            Token::Greater => BinaryOp::Greater,
+            Token::GreaterEqual => BinaryOp::GreaterEqual,
+            Token::Ident(ident) => match ident.to_ascii_lowercase().as_str() {
+                "and" => BinaryOp::And,
+                "or" => BinaryOp::Or,
+                "unless" => BinaryOp::Unless,
+                _ => return None
+            },
+            _ => return None
+        })
+    }
+
+    /// Precedence climbing. `^` is right associative, everything else left.
+    fn expr(&mut self, min_precedence: u8) -> Result<Expr, Error> {
+        let depth = self.depth;
+        self.descend()?;
+        let mut lhs = self.unary()?;
+        while let Some(op) = self.binary_op() {
+            let precedence = op.precedence();
+            if precedence < min_precedence {
+                break;
+            }
+            self.pos += 1;
+
+            let return_bool = self.peek_ident().is_some_and(|ident| ident.eq_ignore_ascii_case("bool"));
+            if return_bool {
+                if !op.is_comparison() {
+                    return Err(self.error("bool is only allowed after comparison operators"));
+                }
+                self.pos += 1;
+            }
+            let matching = self.vector_matching()?;
+
+            let next_min = if op == BinaryOp::Pow { precedence } else { precedence + 1 };
+            // Each operator deepens the tree on the left, so a long chain counts too.
+            self.descend()?;
+            let rhs = self.expr(next_min)?;
+            lhs = Expr::Binary { op, lhs: Box::new(lhs), rhs: Box::new(rhs), matching, return_bool };
+        }
+        self.depth = depth;
+        Ok(lhs)
+    }
+
+    fn vector_matching(&mut self) -> Result<VectorMatching, Error> {
+        let on = match self.peek_ident().map(str::to_ascii_lowercase).as_deref() {
+            Some("on") => true,
+            Some("ignoring") => false,
+            _ => return Ok(VectorMatching::default())
+        };
+        self.pos += 1;
+        let labels = self.label_list()?;
+        if let Some(ident) = self.peek_ident()
+            && (ident.eq_ignore_ascii_case("group_left") || ident.eq_ignore_ascii_case("group_right"))
+        {
+            return Err(self.error("group_left and group_right are not supported"));
+        }
+        Ok(VectorMatching { on, labels })
+    }
+
+    fn unary(&mut self) -> Result<Expr, Error> {
+        match self.peek() {
+            // Binds looser than `^`, so `-2^2` is -4.
+            Some(Token::Sub) => {
+                self.pos += 1;
+                let operand = self.expr(BinaryOp::Pow.precedence())?;
+                Ok(match operand {
+                    Expr::Number(n) => Expr::Number(-n),
+                    operand => Expr::Negate(Box::new(operand))
+                })
+            },
+            Some(Token::Add) => {
+                self.pos += 1;
+                self.expr(BinaryOp::Pow.precedence())
+            },
+            _ => {
+                let expr = self.primary()?;
+                self.modifiers(expr)
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, Error> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Duration(millis)) => Ok(Expr::Number(millis as f64 / 1000.0)),
+            Some(Token::String(s)) => Ok(Expr::String(s)),
+            Some(Token::LeftParen) => {
+                let expr = self.expr(0)?;
+                self.expect(Token::RightParen, "')'")?;
+                Ok(expr)
+            },
+            Some(Token::LeftBrace) => {
+                let matchers = self.matchers()?;
+                self.selector(None, matchers)
+            },
+            Some(Token::Ident(ident)) => {
+                let clause = self.peek_ident().is_some_and(|next| next.eq_ignore_ascii_case("by") || next.eq_ignore_ascii_case("without"));
+                if let Some(op) = AggregateOp::from_name(&ident.to_ascii_lowercase())
+                    && (clause || self.peek() == Some(&Token::LeftParen))
+                {
+                    return self.aggregate(op);
+                }
+                if self.peek() == Some(&Token::LeftParen) {
+                    self.pos += 1;
+                    let args = self.args()?;
+                    return Ok(Expr::Call { function: ident, args });
+                }
+                let matchers = if self.peek() == Some(&Token::LeftBrace) {
+                    self.pos += 1;
+                    self.matchers()?
+                } else {
+                    Vec::new()
+                };
+                self.selector(Some(ident), matchers)
+            },
+            _ => {
+                self.pos -= 1;
+                Err(self.error("Expected an expression"))
+            }
+        }
+    }
+
+    fn selector(&self, name: Option<String>, matchers: Vec<Matcher>) -> Result<Expr, Error> {
+        let selector = Selector { name, matchers };
+        // Like Prometheus, refuse selectors that would match every series in the database.
+        if selector.name.is_none() && selector.matchers.iter().all(|m| m.matches(Some(""))) {
+            return Err(self.error("Selector needs a metric name or a matcher that doesn't match empty values"));
+        }
+        Ok(Expr::Selector { selector, range: None, offset: 0 })
+    }
+
+    /// Reads matchers up to and including the closing brace.
+    fn matchers(&mut self) -> Result<Vec<Matcher>, Error> {
+        let mut matchers = Vec::new();
+        loop {
+            if self.peek() == Some(&Token::RightBrace) {
+                self.pos += 1;
+                return Ok(matchers);
+            }
+            let label = match self.next() {
+                Some(Token::Ident(label)) if !label.contains(':') => label,
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.error("Expected a label name"));
+                }
+            };
+            let op = match self.next() {
+                Some(Token::Assign) => MatchOp::Equal,
+                Some(Token::NotEqual) => MatchOp::NotEqual,
+                Some(Token::RegexMatch) => MatchOp::Regex,
+                Some(Token::RegexNotMatch) => MatchOp::NotRegex,
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.error("Expected =, !=, =~ or !~"));
+                }
+            };
+            let value = match self.next() {
+                Some(Token::String(value)) => value,
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.error("Expected a quoted label value"));
+                }
+            };
+            matchers.push(Matcher::new(&label, op, &value)?);
+
+            match self.peek() {
+                Some(Token::Comma) => self.pos += 1,
+                Some(Token::RightBrace) => {},
+                _ => return Err(self.error("Expected ',' or '}'"))
+            }
+        }
+    }
+
+    /// `[range]` and `offset` after a selector.
+    fn modifiers(&mut self, mut expr: Expr) -> Result<Expr, Error> {
+        if self.peek() == Some(&Token::LeftBracket) {
+            let Expr::Selector { range, .. } = &mut expr else {
+                return Err(self.error("Ranges are only allowed on vector selectors; subqueries are not supported"));
+            };
+            self.pos += 1;
+            let millis = self.duration()?;
+            if millis == 0 {
+                return Err(self.error("Range must be greater than zero"));
+            }
+            *range = Some(millis);
+            self.expect(Token::RightBracket, "']'")?;
+        }
+        if self.peek_ident().is_some_and(|ident| ident.eq_ignore_ascii_case("offset")) {
+            let Expr::Selector { offset, .. } = &mut expr else {
+                return Err(self.error("offset is only allowed on selectors"));
+            };
+            self.pos += 1;
+            *offset = self.duration()?;
+        }
+        if self.peek() == Some(&Token::At) {
+            return Err(self.error("The @ modifier is not supported"));
+        }
+        Ok(expr)
+    }
+
+    /// A duration literal, or a plain number of seconds, at most `MAX_DURATION`.
+    fn duration(&mut self) -> Result<u64, Error> {
+        match self.next() {
+            Some(Token::Duration(millis)) => Ok(millis.min(MAX_DURATION)),
+            Some(Token::Number(seconds)) if seconds >= 0.0 && seconds.is_finite() => Ok(((seconds * 1000.0) as u64).min(MAX_DURATION)),
+            _ => {
+                self.pos -= 1;
+                Err(self.error("Expected a duration"))
+            }
+        }
+    }
+
+    /// Comma separated arguments up to and including the closing parenthesis.
+    fn args(&mut self) -> Result<Vec<Expr>, Error> {
+        let mut args = Vec::new();
+        if self.peek() == Some(&Token::RightParen) {
+            self.pos += 1;
+            return Ok(args);
+        }
+        loop {
+            args.push(self.expr(0)?);
+            match self.next() {
+                Some(Token::Comma) => continue,
+                Some(Token::RightParen) => return Ok(args),
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.error("Expected ',' or ')'"));
+                }
+            }
+        }
+    }
+
+    fn label_list(&mut self) -> Result<Vec<String>, Error> {
+        self.expect(Token::LeftParen, "'('")?;
+        let mut labels = Vec::new();
+        loop {
+            match self.next() {
+                Some(Token::RightParen) => return Ok(labels),
+                Some(Token::Ident(label)) => {
+                    labels.push(label);
+                    match self.next() {
+                        Some(Token::Comma) => continue,
+                        Some(Token::RightParen) => return Ok(labels),
+                        _ => {
+                            self.pos -= 1;
+                            return Err(self.error("Expected ',' or ')'"));
+                        }
+                    }
+                },
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.error("Expected a label name"));
+                }
+            }
+        }
+    }
+
+    /// `op by (labels) (args)`, with the clause allowed before or after the arguments.
+    fn aggregate(&mut self, op: AggregateOp) -> Result<Expr, Error> {
+        let mut grouping = self.grouping()?;
+        self.expect(Token::LeftParen, "'('")?;
+        let mut args = self.args()?;
+        if grouping == Grouping::All {
+            grouping = self.grouping()?;
+        }
+
+        let expected = if op.takes_param() { 2 } else { 1 };
+        if args.len() != expected {
+            return Err(self.error(&format!("{:?} takes {} argument(s), got {}", op, expected, args.len())));
+        }
+        let expr = Box::new(args.pop().unwrap_or(Expr::Number(0.0)));
+        let param = args.pop().map(Box::new);
+        Ok(Expr::Aggregate { op, grouping, param, expr })
+    }
+
+    fn grouping(&mut self) -> Result<Grouping, Error> {
+        match self.peek_ident().map(str::to_ascii_lowercase).as_deref() {
+            Some("by") => {
+                self.pos += 1;
+                Ok(Grouping::By(self.label_list()?))
+            },
+            Some("without") => {
+                self.pos += 1;
+                let mut labels = self.label_list()?;
+                // The name always goes, so listing it changes nothing.
+                labels.retain(|label| label != NAME_LABEL);
+                Ok(Grouping::Without(labels))
+            },
+            _ => Ok(Grouping::All)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selector(input: &str) -> Expr {
+        Expr::Selector { selector: Selector::parse(input).unwrap(), range: None, offset: 0 }
+    }
+
+    #[test]
+    fn parses_precedence() {
+        let expr = parse("1 + 2 * 3 ^ 2 ^ 1 > bool 4 or -up").unwrap();
+        let Expr::Binary { op: BinaryOp::Or, lhs, rhs, .. } = expr else { panic!() };
+        assert_eq!(*rhs, Expr::Negate(Box::new(selector("up"))));
+        let Expr::Binary { op: BinaryOp::Greater, lhs, return_bool: true, .. } = *lhs else { panic!() };
+        let Expr::Binary { op: BinaryOp::Add, rhs, .. } = *lhs else { panic!() };
+        let Expr::Binary { op: BinaryOp::Mul, rhs, .. } = *rhs else { panic!() };
+        let Expr::Binary { op: BinaryOp::Pow, rhs, .. } = *rhs else { panic!() };
+        assert!(matches!(*rhs, Expr::Binary { op: BinaryOp::Pow, .. }));
+
+        assert_eq!(parse("-2 ^ 2").unwrap(), Expr::Negate(Box::new(Expr::Binary {
+            op: BinaryOp::Pow,
+            lhs: Box::new(Expr::Number(2.0)),
+            rhs: Box::new(Expr::Number(2.0)),
+            matching: VectorMatching::default(),
+            return_bool: false
+        })));
+    }
+
+    #[test]
+    fn parses_selectors_and_calls() {
+        let expr = parse(r#"sum without (instance) (rate(http_requests_total{code=~"5.."}[5m] offset 1h))"#).unwrap();
+        let Expr::Aggregate { op: AggregateOp::Sum, grouping, param: None, expr } = expr else { panic!() };
+        assert_eq!(grouping, Grouping::Without(vec![String::from("instance")]));
+        let Expr::Call { function, args } = *expr else { panic!() };
+        assert_eq!(function, "rate");
+        let Expr::Selector { selector: inner, range, offset } = &args[0] else { panic!() };
+        assert_eq!(inner.to_string(), r#"http_requests_total{code=~"5.."}"#);
+        assert_eq!((*range, *offset), (Some(300_000), 3_600_000));
+
+        let expr = parse("topk(3, up) by (job)").unwrap();
+        assert!(matches!(expr, Expr::Aggregate { op: AggregateOp::Topk, grouping: Grouping::By(_), param: Some(_), .. }));
+
+        let expr = parse("a / on (job) b").unwrap();
+        let Expr::Binary { matching, .. } = expr else { panic!() };
+        assert_eq!(matching, VectorMatching { on: true, labels: vec![String::from("job")] });
+
+        // Aggregation names are fine as metric names.
+        assert_eq!(parse("count").unwrap(), selector("count"));
+    }
+
+    #[test]
+    fn rejects_bad_expressions() {
+        for input in ["", "1 +", "sum(", "up[5m", r#"{job=~".*"}"#, "rate(up)[5m]", "up offset", "a + group_left b",
+            "a * on(x) group_left b", "up @ 100", "(1 + 2", "up{job=}", "topk(up)", "1 + bool 2"] {
+            assert!(parse(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        let parens = format!("{}1{}", "(".repeat(10_000), ")".repeat(10_000));
+        assert!(parse(&parens).is_err());
+        assert!(parse(&format!("{}1", "-".repeat(10_000))).is_err());
+        assert!(parse(&format!("{}1", "1 + ".repeat(10_000))).is_err());
+        assert!(parse(&format!("{}1", "abs(".repeat(10_000))).is_err());
+
+        assert_eq!(parse(&format!("{}1{}", "(".repeat(20), ")".repeat(20))).unwrap(), Expr::Number(1.0));
+        assert_eq!(parse(&format!("{}1", "-".repeat(20))).unwrap(), Expr::Number(1.0));
+    }
+
+    #[test]
+    fn clamps_long_durations() {
+        let Expr::Selector { range, offset, .. } = parse("foo[300000000y] offset 300000000y").unwrap() else { panic!() };
+        assert_eq!((range, offset), (Some(MAX_DURATION), MAX_DURATION));
+    }
+}
@@ -0,0 +1,185 @@
+use std::{iter::StepBy, ops::RangeInclusive};
+
+use crate::{error::Error, query::{limits::QueryContext, matrix::{Matrix, Series}}};
+
+/// How far back a step looks for a sample when none lines up with it exactly.
+pub const DEFAULT_LOOKBACK_MS: u64 = 5 * 60 * 1000;
+
+/// Upper bound on the number of steps in a single range query.
+pub const MAX_STEPS: u64 = 11_000;
+
+/// The NaN bit pattern Prometheus writes when a series disappears. It is a regular NaN to
+/// anything that doesn't check for it explicitly.
+pub const STALE_NAN_BITS: u64 = 0x7ff0_0000_0000_0002;
+
+pub fn stale_marker() -> f64 {
+    f64::from_bits(STALE_NAN_BITS)
+}
+
+pub fn is_stale_marker(value: f64) -> bool {
+    value.to_bits() == STALE_NAN_BITS
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Staleness {
+    /// A stale marker ends the series until a newer sample shows up.
+    Markers,
+    /// Stale markers are dropped and only the lookback window limits how long a sample is carried.
+    Ignore
+}
+
+/// A start, end and step grid in milliseconds. Both ends are inclusive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeQuery {
+    pub start: u64,
+    pub end: u64,
+    pub step: u64,
+    pub lookback: u64,
+    pub staleness: Staleness
+}
+
+impl RangeQuery {
+    pub fn new(start: u64, end: u64, step: u64) -> Result<Self, Error> {
+        if step == 0 {
+            return Err(Error::Invalid(String::from("Step must be greater than zero")));
+        }
+        if end < start {
+            return Err(Error::Invalid(format!("End {} is before start {}", end, start)));
+        }
+        if (end - start) / step >= MAX_STEPS {
+            return Err(Error::Invalid(format!("Query would produce more than {} steps, use a larger step", MAX_STEPS)));
+        }
+
+        Ok(RangeQuery {
+            start,
+            end,
+            step,
+            lookback: DEFAULT_LOOKBACK_MS,
+            staleness: Staleness::Markers
+        })
+    }
+
+    /// The oldest sample that can still show up in the result.
+    pub fn earliest_sample(&self) -> u64 {
+        self.start.saturating_sub(self.lookback)
+    }
+
+    pub fn steps(&self) -> StepBy<RangeInclusive<u64>> {
+        (self.start..=self.end).step_by(self.step as usize)
+    }
+}
+
+/// Aligns every series in `raw` (as built by `Matrix::from_samples`) to the query grid.
+/// Series that end up with no points at all are left out.
+pub fn evaluate(raw: &Matrix, query: &RangeQuery, ctx: &mut QueryContext) -> Result<Matrix, Error> {
+    let mut series = Vec::new();
+    for s in &raw.series {
+        ctx.check()?;
+        if let Some(aligned) = align(s, query) {
+            ctx.add_points(aligned.points.len())?;
+            series.push(aligned);
+        }
+    }
+
+    Ok(Matrix { series })
+}
+
+/// Each step takes the latest sample at or before it, as long as that sample is inside the
+/// lookback window. Steps without one are skipped rather than filled, so gaps stay visible.
+pub fn align(series: &Series, query: &RangeQuery) -> Option<Series> {
+    let samples: Vec<(u64, f64)> = match query.staleness {
+        Staleness::Markers => series.points.clone(),
+        Staleness::Ignore => series.points.iter().copied().filter(|(_, v)| !is_stale_marker(*v)).collect()
+    };
+
+    let mut points = Vec::new();
+    let mut next = 0;
+    for timestamp in query.steps() {
+        while next < samples.len() && samples[next].0 <= timestamp {
+            next += 1;
+        }
+        if next == 0 {
+            continue;
+        }
+
+        let (sample_time, value) = samples[next - 1];
+        if timestamp - sample_time >= query.lookback || is_stale_marker(value) {
+            continue;
+        }
+        points.push((timestamp, value));
+    }
+
+    if points.is_empty() {
+        return None;
+    }
+
+    Some(Series {
+        name: series.name.clone(),
+        labels: series.labels.clone(),
+        points
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(points: &[(u64, f64)]) -> Series {
+        Series { name: "cpu".to_string(), labels: vec![], points: points.to_vec() }
+    }
+
+    #[test]
+    fn rejects_bad_grids() {
+        assert!(RangeQuery::new(0, 100, 0).is_err());
+        assert!(RangeQuery::new(100, 0, 10).is_err());
+        assert!(RangeQuery::new(0, MAX_STEPS * 10, 10).is_err());
+        assert_eq!(RangeQuery::new(0, 100, 10).unwrap().steps().count(), 11);
+    }
+
+    #[test]
+    fn aligns_to_steps() {
+        let mut query = RangeQuery::new(100, 400, 100).unwrap();
+        query.lookback = 150;
+
+        let aligned = align(&series(&[(90, 1.0), (210, 2.0), (250, 3.0)]), &query).unwrap();
+        assert_eq!(aligned.points, vec![(100, 1.0), (200, 1.0), (300, 3.0)]);
+    }
+
+    #[test]
+    fn sparse_series_leave_gaps() {
+        let mut query = RangeQuery::new(0, 1000, 100).unwrap();
+        query.lookback = 100;
+
+        let aligned = align(&series(&[(0, 1.0), (550, 2.0)]), &query).unwrap();
+        assert_eq!(aligned.points, vec![(0, 1.0), (600, 2.0)]);
+        assert!(align(&series(&[(1001, 1.0)]), &query).is_none());
+    }
+
+    #[test]
+    fn evaluate_counts_points() {
+        use crate::query::limits::{Limit, QueryLimits};
+
+        let query = RangeQuery::new(0, 300, 100).unwrap();
+        let raw = Matrix { series: vec![series(&[(0, 1.0)])] };
+
+        assert_eq!(evaluate(&raw, &query, &mut QueryContext::unlimited()).unwrap().point_count(), 4);
+
+        let limits = QueryLimits { max_points: 3, ..QueryLimits::UNLIMITED };
+        let result = evaluate(&raw, &query, &mut QueryContext::new(limits));
+        assert!(matches!(result, Err(Error::LimitExceeded { limit: Limit::Points, max: 3 })));
+    }
+
+    #[test]
+    fn stale_markers_end_series() {
+        let query = RangeQuery::new(0, 300, 100).unwrap();
+        let points = [(0, 1.0), (150, stale_marker()), (250, 2.0)];
+
+        let aligned = align(&series(&points), &query).unwrap();
+        assert_eq!(aligned.points, vec![(0, 1.0), (100, 1.0), (300, 2.0)]);
+
+        let mut ignoring = query.clone();
+        ignoring.staleness = Staleness::Ignore;
+        let aligned = align(&series(&points), &ignoring).unwrap();
+        assert_eq!(aligned.points, vec![(0, 1.0), (100, 1.0), (200, 1.0), (300, 2.0)]);
+    }
+}
@@ -0,0 +1,286 @@
+use std::{fmt, iter::Peekable, str::Chars};
+
+use regex::Regex;
+
+use crate::{error::Error, models::Metric};
+
+/// Label name that stands in for the metric name inside `{...}` matchers.
+pub const NAME_LABEL: &str = "__name__";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchOp {
+    Equal,
+    NotEqual,
+    /// The regex has to match the whole value.
+    Regex,
+    NotRegex
+}
+
+impl MatchOp {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            MatchOp::Equal => "=",
+            MatchOp::NotEqual => "!=",
+            MatchOp::Regex => "=~",
+            MatchOp::NotRegex => "!~"
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    pub label: String,
+    pub op: MatchOp,
+    pub value: String,
+    // Compiled from `value` for the regex ops.
+    regex: Option<Regex>
+}
+
+impl PartialEq for Matcher {
+    fn eq(&self, other: &Self) -> bool {
+        self.label == other.label && self.op == other.op && self.value == other.value
+    }
+}
+
+impl Eq for Matcher {}
+
+impl Matcher {
+    /// Fails only for a regex op with a pattern that doesn't compile.
+    pub fn new(label: &str, op: MatchOp, value: &str) -> Result<Self, Error> {
+        let regex = match op {
+            MatchOp::Regex | MatchOp::NotRegex => Some(Regex::new(&format!("^(?:{})$", value))
+                .map_err(|e| Error::Invalid(format!("Invalid regex for label {}: {}", label, e)))?),
+            MatchOp::Equal | MatchOp::NotEqual => None
+        };
+        Ok(Matcher { label: label.to_string(), op, value: value.to_string(), regex })
+    }
+
+    /// A missing label matches like an empty one.
+    pub fn matches(&self, value: Option<&str>) -> bool {
+        let value = value.unwrap_or("");
+        match (self.op, &self.regex) {
+            (MatchOp::Equal, _) => value == self.value,
+            (MatchOp::NotEqual, _) => value != self.value,
+            (MatchOp::Regex, Some(regex)) => regex.is_match(value),
+            (MatchOp::NotRegex, Some(regex)) => !regex.is_match(value),
+            (MatchOp::Regex | MatchOp::NotRegex, None) => false
+        }
+    }
+}
+
+/// A series selector in the usual `name{label="value", other!="value"}` form.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Selector {
+    pub name: Option<String>,
+    pub matchers: Vec<Matcher>
+}
+
+impl Selector {
+    /// Selects every series.
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    pub fn name(name: &str) -> Self {
+        Selector { name: Some(name.to_string()), matchers: Vec::new() }
+    }
+
+    pub fn matches_name(&self, name: &str) -> bool {
+        self.name.as_ref().is_none_or(|n| n == name)
+            && self.matchers.iter().filter(|m| m.label == NAME_LABEL).all(|m| m.matches(Some(name)))
+    }
+
+    pub fn matches_labels(&self, labels: &[(String, String)]) -> bool {
+        self.matchers.iter().filter(|m| m.label != NAME_LABEL).all(|m| {
+            let value = labels.iter().find(|(key, _)| key == &m.label).map(|(_, value)| value.as_str());
+            m.matches(value)
+        })
+    }
+
+    pub fn matches(&self, metric: &Metric) -> bool {
+        self.matches_name(&metric.name) && self.matches_labels(&metric.labels)
+    }
+
+    pub fn parse(input: &str) -> Result<Self, Error> {
+        let mut chars = input.trim().chars().peekable();
+        let mut selector = Selector::default();
+
+        let name = read_identifier(&mut chars, true);
+        if !name.is_empty() {
+            selector.name = Some(name);
+        }
+
+        skip_whitespace(&mut chars);
+        if chars.peek() == Some(&'{') {
+            chars.next();
+            loop {
+                skip_whitespace(&mut chars);
+                if chars.peek() == Some(&'}') {
+                    chars.next();
+                    break;
+                }
+
+                let label = read_identifier(&mut chars, false);
+                if label.is_empty() {
+                    return Err(Error::Invalid(format!("Expected a label name in selector {}", input)));
+                }
+
+                skip_whitespace(&mut chars);
+                let op = match (chars.next(), chars.peek()) {
+                    (Some('='), Some('~')) => {
+                        chars.next();
+                        MatchOp::Regex
+                    },
+                    (Some('='), _) => MatchOp::Equal,
+                    (Some('!'), Some('=')) => {
+                        chars.next();
+                        MatchOp::NotEqual
+                    },
+                    (Some('!'), Some('~')) => {
+                        chars.next();
+                        MatchOp::NotRegex
+                    },
+                    _ => return Err(Error::Invalid(format!("Expected =, !=, =~ or !~ after label {}", label)))
+                };
+
+                skip_whitespace(&mut chars);
+                let value = read_quoted(&mut chars)?;
+                selector.matchers.push(Matcher::new(&label, op, &value)?);
+
+                skip_whitespace(&mut chars);
+                match chars.next() {
+                    Some(',') => continue,
+                    Some('}') => break,
+                    _ => return Err(Error::Invalid(format!("Expected , or }} in selector {}", input)))
+                }
+            }
+        }
+
+        skip_whitespace(&mut chars);
+        if chars.next().is_some() {
+            return Err(Error::Invalid(format!("Unexpected trailing input in selector {}", input)));
+        }
+        if selector.name.is_none() && selector.matchers.is_empty() {
+            return Err(Error::Invalid(String::from("Selector must have a name or at least one matcher")));
+        }
+
+        Ok(selector)
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(name) = &self.name {
+            f.write_str(name)?;
+        }
+        if !self.matchers.is_empty() || self.name.is_none() {
+            let matchers: Vec<String> = self.matchers.iter()
+                .map(|m| format!("{}{}{:?}", m.label, m.op.symbol(), m.value))
+                .collect();
+            write!(f, "{{{}}}", matchers.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+// Metric names may also contain ':', label names may not.
+fn read_identifier(chars: &mut Peekable<Chars>, metric_name: bool) -> String {
+    let mut ident = String::new();
+    while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_' || (metric_name && *c == ':')) {
+        ident.push(c);
+    }
+    ident
+}
+
+fn read_quoted(chars: &mut Peekable<Chars>) -> Result<String, Error> {
+    let quote = match chars.next() {
+        Some(c @ ('"' | '\'')) => c,
+        _ => return Err(Error::Invalid(String::from("Expected a quoted label value")))
+    };
+
+    let mut value = String::new();
+    loop {
+        match chars.next() {
+            Some(c) if c == quote => return Ok(value),
+            Some('\\') => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some(c) => value.push(c),
+                None => break
+            },
+            Some(c) => value.push(c),
+            None => break
+        }
+    }
+
+    Err(Error::Invalid(String::from("Unterminated label value")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_selectors() {
+        let selector = Selector::parse(r#"http_requests_total{method="GET", code!="500"}"#).unwrap();
+        assert_eq!(selector.name.as_deref(), Some("http_requests_total"));
+        assert_eq!(selector.matchers, vec![
+            Matcher::new("method", MatchOp::Equal, "GET").unwrap(),
+            Matcher::new("code", MatchOp::NotEqual, "500").unwrap(),
+        ]);
+
+        assert_eq!(Selector::parse("up").unwrap(), Selector::name("up"));
+        assert_eq!(Selector::parse(r#"{job="a\"b"}"#).unwrap().matchers[0].value, "a\"b");
+        assert_eq!(Selector::parse("up{}").unwrap(), Selector::name("up"));
+
+        assert!(Selector::parse("").is_err());
+        assert!(Selector::parse("{}").is_err());
+        assert!(Selector::parse(r#"up{job="a""#).is_err());
+        assert!(Selector::parse(r#"up{job~"a"}"#).is_err());
+        assert!(Selector::parse(r#"up{job=~"("}"#).is_err());
+        assert!(Selector::parse("up down").is_err());
+    }
+
+    #[test]
+    fn matches_metrics() {
+        let metric = Metric {
+            timestamp: 1,
+            value: 1.0,
+            name: "up".to_string(),
+            labels: vec![("job".to_string(), "api".to_string())]
+        };
+
+        assert!(Selector::parse(r#"up{job="api"}"#).unwrap().matches(&metric));
+        assert!(Selector::parse(r#"{__name__="up",env=""}"#).unwrap().matches(&metric));
+        assert!(!Selector::parse(r#"up{job!="api"}"#).unwrap().matches(&metric));
+        assert!(!Selector::parse("down").unwrap().matches(&metric));
+    }
+
+    #[test]
+    fn regex_matchers_are_anchored() {
+        let matcher = Matcher::new("job", MatchOp::Regex, "ap.|web").unwrap();
+        assert!(matcher.matches(Some("api")));
+        assert!(matcher.matches(Some("web")));
+        assert!(!matcher.matches(Some("rapid")));
+        assert!(!matcher.matches(None));
+
+        let matcher = Matcher::new("job", MatchOp::NotRegex, "a.*").unwrap();
+        assert!(!matcher.matches(Some("api")));
+        assert!(matcher.matches(None));
+
+        let selector = Selector::parse(r#"{__name__=~"up|down", job!~""}"#).unwrap();
+        assert!(selector.matches_name("down"));
+        assert!(!selector.matches_name("sideways"));
+        assert_eq!(Selector::parse(&selector.to_string()).unwrap(), selector);
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let selector = Selector::parse(r#"up{job="api",env!="dev",dc=~"eu-.*"}"#).unwrap();
+        assert_eq!(Selector::parse(&selector.to_string()).unwrap(), selector);
+    }
+}
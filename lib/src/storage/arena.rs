@@ -1,5 +1,7 @@
 
 
+use crate::error::Error;
+
 pub struct Arena {
     buffer: Vec<u8>,
     offset: usize
@@ -13,13 +15,16 @@ impl Arena {
         }
     }
 
-    pub fn alloc_str<'a>(&'a mut self, input: &str) -> &'a str {
+    pub fn alloc_str<'a>(&'a mut self, input: &str) -> Result<&'a str, Error> {
         let bytes = input.as_bytes();
         let len = bytes.len();
+        if len == 0 {
+            return Ok("");
+        }
 
         // Ensure enough capacity
         if self.offset + len > self.buffer.capacity() {
-            panic!("Arena out of memory");
+            return Err(Error::ArenaFull { capacity: self.buffer.capacity() });
         }
 
         // Extend buffer (doesn't reallocate because of capacity)
@@ -31,7 +36,7 @@ impl Arena {
         self.offset += len;
 
         unsafe {
-            std::str::from_utf8_unchecked(std::slice::from_raw_parts(ptr, len))
+            Ok(std::str::from_utf8_unchecked(std::slice::from_raw_parts(ptr, len)))
         }
     }
 
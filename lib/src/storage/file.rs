@@ -1,49 +1,54 @@
 use std::fs::{self, create_dir_all, DirBuilder, File, OpenOptions, ReadDir};
 use std::time::{SystemTime, UNIX_EPOCH};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
 
 
 pub const KIB: u64 = 1024;
 
-pub fn create_file_timed(file_name: &Path, capacity: u64) -> File {
-    let timestamp = SystemTime::now()
+/// Creates `<stem>_<unix millis>.<ext>` next to `file_name`, zero filled up to `capacity`.
+/// Writes start at the beginning of the file, so the zeros mark where the data ends. If a
+/// file of that name already exists the millis are bumped until one doesn't, so a file
+/// created in the same millisecond as another never reuses it. Returns the file with its path.
+pub fn create_file_timed(file_name: &Path, capacity: u64) -> Result<(File, PathBuf), Error> {
+    let mut timestamp = SystemTime::now()
         .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
+        .unwrap_or_default()
         .as_millis();
 
 
     if let Some(parent) = file_name.parent() {
-        create_dir_all(parent).expect("Failed to create parent directories.");
+        create_dir_all(parent)?;
     }    
 
     let stem = file_name.file_stem().unwrap_or_default().to_string_lossy();
     let ext = file_name.extension().map_or(String::new(), |e| format!(".{}", e.to_string_lossy()));
 
-    let new_file_name = format!("{}_{}{}", stem, timestamp, ext);
-    let new_path = file_name.with_file_name(new_file_name);
+    let (file, new_path) = loop {
+        let new_file_name = format!("{}_{}{}", stem, timestamp, ext);
+        let new_path = file_name.with_file_name(new_file_name);
 
-    let file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .append(true)
-        .create(true)
-        .open(&new_path)
-        .unwrap_or_else(|e| panic!("Failed to open file {}: {}", new_path.display(), e));
+        match OpenOptions::new().read(true).write(true).create_new(true).open(&new_path) {
+            Ok(file) => break (file, new_path),
+            Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => timestamp += 1,
+            Err(error) => return Err(error.into()),
+        }
+    };
 
-    file.set_len(capacity).expect(format!("Unable to expand file size to capcity: {}", capacity).as_str());
+    file.set_len(capacity)?;
 
-    file
+    Ok((file, new_path))
 }
 
-pub fn open_or_create(file_name: &str) -> File
+pub fn open_or_create(file_name: &Path) -> Result<File, Error>
 {
     let file = OpenOptions::new()
     .read(true)
-    .write(true)
     .append(true)
     .create(true)
-    .open(file_name).unwrap();
-    file
+    .open(file_name)?;
+    Ok(file)
 }
 
 pub fn open_or_create_directory(path: &Path) -> std::io::Result<ReadDir>
@@ -61,23 +66,30 @@ pub fn open_or_create_directory(path: &Path) -> std::io::Result<ReadDir>
 
 #[cfg(test)]
 mod tests {
-    use std::fs::remove_dir_all;
+    use crate::testing::TempDir;
 
     use super::*; 
 
-    fn dir_exists(path: &str) -> bool {
-        Path::new(path).is_dir()
+    #[test]
+    fn can_create_file_sized()
+    {
+        let dir = TempDir::new("file_sized");
+        let test_dir = dir.join("wals");
+        let (test_file, path) = create_file_timed(&test_dir.join("test.wal"), KIB * 4).unwrap();
+
+        assert!(test_dir.is_dir());
+        assert_eq!(path.parent(), Some(test_dir.as_path()));
+        assert_eq!(test_file.metadata().unwrap().len(), KIB * 4);
     }
 
     #[test]
-    fn can_create_file_sized()
+    fn never_reuses_an_existing_file()
     {
-        let test_dir = "wals";
-        let file_name = "wals/test.wal";
-        let _test_file = create_file_timed(Path::new(file_name), KIB * 4);
+        let dir = TempDir::new("file_unique");
+        let file_name = dir.join("test.wal");
+        let (_, first) = create_file_timed(&file_name, KIB).unwrap();
+        let (_, second) = create_file_timed(&file_name, KIB).unwrap();
 
-        assert!(dir_exists(test_dir));
-        //cleanup
-        remove_dir_all(test_dir).unwrap()
+        assert_ne!(first, second);
     }
 }
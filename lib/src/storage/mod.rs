@@ -1,4 +1,30 @@
 pub mod wal;
 pub mod store;
 pub mod arena;
-pub mod file;
\ No newline at end of file
+pub mod file;
+
+use std::path::PathBuf;
+
+/// Where data goes on disk and how often it gets there.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageOptions {
+    /// WAL files go in its `wals` directory, flushed series files directly in it.
+    pub data_dir: PathBuf,
+    /// WAL appends buffered before they're flushed and synced to disk.
+    pub wal_flush_interval: u64,
+    /// Bytes each new WAL file is zero filled to up front.
+    pub wal_file_size: u64,
+    /// Samples a series takes in between flushes to its data file.
+    pub series_flush_threshold: u32
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        StorageOptions {
+            data_dir: PathBuf::from("."),
+            wal_flush_interval: 10,
+            wal_file_size: file::KIB * 4,
+            series_flush_threshold: 1000
+        }
+    }
+}
\ No newline at end of file
@@ -1,25 +1,73 @@
-use std::{collections::HashMap, io::Write};
+use std::{collections::{HashMap, HashSet}, fs::File, io::Write, path::PathBuf, time::Instant};
 
-use crate::{models::metric::Metric, storage::file, traits::serializable::BinarySerializable};
+use crate::{collections::trie::Trie, error::Error, models::metric::Metric, telemetry::{self, DURATION_BUCKETS}, traits::serializable::BinarySerializable};
 
+/// What the store holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HeadStats {
+    pub series: usize,
+    pub samples: usize,
+    /// Samples and their strings, without what the indexes take.
+    pub memory_bytes: usize
+}
+
+/// Where the series come from, each list highest first.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Cardinality {
+    pub series: usize,
+    pub series_by_metric: Vec<(String, usize)>,
+    /// Distinct values of each label name.
+    pub values_by_label: Vec<(String, usize)>,
+    /// Series carrying each `name=value` pair.
+    pub series_by_label_pair: Vec<(String, usize)>
+}
 
 pub struct InMemoryStore {
+    data_dir: PathBuf,
     flush_max: u32,
     count_table: HashMap<String, u32>,
-    series: HashMap<String, Vec<Metric>>
+    series: HashMap<String, Vec<Metric>>,
+    names: Trie,
+    // Distinct (sorted) label sets seen under each metric name.
+    label_sets: HashMap<String, HashSet<Vec<(String, String)>>>
+}
+
+impl Default for InMemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl InMemoryStore {
     pub fn new() -> Self {
+        Self::with_options(PathBuf::from("."), 1000)
+    }
+
+    /// A store that flushes a series to `<name>.metricdata` in `data_dir` every `flush_max`
+    /// samples.
+    pub fn with_options(data_dir: PathBuf, flush_max: u32) -> Self {
         InMemoryStore {
-            flush_max: 1000,
+            data_dir,
+            flush_max,
             count_table: HashMap::new(),
-            series: HashMap::new()
+            series: HashMap::new(),
+            names: Trie::new(),
+            label_sets: HashMap::new()
         }
     }
 
-    pub fn insert(&mut self, metric: Metric) {
+    /// Stores `metric`, flushing its series to disk every `flush_max` samples. The sample is
+    /// kept in memory even when the flush fails.
+    pub fn insert(&mut self, metric: Metric) -> Result<(), Error> {
         let key = metric.name.to_string();
+        if !self.series.contains_key(&key) {
+            self.names.insert(&key);
+        }
+
+        let mut labels = metric.labels.clone();
+        labels.sort();
+        self.label_sets.entry(key.to_string()).or_default().insert(labels);
+
         self.series.entry(key.to_string()).or_default().push(metric);
         
         let should_flush = {
@@ -30,22 +78,152 @@ impl InMemoryStore {
 
         if should_flush
         {
-            self.flush_metric(key.as_str());
-            *self.count_table.entry(key).or_default() = 0;
+            *self.count_table.entry(key.to_string()).or_default() = 0;
+            self.flush_metric(key.as_str())?;
         }
+        Ok(())
     }
 
     pub fn query(&self, name: &str) -> Option<&Vec<Metric>> {
         self.series.get(name)
     }
 
-    pub fn flush_metric(&mut self, name: &str)
+    pub fn names_with_prefix(&self, prefix: &str) -> Vec<String> {
+        self.names.starts_with(prefix)
+    }
+
+    /// Whether a series `name` with exactly `labels`, which must be sorted, is held.
+    pub fn contains_series(&self, name: &str, labels: &[(String, String)]) -> bool {
+        self.label_sets.get(name).is_some_and(|label_sets| label_sets.contains(labels))
+    }
+
+    pub fn series_count(&self) -> usize {
+        self.label_sets.values().map(HashSet::len).sum()
+    }
+
+    /// The distinct label sets stored under `name`, each sorted by label name.
+    pub fn label_sets(&self, name: &str) -> impl Iterator<Item = &Vec<(String, String)>> {
+        self.label_sets.get(name).into_iter().flatten()
+    }
+
+    /// Drops every sample stored under `name`. Returns false if there was nothing to drop.
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.count_table.remove(name);
+        self.label_sets.remove(name);
+        self.names.remove(name);
+        self.series.remove(name).is_some()
+    }
+
+    /// Drops every sample older than `cutoff`, and series left with none. Returns how many
+    /// samples went.
+    pub fn remove_before(&mut self, cutoff: u64) -> usize {
+        self.remove_matching(|metric| metric.timestamp < cutoff)
+    }
+
+    /// Drops every sample `matches` picks out, and series left with none. Returns how many
+    /// samples went.
+    pub fn remove_matching(&mut self, matches: impl Fn(&Metric) -> bool) -> usize {
+        let mut removed = 0;
+        let mut emptied = Vec::new();
+        for (name, samples) in &mut self.series {
+            let before = samples.len();
+            samples.retain(|metric| !matches(metric));
+            if samples.len() == before {
+                continue;
+            }
+            removed += before - samples.len();
+            if samples.is_empty() {
+                emptied.push(name.clone());
+            } else {
+                let label_sets = samples.iter().map(|metric| {
+                    let mut labels = metric.labels.clone();
+                    labels.sort();
+                    labels
+                }).collect();
+                self.label_sets.insert(name.clone(), label_sets);
+            }
+        }
+        for name in emptied {
+            self.remove(&name);
+        }
+        removed
+    }
+
+    /// Walks every sample, so it's meant for occasional use.
+    pub fn stats(&self) -> HeadStats {
+        let mut stats = HeadStats { series: self.series_count(), ..HeadStats::default() };
+        for samples in self.series.values() {
+            stats.samples += samples.len();
+            stats.memory_bytes += samples.capacity() * size_of::<Metric>();
+            stats.memory_bytes += samples.iter()
+                .map(|metric| metric.name.len() + metric.labels.iter().map(|(key, value)| key.len() + value.len() + size_of::<(String, String)>()).sum::<usize>())
+                .sum::<usize>();
+        }
+        stats
+    }
+
+    /// The top `limit` of each breakdown. Walks every label set.
+    pub fn cardinality(&self, limit: usize) -> Cardinality {
+        let mut cardinality = Cardinality::default();
+        let mut values: HashMap<&str, HashSet<&str>> = HashMap::new();
+        let mut pairs: HashMap<String, usize> = HashMap::new();
+        for (name, label_sets) in &self.label_sets {
+            cardinality.series += label_sets.len();
+            cardinality.series_by_metric.push((name.clone(), label_sets.len()));
+            for (key, value) in label_sets.iter().flatten() {
+                values.entry(key).or_default().insert(value);
+                *pairs.entry(format!("{}={}", key, value)).or_default() += 1;
+            }
+        }
+        cardinality.values_by_label = values.into_iter().map(|(key, values)| (key.to_string(), values.len())).collect();
+        cardinality.series_by_label_pair = pairs.into_iter().collect();
+        for counts in [&mut cardinality.series_by_metric, &mut cardinality.values_by_label, &mut cardinality.series_by_label_pair] {
+            counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            counts.truncate(limit);
+        }
+        cardinality
+    }
+
+    /// Every sample held, in the WAL's encoding, series by series.
+    pub fn serialize_all(&self) -> Vec<u8> {
+        let mut names: Vec<&String> = self.series.keys().collect();
+        names.sort();
+        let mut data = Vec::new();
+        for name in names {
+            for metric in &self.series[name] {
+                data.extend(metric.serialize());
+            }
+        }
+        data
+    }
+
+    /// Writes every series to its data file, and deletes the data files of series no longer
+    /// held.
+    pub fn flush_all(&mut self) -> Result<(), Error> {
+        let names: Vec<String> = self.series.keys().cloned().collect();
+        for name in names {
+            self.count_table.insert(name.clone(), 0);
+            self.flush_metric(&name)?;
+        }
+        for entry in std::fs::read_dir(&self.data_dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "metricdata")
+                && path.file_stem().is_some_and(|stem| !self.series.contains_key(stem.to_string_lossy().as_ref())) {
+                std::fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes every sample held for `name` to its data file, replacing what was there.
+    pub fn flush_metric(&mut self, name: &str) -> Result<(), Error>
     {   
-        let file_name = format!("{}.metricdata", name);
-        let mut file = file::open_or_create(&file_name);
+        let started = Instant::now();
+        let metrics = self.series.get(name)
+            .ok_or_else(|| Error::NotFound(format!("metric {}", name)))?;
+        let mut file = File::create(self.data_dir.join(format!("{}.metricdata", name)))?;
 
         let mut write_data: Vec<u8> = Vec::new();
-        let metrics = self.series.get(name).unwrap();
 
         for metric in metrics
         {
@@ -53,6 +231,56 @@ impl InMemoryStore {
             write_data.extend(bin_metric)
         }
 
-        file.write_all(&write_data).expect("Failed to write to file.");
+        file.write_all(&write_data)?;
+        telemetry::global().histogram("metrichouse_series_flush_duration_seconds", "Time taken to write a series to its data file.", &[], DURATION_BUCKETS)
+            .observe_duration(started.elapsed());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric(name: &str, labels: &[(&str, &str)]) -> Metric {
+        Metric {
+            timestamp: 1,
+            value: 1.0,
+            name: name.to_string(),
+            labels: labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        }
+    }
+
+    #[test]
+    fn indexes_names_and_label_sets() {
+        let mut store = InMemoryStore::new();
+        store.insert(metric("cpu_user", &[("host", "a"), ("dc", "x")])).unwrap();
+        store.insert(metric("cpu_user", &[("dc", "x"), ("host", "a")])).unwrap();
+        store.insert(metric("cpu_user", &[("host", "b")])).unwrap();
+        store.insert(metric("mem_free", &[])).unwrap();
+
+        assert_eq!(store.names_with_prefix("cpu"), vec!["cpu_user"]);
+        assert_eq!(store.names_with_prefix(""), vec!["cpu_user", "mem_free"]);
+        assert_eq!(store.label_sets("cpu_user").count(), 2);
+
+        assert!(store.remove("cpu_user"));
+        assert!(!store.remove("cpu_user"));
+        assert_eq!(store.names_with_prefix(""), vec!["mem_free"]);
+        assert_eq!(store.label_sets("cpu_user").count(), 0);
+        assert!(store.query("cpu_user").is_none());
+    }
+
+    #[test]
+    fn removes_old_samples() {
+        let mut store = InMemoryStore::new();
+        store.insert(Metric { timestamp: 5, ..metric("cpu", &[("host", "a")]) }).unwrap();
+        store.insert(metric("cpu", &[("host", "b")])).unwrap();
+        store.insert(metric("mem", &[])).unwrap();
+        assert_eq!((store.stats().series, store.stats().samples), (3, 3));
+
+        assert_eq!(store.remove_before(2), 2);
+        assert_eq!((store.stats().series, store.stats().samples), (1, 1));
+        assert_eq!(store.names_with_prefix(""), vec!["cpu"]);
+        assert_eq!(store.label_sets("cpu").collect::<Vec<_>>(), vec![&vec![(String::from("host"), String::from("a"))]]);
     }
 }
\ No newline at end of file
@@ -1,49 +1,95 @@
-use std::{fs::File, io::{BufWriter, Write}, path::Path};
+use std::{fs::File, io::{BufWriter, Write}, path::{Path, PathBuf}, time::Instant};
 
-use crate::storage::file;
+use crate::{error::Error, storage::file, telemetry::{self, DURATION_BUCKETS}};
 
 pub const WAL_DIR: &str = "wals/";
 
 pub struct WalWriter {
+    path: PathBuf,
     writer: BufWriter<File>,
     counter: u64,
     flush_interval: u64
 }
 
 impl WalWriter {
-    pub fn open(file: File) -> Self {
+    /// Writes to `file`, which is at `path`, from its current position.
+    pub fn open(path: PathBuf, file: File) -> Self {
         Self {
+            path,
             writer: BufWriter::new(file),
             counter: 0,
             flush_interval: 100
         }
     }
 
-    pub fn new() -> Self {
-        Self {
-            writer: BufWriter::new(file::create_file_timed(&Path::new(format!("{}{}", WAL_DIR, "wal.bin").as_str()),file::KIB * 4)),
+    pub fn new() -> Result<Self, Error> {
+        Self::create(Path::new(WAL_DIR))
+    }
+
+    /// Starts a fresh WAL file inside `dir`, creating the directory if needed.
+    pub fn create(dir: &Path) -> Result<Self, Error> {
+        Self::create_with(dir, file::KIB * 4, 10)
+    }
+
+    /// `create` with a file of `file_size` bytes, flushed every `flush_interval` writes.
+    pub fn create_with(dir: &Path, file_size: u64, flush_interval: u64) -> Result<Self, Error> {
+        let (file, path) = file::create_file_timed(&dir.join("wal.bin"), file_size)?;
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
             counter: 0,
-            flush_interval: 10
-        }
+            flush_interval
+        })
     }
 
+    /// Appends `bin`, and every `flush_interval` appends flushes and syncs the file.
     pub fn write(&mut self, bin: &[u8]) -> std::io::Result<()> {
-        self.writer.write_all(&bin)?;
+        self.writer.write_all(bin)?;
         self.counter += 1;
+        let registry = telemetry::global();
+        registry.counter("metrichouse_wal_bytes_written_total", "Bytes appended to the WAL.", &[]).add(bin.len() as u64);
 
-        //TODO: If the incoming flush will expand the file beyond it's initial capacity, create a new WAL. 
+        // The file grows past its initial size rather than rolling over. `MetricsDb::compact`,
+        // which retention runs, keeps it to what's held in memory.
 
-        if (self.counter % self.flush_interval) == 0 {
-            println!("Flushing writer after {} writes", self.counter);
-            self.writer.flush()?; // Flush every 1000 writes
+        if self.counter.is_multiple_of(self.flush_interval) {
+            crate::trace!(writes = self.counter; "Flushing WAL");
+            self.sync()?;
         }
         Ok(())
     }
+
+    /// Flushes buffered appends and syncs the file, without waiting for `flush_interval`.
+    pub fn sync(&mut self) -> std::io::Result<()> {
+        let started = Instant::now();
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        telemetry::global().histogram("metrichouse_wal_fsync_duration_seconds", "Time taken to flush and sync the WAL file.", &[], DURATION_BUCKETS)
+            .observe_duration(started.elapsed());
+        Ok(())
+    }
+
+    /// Replaces everything in the file with `data`, and appends after it from then on. `data`
+    /// goes to a `.tmp` file that's renamed over this one, so a crash leaves either the old
+    /// contents or the new.
+    pub fn rewrite(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.writer.flush()?;
+        let temporary = self.path.with_extension("tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(&temporary, &self.path)?;
+        self.writer = BufWriter::new(file::open_or_create(&self.path)?);
+        self.counter = 0;
+        Ok(())
+    }
 }
 
 impl Drop for WalWriter {
     fn drop(&mut self) {
-        self.writer.flush().expect("Failed to flush writer on drop");
+        if let Err(e) = self.writer.flush() {
+            crate::error!(error = e; "Failed to flush WAL on drop");
+        }
     }
 }
 
@@ -60,7 +106,7 @@ mod tests {
     #[test]
     fn create_wal_test()
     {
-        let _wal_writer = WalWriter::new();
+        let _wal_writer = WalWriter::new().unwrap();
         assert!(dir_exists(WAL_DIR))
     }
 }
\ No newline at end of file
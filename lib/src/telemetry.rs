@@ -0,0 +1,259 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::{models::{Metric, MetricKind}, traits::json::format_value};
+
+/// Seconds, from half a millisecond up to ten seconds.
+pub const DURATION_BUCKETS: &[f64] = &[0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+static GLOBAL: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+/// The registry the server and the database record into.
+pub fn global() -> &'static Registry {
+    &GLOBAL
+}
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// An `f64` that goes up and down, kept as its bits.
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn add(&self, delta: f64) {
+        add_f64(&self.0, delta);
+    }
+
+    pub fn inc(&self) {
+        self.add(1.0);
+    }
+
+    pub fn dec(&self) {
+        self.add(-1.0);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// Counts observations into cumulative buckets, as Prometheus histograms do.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// One per bound, not cumulative; `count` covers the `+Inf` bucket.
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+    count: AtomicU64
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram { bounds, buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(), sum: AtomicU64::new(0), count: AtomicU64::new(0) }
+    }
+
+    pub fn observe(&self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        add_f64(&self.sum, value);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_secs_f64());
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> f64 {
+        f64::from_bits(self.sum.load(Ordering::Relaxed))
+    }
+
+    /// `(le, cumulative count)` for every bucket, ending with `+Inf`.
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        let mut total = 0;
+        let mut buckets: Vec<(f64, u64)> = self.bounds.iter().zip(&self.buckets).map(|(bound, count)| {
+            total += count.load(Ordering::Relaxed);
+            (*bound, total)
+        }).collect();
+        buckets.push((f64::INFINITY, self.count()));
+        buckets
+    }
+}
+
+fn add_f64(bits: &AtomicU64, delta: f64) {
+    let _ = bits.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| Some((f64::from_bits(current) + delta).to_bits()));
+}
+
+#[derive(Debug, Clone)]
+enum Instrument {
+    Counter(Arc<Counter>),
+    Gauge(Arc<Gauge>),
+    Histogram(Arc<Histogram>)
+}
+
+type Labels = Vec<(String, String)>;
+
+#[derive(Debug)]
+struct Family {
+    help: String,
+    kind: MetricKind,
+    series: BTreeMap<Labels, Instrument>
+}
+
+/// Named instruments, each family with its help text and one instrument per label set.
+/// Asking for the same name and labels again returns the same instrument, so callers can
+/// either keep it or look it up every time.
+#[derive(Debug, Default)]
+pub struct Registry {
+    families: Mutex<BTreeMap<String, Family>>
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Panics if `name` was registered as another kind, which is a bug in the caller.
+    pub fn counter(&self, name: &str, help: &str, labels: &[(&str, &str)]) -> Arc<Counter> {
+        match self.instrument(name, help, MetricKind::Counter, labels, || Instrument::Counter(Arc::default())) {
+            Instrument::Counter(counter) => counter,
+            _ => unreachable!()
+        }
+    }
+
+    pub fn gauge(&self, name: &str, help: &str, labels: &[(&str, &str)]) -> Arc<Gauge> {
+        match self.instrument(name, help, MetricKind::Gauge, labels, || Instrument::Gauge(Arc::default())) {
+            Instrument::Gauge(gauge) => gauge,
+            _ => unreachable!()
+        }
+    }
+
+    /// `bounds` must be sorted, and are only used the first time the name is registered.
+    pub fn histogram(&self, name: &str, help: &str, labels: &[(&str, &str)], bounds: &'static [f64]) -> Arc<Histogram> {
+        match self.instrument(name, help, MetricKind::Histogram, labels, || Instrument::Histogram(Arc::new(Histogram::new(bounds)))) {
+            Instrument::Histogram(histogram) => histogram,
+            _ => unreachable!()
+        }
+    }
+
+    fn instrument(&self, name: &str, help: &str, kind: MetricKind, labels: &[(&str, &str)], new: impl FnOnce() -> Instrument) -> Instrument {
+        let mut families = match self.families.lock() {
+            Ok(families) => families,
+            Err(poisoned) => poisoned.into_inner()
+        };
+        let family = families.entry(name.to_string()).or_insert_with(|| Family { help: help.to_string(), kind, series: BTreeMap::new() });
+        assert_eq!(family.kind, kind, "{} is already registered as a {}", name, family.kind.as_str());
+        let mut labels: Labels = labels.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        labels.sort();
+        family.series.entry(labels).or_insert_with(new).clone()
+    }
+
+    /// Every family with its kind and samples, sorted by name. Histograms are expanded into
+    /// their `_bucket`, `_sum` and `_count` series.
+    pub fn gather(&self, timestamp: u64) -> Vec<(String, String, MetricKind, Vec<Metric>)> {
+        let families = match self.families.lock() {
+            Ok(families) => families,
+            Err(poisoned) => poisoned.into_inner()
+        };
+        families.iter().map(|(name, family)| {
+            let mut samples = Vec::new();
+            let mut sample = |suffix: &str, labels: Labels, value: f64| {
+                samples.push(Metric { timestamp, value, name: format!("{}{}", name, suffix), labels });
+            };
+            for (labels, instrument) in &family.series {
+                match instrument {
+                    Instrument::Counter(counter) => sample("", labels.clone(), counter.get() as f64),
+                    Instrument::Gauge(gauge) => sample("", labels.clone(), gauge.get()),
+                    Instrument::Histogram(histogram) => {
+                        for (bound, count) in histogram.buckets() {
+                            let mut labels = labels.clone();
+                            labels.push((String::from("le"), format_value(bound)));
+                            labels.sort();
+                            sample("_bucket", labels, count as f64);
+                        }
+                        sample("_sum", labels.clone(), histogram.sum());
+                        sample("_count", labels.clone(), histogram.count() as f64);
+                    }
+                }
+            }
+            (name.clone(), family.help.clone(), family.kind, samples)
+        }).collect()
+    }
+
+    /// The Prometheus text format, version 0.0.4, without timestamps.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, help, kind, samples) in self.gather(0) {
+            out.push_str(&format!("# HELP {} {}\n", name, help.replace('\\', "\\\\").replace('\n', "\\n")));
+            out.push_str(&format!("# TYPE {} {}\n", name, kind.as_str()));
+            for sample in samples {
+                out.push_str(&sample.name);
+                if !sample.labels.is_empty() {
+                    let labels: Vec<String> = sample.labels.iter().map(|(key, value)| {
+                        format!("{}=\"{}\"", key, value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+                    }).collect();
+                    out.push_str(&format!("{{{}}}", labels.join(",")));
+                }
+                out.push_str(&format!(" {}\n", format_value(sample.value)));
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::exposition::{self, Format};
+
+    #[test]
+    fn renders_what_was_recorded() {
+        let registry = Registry::new();
+        registry.counter("metrichouse_samples_total", "Samples stored.", &[]).add(3);
+        registry.counter("metrichouse_rejected_total", "Samples rejected.", &[("reason", "too_old")]).inc();
+        registry.counter("metrichouse_rejected_total", "Samples rejected.", &[("reason", "too_old")]).inc();
+        registry.gauge("metrichouse_connections", "Open connections.", &[("protocol", "http")]).set(2.0);
+        let latency = registry.histogram("metrichouse_query_duration_seconds", "Query latency.", &[], &[0.1, 1.0]);
+        latency.observe(0.05);
+        latency.observe(0.5);
+        latency.observe(5.0);
+
+        let text = registry.render();
+        assert!(text.contains("# TYPE metrichouse_query_duration_seconds histogram\n"));
+        assert!(text.contains("metrichouse_rejected_total{reason=\"too_old\"} 2\n"));
+        assert!(text.contains("metrichouse_query_duration_seconds_bucket{le=\"1\"} 2\n"));
+        assert!(text.contains("metrichouse_query_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("metrichouse_query_duration_seconds_sum 5.55\n"));
+
+        // What's rendered reads back as the same samples.
+        let parsed = exposition::parse(&text, Format::Text, 1000);
+        assert!(parsed.samples.1.is_empty());
+        let gathered: Vec<Metric> = registry.gather(1000).into_iter().flat_map(|(_, _, _, samples)| samples).collect();
+        assert_eq!(parsed.samples.0.len(), gathered.len());
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::{db::MetricsDb, error::Error, models::{RejectCode, Validation}, query::QueryLimits, storage::{store::HeadStats, StorageOptions}, telemetry};
+
+/// Writes and queries that don't name a tenant belong to this one, whose data is in the data
+/// directory itself.
+pub const DEFAULT_TENANT: &str = "default";
+/// Where the data directory of every other tenant goes, inside the default one's.
+pub const TENANTS_DIR: &str = "tenants";
+const MAX_ID_LEN: usize = 64;
+
+/// What a tenant may store. `None` is no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TenantLimits {
+    /// Series held at once. Samples that would start another are rejected.
+    pub max_series: Option<usize>,
+    /// Samples accepted per second, in bursts of up to a second's worth.
+    pub ingest_rate: Option<u64>,
+    /// How long samples are kept.
+    pub retention: Option<Duration>
+}
+
+/// What every tenant's database is set up with.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TenantSettings {
+    pub validation: Validation,
+    pub query_limits: QueryLimits,
+    /// For tenants without limits of their own.
+    pub limits: TenantLimits,
+    pub overrides: Vec<(String, TenantLimits)>,
+    /// Tenants that may be open at once, the default one included. Writes from any more are
+    /// rejected, while those with data from before a restart are always opened. `None` is no limit.
+    pub max_tenants: Option<usize>
+}
+
+impl TenantSettings {
+    pub fn limits_for(&self, id: &str) -> TenantLimits {
+        self.overrides.iter().find(|(tenant, _)| tenant == id).map_or(self.limits, |(_, limits)| *limits)
+    }
+}
+
+/// Ids name directories, so they're kept to `[a-zA-Z0-9_-]`, as in Mimir.
+pub fn validate_id(id: &str) -> Result<(), Error> {
+    if id.is_empty() || id.len() > MAX_ID_LEN {
+        return Err(Error::Invalid(format!("Tenant id {:?} must be 1 to {} characters", id, MAX_ID_LEN)));
+    }
+    if let Some(c) = id.chars().find(|c| !(c.is_ascii_alphanumeric() || *c == '_' || *c == '-')) {
+        return Err(Error::Invalid(format!("Tenant id {:?} can't hold {:?}, only letters, digits, _ and -", id, c)));
+    }
+    Ok(())
+}
+
+/// Rate limiting state: the samples that may still come in, which goes below zero after a
+/// burst bigger than a second's worth.
+struct Allowance {
+    samples: f64,
+    updated: Instant
+}
+
+/// One tenant's database, which holds nothing of anyone else's.
+pub struct Tenant {
+    id: String,
+    db: Arc<RwLock<MetricsDb>>,
+    limits: Mutex<(TenantLimits, Allowance)>
+}
+
+impl Tenant {
+    fn new(id: &str, db: MetricsDb, limits: TenantLimits) -> Self {
+        let allowance = Allowance { samples: limits.ingest_rate.unwrap_or(0) as f64, updated: Instant::now() };
+        Tenant { id: id.to_string(), db: Arc::new(RwLock::new(db)), limits: Mutex::new((limits, allowance)) }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn db(&self) -> &Arc<RwLock<MetricsDb>> {
+        &self.db
+    }
+
+    pub fn limits(&self) -> TenantLimits {
+        self.lock_limits().0
+    }
+
+    /// Takes `samples` out of what the ingest rate allows, all of them or none. A batch bigger
+    /// than a second's worth is let in when nothing's been sent for a second, and the ones
+    /// after wait until the rate has caught up.
+    pub fn admit(&self, samples: usize) -> bool {
+        let mut limits = self.lock_limits();
+        let (limits, allowance) = &mut *limits;
+        let Some(rate) = limits.ingest_rate.map(|rate| rate as f64) else {
+            return true;
+        };
+        let now = Instant::now();
+        allowance.samples = (allowance.samples + now.duration_since(allowance.updated).as_secs_f64() * rate).min(rate);
+        allowance.updated = now;
+        if allowance.samples < (samples as f64).min(rate) {
+            return false;
+        }
+        allowance.samples -= samples as f64;
+        true
+    }
+
+    fn configure(&self, settings: &TenantSettings) -> Result<(), Error> {
+        let limits = settings.limits_for(&self.id);
+        let mut db = self.db.write().map_err(|_| Error::Invalid(String::from("Database lock is poisoned")))?;
+        db.set_validation(settings.validation.clone());
+        db.set_query_limits(settings.query_limits);
+        db.set_max_series(limits.max_series);
+        let mut current = self.lock_limits();
+        // A new rate starts with a full second's worth, as a new tenant does.
+        if current.0.ingest_rate != limits.ingest_rate {
+            current.1 = Allowance { samples: limits.ingest_rate.unwrap_or(0) as f64, updated: Instant::now() };
+        }
+        current.0 = limits;
+        Ok(())
+    }
+
+    /// The limits are only ever swapped whole, so a poisoned lock still holds good ones.
+    fn lock_limits(&self) -> std::sync::MutexGuard<'_, (TenantLimits, Allowance)> {
+        self.limits.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Every tenant's database, opened at startup if it has data and otherwise on its first
+/// write. Each has its own WAL, series files and indexes under `<data_dir>/tenants/<id>`,
+/// except the default tenant, which keeps the data directory to itself as before there were
+/// tenants.
+pub struct Tenants {
+    options: StorageOptions,
+    settings: RwLock<TenantSettings>,
+    tenants: RwLock<HashMap<String, Arc<Tenant>>>
+}
+
+impl Tenants {
+    /// `default` is the database in `options.data_dir`, which may still have its WAL to replay.
+    pub fn new(options: StorageOptions, mut default: MetricsDb, settings: TenantSettings) -> Self {
+        let limits = settings.limits_for(DEFAULT_TENANT);
+        default.set_validation(settings.validation.clone());
+        default.set_query_limits(settings.query_limits);
+        default.set_max_series(limits.max_series);
+        let tenant = Arc::new(Tenant::new(DEFAULT_TENANT, default, limits));
+        Tenants {
+            options,
+            settings: RwLock::new(settings),
+            tenants: RwLock::new(HashMap::from([(DEFAULT_TENANT.to_string(), tenant)]))
+        }
+    }
+
+    pub fn default_tenant(&self) -> Arc<Tenant> {
+        self.read_tenants().get(DEFAULT_TENANT).cloned().expect("The default tenant is never removed")
+    }
+
+    /// The tenant `id` to write to, created if it has never been written to before. An empty
+    /// id is the default tenant.
+    pub fn get(&self, id: &str) -> Result<Arc<Tenant>, Error> {
+        match self.find(id)? {
+            Some(tenant) => Ok(tenant),
+            None => self.open(id, true)
+        }
+    }
+
+    /// The tenant `id` to read from, or `None` if it has no data. Tenants with data on disk are
+    /// all opened at startup, so reads never need to create one.
+    pub fn find(&self, id: &str) -> Result<Option<Arc<Tenant>>, Error> {
+        let id = if id.is_empty() { DEFAULT_TENANT } else { id };
+        if let Some(tenant) = self.read_tenants().get(id) {
+            return Ok(Some(tenant.clone()));
+        }
+        validate_id(id)?;
+        Ok(None)
+    }
+
+    /// Opens the tenant `id`, replaying its WAL. `capped` holds it to `max_tenants`.
+    fn open(&self, id: &str, capped: bool) -> Result<Arc<Tenant>, Error> {
+        let mut tenants = self.tenants.write().map_err(|_| Error::Invalid(String::from("Tenant lock is poisoned")))?;
+        // Another request may have opened it while this one waited for the lock.
+        if let Some(tenant) = tenants.get(id) {
+            return Ok(tenant.clone());
+        }
+        let settings = self.read_settings();
+        if capped && let Some(max) = settings.max_tenants && tenants.len() >= max {
+            return Err(Error::Rejected { code: RejectCode::TenantLimit, detail: format!("Already holding the limit of {} tenants", max) });
+        }
+        let options = StorageOptions { data_dir: self.options.data_dir.join(TENANTS_DIR).join(id), ..self.options.clone() };
+        let db = MetricsDb::open_with(&options)?;
+        let tenant = Arc::new(Tenant::new(id, db, TenantLimits::default()));
+        tenant.configure(&settings)?;
+        crate::info!(tenant = id, data_dir = options.data_dir.display(); "Opened tenant");
+        tenants.insert(id.to_string(), tenant.clone());
+        Ok(tenant)
+    }
+
+    /// Opens every tenant that has a data directory, so their data is queryable and retention
+    /// applies to it before they write again. Returns how many there were.
+    pub fn open_existing(&self) -> Result<usize, Error> {
+        let dir = self.options.data_dir.join(TENANTS_DIR);
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            return Ok(0);
+        };
+        let mut opened = 0;
+        for entry in entries {
+            let entry = entry?;
+            if let Some(id) = entry.file_name().to_str() && entry.file_type()?.is_dir() && validate_id(id).is_ok() {
+                self.open(id, false)?;
+                opened += 1;
+            }
+        }
+        Ok(opened)
+    }
+
+    /// Every open tenant, sorted by id.
+    pub fn all(&self) -> Vec<Arc<Tenant>> {
+        let mut tenants: Vec<Arc<Tenant>> = self.read_tenants().values().cloned().collect();
+        tenants.sort_by(|a, b| a.id.cmp(&b.id));
+        tenants
+    }
+
+    /// Applies to the open tenants straight away, and to the rest when they're opened.
+    pub fn configure(&self, settings: TenantSettings) -> Result<(), Error> {
+        for tenant in self.all() {
+            tenant.configure(&settings)?;
+        }
+        *self.settings.write().map_err(|_| Error::Invalid(String::from("Tenant lock is poisoned")))? = settings;
+        Ok(())
+    }
+
+    /// Sets the head gauges of the global registry to the sum over every tenant, and the
+    /// series gauge of each.
+    pub fn record_stats(&self) {
+        let registry = telemetry::global();
+        let mut total = HeadStats::default();
+        for tenant in self.all() {
+            let Ok(db) = tenant.db.read() else {
+                continue;
+            };
+            let stats = db.head_stats();
+            registry.gauge("metrichouse_tenant_series", "Series held in memory, by tenant.", &[("tenant", &tenant.id)]).set(stats.series as f64);
+            total.series += stats.series;
+            total.samples += stats.samples;
+            total.memory_bytes += stats.memory_bytes;
+        }
+        registry.gauge("metrichouse_head_series", "Series held in memory.", &[]).set(total.series as f64);
+        registry.gauge("metrichouse_head_samples", "Samples held in memory.", &[]).set(total.samples as f64);
+        registry.gauge("metrichouse_head_memory_bytes", "Rough size of the samples held in memory.", &[]).set(total.memory_bytes as f64);
+    }
+
+    fn read_tenants(&self) -> std::sync::RwLockReadGuard<'_, HashMap<String, Arc<Tenant>>> {
+        // Tenants are only ever added whole, so a poisoned map is still a good one.
+        self.tenants.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn read_settings(&self) -> TenantSettings {
+        self.settings.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::Metric, testing::TempDir};
+
+    fn metric(name: &str, host: &str) -> Metric {
+        Metric { timestamp: 1, value: 1.0, name: name.to_string(), labels: vec![(String::from("host"), host.to_string())] }
+    }
+
+    #[test]
+    fn keeps_tenants_apart() {
+        let dir = TempDir::new("tenants");
+        let options = StorageOptions { data_dir: dir.to_path_buf(), ..StorageOptions::default() };
+        let limited = TenantLimits { max_series: Some(2), ingest_rate: Some(5), retention: None };
+        let settings = TenantSettings { overrides: vec![(String::from("team-b"), limited)], ..TenantSettings::default() };
+        let tenants = Tenants::new(options.clone(), MetricsDb::open_with(&options).unwrap(), settings.clone());
+
+        assert!(tenants.get("../etc").is_err());
+        assert!(tenants.get(&"a".repeat(65)).is_err());
+        assert_eq!(tenants.get("").unwrap().id(), DEFAULT_TENANT);
+        let (a, b) = (tenants.get("team-a").unwrap(), tenants.get("team-b").unwrap());
+        assert_eq!(b.limits(), limited);
+        assert_eq!(a.db().write().unwrap().ingest_batch(vec![metric("up", "a")]).accepted, 1);
+        assert!(b.db().read().unwrap().query("up").is_err());
+        assert!(tenants.default_tenant().db().read().unwrap().query("up").is_err());
+
+        // The third series is over the limit, while more samples of the first two aren't.
+        let result = b.db().write().unwrap().ingest_batch(vec![metric("up", "a"), metric("up", "b"), metric("up", "c"), metric("up", "a")]);
+        assert_eq!((result.accepted, result.errors[0].index, result.errors[0].code), (3, 2, RejectCode::SeriesLimit));
+        assert!(b.admit(5));
+        assert!(!b.admit(1));
+        assert!(a.admit(1_000_000));
+
+        // Settings are shared, limits are each tenant's own.
+        tenants.configure(TenantSettings { limits: TenantLimits { max_series: Some(1), ..TenantLimits::default() }, ..settings }).unwrap();
+        assert_eq!(a.limits().max_series, Some(1));
+        assert_eq!(a.db().write().unwrap().ingest_batch(vec![metric("up", "z")]).errors[0].code, RejectCode::SeriesLimit);
+
+        drop((a, b, tenants));
+        let tenants = Tenants::new(options.clone(), MetricsDb::open_with(&options).unwrap(), TenantSettings::default());
+        assert_eq!(tenants.open_existing().unwrap(), 2);
+        assert_eq!(tenants.all().iter().map(|tenant| tenant.id()).collect::<Vec<_>>(), vec![DEFAULT_TENANT, "team-a", "team-b"]);
+        assert_eq!(tenants.get("team-b").unwrap().db().read().unwrap().query("up").unwrap().len(), 3);
+
+        // Reads never create a tenant, and writes stop creating them at the cap, though
+        // tenants with data were all opened above.
+        assert!(tenants.find("team-c").unwrap().is_none());
+        assert!(tenants.find("../etc").is_err());
+        assert!(!dir.join(TENANTS_DIR).join("team-c").exists());
+        tenants.configure(TenantSettings { max_tenants: Some(3), ..TenantSettings::default() }).unwrap();
+        assert!(matches!(tenants.get("team-c"), Err(Error::Rejected { code: RejectCode::TenantLimit, .. })));
+        assert!(tenants.find("team-a").unwrap().is_some());
+        tenants.configure(TenantSettings { max_tenants: Some(4), ..TenantSettings::default() }).unwrap();
+        assert_eq!(tenants.get("team-c").unwrap().id(), "team-c");
+    }
+}
@@ -0,0 +1,40 @@
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// An empty directory under the system temp dir, removed along with everything in it when
+/// dropped. Only built for tests, here and in crates that turn on the `testing` feature.
+#[derive(Debug)]
+pub struct TempDir {
+    path: PathBuf
+}
+
+impl TempDir {
+    /// `name` only needs to be unique within one test binary, as the process id is added to
+    /// it. Whatever an earlier run left under the same name is cleared first.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("metrichouse_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).expect("Failed to create a temp dir");
+        TempDir { path }
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
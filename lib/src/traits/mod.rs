@@ -1 +1,2 @@
-pub mod serializable;
\ No newline at end of file
+pub mod serializable;
+pub mod json;
\ No newline at end of file
@@ -1,70 +1,181 @@
+use crate::error::Error;
+
 pub trait BinarySerializable {
     fn serialize(&self) -> Vec<u8>;
-    fn deserialize(data: &[u8], byte_offset: &mut usize) -> Result<Self, String> where Self: Sized;
+    fn deserialize(data: &[u8], byte_offset: &mut usize) -> Result<Self, Error> where Self: Sized;
+}
+
+// Little-endian helpers shared by the hand written impls. The readers check bounds and only
+// advance `byte_offset` when the read succeeds.
+
+fn take<'a>(data: &'a [u8], byte_offset: &mut usize, len: usize) -> Result<&'a [u8], Error> {
+    let end = byte_offset.checked_add(len).filter(|end| *end <= data.len())
+        .ok_or_else(|| Error::Decode(format!("Unexpected end of data at offset {}", byte_offset)))?;
+    let bytes = &data[*byte_offset..end];
+    *byte_offset = end;
+    Ok(bytes)
+}
+
+pub fn read_u16(data: &[u8], byte_offset: &mut usize) -> Result<u16, Error> {
+    let bytes = take(data, byte_offset, 2)?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+pub fn read_u32(data: &[u8], byte_offset: &mut usize) -> Result<u32, Error> {
+    let bytes = take(data, byte_offset, 4)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+pub fn read_u64(data: &[u8], byte_offset: &mut usize) -> Result<u64, Error> {
+    let bytes = take(data, byte_offset, 8)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+pub fn read_bool(data: &[u8], byte_offset: &mut usize) -> Result<bool, Error> {
+    let bytes = take(data, byte_offset, 1)?;
+    Ok(bytes[0] != 0)
+}
+
+pub fn read_f64(data: &[u8], byte_offset: &mut usize) -> Result<f64, Error> {
+    read_u64(data, byte_offset).map(f64::from_bits)
+}
+
+pub fn read_string(data: &[u8], byte_offset: &mut usize) -> Result<String, Error> {
+    let start = *byte_offset;
+    let len = read_u32(data, byte_offset)? as usize;
+    let bytes = take(data, byte_offset, len).inspect_err(|_| *byte_offset = start)?;
+    String::from_utf8(bytes.to_vec()).map_err(|e| {
+        *byte_offset = start;
+        Error::Decode(format!("Invalid utf-8 string: {}", e))
+    })
+}
+
+pub fn read_bytes(data: &[u8], byte_offset: &mut usize) -> Result<Vec<u8>, Error> {
+    let start = *byte_offset;
+    let len = read_u32(data, byte_offset)? as usize;
+    let bytes = take(data, byte_offset, len).inspect_err(|_| *byte_offset = start)?;
+    Ok(bytes.to_vec())
+}
+
+pub fn write_bytes(data: &mut Vec<u8>, value: &[u8]) {
+    data.extend((value.len() as u32).to_le_bytes());
+    data.extend(value);
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use binser_derive::BinarySerializable;
-
-//     use super::*;
-
-//     #[derive(Default, BinarySerializable)]
-//     pub struct Test {
-//         id: u32,
-//         description: String
-//     }
-
-//     #[derive(Default, BinarySerializable)]
-//     pub struct TestTwo {
-//         first: u32,
-//         second: u32,
-//         third: u32
-//     }
-
-//     #[test]
-//     fn can_build() {
-//         let trace = Test {
-//             id: 1,
-//             description: String::from("This is a test trace"),
-//         };
-
-//         assert_eq!(trace.id, 1);
-//         assert_eq!(trace.description, "This is a test trace");
-//     }
-
-//     #[test]
-//     fn can_serialize() {
-//         let trace = Test {
-//             id: 5,
-//             description: String::from("This is a test trace"),
-//         };
-
-//         let serialized = trace.serialize();
-//         assert!(!serialized.is_empty());
-//         println!("{:?}", serialized);
-
-//         let mut byte_offset: usize = 0;
-//         let deserialized = Test::deserialize(&serialized, &mut byte_offset).unwrap();
-//         assert_eq!(deserialized.id, trace.id);
-//         assert_eq!(deserialized.description, trace.description);
-//     }
-
-//     #[test]
-//     fn can_serialize_test() {
-//         let test = TestTwo {
-//             first: 10,
-//             second: 15,
-//             third: 3
-//         };
-
-//         let serialized = test.serialize();
-//         assert!(!serialized.is_empty());
-//         println!("{:?}", serialized);
-//         let mut byte_offset: usize = 0;
-//         let deserialized = TestTwo::deserialize(&serialized, &mut byte_offset).unwrap();
-//         assert_eq!(deserialized.first, test.first);
-//         assert_eq!(deserialized.second, test.second);
-//         assert_eq!(deserialized.third, test.third);
-//     }
-// }
\ No newline at end of file
+pub fn write_string(data: &mut Vec<u8>, value: &str) {
+    data.extend((value.len() as u32).to_le_bytes());
+    data.extend(value.as_bytes());
+}
+
+pub fn write_labels(data: &mut Vec<u8>, labels: &[(String, String)]) {
+    data.extend((labels.len() as u32).to_le_bytes());
+    for (key, value) in labels {
+        write_string(data, key);
+        write_string(data, value);
+    }
+}
+
+pub fn read_labels(data: &[u8], byte_offset: &mut usize) -> Result<Vec<(String, String)>, Error> {
+    let count = read_u32(data, byte_offset)? as usize;
+    // Each label takes at least 8 bytes, so don't trust a count the buffer can't hold.
+    let mut labels = Vec::with_capacity(count.min(data.len() / 8));
+    for _ in 0..count {
+        let key = read_string(data, byte_offset)?;
+        let value = read_string(data, byte_offset)?;
+        labels.push((key, value));
+    }
+    Ok(labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use binser_derive::BinarySerializable;
+
+    use super::*;
+
+    #[derive(Default, BinarySerializable)]
+    #[binser(crate = "crate")]
+    pub struct Test {
+        id: u32,
+        description: String
+    }
+
+    #[derive(Default, BinarySerializable)]
+    #[binser(crate = "crate")]
+    pub struct TestTwo {
+        first: u32,
+        second: u32,
+        third: u32
+    }
+
+    #[derive(Default, BinarySerializable)]
+    #[binser(crate = "crate")]
+    pub struct TestBytes {
+        id: u64,
+        payload: Vec<u8>
+    }
+
+    #[test]
+    fn can_build() {
+        let trace = Test {
+            id: 1,
+            description: String::from("This is a test trace"),
+        };
+
+        assert_eq!(trace.id, 1);
+        assert_eq!(trace.description, "This is a test trace");
+    }
+
+    #[test]
+    fn can_serialize() {
+        let trace = Test {
+            id: 5,
+            description: String::from("This is a test trace"),
+        };
+
+        let serialized = trace.serialize();
+        assert!(!serialized.is_empty());
+
+        let mut byte_offset: usize = 0;
+        let deserialized = Test::deserialize(&serialized, &mut byte_offset).unwrap();
+        assert_eq!(deserialized.id, trace.id);
+        assert_eq!(deserialized.description, trace.description);
+    }
+
+    #[test]
+    fn can_serialize_test() {
+        let test = TestTwo {
+            first: 10,
+            second: 15,
+            third: 3
+        };
+
+        let serialized = test.serialize();
+        assert!(!serialized.is_empty());
+        let mut byte_offset: usize = 0;
+        let deserialized = TestTwo::deserialize(&serialized, &mut byte_offset).unwrap();
+        assert_eq!(deserialized.first, test.first);
+        assert_eq!(deserialized.second, test.second);
+        assert_eq!(deserialized.third, test.third);
+    }
+
+    #[test]
+    fn can_serialize_bytes() {
+        let test = TestBytes { id: 7, payload: vec![1, 2, 3] };
+
+        let serialized = test.serialize();
+        let mut byte_offset: usize = 0;
+        let deserialized = TestBytes::deserialize(&serialized, &mut byte_offset).unwrap();
+        assert_eq!(deserialized.payload, test.payload);
+        assert_eq!(byte_offset, serialized.len());
+    }
+
+    #[test]
+    fn truncated_input_is_a_decode_error() {
+        let serialized = Test { id: 5, description: String::from("truncated") }.serialize();
+        for len in 0..serialized.len() {
+            let result = Test::deserialize(&serialized[..len], &mut 0);
+            assert!(matches!(result, Err(Error::Decode(_))));
+        }
+    }
+}
\ No newline at end of file
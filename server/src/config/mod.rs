@@ -0,0 +1,911 @@
+pub mod reload;
+pub mod toml;
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use lib::{ingest::{graphite::Template, pipeline::PipelineOptions, relabel::{Action, RelabelConfig, Relabeler}}, log, models::{validation::NanPolicy, Validation}, query::{promql::parse_duration, QueryLimits}, storage::StorageOptions, tenant::{validate_id, TenantLimits, TenantSettings}, Error};
+
+use crate::config::toml::{Table, Value};
+use crate::scrape::{discovery::valid_address, Job};
+
+pub const USAGE: &str = "\
+Usage: server [options]
+
+  --config <file>                 Read settings from a TOML file
+  --set <key>=<value>             Override one setting, as in --set http.enabled=false
+  --data-dir <dir>                Where WAL and series files go (storage.data_dir)
+  --retention <duration>          How long samples are kept, or off (storage.retention)
+  --listen <address>              Binary protocol address (binary.listen)
+  --http-listen <address>         HTTP API address (http.listen)
+  --influx-listen <address>       Line protocol TCP and UDP address (influx.listen)
+  --graphite-listen <address>     Graphite plaintext address (graphite.listen)
+  --graphite-pickle-listen <address>
+                                  Graphite pickle address (graphite.pickle_listen)
+  --statsd-listen <address>       StatsD UDP address (statsd.listen)
+  --disable <protocol>            Turn off binary, http, influx, graphite or statsd
+  --log-level <level>             error, warn, info, debug or trace (log.level)
+  --log-format <format>           text or json (log.format)
+  --admin-token-file <file>       Bearer token for /-/admin operations (admin.token_file)
+  --print-config                  Print the effective settings and exit
+  --help                          Print this and exit";
+
+/// Flags that set one key, always to a string.
+const KEY_FLAGS: &[(&str, &str)] = &[
+    ("--data-dir", "storage.data_dir"),
+    ("--retention", "storage.retention"),
+    ("--listen", "binary.listen"),
+    ("--http-listen", "http.listen"),
+    ("--influx-listen", "influx.listen"),
+    ("--graphite-listen", "graphite.listen"),
+    ("--graphite-pickle-listen", "graphite.pickle_listen"),
+    ("--statsd-listen", "statsd.listen"),
+    ("--log-level", "log.level"),
+    ("--log-format", "log.format"),
+    ("--admin-token-file", "admin.token_file")
+];
+const PROTOCOLS: &[&str] = &["binary", "http", "influx", "graphite", "statsd"];
+
+/// The command line.
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    pub config_file: Option<PathBuf>,
+    /// Dotted keys and their values, applied over the file in order.
+    pub overrides: Vec<(String, Value)>,
+    pub print_config: bool,
+    pub help: bool
+}
+
+impl Args {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, Error> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        while let Some(flag) = args.next() {
+            let mut value = |flag: &str| args.next().ok_or_else(|| Error::Invalid(format!("{} needs a value", flag)));
+            match flag.as_str() {
+                "--help" | "-h" => parsed.help = true,
+                "--print-config" => parsed.print_config = true,
+                "--config" => parsed.config_file = Some(PathBuf::from(value(&flag)?)),
+                "--set" => {
+                    let setting = value(&flag)?;
+                    let (key, text) = setting.split_once('=')
+                        .ok_or_else(|| Error::Invalid(format!("--set takes key=value, got {:?}", setting)))?;
+                    // Anything that isn't a TOML value is taken as a string, so quotes can be left out.
+                    let value = toml::parse_value(text).unwrap_or_else(|| Value::String(text.to_string()));
+                    parsed.overrides.push((key.trim().to_string(), value));
+                },
+                "--disable" => {
+                    let protocol = value(&flag)?;
+                    if !PROTOCOLS.contains(&protocol.as_str()) {
+                        return Err(Error::Invalid(format!("Unknown protocol {:?}, expected one of {}", protocol, PROTOCOLS.join(", "))));
+                    }
+                    parsed.overrides.push((format!("{}.enabled", protocol), Value::Boolean(false)));
+                },
+                _ => match KEY_FLAGS.iter().find(|(name, _)| *name == flag) {
+                    Some((_, key)) => {
+                        let text = value(&flag)?;
+                        parsed.overrides.push((key.to_string(), Value::String(text)));
+                    },
+                    None => return Err(Error::Invalid(format!("Unknown option {:?}, see --help", flag)))
+                }
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// A listener that can be turned off.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listener {
+    pub enabled: bool,
+    pub listen: String
+}
+
+impl Listener {
+    fn new(listen: &str) -> Self {
+        Listener { enabled: true, listen: listen.to_string() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphiteConfig {
+    pub enabled: bool,
+    pub listen: String,
+    pub pickle_listen: String,
+    /// Tried in order; paths no template matches are stored under their whole path.
+    pub templates: Vec<String>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatsdConfig {
+    pub enabled: bool,
+    pub listen: String,
+    pub flush_interval: Duration,
+    pub percentiles: Vec<f64>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LogConfig {
+    pub level: log::Level,
+    pub format: log::Format
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct AdminConfig {
+    /// Holds the bearer token `/-/admin` operations take. Without one they're turned off.
+    pub token_file: Option<PathBuf>
+}
+
+impl AdminConfig {
+    /// Reads the token, without the whitespace around it.
+    pub fn token(&self) -> Result<Option<String>, Error> {
+        let Some(path) = &self.token_file else {
+            return Ok(None);
+        };
+        let token = std::fs::read_to_string(path)
+            .map_err(|e| invalid("admin.token_file", &format!("can't be read from {}: {}", path.display(), e)))?;
+        match token.trim() {
+            "" => Err(invalid("admin.token_file", &format!("{} is empty", path.display()))),
+            token => Ok(Some(token.to_string()))
+        }
+    }
+}
+
+/// Everything the server can be told at startup.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub storage: StorageOptions,
+    /// How long samples are kept in memory. `None` keeps them.
+    pub retention: Option<Duration>,
+    /// How samples get from the listeners to storage.
+    pub ingest: PipelineOptions,
+    /// `QueryLimits::default()`, except that queries time out after 30 seconds rather than two
+    /// minutes. The only place the server's query limits come from.
+    pub query_limits: QueryLimits,
+    /// Applied after relabeling.
+    pub validation: Validation,
+    /// For every tenant not in `tenants`. Its retention is always `retention`.
+    pub tenant_limits: TenantLimits,
+    /// Tenants with limits of their own, by id.
+    pub tenants: Vec<(String, TenantLimits)>,
+    /// Tenants that may be open at once, the default one included. `None` is no limit.
+    pub max_tenants: Option<usize>,
+    pub binary: Listener,
+    pub http: Listener,
+    /// Line protocol over both TCP and UDP, on the port InfluxDB uses for UDP.
+    pub influx: Listener,
+    pub graphite: GraphiteConfig,
+    pub statsd: StatsdConfig,
+    /// Run in order over every sample before it's stored, whichever protocol it came in by.
+    pub relabel: Vec<RelabelConfig>,
+    pub scrape: Vec<Job>,
+    /// How often the server stores its own metrics. `None` only serves them on `/metrics`.
+    pub self_monitoring: Option<Duration>,
+    pub log: LogConfig,
+    pub admin: AdminConfig
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            storage: StorageOptions::default(),
+            retention: None,
+            ingest: PipelineOptions::default(),
+            query_limits: QueryLimits { timeout: Duration::from_secs(30), ..QueryLimits::default() },
+            validation: Validation { max_future: Some(Duration::from_secs(10 * 60)), ..Validation::default() },
+            tenant_limits: TenantLimits::default(),
+            tenants: Vec::new(),
+            max_tenants: None,
+            binary: Listener::new("127.0.0.1:1227"),
+            http: Listener::new("127.0.0.1:1228"),
+            influx: Listener::new("127.0.0.1:8089"),
+            graphite: GraphiteConfig {
+                enabled: true,
+                listen: String::from("127.0.0.1:2003"),
+                pickle_listen: String::from("127.0.0.1:2004"),
+                templates: vec![String::from("servers.*.cpu.* -> host, metric")]
+            },
+            statsd: StatsdConfig {
+                enabled: true,
+                listen: String::from("127.0.0.1:8125"),
+                flush_interval: Duration::from_secs(10),
+                percentiles: vec![50.0, 90.0, 99.0]
+            },
+            relabel: Vec::new(),
+            // File SD files are JSON lists of `{"targets": ["host:port"], "labels": {...}}`,
+            // reread when they change.
+            scrape: vec![Job { file_sd: vec![PathBuf::from("scrape_targets.json")], ..Job::new("node") }],
+            self_monitoring: Some(Duration::from_secs(15)),
+            log: LogConfig::default(),
+            admin: AdminConfig::default()
+        }
+    }
+}
+
+impl Config {
+    /// The defaults, overridden by the config file and then by the command line, and validated.
+    pub fn load(args: &Args) -> Result<Self, Error> {
+        let table = match &args.config_file {
+            Some(path) => {
+                let input = std::fs::read_to_string(path)
+                    .map_err(|e| Error::Invalid(format!("Can't read config file {}: {}", path.display(), e)))?;
+                toml::parse(&input).map_err(|e| Error::Invalid(format!("Config file {}: {}", path.display(), detail(e))))?
+            },
+            None => Table::new()
+        };
+        Self::with_overrides(table, &args.overrides)
+    }
+
+    /// `load` for a file that's already been read.
+    pub fn with_overrides(mut table: Table, overrides: &[(String, Value)]) -> Result<Self, Error> {
+        for (key, value) in overrides {
+            let path: Vec<String> = key.split('.').map(String::from).collect();
+            table_for_override(&mut table, &path)?.insert(path[path.len() - 1].clone(), value.clone());
+        }
+        let config = Self::from_table(&table)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reads settings over the defaults. Keys that aren't settings are errors, so typos don't
+    /// go unnoticed.
+    pub fn from_table(table: &Table) -> Result<Self, Error> {
+        let defaults = Config::default();
+        let mut root = Reader::new("", table);
+
+        let mut section = root.table("storage")?;
+        let storage = StorageOptions {
+            data_dir: PathBuf::from(section.string("data_dir", &defaults.storage.data_dir.to_string_lossy())?),
+            wal_flush_interval: section.integer("wal_flush_interval", defaults.storage.wal_flush_interval)?,
+            wal_file_size: section.integer("wal_file_size", defaults.storage.wal_file_size)?,
+            series_flush_threshold: section.integer("series_flush_threshold", defaults.storage.series_flush_threshold)?
+        };
+        let retention = section.optional_duration("retention", defaults.retention)?;
+        section.finish()?;
+
+        let mut section = root.table("ingest")?;
+        let ingest = PipelineOptions {
+            shards: section.integer("shards", defaults.ingest.shards)?,
+            capacity: section.integer("queue_capacity", defaults.ingest.capacity)?,
+            max_batch: section.integer("max_batch", defaults.ingest.max_batch)?
+        };
+        section.finish()?;
+
+        let mut section = root.table("limits")?;
+        let query_limits = QueryLimits {
+            max_series: section.integer("max_series", defaults.query_limits.max_series)?,
+            max_samples: section.integer("max_samples", defaults.query_limits.max_samples)?,
+            max_points: section.integer("max_points", defaults.query_limits.max_points)?,
+            timeout: section.duration("query_timeout", defaults.query_limits.timeout)?
+        };
+        section.finish()?;
+
+        let mut section = root.table("validation")?;
+        let validation = Validation {
+            max_age: section.optional_duration("max_age", defaults.validation.max_age)?,
+            max_future: section.optional_duration("max_future", defaults.validation.max_future)?,
+            max_label_name_len: section.integer("max_label_name_length", defaults.validation.max_label_name_len)?,
+            max_label_value_len: section.integer("max_label_value_length", defaults.validation.max_label_value_len)?,
+            max_labels: section.integer("max_labels", defaults.validation.max_labels)?,
+            nan_policy: match section.string("nan", nan_policy_name(defaults.validation.nan_policy))?.as_str() {
+                "accept" => NanPolicy::Accept,
+                "reject_except_stale" => NanPolicy::RejectExceptStale,
+                "reject" => NanPolicy::Reject,
+                other => return Err(invalid("validation.nan", &format!("must be accept, reject_except_stale or reject, not {:?}", other)))
+            }
+        };
+        section.finish()?;
+
+        // Zero is no limit, as `to_toml` writes it.
+        let mut section = root.table("tenant_limits")?;
+        let tenant_limits = TenantLimits {
+            max_series: nonzero(section.integer("max_series", 0)?),
+            ingest_rate: nonzero(section.integer("ingest_rate", 0)?),
+            retention
+        };
+        let max_tenants = nonzero(section.integer("max_tenants", 0)?);
+        section.finish()?;
+
+        let tenants = match root.tables("tenant")? {
+            None => defaults.tenants,
+            Some(tenants) => tenants.into_iter().map(|mut tenant| {
+                let id = tenant.required_string("id")?;
+                let limits = TenantLimits {
+                    max_series: nonzero(tenant.integer("max_series", tenant_limits.max_series.unwrap_or(0))?),
+                    ingest_rate: nonzero(tenant.integer("ingest_rate", tenant_limits.ingest_rate.unwrap_or(0))?),
+                    retention: tenant.optional_duration("retention", retention)?
+                };
+                tenant.finish()?;
+                Ok((id, limits))
+            }).collect::<Result<_, Error>>()?
+        };
+
+        let binary = root.listener("binary", &defaults.binary)?;
+        let http = root.listener("http", &defaults.http)?;
+        let influx = root.listener("influx", &defaults.influx)?;
+
+        let mut section = root.table("graphite")?;
+        let graphite = GraphiteConfig {
+            enabled: section.boolean("enabled", defaults.graphite.enabled)?,
+            listen: section.string("listen", &defaults.graphite.listen)?,
+            pickle_listen: section.string("pickle_listen", &defaults.graphite.pickle_listen)?,
+            templates: section.strings("templates", &defaults.graphite.templates)?
+        };
+        section.finish()?;
+
+        let mut section = root.table("statsd")?;
+        let statsd = StatsdConfig {
+            enabled: section.boolean("enabled", defaults.statsd.enabled)?,
+            listen: section.string("listen", &defaults.statsd.listen)?,
+            flush_interval: section.duration("flush_interval", defaults.statsd.flush_interval)?,
+            percentiles: section.floats("percentiles", &defaults.statsd.percentiles)?
+        };
+        section.finish()?;
+
+        let relabel = match root.tables("relabel")? {
+            None => defaults.relabel,
+            Some(rules) => rules.into_iter().map(|mut rule| {
+                let default = RelabelConfig::default();
+                let config = RelabelConfig {
+                    source_labels: rule.strings("source_labels", &default.source_labels)?,
+                    separator: rule.string("separator", &default.separator)?,
+                    target_label: rule.string("target_label", &default.target_label)?,
+                    regex: rule.string("regex", &default.regex)?,
+                    modulus: rule.integer("modulus", default.modulus)?,
+                    replacement: rule.string("replacement", &default.replacement)?,
+                    action: Action::parse(&rule.string("action", default.action.as_str())?)
+                        .map_err(|e| invalid(&rule.key("action"), &detail(e)))?
+                };
+                rule.finish()?;
+                Ok(config)
+            }).collect::<Result<_, Error>>()?
+        };
+
+        let scrape = match root.tables("scrape")? {
+            None => defaults.scrape,
+            Some(jobs) => jobs.into_iter().map(|mut job| {
+                let name = job.required_string("job")?;
+                let default = Job::new(&name);
+                let config = Job {
+                    targets: job.strings("targets", &default.targets)?,
+                    file_sd: job.strings("file_sd", &[])?.into_iter().map(PathBuf::from).collect(),
+                    interval: job.duration("interval", default.interval)?,
+                    timeout: job.duration("timeout", default.timeout)?,
+                    metrics_path: job.string("metrics_path", &default.metrics_path)?,
+                    tenant: job.string("tenant", &default.tenant)?,
+                    ..default
+                };
+                job.finish()?;
+                Ok(config)
+            }).collect::<Result<_, Error>>()?
+        };
+
+        let mut section = root.table("self_monitoring")?;
+        let self_monitoring = section.optional_duration("interval", defaults.self_monitoring)?;
+        section.finish()?;
+
+        let mut section = root.table("log")?;
+        let log = LogConfig {
+            level: log::Level::parse(&section.string("level", defaults.log.level.as_str())?)
+                .map_err(|e| invalid("log.level", &detail(e)))?,
+            format: log::Format::parse(&section.string("format", defaults.log.format.as_str())?)
+                .map_err(|e| invalid("log.format", &detail(e)))?
+        };
+        section.finish()?;
+
+        let mut section = root.table("admin")?;
+        // Empty for none, as `to_toml` writes it.
+        let admin = AdminConfig {
+            token_file: Some(section.string("token_file", "")?).filter(|path| !path.is_empty()).map(PathBuf::from)
+        };
+        section.finish()?;
+        root.finish()?;
+
+        Ok(Config { storage, retention, ingest, query_limits, validation, tenant_limits, tenants, max_tenants, binary, http, influx, graphite, statsd, relabel, scrape, self_monitoring, log, admin })
+    }
+
+    /// Checks what `from_table` can't see key by key.
+    pub fn validate(&self) -> Result<(), Error> {
+        let positive = [
+            ("storage.wal_flush_interval", self.storage.wal_flush_interval),
+            ("storage.wal_file_size", self.storage.wal_file_size),
+            ("storage.series_flush_threshold", self.storage.series_flush_threshold as u64),
+            ("ingest.shards", self.ingest.shards as u64),
+            ("ingest.queue_capacity", self.ingest.capacity as u64),
+            ("ingest.max_batch", self.ingest.max_batch as u64),
+            ("limits.max_series", self.query_limits.max_series as u64),
+            ("limits.max_samples", self.query_limits.max_samples as u64),
+            ("limits.max_points", self.query_limits.max_points as u64),
+            ("limits.query_timeout", self.query_limits.timeout.as_millis() as u64),
+            ("statsd.flush_interval", self.statsd.flush_interval.as_millis() as u64)
+        ];
+        if let Some((key, _)) = positive.iter().find(|(_, value)| *value == 0) {
+            return Err(invalid(key, "must be greater than zero"));
+        }
+        if self.retention.is_some_and(|retention| retention.is_zero()) {
+            return Err(invalid("storage.retention", "must be greater than zero, or off"));
+        }
+        let mut tenants = HashSet::new();
+        for (i, (id, limits)) in self.tenants.iter().enumerate() {
+            validate_id(id).map_err(|e| invalid(&format!("tenant[{}].id", i), &detail(e)))?;
+            if !tenants.insert(id) {
+                return Err(invalid("tenant", &format!("{:?} is defined twice", id)));
+            }
+            if limits.retention.is_some_and(|retention| retention.is_zero()) {
+                return Err(invalid(&format!("tenant[{}].retention", i), "must be greater than zero, or off"));
+            }
+        }
+        if self.self_monitoring.is_some_and(|interval| interval.is_zero()) {
+            return Err(invalid("self_monitoring.interval", "must be greater than zero, or off"));
+        }
+        if let Some(percentile) = self.statsd.percentiles.iter().find(|p| !(**p > 0.0 && **p <= 100.0)) {
+            return Err(invalid("statsd.percentiles", &format!("{} is not in (0, 100]", percentile)));
+        }
+
+        // TCP listeners can't share an address. UDP ones may share one with TCP.
+        let mut tcp = HashSet::new();
+        let listeners = [
+            ("binary.listen", self.binary.enabled, &self.binary.listen, true),
+            ("http.listen", self.http.enabled, &self.http.listen, true),
+            ("influx.listen", self.influx.enabled, &self.influx.listen, true),
+            ("graphite.listen", self.graphite.enabled, &self.graphite.listen, true),
+            ("graphite.pickle_listen", self.graphite.enabled, &self.graphite.pickle_listen, true),
+            ("statsd.listen", self.statsd.enabled, &self.statsd.listen, false)
+        ];
+        for (key, enabled, address, is_tcp) in listeners {
+            if !enabled {
+                continue;
+            }
+            if !valid_address(address) {
+                return Err(invalid(key, &format!("{:?} is not a host:port address", address)));
+            }
+            if is_tcp && !tcp.insert(address.as_str()) {
+                return Err(invalid(key, &format!("{} is already used by another listener", address)));
+            }
+        }
+
+        for spec in &self.graphite.templates {
+            Template::parse(spec).map_err(|e| invalid("graphite.templates", &detail(e)))?;
+        }
+        Relabeler::new(self.relabel.clone()).map_err(|e| invalid("relabel", &detail(e)))?;
+        let mut jobs = HashSet::new();
+        for job in &self.scrape {
+            job.validate()?;
+            if !jobs.insert(&job.name) {
+                return Err(invalid("scrape", &format!("job {:?} is defined twice", job.name)));
+            }
+        }
+        Ok(())
+    }
+
+    /// What the tenants' databases are set up with.
+    pub fn tenant_settings(&self) -> TenantSettings {
+        TenantSettings {
+            validation: self.validation.clone(),
+            query_limits: self.query_limits,
+            limits: TenantLimits { retention: self.retention, ..self.tenant_limits },
+            overrides: self.tenants.clone(),
+            max_tenants: self.max_tenants
+        }
+    }
+
+    /// The settings as a config file that `load` reads back to the same `Config`.
+    pub fn to_toml(&self) -> String {
+        let mut root = Table::new();
+        let mut storage = Table::new();
+        storage.insert("data_dir".into(), string(&self.storage.data_dir.to_string_lossy()));
+        storage.insert("wal_flush_interval".into(), integer(self.storage.wal_flush_interval));
+        storage.insert("wal_file_size".into(), integer(self.storage.wal_file_size));
+        storage.insert("series_flush_threshold".into(), integer(self.storage.series_flush_threshold));
+        storage.insert("retention".into(), optional_duration(self.retention));
+        root.insert("storage".into(), Value::Table(storage));
+
+        let mut ingest = Table::new();
+        ingest.insert("shards".into(), integer(self.ingest.shards));
+        ingest.insert("queue_capacity".into(), integer(self.ingest.capacity));
+        ingest.insert("max_batch".into(), integer(self.ingest.max_batch));
+        root.insert("ingest".into(), Value::Table(ingest));
+
+        let mut limits = Table::new();
+        limits.insert("max_series".into(), integer(self.query_limits.max_series));
+        limits.insert("max_samples".into(), integer(self.query_limits.max_samples));
+        limits.insert("max_points".into(), integer(self.query_limits.max_points));
+        limits.insert("query_timeout".into(), duration(self.query_limits.timeout));
+        root.insert("limits".into(), Value::Table(limits));
+
+        let mut validation = Table::new();
+        validation.insert("max_age".into(), optional_duration(self.validation.max_age));
+        validation.insert("max_future".into(), optional_duration(self.validation.max_future));
+        validation.insert("max_label_name_length".into(), integer(self.validation.max_label_name_len));
+        validation.insert("max_label_value_length".into(), integer(self.validation.max_label_value_len));
+        validation.insert("max_labels".into(), integer(self.validation.max_labels));
+        validation.insert("nan".into(), string(nan_policy_name(self.validation.nan_policy)));
+        root.insert("validation".into(), Value::Table(validation));
+
+        let mut tenant_limits = Table::new();
+        tenant_limits.insert("max_series".into(), integer(self.tenant_limits.max_series.unwrap_or(0)));
+        tenant_limits.insert("ingest_rate".into(), integer(self.tenant_limits.ingest_rate.unwrap_or(0)));
+        tenant_limits.insert("max_tenants".into(), integer(self.max_tenants.unwrap_or(0)));
+        root.insert("tenant_limits".into(), Value::Table(tenant_limits));
+        root.insert("tenant".into(), Value::Array(self.tenants.iter().map(|(id, limits)| {
+            let mut table = Table::new();
+            table.insert("id".into(), string(id));
+            table.insert("max_series".into(), integer(limits.max_series.unwrap_or(0)));
+            table.insert("ingest_rate".into(), integer(limits.ingest_rate.unwrap_or(0)));
+            table.insert("retention".into(), optional_duration(limits.retention));
+            Value::Table(table)
+        }).collect()));
+
+        for (name, listener) in [("binary", &self.binary), ("http", &self.http), ("influx", &self.influx)] {
+            let mut table = Table::new();
+            table.insert("enabled".into(), Value::Boolean(listener.enabled));
+            table.insert("listen".into(), string(&listener.listen));
+            root.insert(name.into(), Value::Table(table));
+        }
+
+        let mut graphite = Table::new();
+        graphite.insert("enabled".into(), Value::Boolean(self.graphite.enabled));
+        graphite.insert("listen".into(), string(&self.graphite.listen));
+        graphite.insert("pickle_listen".into(), string(&self.graphite.pickle_listen));
+        graphite.insert("templates".into(), strings(&self.graphite.templates));
+        root.insert("graphite".into(), Value::Table(graphite));
+
+        let mut statsd = Table::new();
+        statsd.insert("enabled".into(), Value::Boolean(self.statsd.enabled));
+        statsd.insert("listen".into(), string(&self.statsd.listen));
+        statsd.insert("flush_interval".into(), duration(self.statsd.flush_interval));
+        statsd.insert("percentiles".into(), Value::Array(self.statsd.percentiles.iter().map(|p| Value::Float(*p)).collect()));
+        root.insert("statsd".into(), Value::Table(statsd));
+
+        root.insert("relabel".into(), Value::Array(self.relabel.iter().map(|rule| {
+            let mut table = Table::new();
+            table.insert("source_labels".into(), strings(&rule.source_labels));
+            table.insert("separator".into(), string(&rule.separator));
+            table.insert("target_label".into(), string(&rule.target_label));
+            table.insert("regex".into(), string(&rule.regex));
+            table.insert("modulus".into(), integer(rule.modulus));
+            table.insert("replacement".into(), string(&rule.replacement));
+            table.insert("action".into(), string(rule.action.as_str()));
+            Value::Table(table)
+        }).collect()));
+
+        root.insert("scrape".into(), Value::Array(self.scrape.iter().map(|job| {
+            let mut table = Table::new();
+            table.insert("job".into(), string(&job.name));
+            table.insert("targets".into(), strings(&job.targets));
+            table.insert("file_sd".into(), Value::Array(job.file_sd.iter().map(|path| string(&path.to_string_lossy())).collect()));
+            table.insert("interval".into(), duration(job.interval));
+            table.insert("timeout".into(), duration(job.timeout));
+            table.insert("metrics_path".into(), string(&job.metrics_path));
+            table.insert("tenant".into(), string(&job.tenant));
+            Value::Table(table)
+        }).collect()));
+
+        let mut self_monitoring = Table::new();
+        self_monitoring.insert("interval".into(), optional_duration(self.self_monitoring));
+        root.insert("self_monitoring".into(), Value::Table(self_monitoring));
+
+        let mut log = Table::new();
+        log.insert("level".into(), string(self.log.level.as_str()));
+        log.insert("format".into(), string(self.log.format.as_str()));
+        root.insert("log".into(), Value::Table(log));
+
+        let mut admin = Table::new();
+        admin.insert("token_file".into(), string(&self.admin.token_file.as_ref().map_or(String::new(), |path| path.to_string_lossy().into_owned())));
+        root.insert("admin".into(), Value::Table(admin));
+        toml::write(&root)
+    }
+}
+
+fn invalid(key: &str, detail: &str) -> Error {
+    Error::Invalid(format!("Setting {} {}", key, detail))
+}
+
+/// An error's message without the kind of error in front.
+pub fn detail(error: Error) -> String {
+    match error {
+        Error::Invalid(detail) | Error::Decode(detail) => detail,
+        error => error.to_string()
+    }
+}
+
+/// Where `--set` puts a dotted key: tables along the way are created, but an array of tables
+/// can't be reached.
+fn table_for_override<'a>(table: &'a mut Table, path: &[String]) -> Result<&'a mut Table, Error> {
+    let mut table = table;
+    for (i, key) in path[..path.len() - 1].iter().enumerate() {
+        let entry = table.entry(key.clone()).or_insert_with(|| Value::Table(Table::new()));
+        table = match entry {
+            Value::Table(table) => table,
+            _ => return Err(Error::Invalid(format!("Can't override {}: {} is not a table", path.join("."), path[..=i].join("."))))
+        };
+    }
+    Ok(table)
+}
+
+fn nan_policy_name(policy: NanPolicy) -> &'static str {
+    match policy {
+        NanPolicy::Accept => "accept",
+        NanPolicy::RejectExceptStale => "reject_except_stale",
+        NanPolicy::Reject => "reject"
+    }
+}
+
+fn nonzero<T: PartialEq + Default>(value: T) -> Option<T> {
+    (value != T::default()).then_some(value)
+}
+
+fn string(text: &str) -> Value {
+    Value::String(text.to_string())
+}
+
+fn strings(values: &[String]) -> Value {
+    Value::Array(values.iter().map(|value| string(value)).collect())
+}
+
+fn integer(value: impl TryInto<i64>) -> Value {
+    Value::Integer(value.try_into().unwrap_or(i64::MAX))
+}
+
+fn duration(value: Duration) -> Value {
+    string(&format_duration(value))
+}
+
+fn optional_duration(value: Option<Duration>) -> Value {
+    value.map_or_else(|| string("off"), duration)
+}
+
+/// `1h30m`, `500ms`: the largest units first, as durations are written in PromQL.
+pub fn format_duration(duration: Duration) -> String {
+    let mut millis = duration.as_millis();
+    if millis == 0 {
+        return String::from("0s");
+    }
+    let mut out = String::new();
+    for (unit, scale) in [("d", 86_400_000), ("h", 3_600_000), ("m", 60_000), ("s", 1000), ("ms", 1)] {
+        if millis >= scale {
+            out.push_str(&format!("{}{}", millis / scale, unit));
+            millis %= scale;
+        }
+    }
+    out
+}
+
+/// Reads typed settings out of one table, remembering which keys were read so the rest can
+/// be reported as unknown.
+struct Reader<'a> {
+    path: String,
+    table: &'a Table,
+    read: HashSet<String>
+}
+
+impl<'a> Reader<'a> {
+    fn new(path: &str, table: &'a Table) -> Self {
+        Reader { path: path.to_string(), table, read: HashSet::new() }
+    }
+
+    fn key(&self, key: &str) -> String {
+        if self.path.is_empty() { key.to_string() } else { format!("{}.{}", self.path, key) }
+    }
+
+    fn get(&mut self, key: &str) -> Option<&'a Value> {
+        self.read.insert(key.to_string());
+        self.table.get(key)
+    }
+
+    fn wrong_type(&self, key: &str, expected: &str, value: &Value) -> Error {
+        invalid(&self.key(key), &format!("must be {}, not {}", expected, value.type_name()))
+    }
+
+    fn string(&mut self, key: &str, default: &str) -> Result<String, Error> {
+        match self.get(key) {
+            None => Ok(default.to_string()),
+            Some(Value::String(text)) => Ok(text.clone()),
+            Some(value) => Err(self.wrong_type(key, "a string", value))
+        }
+    }
+
+    fn required_string(&mut self, key: &str) -> Result<String, Error> {
+        match self.get(key) {
+            None => Err(invalid(&self.key(key), "is required")),
+            Some(_) => self.string(key, "")
+        }
+    }
+
+    fn boolean(&mut self, key: &str, default: bool) -> Result<bool, Error> {
+        match self.get(key) {
+            None => Ok(default),
+            Some(Value::Boolean(boolean)) => Ok(*boolean),
+            Some(value) => Err(self.wrong_type(key, "true or false", value))
+        }
+    }
+
+    /// A whole number that fits `T`.
+    fn integer<T: TryFrom<i64>>(&mut self, key: &str, default: T) -> Result<T, Error> {
+        match self.get(key) {
+            None => Ok(default),
+            Some(Value::Integer(integer)) => T::try_from(*integer)
+                .map_err(|_| invalid(&self.key(key), &format!("{} is out of range", integer))),
+            Some(value) => Err(self.wrong_type(key, "an integer", value))
+        }
+    }
+
+    fn strings(&mut self, key: &str, default: &[String]) -> Result<Vec<String>, Error> {
+        match self.get(key) {
+            None => Ok(default.to_vec()),
+            Some(Value::Array(values)) => values.iter().map(|value| match value {
+                Value::String(text) => Ok(text.clone()),
+                value => Err(self.wrong_type(key, "a list of strings", value))
+            }).collect(),
+            Some(value) => Err(self.wrong_type(key, "a list of strings", value))
+        }
+    }
+
+    fn floats(&mut self, key: &str, default: &[f64]) -> Result<Vec<f64>, Error> {
+        match self.get(key) {
+            None => Ok(default.to_vec()),
+            Some(Value::Array(values)) => values.iter().map(|value| match value {
+                Value::Float(float) => Ok(*float),
+                Value::Integer(integer) => Ok(*integer as f64),
+                value => Err(self.wrong_type(key, "a list of numbers", value))
+            }).collect(),
+            Some(value) => Err(self.wrong_type(key, "a list of numbers", value))
+        }
+    }
+
+    /// A duration like `30s` or `1h30m`.
+    fn duration(&mut self, key: &str, default: Duration) -> Result<Duration, Error> {
+        match self.get(key) {
+            None => Ok(default),
+            Some(Value::String(text)) => parse_duration(text).map(Duration::from_millis)
+                .map_err(|_| invalid(&self.key(key), &format!("has an invalid duration {:?}", text))),
+            Some(value) => Err(self.wrong_type(key, "a duration like \"30s\"", value))
+        }
+    }
+
+    /// A duration, or `off`.
+    fn optional_duration(&mut self, key: &str, default: Option<Duration>) -> Result<Option<Duration>, Error> {
+        match self.get(key) {
+            Some(Value::String(text)) if text == "off" => Ok(None),
+            Some(_) => self.duration(key, Duration::ZERO).map(Some),
+            None => Ok(default)
+        }
+    }
+
+    /// A sub-table, empty if it isn't there.
+    fn table(&mut self, key: &str) -> Result<Reader<'a>, Error> {
+        static EMPTY: Table = Table::new();
+        match self.get(key) {
+            None => Ok(Reader::new(&self.key(key), &EMPTY)),
+            Some(Value::Table(table)) => Ok(Reader::new(&self.key(key), table)),
+            Some(value) => Err(self.wrong_type(key, "a table", value))
+        }
+    }
+
+    fn listener(&mut self, key: &str, default: &Listener) -> Result<Listener, Error> {
+        let mut section = self.table(key)?;
+        let listener = Listener {
+            enabled: section.boolean("enabled", default.enabled)?,
+            listen: section.string("listen", &default.listen)?
+        };
+        section.finish()?;
+        Ok(listener)
+    }
+
+    /// An array of tables, each read by its index, or `None` if it isn't there.
+    fn tables(&mut self, key: &str) -> Result<Option<Vec<Reader<'a>>>, Error> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::Array(values)) => values.iter().enumerate().map(|(i, value)| match value {
+                Value::Table(table) => Ok(Reader::new(&format!("{}[{}]", self.key(key), i), table)),
+                value => Err(self.wrong_type(key, "a list of tables", value))
+            }).collect::<Result<_, _>>().map(Some),
+            Some(value) => Err(self.wrong_type(key, "a list of tables", value))
+        }
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        match self.table.keys().find(|key| !self.read.contains(*key)) {
+            Some(key) => Err(Error::Invalid(format!("Unknown setting {}", self.key(key)))),
+            None => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Args {
+        Args::parse(args.iter().map(|arg| arg.to_string())).unwrap()
+    }
+
+    fn load(input: &str, flags: &[&str]) -> Result<Config, Error> {
+        Config::with_overrides(toml::parse(input)?, &args(flags).overrides)
+    }
+
+    #[test]
+    fn loads_files_and_flags() {
+        let input = r#"
+            [storage]
+            data_dir = "/var/lib/metrichouse"
+            retention = "15d"
+
+            [ingest]
+            shards = 8
+
+            [validation]
+            max_age = "1h"
+            nan = "reject_except_stale"
+
+            [statsd]
+            enabled = false
+
+            [tenant_limits]
+            max_series = 1000
+            max_tenants = 20
+
+            [[tenant]]
+            id = "team-a"
+            ingest_rate = 500
+            retention = "1d"
+
+            [[relabel]]
+            source_labels = ["job"]
+            regex = "test"
+            action = "drop"
+
+            [[scrape]]
+            job = "app"
+            targets = ["localhost:9100"]
+            interval = "30s"
+        "#;
+        let config = load(input, &["--http-listen", "0.0.0.0:9090", "--set", "limits.max_series=5", "--disable", "influx", "--log-format", "json", "--admin-token-file", "admin.token"]).unwrap();
+        assert_eq!(config.storage.data_dir, PathBuf::from("/var/lib/metrichouse"));
+        assert_eq!(config.retention, Some(Duration::from_secs(15 * 86_400)));
+        assert_eq!(config.ingest, PipelineOptions { shards: 8, ..PipelineOptions::default() });
+        assert_eq!(config.validation.max_age, Some(Duration::from_secs(3600)));
+        assert_eq!(config.validation.nan_policy, NanPolicy::RejectExceptStale);
+        assert!(!config.statsd.enabled && !config.influx.enabled && config.http.enabled);
+        assert_eq!(config.http.listen, "0.0.0.0:9090");
+        assert_eq!(config.query_limits.max_series, 5);
+        assert_eq!(config.relabel[0].action, Action::Drop);
+        assert_eq!(config.scrape[0].name, "app");
+        assert_eq!(config.scrape[0].interval, Duration::from_secs(30));
+        assert_eq!(config.log, LogConfig { level: log::Level::Info, format: log::Format::Json });
+        assert_eq!(config.admin.token_file, Some(PathBuf::from("admin.token")));
+        let settings = config.tenant_settings();
+        assert_eq!(settings.limits_for("team-b"), TenantLimits { max_series: Some(1000), ingest_rate: None, retention: config.retention });
+        assert_eq!(settings.limits_for("team-a"), TenantLimits { max_series: Some(1000), ingest_rate: Some(500), retention: Some(Duration::from_secs(86_400)) });
+        assert_eq!(settings.max_tenants, Some(20));
+
+        // What --print-config shows reads back the same.
+        assert_eq!(Config::from_table(&toml::parse(&config.to_toml()).unwrap()).unwrap(), config);
+        assert_eq!(Config::from_table(&toml::parse(&Config::default().to_toml()).unwrap()).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn reports_bad_settings() {
+        let error = |input: &str, flags: &[&str]| load(input, flags).unwrap_err().to_string();
+        assert_eq!(error("[storage]\nwal_flush_interval = \"often\"", &[]), "Invalid request: Setting storage.wal_flush_interval must be an integer, not a string");
+        assert_eq!(error("[http]\nlisten = \"127.0.0.1:1228\"\nport = 1", &[]), "Invalid request: Unknown setting http.port");
+        assert_eq!(error("", &["--set", "storage.series_flush_threshold=0"]), "Invalid request: Setting storage.series_flush_threshold must be greater than zero");
+        assert_eq!(error("", &["--set", "ingest.queue_capacity=0"]), "Invalid request: Setting ingest.queue_capacity must be greater than zero");
+        assert_eq!(error("", &["--graphite-listen", "127.0.0.1:1228"]), "Invalid request: Setting graphite.listen 127.0.0.1:1228 is already used by another listener");
+        assert!(error("[limits]\nquery_timeout = \"soon\"", &[]).contains("invalid duration"));
+        assert!(error("[[relabel]]\naction = \"shuffle\"", &[]).contains("relabel[0].action"));
+        assert!(error("[[scrape]]\ntargets = []", &[]).contains("scrape[0].job is required"));
+        assert!(error("[validation]\nnan = \"maybe\"", &[]).contains("validation.nan"));
+        assert!(error("", &["--log-level", "loud"]).contains("log.level"));
+        assert!(error("[[tenant]]\nid = \"a/b\"", &[]).contains("tenant[0].id"));
+        assert!(error("[[tenant]]\nid = \"a\"\n[[tenant]]\nid = \"a\"", &[]).contains("\"a\" is defined twice"));
+        let token_file = AdminConfig { token_file: Some(std::env::temp_dir().join("metrichouse_no_such_token")) };
+        assert!(detail(token_file.token().unwrap_err()).starts_with("Setting admin.token_file can't be read"));
+
+        assert!(Args::parse([String::from("--bogus")]).is_err());
+        assert!(Args::parse([String::from("--config")]).is_err());
+        assert!(Args::parse([String::from("--disable"), String::from("smtp")]).is_err());
+        assert_eq!(format_duration(Duration::from_millis(5_400_500)), "1h30m500ms");
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use lib::{error, info, ingest::pipeline::Pipeline, log, telemetry, tenant::Tenants, time::now_ms, warn, Error};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::config::{detail, Args, Config};
+use crate::scrape::{self, Job};
+use crate::telemetry as self_monitoring;
+
+/// How often samples past the retention period are dropped.
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+const LAST_RELOAD_SUCCESSFUL: &str = "metrichouse_config_last_reload_successful";
+const LAST_RELOAD_SUCCESSFUL_HELP: &str = "Whether the last config reload succeeded.";
+const LAST_RELOAD_SUCCESS_TIME: &str = "metrichouse_config_last_reload_success_timestamp_seconds";
+const RELOADS: &str = "metrichouse_config_reloads_total";
+const RELOADS_HELP: &str = "Config reloads, by result.";
+
+/// Where the outcome of a reload asked for over HTTP is sent.
+pub type Reply = oneshot::Sender<Result<(), String>>;
+
+/// Owns the parts of the server that follow the config file while it runs: scrape jobs,
+/// retention, self-monitoring, logging, relabel rules, and the limits and validation each
+/// tenant's database applies. Listeners and storage are only read at startup.
+pub struct Reloader {
+    args: Args,
+    config: Config,
+    tenants: Arc<Tenants>,
+    pipeline: Arc<Pipeline>,
+    scrapes: HashMap<String, (Job, JoinHandle<()>)>,
+    retention: Option<JoinHandle<()>>,
+    self_monitoring: Option<JoinHandle<()>>
+}
+
+impl Reloader {
+    /// Starts the scrape jobs, retention and self-monitoring of `config`, which the tenants
+    /// and the pipeline's relabeler were already set up with.
+    pub fn start(args: Args, config: Config, tenants: Arc<Tenants>, pipeline: Arc<Pipeline>) -> Self {
+        let mut reloader = Reloader {
+            args,
+            config: Config::default(),
+            tenants,
+            pipeline,
+            scrapes: HashMap::new(),
+            retention: None,
+            self_monitoring: None
+        };
+        reloader.start_tasks(&config);
+        reloader.config = config;
+        reloader.record(true);
+        for result in ["success", "failure"] {
+            telemetry::global().counter(RELOADS, RELOADS_HELP, &[("result", result)]);
+        }
+        reloader
+    }
+
+    /// Reloads on SIGHUP and on every request sent to `requests`, one reload at a time.
+    pub async fn run(mut self, mut requests: mpsc::Receiver<Reply>) {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => Some(hangups),
+            Err(e) => {
+                warn!(error = e; "Failed to listen for SIGHUP, config is only reloaded over HTTP");
+                None
+            }
+        };
+        loop {
+            tokio::select! {
+                Some(()) = async { hangups.as_mut()?.recv().await } => {
+                    let _ = self.reload();
+                },
+                request = requests.recv() => match request {
+                    Some(reply) => {
+                        let _ = reply.send(self.reload().map_err(detail));
+                    },
+                    None => return
+                }
+            }
+        }
+    }
+
+    /// Reads the config again, the way it was read at startup. Either every reloadable
+    /// setting changes or, if the new config is invalid, none does.
+    pub fn reload(&mut self) -> Result<(), Error> {
+        let result = Config::load(&self.args).and_then(|config| self.apply(config));
+        match &result {
+            Ok(()) => info!("Reloaded config"),
+            Err(e) => error!(error = e; "Failed to reload config, keeping the old one")
+        }
+        self.record(result.is_ok());
+        telemetry::global().counter(RELOADS, RELOADS_HELP, &[("result", if result.is_ok() { "success" } else { "failure" })]).inc();
+        result
+    }
+
+    fn apply(&mut self, mut config: Config) -> Result<(), Error> {
+        let fixed: Vec<&str> = [
+            ("storage", config.storage != self.config.storage),
+            ("ingest", config.ingest != self.config.ingest),
+            ("binary", config.binary != self.config.binary),
+            ("http", config.http != self.config.http),
+            ("influx", config.influx != self.config.influx),
+            ("graphite", config.graphite != self.config.graphite),
+            ("statsd", config.statsd != self.config.statsd),
+            ("admin", config.admin != self.config.admin)
+        ].into_iter().filter(|(_, changed)| *changed).map(|(section, _)| section).collect();
+        if !fixed.is_empty() {
+            warn!(sections = fixed.join(","); "Changes to these sections take a restart, the running settings are kept");
+        }
+        config.storage = self.config.storage.clone();
+        config.ingest = self.config.ingest;
+        config.binary = self.config.binary.clone();
+        config.http = self.config.http.clone();
+        config.influx = self.config.influx.clone();
+        config.graphite = self.config.graphite.clone();
+        config.statsd = self.config.statsd.clone();
+        config.admin = self.config.admin.clone();
+
+        // The rules were validated with the config, so only a poisoned lock fails from here
+        // on. Each tenant changes under its own lock, the rest of them a moment later, and
+        // they're put back if one of them or the relabel rules fail.
+        let applied = self.tenants.configure(config.tenant_settings())
+            .and_then(|_| self.pipeline.relabeler().replace(config.relabel.clone()));
+        if let Err(e) = applied {
+            if let Err(restore) = self.tenants.configure(self.config.tenant_settings()) {
+                error!(error = restore; "Failed to put the tenants back to the running config");
+            }
+            return Err(e);
+        }
+        log::configure(config.log.level, config.log.format);
+        self.start_tasks(&config);
+        self.config = config;
+        Ok(())
+    }
+
+    /// Restarts the scrape jobs that changed and stops the ones that are gone. Jobs that
+    /// didn't change keep running, so their targets aren't marked stale.
+    fn start_tasks(&mut self, config: &Config) {
+        self.scrapes.retain(|name, (job, task)| {
+            let keep = config.scrape.contains(job);
+            if !keep {
+                info!(job = name; "Stopped scrape job");
+                task.abort();
+            }
+            keep
+        });
+        for job in &config.scrape {
+            if !self.scrapes.contains_key(&job.name) {
+                info!(job = job.name, interval = super::format_duration(job.interval); "Started scrape job");
+                let task = tokio::spawn(scrape::run(job.clone(), self.tenants.clone(), self.pipeline.clone()));
+                self.scrapes.insert(job.name.clone(), (job.clone(), task));
+            }
+        }
+
+        // Tenants may have a retention of their own, so it runs whether or not there's one.
+        let starting = self.retention.is_none();
+        if starting {
+            self.retention = Some(tokio::spawn(enforce_retention(self.tenants.clone())));
+        }
+        if starting || config.retention != self.config.retention {
+            match config.retention {
+                Some(retention) => info!(retention = super::format_duration(retention); "Enforcing retention"),
+                None if self.config.retention.is_some() => info!("Retention is off, samples are kept until they're deleted"),
+                None => {}
+            }
+        }
+
+        if config.self_monitoring != self.config.self_monitoring || self.self_monitoring.is_none() {
+            if let Some(task) = self.self_monitoring.take() {
+                task.abort();
+            }
+            if let Some(interval) = config.self_monitoring {
+                info!(interval = super::format_duration(interval); "Storing self-monitoring metrics");
+                self.self_monitoring = Some(tokio::spawn(self_monitoring::run(self.tenants.clone(), self.pipeline.clone(), interval)));
+            }
+        }
+    }
+
+    /// Sets how the last load went, as Prometheus reports it about itself.
+    fn record(&self, success: bool) {
+        let registry = telemetry::global();
+        registry.gauge(LAST_RELOAD_SUCCESSFUL, LAST_RELOAD_SUCCESSFUL_HELP, &[]).set(if success { 1.0 } else { 0.0 });
+        if success {
+            registry.gauge(LAST_RELOAD_SUCCESS_TIME, "When the config was last loaded successfully, in seconds since the epoch.", &[])
+                .set(now_ms() as f64 / 1000.0);
+        }
+    }
+}
+
+/// Runs `apply_retention` every `RETENTION_INTERVAL`. It holds each tenant's write lock
+/// while it rewrites the WAL, so it runs on the blocking pool.
+async fn enforce_retention(tenants: Arc<Tenants>) {
+    let mut ticker = tokio::time::interval(RETENTION_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let tenants = tenants.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || apply_retention(&tenants)).await {
+            error!(error = e; "Failed to apply retention");
+        }
+    }
+}
+
+/// Drops what each tenant has kept past its retention, one tenant at a time, and compacts
+/// the WAL of any that lost samples. Otherwise they'd stay there, to be replayed and copied
+/// into the new WAL on every restart.
+fn apply_retention(tenants: &Tenants) {
+    for tenant in tenants.all() {
+        let Some(retention) = tenant.limits().retention else {
+            continue;
+        };
+        let cutoff = now_ms().saturating_sub(retention.as_millis() as u64);
+        let Ok(mut db) = tenant.db().write() else {
+            error!(tenant = tenant.id(); "Failed to apply retention: database lock is poisoned");
+            continue;
+        };
+        let removed = db.delete_before(cutoff);
+        if removed == 0 {
+            continue;
+        }
+        info!(tenant = tenant.id(), removed = removed; "Dropped samples past the retention period");
+        if let Err(e) = db.compact() {
+            error!(tenant = tenant.id(), error = e; "Failed to compact the WAL after applying retention");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib::{db::MetricsDb, ingest::relabel::Relabeler, models::Metric, storage::StorageOptions, tenant::{TenantLimits, TenantSettings}, testing::TempDir};
+
+    fn last_reload_successful() -> f64 {
+        telemetry::global().gauge(LAST_RELOAD_SUCCESSFUL, LAST_RELOAD_SUCCESSFUL_HELP, &[]).get()
+    }
+
+    fn reloads(result: &str) -> u64 {
+        telemetry::global().counter(RELOADS, RELOADS_HELP, &[("result", result)]).get()
+    }
+
+    #[tokio::test]
+    async fn reloads_or_keeps_the_old_config() {
+        let dir = TempDir::new("reload_reloads");
+        let file = dir.join("metrichouse.toml");
+        std::fs::write(&file, "scrape = []\n[self_monitoring]\ninterval = \"off\"\n[limits]\nmax_series = 10\n").unwrap();
+
+        let args = Args { config_file: Some(file.clone()), ..Args::default() };
+        let config = Config::load(&args).unwrap();
+        let options = StorageOptions { data_dir: dir.join("data"), ..StorageOptions::default() };
+        let tenants = Arc::new(Tenants::new(options.clone(), MetricsDb::open_with(&options).unwrap(), config.tenant_settings()));
+        let tenant = tenants.get("team-a").unwrap();
+        let pipeline = Arc::new(Pipeline::start(Arc::new(Relabeler::default()), config.ingest).unwrap());
+        // Other tests share the registry, so only what this one adds is counted.
+        let (successes, failures) = (reloads("success"), reloads("failure"));
+        let mut reloader = Reloader::start(args, config, tenants.clone(), pipeline.clone());
+        assert_eq!((last_reload_successful(), reloads("success") - successes, reloads("failure") - failures), (1.0, 0, 0));
+
+        std::fs::write(&file, "\
+scrape = []
+[self_monitoring]
+interval = \"off\"
+[limits]
+max_series = 20
+[http]
+listen = \"127.0.0.1:9999\"
+[tenant_limits]
+ingest_rate = 100
+[[tenant]]
+id = \"team-a\"
+max_series = 3
+[[relabel]]
+action = \"labeldrop\"
+regex = \"pod\"
+").unwrap();
+        reloader.reload().unwrap();
+        assert_eq!(tenant.db().read().unwrap().query_limits().max_series, 20);
+        assert_eq!((tenant.limits().max_series, tenant.limits().ingest_rate), (Some(3), Some(100)));
+        assert_eq!(tenants.default_tenant().limits().ingest_rate, Some(100));
+        assert_eq!(pipeline.relabeler().rules().len(), 1);
+        // Listeners only change on restart, which doesn't fail the reload.
+        assert_eq!(reloader.config.http.listen, "127.0.0.1:1228");
+
+        std::fs::write(&file, "scrape = []\n[limits]\nmax_series = 0\n[[relabel]]\nregex = \"(\"\ntarget_label = \"x\"\n").unwrap();
+        assert!(reloader.reload().is_err());
+        assert_eq!(tenant.db().read().unwrap().query_limits().max_series, 20);
+        assert_eq!(pipeline.relabeler().rules().len(), 1);
+        assert_eq!((last_reload_successful(), reloads("success") - successes, reloads("failure") - failures), (0.0, 1, 1));
+    }
+
+    #[test]
+    fn retention_compacts_the_wal() {
+        let dir = TempDir::new("reload_retention");
+        let options = StorageOptions { data_dir: dir.to_path_buf(), ..StorageOptions::default() };
+        let limits = TenantLimits { retention: Some(Duration::from_secs(3600)), ..TenantLimits::default() };
+        let tenants = Tenants::new(options.clone(), MetricsDb::open_with(&options).unwrap(), TenantSettings { limits, ..TenantSettings::default() });
+        let metric = |timestamp| Metric { timestamp, value: 1.0, name: String::from("up"), labels: Vec::new() };
+        tenants.default_tenant().db().write().unwrap().ingest_batch(vec![metric(1000), metric(now_ms())]);
+
+        apply_retention(&tenants);
+        assert_eq!(tenants.default_tenant().db().read().unwrap().query("up").unwrap().len(), 1);
+        drop(tenants);
+        // The dropped sample isn't replayed.
+        assert_eq!(MetricsDb::open_with(&options).unwrap().query("up").unwrap().len(), 1);
+    }
+}
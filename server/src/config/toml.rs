@@ -0,0 +1,546 @@
+use std::collections::BTreeMap;
+
+use lib::Error;
+
+/// The part of TOML a config file needs: tables, arrays of tables, inline tables, arrays,
+/// strings, integers, floats and booleans. Dates and multi-line strings aren't supported.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table)
+}
+
+pub type Table = BTreeMap<String, Value>;
+
+/// Arrays and inline tables nested in one another, and separately the parts of a dotted key.
+/// Values are parsed, written and dropped recursively, so deeper ones could overflow the stack.
+const MAX_DEPTH: usize = 64;
+
+impl Value {
+    /// How the type is named in error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Float(_) => "a float",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) => "an array",
+            Value::Table(_) => "a table"
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<Table, Error> {
+    let mut parser = Parser { chars: input.chars().collect(), pos: 0, line: 1, depth: 0 };
+    parser.document().map_err(|detail| Error::Decode(format!("Line {}: {}", parser.line, detail)))
+}
+
+/// A single value, as given on the command line. `None` unless all of `input` is the value.
+pub fn parse_value(input: &str) -> Option<Value> {
+    let mut parser = Parser { chars: input.chars().collect(), pos: 0, line: 1, depth: 0 };
+    let value = parser.value().ok()?;
+    parser.skip_whitespace();
+    (parser.pos == parser.chars.len()).then_some(value)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    /// Arrays and inline tables the parser is inside of.
+    depth: usize
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let matched = self.peek() == Some(c);
+        if matched {
+            self.next();
+        }
+        matched
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) { Ok(()) } else { Err(format!("Expected {:?}", c)) }
+    }
+
+    /// Spaces and tabs, not newlines.
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.next();
+        }
+    }
+
+    /// Whitespace, newlines and comments.
+    fn skip_blank(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ' | '\t' | '\r' | '\n') => { self.next(); },
+                Some('#') => self.skip_comment(),
+                _ => return
+            }
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        while self.peek().is_some_and(|c| c != '\n') {
+            self.next();
+        }
+    }
+
+    /// After a header or a key/value pair, only a comment may follow on the same line.
+    fn end_of_line(&mut self) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.peek() == Some('#') {
+            self.skip_comment();
+        }
+        self.eat('\r');
+        match self.peek() {
+            None => Ok(()),
+            Some('\n') => {
+                self.next();
+                Ok(())
+            },
+            Some(c) => Err(format!("Unexpected {:?} after the value", c))
+        }
+    }
+
+    fn document(&mut self) -> Result<Table, String> {
+        let mut root = Table::new();
+        let mut current: Vec<String> = Vec::new();
+        loop {
+            self.skip_blank();
+            match self.peek() {
+                None => return Ok(root),
+                Some('[') => {
+                    self.next();
+                    let array = self.eat('[');
+                    self.skip_whitespace();
+                    let path = self.key()?;
+                    self.skip_whitespace();
+                    self.expect(']')?;
+                    if array {
+                        self.expect(']')?;
+                    }
+                    self.end_of_line()?;
+                    open_table(&mut root, &path, array)?;
+                    current = path;
+                },
+                Some(_) => {
+                    let path = self.key()?;
+                    self.skip_whitespace();
+                    self.expect('=')?;
+                    self.skip_whitespace();
+                    let value = self.value()?;
+                    self.end_of_line()?;
+                    let table = table_at(&mut root, &current)?;
+                    insert(table, &path, value)?;
+                }
+            }
+        }
+    }
+
+    /// A dotted key, each part bare or quoted.
+    fn key(&mut self) -> Result<Vec<String>, String> {
+        let mut parts = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let part = match self.peek() {
+                Some('"') => self.basic_string()?,
+                Some('\'') => self.literal_string()?,
+                _ => {
+                    let start = self.pos;
+                    while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                        self.next();
+                    }
+                    if start == self.pos {
+                        return Err(String::from("Expected a key"));
+                    }
+                    self.chars[start..self.pos].iter().collect()
+                }
+            };
+            parts.push(part);
+            if parts.len() > MAX_DEPTH {
+                return Err(format!("Keys have at most {} parts", MAX_DEPTH));
+            }
+            self.skip_whitespace();
+            if !self.eat('.') {
+                return Ok(parts);
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some('"') => self.basic_string().map(Value::String),
+            Some('\'') => self.literal_string().map(Value::String),
+            Some('[') => self.nested(Self::array),
+            Some('{') => self.nested(Self::inline_table),
+            Some(_) => self.scalar(),
+            None => Err(String::from("Expected a value"))
+        }
+    }
+
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Value, String>) -> Result<Value, String> {
+        if self.depth == MAX_DEPTH {
+            return Err(format!("Arrays and tables are nested more than {} deep", MAX_DEPTH));
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn basic_string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        if self.peek() == Some('"') && self.chars.get(self.pos + 1) == Some(&'"') {
+            return Err(String::from("Multi-line strings are not supported"));
+        }
+        let mut out = String::new();
+        loop {
+            match self.next() {
+                None | Some('\n') => return Err(String::from("Unterminated string")),
+                Some('"') => return Ok(out),
+                Some('\\') => out.push(match self.next() {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some(c @ ('u' | 'U')) => {
+                        let len = if c == 'u' { 4 } else { 8 };
+                        let hex: String = (0..len).filter_map(|_| self.next()).collect();
+                        u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32)
+                            .ok_or_else(|| format!("Invalid escape \\{}{}", c, hex))?
+                    },
+                    c => return Err(format!("Invalid escape \\{}", c.unwrap_or(' ')))
+                }),
+                Some(c) => out.push(c)
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String, String> {
+        self.expect('\'')?;
+        let mut out = String::new();
+        loop {
+            match self.next() {
+                None | Some('\n') => return Err(String::from("Unterminated string")),
+                Some('\'') => return Ok(out),
+                Some(c) => out.push(c)
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.expect('[')?;
+        let mut values = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.eat(']') {
+                return Ok(Value::Array(values));
+            }
+            values.push(self.value()?);
+            self.skip_blank();
+            if !self.eat(',') {
+                self.skip_blank();
+                self.expect(']')?;
+                return Ok(Value::Array(values));
+            }
+        }
+    }
+
+    /// `{ key = value, ... }`, on one line.
+    fn inline_table(&mut self) -> Result<Value, String> {
+        self.expect('{')?;
+        let mut table = Table::new();
+        self.skip_whitespace();
+        if self.eat('}') {
+            return Ok(Value::Table(table));
+        }
+        loop {
+            let path = self.key()?;
+            self.skip_whitespace();
+            self.expect('=')?;
+            self.skip_whitespace();
+            let value = self.value()?;
+            insert(&mut table, &path, value)?;
+            self.skip_whitespace();
+            if self.eat('}') {
+                return Ok(Value::Table(table));
+            }
+            self.expect(',')?;
+            self.skip_whitespace();
+        }
+    }
+
+    /// Booleans and numbers.
+    fn scalar(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '_' | '.')) {
+            self.next();
+        }
+        let token: String = self.chars[start..self.pos].iter().collect();
+        match token.as_str() {
+            "true" => return Ok(Value::Boolean(true)),
+            "false" => return Ok(Value::Boolean(false)),
+            "inf" | "+inf" => return Ok(Value::Float(f64::INFINITY)),
+            "-inf" => return Ok(Value::Float(f64::NEG_INFINITY)),
+            "nan" | "+nan" | "-nan" => return Ok(Value::Float(f64::NAN)),
+            _ => {}
+        }
+        let digits = token.replace('_', "");
+        if let Ok(integer) = digits.parse::<i64>() {
+            return Ok(Value::Integer(integer));
+        }
+        if digits.contains(['.', 'e', 'E']) && let Ok(float) = digits.parse::<f64>() {
+            return Ok(Value::Float(float));
+        }
+        Err(format!("Invalid value {:?}", token))
+    }
+}
+
+/// Makes `path` the table later keys go into, creating it, or with `array` a new element of
+/// the array of tables there.
+fn open_table(root: &mut Table, path: &[String], array: bool) -> Result<(), String> {
+    let (last, parents) = path.split_last().ok_or("Empty table name")?;
+    let parent = table_at(root, parents)?;
+    let entry = parent.entry(last.clone()).or_insert_with(|| if array { Value::Array(Vec::new()) } else { Value::Table(Table::new()) });
+    match (entry, array) {
+        (Value::Array(tables), true) if tables.iter().all(|value| matches!(value, Value::Table(_))) => {
+            tables.push(Value::Table(Table::new()));
+            Ok(())
+        },
+        (Value::Table(_), false) => Ok(()),
+        _ => Err(format!("{} is already defined as something else", path.join(".")))
+    }
+}
+
+/// The table at `path`, creating missing ones. An array of tables stands for its last element.
+fn table_at<'a>(root: &'a mut Table, path: &[String]) -> Result<&'a mut Table, String> {
+    let mut table = root;
+    for (i, key) in path.iter().enumerate() {
+        let entry = table.entry(key.clone()).or_insert_with(|| Value::Table(Table::new()));
+        table = match entry {
+            Value::Table(table) => table,
+            Value::Array(values) => match values.last_mut() {
+                Some(Value::Table(table)) => table,
+                _ => return Err(format!("{} is not a table", path[..=i].join(".")))
+            },
+            _ => return Err(format!("{} is not a table", path[..=i].join(".")))
+        };
+    }
+    Ok(table)
+}
+
+/// Sets the dotted key `path` in `table`. A key may only be set once.
+pub fn insert(table: &mut Table, path: &[String], value: Value) -> Result<(), String> {
+    let (last, parents) = path.split_last().ok_or("Empty key")?;
+    let table = table_at(table, parents)?;
+    if table.contains_key(last) {
+        return Err(format!("Duplicate key {}", path.join(".")));
+    }
+    table.insert(last.clone(), value);
+    Ok(())
+}
+
+/// Writes `table` as a document: its plain keys first, then a `[section]` per sub-table and
+/// a `[[section]]` per element of an array of tables.
+pub fn write(table: &Table) -> String {
+    let mut out = String::new();
+    write_section(&mut out, &[], table);
+    out
+}
+
+fn write_section(out: &mut String, path: &[String], table: &Table) {
+    for (key, value) in table {
+        if !is_section(value) {
+            write_key(out, key);
+            out.push_str(" = ");
+            write_value(out, value);
+            out.push('\n');
+        }
+    }
+    for (key, value) in table {
+        let mut child = path.to_vec();
+        child.push(key.clone());
+        match value {
+            Value::Table(sub) => {
+                out.push_str(&format!("\n[{}]\n", header(&child)));
+                write_section(out, &child, sub);
+            },
+            Value::Array(elements) if is_section(value) => for element in elements {
+                if let Value::Table(sub) = element {
+                    out.push_str(&format!("\n[[{}]]\n", header(&child)));
+                    write_section(out, &child, sub);
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Tables, and arrays holding nothing but tables, are written as sections.
+fn is_section(value: &Value) -> bool {
+    match value {
+        Value::Table(_) => true,
+        Value::Array(values) => !values.is_empty() && values.iter().all(|value| matches!(value, Value::Table(_))),
+        _ => false
+    }
+}
+
+fn header(path: &[String]) -> String {
+    let mut out = String::new();
+    for (i, key) in path.iter().enumerate() {
+        if i > 0 {
+            out.push('.');
+        }
+        write_key(&mut out, key);
+    }
+    out
+}
+
+fn write_key(out: &mut String, key: &str) {
+    if !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        out.push_str(key);
+    } else {
+        write_string(out, key);
+    }
+}
+
+pub fn write_value(out: &mut String, value: &Value) {
+    match value {
+        Value::String(text) => write_string(out, text),
+        Value::Integer(integer) => out.push_str(&integer.to_string()),
+        Value::Float(float) if float.is_nan() => out.push_str("nan"),
+        Value::Float(float) if float.is_infinite() => out.push_str(if *float > 0.0 { "inf" } else { "-inf" }),
+        Value::Float(float) => out.push_str(&format!("{:?}", float)),
+        Value::Boolean(boolean) => out.push_str(&boolean.to_string()),
+        Value::Array(values) => {
+            out.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_value(out, value);
+            }
+            out.push(']');
+        },
+        Value::Table(table) => {
+            out.push('{');
+            for (i, (key, value)) in table.iter().enumerate() {
+                out.push_str(if i > 0 { ", " } else { " " });
+                write_key(out, key);
+                out.push_str(" = ");
+                write_value(out, value);
+            }
+            out.push_str(if table.is_empty() { "}" } else { " }" });
+        }
+    }
+}
+
+fn write_string(out: &mut String, text: &str) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(text: &str) -> Value {
+        Value::String(text.to_string())
+    }
+
+    #[test]
+    fn parses_and_writes_documents() {
+        let input = r#"
+            # Comments go anywhere.
+            title = "metric\"house" # after values too
+            path = 'C:\data'
+            ports = [1, 2_000,
+                     3, ]   # trailing comma
+            ratio = 0.5
+            enabled = true
+
+            [http]
+            listen = "127.0.0.1:1228"
+            limits.timeout = "30s"
+
+            [[job]]
+            name = "a"
+            labels = { env = "prod", "tier name" = 'web' }
+
+            [[job]]
+            name = "b"
+        "#;
+        let table = parse(input).unwrap();
+        assert_eq!(table["title"], string("metric\"house"));
+        assert_eq!(table["path"], string("C:\\data"));
+        assert_eq!(table["ports"], Value::Array(vec![Value::Integer(1), Value::Integer(2000), Value::Integer(3)]));
+        assert_eq!(table["ratio"], Value::Float(0.5));
+        let Value::Table(http) = &table["http"] else { panic!() };
+        let Value::Table(limits) = &http["limits"] else { panic!() };
+        assert_eq!(limits["timeout"], string("30s"));
+        let Value::Array(jobs) = &table["job"] else { panic!() };
+        assert_eq!(jobs.len(), 2);
+        let Value::Table(job) = &jobs[0] else { panic!() };
+        let Value::Table(labels) = &job["labels"] else { panic!() };
+        assert_eq!(labels["tier name"], string("web"));
+
+        assert_eq!(parse(&write(&table)).unwrap(), table);
+    }
+
+    #[test]
+    fn reports_errors_by_line() {
+        assert_eq!(parse("a = 1\nb = \n").unwrap_err().to_string(), "Decode error: Line 2: Invalid value \"\"");
+        assert!(parse("a = 1\na = 2").is_err());
+        assert!(parse("a = 1 b = 2").is_err());
+        assert!(parse("a = \"open").is_err());
+        assert!(parse("a = 1\n[a]").is_err());
+        assert!(parse("d = 1979-05-27").is_err());
+
+        let nested = |depth: usize| format!("a = {}1{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(parse(&nested(100_000)).unwrap_err().to_string(), format!("Decode error: Line 1: Arrays and tables are nested more than {} deep", MAX_DEPTH));
+        let tables = |depth: usize| format!("a = {}1{}", "{b = ".repeat(depth), "}".repeat(depth));
+        assert!(parse(&tables(MAX_DEPTH)).is_ok());
+        assert!(parse(&tables(MAX_DEPTH + 1)).is_err());
+        assert!(parse(&format!("{} = 1", vec!["a"; MAX_DEPTH + 1].join("."))).is_err());
+        assert!(parse(&format!("[{}]", vec!["a"; MAX_DEPTH + 1].join("."))).is_err());
+
+        assert_eq!(parse_value("false"), Some(Value::Boolean(false)));
+        assert_eq!(parse_value("[\"x\"]"), Some(Value::Array(vec![string("x")])));
+        assert_eq!(parse_value("127.0.0.1:80"), None);
+    }
+}
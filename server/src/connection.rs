@@ -0,0 +1,267 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+use lib::{db::MetricsDb, debug, info, ingest::pipeline::Pipeline, models::{batch::DecodedBatch, BatchResult, Metric, WriteBatch}, protocol::{read::{chunk_matrix, MAX_CHUNK_BYTES}, Frame, FrameHeader, Hello, Opcode, ProtocolError, ReadRequest, Response, HEADER_LEN, PROTOCOL_VERSION}, query::{CancellationToken, Matrix, QueryContext}, tenant::{Tenant, Tenants, DEFAULT_TENANT}, traits::serializable::BinarySerializable, warn, Error};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{tcp::OwnedReadHalf, TcpStream};
+use tokio::sync::oneshot;
+use tokio::task::{JoinError, JoinHandle};
+
+use crate::telemetry::time_query;
+
+const SERVER_NAME: &str = "metrichouse";
+
+pub enum ReadError {
+    Io(std::io::Error),
+    /// The header didn't decode. `skip` is the payload length it claimed, which is only worth
+    /// skipping if the error isn't fatal.
+    Protocol { error: ProtocolError, request_id: u32, skip: u32 }
+}
+
+/// Reads one frame, waiting for as many reads as it takes. `Ok(None)` means the peer closed
+/// the connection between frames; closing it partway through a header is a protocol error.
+pub async fn read_frame(reader: &mut (impl AsyncRead + Unpin)) -> Result<Option<Frame>, ReadError> {
+    let mut header_bytes = [0u8; HEADER_LEN];
+    let mut filled = 0;
+    while filled < HEADER_LEN {
+        match reader.read(&mut header_bytes[filled..]).await.map_err(ReadError::Io)? {
+            0 => break,
+            read => filled += read
+        }
+    }
+
+    let header = match FrameHeader::parse(&header_bytes[..filled]) {
+        Ok(Some(header)) => header,
+        Ok(None) => return Ok(None),
+        Err(error) => {
+            // A truncated header is fatal, so there's nothing to skip and no id to answer.
+            let (request_id, skip) = if filled == HEADER_LEN { FrameHeader::peek(&header_bytes) } else { (0, 0) };
+            return Err(ReadError::Protocol { error, request_id, skip });
+        }
+    };
+    let mut payload = vec![0u8; header.length as usize];
+    reader.read_exact(&mut payload).await.map_err(ReadError::Io)?;
+
+    Ok(Some(Frame { header, payload }))
+}
+
+pub async fn write_frame(writer: &mut (impl AsyncWrite + Unpin), frame: &Frame) -> std::io::Result<()> {
+    writer.write_all(&frame.encode()).await?;
+    writer.flush().await
+}
+
+async fn respond(writer: &mut (impl AsyncWrite + Unpin), request_id: u32, response: Response) -> std::io::Result<()> {
+    write_frame(writer, &Frame::new(Opcode::Response, request_id, response.serialize())).await
+}
+
+/// Resolves once the client has closed its end of the connection. Bytes it has sent ahead
+/// of the response leave that unknown, so it never resolves while there are any.
+async fn closed(reader: &mut BufReader<OwnedReadHalf>) {
+    let mut byte = [0u8; 1];
+    if !reader.buffer().is_empty() || matches!(reader.get_mut().peek(&mut byte).await, Ok(1..)) {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Waits for `task`, cancelling `token` if the client closes the connection first. The task
+/// still runs to the end, which a cancelled query reaches at its next check.
+pub async fn cancel_on_close<T>(task: JoinHandle<T>, reader: &mut BufReader<OwnedReadHalf>, token: &CancellationToken) -> Result<T, JoinError> {
+    tokio::pin!(task);
+    tokio::select! {
+        result = &mut task => return result,
+        _ = closed(reader) => token.cancel()
+    }
+    task.await
+}
+
+async fn send_error(writer: &mut (impl AsyncWrite + Unpin), request_id: u32, error: &ProtocolError) -> std::io::Result<()> {
+    write_frame(writer, &Frame::new(Opcode::Error, request_id, error.serialize())).await
+}
+
+/// Serves one client connection: a handshake, which names the tenant, then any number of
+/// requests on that tenant's data until the client says goodbye or goes away. Failed requests get an error frame; the connection is only
+/// closed when the error is fatal. The returned error is always an I/O error. Failed requests
+/// are logged at debug level, and fatal errors at warn.
+pub async fn handle_client(stream: TcpStream, peer: SocketAddr, tenants: &Arc<Tenants>, pipeline: &Pipeline) -> std::io::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    // Only the id is kept, since a tenant that has never written only comes to be on its first write.
+    let mut tenant: Option<String> = None;
+
+    loop {
+        let frame = match read_frame(&mut reader).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Ok(()),
+            Err(ReadError::Io(e)) => return Err(e),
+            Err(ReadError::Protocol { error, request_id, skip }) => {
+                log_error(peer, request_id, None, &error);
+                send_error(&mut writer, request_id, &error).await?;
+                if error.is_fatal() {
+                    return Ok(());
+                }
+                tokio::io::copy(&mut (&mut reader).take(skip as u64), &mut tokio::io::sink()).await?;
+                continue;
+            }
+        };
+
+        let request_id = frame.header.request_id;
+        let result = if frame.header.version != PROTOCOL_VERSION {
+            Err(ProtocolError::UnsupportedVersion(frame.header.version))
+        } else if let Some(tenant) = &tenant {
+            match frame.header.opcode {
+                Opcode::Read => match read(frame.payload, tenant, tenants, &mut reader).await {
+                    None => return Ok(()),
+                    Some(Ok(matrix)) => {
+                        let chunks = chunk_matrix(&matrix, MAX_CHUNK_BYTES);
+                        for chunk in &chunks {
+                            write_frame(&mut writer, &Frame::new(Opcode::ReadChunk, request_id, chunk.serialize())).await?;
+                        }
+                        Ok(Response::ok((chunks.len() as u32).to_le_bytes().to_vec()))
+                    },
+                    Some(Err(error)) => Err(error)
+                },
+                Opcode::Write => handle_write(&frame.payload, tenant, tenants, pipeline).await,
+                Opcode::WriteBatch => handle_write_batch(&frame.payload, tenant, tenants, pipeline).await,
+                Opcode::Goodbye => return respond(&mut writer, request_id, Response::ok(Vec::new())).await,
+                opcode => Err(ProtocolError::UnexpectedFrame(format!("{:?} is not a request", opcode)))
+            }
+        } else {
+            handshake(&frame, peer, tenants).map(|(ack, greeted)| {
+                tenant = Some(greeted);
+                ack
+            })
+        };
+
+        match result {
+            Ok(response) => respond(&mut writer, request_id, response).await?,
+            Err(error) => {
+                log_error(peer, request_id, Some(frame.header.opcode), &error);
+                send_error(&mut writer, request_id, &error).await?;
+                if error.is_fatal() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+fn log_error(peer: SocketAddr, request_id: u32, opcode: Option<Opcode>, error: &ProtocolError) {
+    let opcode = opcode.map(|opcode| format!("{:?}", opcode)).unwrap_or_default();
+    if error.is_fatal() {
+        warn!(protocol = "binary", peer = peer, request_id = request_id, opcode = opcode, error = error; "Closing connection after a fatal error");
+    } else {
+        debug!(protocol = "binary", peer = peer, request_id = request_id, opcode = opcode, error = error; "Request failed");
+    }
+}
+
+/// The ack names the tenant the connection ended up with.
+fn handshake(frame: &Frame, peer: SocketAddr, tenants: &Tenants) -> Result<(Response, String), ProtocolError> {
+    if frame.header.opcode != Opcode::Hello {
+        return Err(ProtocolError::HandshakeRequired);
+    }
+
+    let hello = Hello::deserialize(&frame.payload, &mut 0)?;
+    // Checks the id without creating the tenant.
+    tenants.find(&hello.tenant)?;
+    let tenant = if hello.tenant.is_empty() { String::from(DEFAULT_TENANT) } else { hello.tenant };
+    info!(protocol = "binary", peer = peer, client = hello.client, tenant = tenant; "Handshake");
+
+    let ack = Hello { client: String::from(SERVER_NAME), tenant: tenant.clone() };
+    Ok((Response::ok(ack.serialize()), tenant))
+}
+
+fn read_db(db: &Arc<RwLock<MetricsDb>>) -> Result<RwLockReadGuard<'_, MetricsDb>, ProtocolError> {
+    db.read().map_err(|_| ProtocolError::Internal(String::from("Database lock is poisoned")))
+}
+
+/// Runs `handle_read` on the blocking pool, since the query holds the tenant's read lock for
+/// as long as it takes. It's cancelled if the client goes away in the meantime, which leaves
+/// no one to answer and gives `None`.
+async fn read(payload: Vec<u8>, tenant: &str, tenants: &Arc<Tenants>, reader: &mut BufReader<OwnedReadHalf>) -> Option<Result<Matrix, ProtocolError>> {
+    let (tenant, tenants, token) = (tenant.to_string(), tenants.clone(), CancellationToken::new());
+    let task = tokio::task::spawn_blocking({
+        let token = token.clone();
+        move || time_query("binary", "read", || handle_read(&payload, &tenant, &tenants, token))
+    });
+    let result = cancel_on_close(task, reader, &token).await
+        .unwrap_or_else(|_| Err(ProtocolError::Internal(String::from("Read failed unexpectedly"))));
+    (!token.is_cancelled()).then_some(result)
+}
+
+/// The matched series go back as `ReadChunk` frames followed by a response whose body is
+/// the chunk count.
+fn handle_read(payload: &[u8], tenant: &str, tenants: &Tenants, token: CancellationToken) -> Result<Matrix, ProtocolError> {
+    let request = ReadRequest::deserialize(payload, &mut 0)?;
+
+    // Reads never create the tenant, so one that has never written has nothing to find.
+    let tenant = tenants.find(tenant)?.ok_or_else(|| ProtocolError::NotFound(format!("metric {}", request.name)))?;
+    let guard = read_db(tenant.db())?;
+    let mut ctx = QueryContext::with_token(guard.query_limits(), token);
+    Ok(guard.select(&request.name, &mut ctx)?)
+}
+
+/// The tenant to write to. Opening one creates its WAL, so that's left to the blocking pool.
+async fn open(tenant: &str, tenants: &Arc<Tenants>) -> Result<Arc<Tenant>, ProtocolError> {
+    if let Some(tenant) = tenants.find(tenant)? {
+        return Ok(tenant);
+    }
+    let (tenant, tenants) = (tenant.to_string(), tenants.clone());
+    tokio::task::spawn_blocking(move || tenants.get(&tenant)).await
+        .unwrap_or_else(|_| Err(Error::Invalid(String::from("Opening the tenant failed unexpectedly"))))
+        .map_err(ProtocolError::from)
+}
+
+/// Waits for the pipeline without holding up the connection's thread.
+async fn store(tenant: &Arc<Tenant>, pipeline: &Pipeline, batch: DecodedBatch) -> BatchResult {
+    let (sender, result) = oneshot::channel();
+    pipeline.submit(tenant, batch, move |stored| {
+        let _ = sender.send(stored);
+    });
+    result.await.unwrap_or_default()
+}
+
+/// A sample the relabeling rules drop is acknowledged like one that was stored.
+async fn handle_write(payload: &[u8], tenant: &str, tenants: &Arc<Tenants>, pipeline: &Pipeline) -> Result<Response, ProtocolError> {
+    let metric = Metric::deserialize(payload, &mut 0)?;
+
+    let result = store(&open(tenant, tenants).await?, pipeline, (vec![(0, metric)], Vec::new())).await;
+    if let Some(error) = result.errors.into_iter().next() {
+        return Err(ProtocolError::Rejected(error.code, error.reason));
+    }
+
+    Ok(Response::ok(Vec::new()))
+}
+
+/// Replies with a `BatchResult`. Samples that don't decode are rejected alongside the ones
+/// the database turns down, all by their index in the request.
+async fn handle_write_batch(payload: &[u8], tenant: &str, tenants: &Arc<Tenants>, pipeline: &Pipeline) -> Result<Response, ProtocolError> {
+    let result = store(&open(tenant, tenants).await?, pipeline, WriteBatch::decode_lenient(payload, &mut 0)?).await;
+
+    Ok(Response::ok(result.serialize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn cancels_when_the_client_goes_away() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let mut reader = BufReader::new(server.into_split().0);
+
+        let token = CancellationToken::new();
+        let task = tokio::task::spawn_blocking({
+            let token = token.clone();
+            move || while !token.is_cancelled() {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+        });
+        drop(client);
+        cancel_on_close(task, &mut reader, &token).await.unwrap();
+        assert!(token.is_cancelled());
+    }
+}
@@ -0,0 +1,106 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use lib::{error, warn, ingest::{graphite::{self, Template}, pipeline::Pipeline}, models::batch::DecodedBatch, tenant::Tenant, time::now_ms};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::lines::read_lines;
+use crate::telemetry::Connection;
+
+/// Carbon refuses bigger pickle messages, and so do we.
+const MAX_PICKLE_LEN: usize = 1024 * 1024;
+
+/// Accepts plaintext `path value timestamp` lines over TCP, one task per connection.
+pub async fn serve_plaintext(listener: TcpListener, tenant: Arc<Tenant>, pipeline: Arc<Pipeline>, templates: Arc<Vec<Template>>) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!(protocol = "graphite", error = e; "Failed to accept connection");
+                continue;
+            }
+        };
+        tokio::spawn({
+            let tenant = tenant.clone();
+            let pipeline = pipeline.clone();
+            let templates = templates.clone();
+            async move {
+                let _connection = Connection::open("graphite");
+                let result = read_lines(socket, |lines| match std::str::from_utf8(lines) {
+                    Ok(text) => store(&tenant, &pipeline, graphite::parse_plaintext(text, &templates, now_ms()), "plaintext line", addr),
+                    Err(_) => warn!(protocol = "graphite", peer = addr; "Dropped plaintext that is not valid utf-8")
+                }).await;
+                if let Err(e) = result {
+                    warn!(protocol = "graphite", peer = addr, error = e; "Connection closed with error");
+                }
+            }
+        });
+    }
+}
+
+/// Accepts the pickle protocol over TCP: messages of a 4 byte big-endian length and a pickled
+/// list of `(path, (timestamp, value))`.
+pub async fn serve_pickle(listener: TcpListener, tenant: Arc<Tenant>, pipeline: Arc<Pipeline>, templates: Arc<Vec<Template>>) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!(protocol = "graphite_pickle", error = e; "Failed to accept connection");
+                continue;
+            }
+        };
+        tokio::spawn({
+            let tenant = tenant.clone();
+            let pipeline = pipeline.clone();
+            let templates = templates.clone();
+            async move {
+                let _connection = Connection::open("graphite_pickle");
+                if let Err(e) = read_pickles(socket, addr, &tenant, &pipeline, &templates).await {
+                    warn!(protocol = "graphite_pickle", peer = addr, error = e; "Connection closed with error");
+                }
+            }
+        });
+    }
+}
+
+async fn read_pickles(mut stream: TcpStream, peer: SocketAddr, tenant: &Arc<Tenant>, pipeline: &Pipeline, templates: &Arc<Vec<Template>>) -> std::io::Result<()> {
+    let mut header = [0; 4];
+    loop {
+        match stream.read_exact(&mut header).await {
+            Ok(_) => {},
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e)
+        }
+        let len = u32::from_be_bytes(header) as usize;
+        if len > MAX_PICKLE_LEN {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Pickle message of {} bytes is over the limit of {}", len, MAX_PICKLE_LEN)));
+        }
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).await?;
+
+        // Decoding a megabyte of pickle is CPU work, so keep it off the async workers. A
+        // payload that doesn't decode is dropped, but the framing is intact so the connection
+        // carries on.
+        let templates = templates.clone();
+        let decoded = tokio::task::spawn_blocking(move || graphite::parse_pickle(&payload, &templates, now_ms()))
+            .await
+            .map_err(std::io::Error::other)?;
+        match decoded {
+            Ok(decoded) => store(tenant, pipeline, decoded, "pickle entry", peer),
+            Err(e) => warn!(protocol = "graphite_pickle", peer = peer, error = e; "Dropped pickle message")
+        }
+    }
+}
+
+/// `item` names what the error indices count, for the log.
+fn store(tenant: &Arc<Tenant>, pipeline: &Pipeline, decoded: DecodedBatch, item: &'static str, peer: SocketAddr) {
+    pipeline.submit(tenant, decoded, move |result| {
+        for error in result.errors.iter().take(10) {
+            warn!(protocol = "graphite", peer = peer, code = error.code; "Rejected {} {}: {}", item, error.index, error.reason);
+        }
+        if result.errors.len() > 10 {
+            warn!(protocol = "graphite", peer = peer; "Rejected {} more {}s", result.errors.len() - 10, item);
+        }
+    });
+}
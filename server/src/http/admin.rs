@@ -0,0 +1,263 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use lib::{info, storage::store::Cardinality, tenant::Tenants, traits::json};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::config::reload::Reply;
+use crate::http::api::{allow, error_response, known_tenant, millis_param, param, params, read_db, selector_param, write_db};
+use crate::http::{request::Request, response::Response};
+
+const TEXT: &str = "text/plain; charset=utf-8";
+/// Entries in each list of the cardinality report, unless asked otherwise.
+const CARDINALITY_LIMIT: usize = 10;
+
+/// What the `/-/` endpoints answer from, besides the database.
+pub struct Admin {
+    ready: AtomicBool,
+    /// `None` turns the `/-/admin` operations off.
+    token: Option<String>,
+    reloads: mpsc::Sender<Reply>
+}
+
+impl Admin {
+    /// Not ready until `set_ready`. Config reloads asked for are sent to `reloads`.
+    pub fn new(token: Option<String>, reloads: mpsc::Sender<Reply>) -> Self {
+        Admin { ready: AtomicBool::new(false), token, reloads }
+    }
+
+    /// Called once the WAL has been replayed, after which the database is complete.
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::Release);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    /// Checks the request carries `Authorization: Bearer <token>`.
+    fn authorize(&self, request: &Request) -> Result<(), Response> {
+        let Some(token) = &self.token else {
+            return Err(Response::error(403, "Admin operations are off, set admin.token_file to turn them on"));
+        };
+        let given = request.header("authorization").and_then(|value| value.strip_prefix("Bearer ")).unwrap_or("");
+        if !same_bytes(given.trim().as_bytes(), token.as_bytes()) {
+            return Err(Response::error(401, "Missing or wrong admin token").with_header("WWW-Authenticate", "Bearer"));
+        }
+        Ok(())
+    }
+}
+
+/// Compares in time that only depends on the lengths, so a token can't be guessed a byte at
+/// a time.
+fn same_bytes(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Operations on the server itself, under `/-/` as in Prometheus. The probes and reload
+/// answer while the WAL is replayed; the rest wait for it, and work on the tenant the
+/// request names, on the blocking pool since they hold database locks for as long as they take.
+pub async fn handle(request: &Request, admin: &Arc<Admin>, tenants: &Arc<Tenants>) -> Response {
+    let path = request.path.trim_end_matches('/');
+    let result = match path {
+        "/-/healthy" => allow(request, &["GET"]).map(|_| Response::new(200, TEXT, b"MetricHouse is Healthy.\n".to_vec())),
+        "/-/ready" => allow(request, &["GET"]).map(|_| if admin.is_ready() {
+            Response::new(200, TEXT, b"MetricHouse is Ready.\n".to_vec())
+        } else {
+            Response::new(503, TEXT, b"MetricHouse is not ready, the WAL is being replayed.\n".to_vec())
+        }),
+        // Open to anyone when there's no token, as it was before there could be one.
+        "/-/reload" => match allow(request, &["POST", "PUT"]).and_then(|_| if admin.token.is_some() { admin.authorize(request) } else { Ok(()) }) {
+            Ok(()) => Ok(reload(&admin.reloads).await),
+            Err(response) => Err(response)
+        },
+        _ => match path.strip_prefix("/-/admin/") {
+            Some(operation) => match admin.authorize(request) {
+                Ok(()) => {
+                    let (operation, request, admin, tenants) = (operation.to_string(), request.clone(), admin.clone(), tenants.clone());
+                    tokio::task::spawn_blocking(move || run(&operation, &request, &admin, &tenants)).await
+                        .unwrap_or_else(|_| Err(Response::error(500, "Admin operation failed unexpectedly")))
+                },
+                Err(response) => Err(response)
+            },
+            None => Err(Response::error(404, &format!("No endpoint at {}", request.path)))
+        }
+    };
+    result.unwrap_or_else(|response| response)
+}
+
+/// Waits for the reload, so the response says whether the new config is in effect.
+async fn reload(reloads: &mpsc::Sender<Reply>) -> Response {
+    let (reply, outcome) = oneshot::channel();
+    if reloads.send(reply).await.is_err() {
+        return Response::error(503, "Config reloading has stopped");
+    }
+    match outcome.await {
+        Ok(Ok(())) => Response::json(200, String::from("{\"status\":\"success\"}")),
+        Ok(Err(e)) => Response::error(500, &format!("Failed to reload config, keeping the old one: {}", e)),
+        Err(_) => Response::error(503, "Config reloading has stopped")
+    }
+}
+
+fn run(operation: &str, request: &Request, admin: &Admin, tenants: &Tenants) -> Result<Response, Response> {
+    if !admin.is_ready() {
+        return Err(Response::error(503, "Not ready, the WAL is being replayed"));
+    }
+    let tenant = known_tenant(request, tenants)?;
+    let db = tenant.db();
+    let data = match operation {
+        "flush" => {
+            allow(request, &["POST"])?;
+            write_db(db)?.flush().map_err(error_response)?;
+            None
+        },
+        "compact" => {
+            allow(request, &["POST"])?;
+            write_db(db)?.compact().map_err(error_response)?;
+            None
+        },
+        "snapshot" => {
+            allow(request, &["POST"])?;
+            let name = read_db(db)?.snapshot().map_err(error_response)?;
+            let mut data = String::from("{\"name\":");
+            json::write_str(&mut data, &name);
+            data.push('}');
+            Some(data)
+        },
+        "delete_series" => {
+            allow(request, &["POST"])?;
+            let params = params(request)?;
+            let selector = selector_param(&params, true)?;
+            let start = millis_param(&params, "start")?.unwrap_or(0);
+            let end = millis_param(&params, "end")?.unwrap_or(u64::MAX);
+            let deleted = write_db(db)?.delete_series(&selector, start..=end).map_err(error_response)?;
+            Some(format!("{{\"deleted\":{}}}", deleted))
+        },
+        "cardinality" => {
+            allow(request, &["GET"])?;
+            let params = params(request)?;
+            let limit = match param(&params, "limit") {
+                Some(limit) => limit.parse().map_err(|_| Response::error(400, "limit must be a whole number"))?,
+                None => CARDINALITY_LIMIT
+            };
+            Some(cardinality_json(&read_db(db)?.cardinality(limit)))
+        },
+        _ => return Err(Response::error(404, &format!("No admin operation {:?}", operation)))
+    };
+    info!(operation = operation, tenant = tenant.id(); "Ran admin operation");
+    Ok(Response::json(200, match data {
+        Some(data) => format!("{{\"status\":\"success\",\"data\":{}}}", data),
+        None => String::from("{\"status\":\"success\"}")
+    }))
+}
+
+/// Shaped like Prometheus' `/api/v1/status/tsdb`.
+fn cardinality_json(cardinality: &Cardinality) -> String {
+    let mut out = format!("{{\"headStats\":{{\"numSeries\":{}}}", cardinality.series);
+    for (key, counts) in [
+        ("seriesCountByMetricName", &cardinality.series_by_metric),
+        ("labelValueCountByLabelName", &cardinality.values_by_label),
+        ("seriesCountByLabelValuePair", &cardinality.series_by_label_pair)
+    ] {
+        out.push_str(&format!(",\"{}\":[", key));
+        for (i, (name, count)) in counts.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push_str("{\"name\":");
+            json::write_str(&mut out, name);
+            out.push_str(&format!(",\"value\":{}}}", count));
+        }
+        out.push(']');
+    }
+    out.push('}');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib::{db::MetricsDb, models::Metric, storage::StorageOptions, tenant::TenantSettings, testing::TempDir};
+
+    fn request(method: &str, path: &str, token: Option<&str>) -> Request {
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: crate::http::request::parse_form(query).unwrap(),
+            headers: token.map(|token| (String::from("authorization"), format!("Bearer {}", token))).into_iter().collect(),
+            body: Vec::new(),
+            keep_alive: true
+        }
+    }
+
+    fn test_tenants(test: &str) -> (TempDir, Tenants) {
+        let dir = TempDir::new(&format!("admin_{}", test));
+        // Series files go in the data directory, which mustn't be the working one.
+        let options = StorageOptions { data_dir: dir.to_path_buf(), ..StorageOptions::default() };
+        (dir, Tenants::new(options.clone(), MetricsDb::open_with(&options).unwrap(), TenantSettings::default()))
+    }
+
+    #[tokio::test]
+    async fn reports_reloads() {
+        let (_dir, tenants) = test_tenants("reloads");
+        let tenants = Arc::new(tenants);
+        let (reloads, mut requests) = mpsc::channel::<Reply>(1);
+        tokio::spawn(async move {
+            let mut outcomes = vec![Err(String::from("Setting limits.max_series must be greater than zero")), Ok(())];
+            while let Some(reply) = requests.recv().await {
+                let _ = reply.send(outcomes.pop().unwrap());
+            }
+        });
+        let admin = Arc::new(Admin::new(None, reloads));
+
+        assert_eq!(handle(&request("POST", "/-/reload", None), &admin, &tenants).await.status, 200);
+        let response = handle(&request("POST", "/-/reload", None), &admin, &tenants).await;
+        assert_eq!(response.status, 500);
+        assert!(String::from_utf8(response.body).unwrap().contains("max_series"));
+        assert_eq!(handle(&request("GET", "/-/reload", None), &admin, &tenants).await.status, 405);
+        assert_eq!(handle(&request("POST", "/-/quit", None), &admin, &tenants).await.status, 404);
+    }
+
+    #[tokio::test]
+    async fn probes_and_guards_admin_operations() {
+        let (_dir, tenants) = test_tenants("operations");
+        let tenants = Arc::new(tenants);
+        let metric = |host: &str| Metric { timestamp: 1, value: 1.0, name: String::from("up"), labels: vec![(String::from("host"), host.to_string())] };
+        tenants.default_tenant().db().write().unwrap().ingest_batch(vec![metric("a"), metric("b")]);
+        tenants.get("team-a").unwrap().db().write().unwrap().ingest_batch(vec![metric("a"), metric("b"), metric("c")]);
+        let admin = Arc::new(Admin::new(Some(String::from("secret")), mpsc::channel(1).0));
+        let status = |method: &'static str, path: &'static str, token: Option<&'static str>| {
+            let (admin, tenants) = (&admin, &tenants);
+            async move { handle(&request(method, path, token), admin, tenants).await.status }
+        };
+
+        assert_eq!(status("GET", "/-/healthy", None).await, 200);
+        assert_eq!(status("GET", "/-/ready", None).await, 503);
+        assert_eq!(status("GET", "/-/admin/cardinality", Some("secret")).await, 503);
+        admin.set_ready();
+        assert_eq!(status("GET", "/-/ready", None).await, 200);
+
+        assert_eq!(status("POST", "/-/admin/compact", None).await, 401);
+        assert_eq!(status("POST", "/-/admin/compact", Some("secre")).await, 401);
+        assert_eq!(status("POST", "/-/reload", None).await, 401);
+        assert_eq!(status("POST", "/-/admin/compact", Some("secret")).await, 200);
+        assert_eq!(status("GET", "/-/admin/compact", Some("secret")).await, 405);
+        assert_eq!(status("POST", "/-/admin/defrag", Some("secret")).await, 404);
+        assert_eq!(status("POST", "/-/admin/delete_series", Some("secret")).await, 400);
+
+        let response = handle(&request("POST", "/-/admin/delete_series?selector=up%7Bhost%3D%22a%22%7D", Some("secret")), &admin, &tenants).await;
+        assert_eq!(String::from_utf8(response.body).unwrap(), r#"{"status":"success","data":{"deleted":1}}"#);
+        let response = handle(&request("GET", "/-/admin/cardinality?limit=1", Some("secret")), &admin, &tenants).await;
+        let body = String::from_utf8(response.body).unwrap();
+        assert!(body.starts_with(r#"{"status":"success","data":{"headStats":{"numSeries":1},"seriesCountByMetricName":[{"name":"up","value":1}]"#), "{}", body);
+
+        // Operations work on the tenant the request names.
+        let mut for_team = request("GET", "/-/admin/cardinality", Some("secret"));
+        for_team.headers.push((String::from("x-scope-orgid"), String::from("team-a")));
+        assert!(String::from_utf8(handle(&for_team, &admin, &tenants).await.body).unwrap().contains(r#""numSeries":3}"#));
+
+        let off = Arc::new(Admin::new(None, mpsc::channel(1).0));
+        assert_eq!(handle(&request("POST", "/-/admin/flush", Some("secret")), &off, &tenants).await.status, 403);
+    }
+}
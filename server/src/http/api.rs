@@ -0,0 +1,446 @@
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use lib::{db::MetricsDb, ingest::{pipeline::Pipeline, relabel::Relabeler}, models::{batch::DecodedBatch, BatchResult, Metric, RejectCode, SampleError, WriteBatch}, query::{CancellationToken, Limit, Matrix, QueryContext, RangeQuery, Selector}, tenant::{Tenant, Tenants}, time::now_ms, traits::{json::{self, JsonSerializable, JsonValue}, serializable::BinarySerializable}, Error};
+
+use crate::http::{influx, otlp, prometheus, remote_write, request::{parse_form, Request}, response::{negotiate, Response, BINARY, JSON}};
+use crate::telemetry::{self, time_query};
+
+const FORM: &str = "application/x-www-form-urlencoded";
+const EXPOSITION: &str = "text/plain; version=0.0.4; charset=utf-8";
+/// Names the tenant a request reads or writes, as in Cortex and Mimir.
+pub const TENANT_HEADER: &str = "x-scope-orgid";
+
+/// Routes a request to its handler. Every endpoint answers in JSON, and the ones returning
+/// a `Matrix` or `BatchResult` also speak the binary encoding used on the TCP protocol.
+/// Everything but the server's own metrics works on the data of the request's tenant alone.
+/// Queries stop early once `token` is cancelled.
+pub fn handle(request: &Request, tenants: &Tenants, pipeline: &Pipeline, token: &CancellationToken) -> Response {
+    if request.path.trim_end_matches('/') == "/metrics" {
+        return allow(request, &["GET"]).map(|_| own_metrics(tenants)).unwrap_or_else(|response| response);
+    }
+    // Only writes may create a tenant, so reads of one that has never written are a 404.
+    let writes = request.method == "POST" && matches!(request.path.trim_end_matches('/'), "/api/v1/write" | "/write" | "/v1/metrics" | "/api/write");
+    let tenant = match if writes { tenant(request, tenants) } else { known_tenant(request, tenants) } {
+        Ok(tenant) => tenant,
+        Err(response) => return response
+    };
+    let db = tenant.db();
+    // Writers from other ecosystems get the status codes and bodies their clients expect.
+    match request.path.trim_end_matches('/') {
+        "/api/v1/write" => return remote_write::handle(request, &tenant, pipeline),
+        "/write" => return influx::handle(request, &tenant, pipeline),
+        "/v1/metrics" => return otlp::handle(request, &tenant, pipeline),
+        _ => {}
+    }
+    if request.path.starts_with("/api/v1/") {
+        return prometheus::handle(request, db, token);
+    }
+    let path = request.path.trim_end_matches('/');
+    let result = match path {
+        "/api/write" => allow(request, &["POST"]).and_then(|_| write(request, &tenant, pipeline)),
+        "/api/query" => allow(request, &["GET", "POST"]).and_then(|_| time_query("native", "query", || query(request, db, token))),
+        "/api/query_range" => allow(request, &["GET", "POST"]).and_then(|_| time_query("native", "query_range", || query_range(request, db, token))),
+        "/api/series" => allow(request, &["GET", "POST"]).and_then(|_| series(request, db)),
+        "/api/labels" => allow(request, &["GET", "POST"]).and_then(|_| label_names(request, db)),
+        "/api/relabel" => allow(request, &["GET"]).map(|_| relabel_rules(pipeline.relabeler())),
+        _ => match path.strip_prefix("/api/labels/").and_then(|rest| rest.strip_suffix("/values")) {
+            Some(label) if !label.is_empty() && !label.contains('/') => {
+                allow(request, &["GET", "POST"]).and_then(|_| label_values(label, request, db))
+            },
+            _ => Err(Response::error(404, &format!("No endpoint at {}", request.path)))
+        }
+    };
+    result.unwrap_or_else(|response| response)
+}
+
+/// The tenant named in the `X-Scope-OrgID` header, or the default tenant without one,
+/// created if this is its first write.
+pub fn tenant(request: &Request, tenants: &Tenants) -> Result<Arc<Tenant>, Response> {
+    tenants.get(request.header(TENANT_HEADER).unwrap_or("").trim()).map_err(error_response)
+}
+
+/// `tenant` for reads, which never create one.
+pub fn known_tenant(request: &Request, tenants: &Tenants) -> Result<Arc<Tenant>, Response> {
+    let id = request.header(TENANT_HEADER).unwrap_or("").trim();
+    match tenants.find(id) {
+        Ok(Some(tenant)) => Ok(tenant),
+        Ok(None) => Err(Response::error(404, &format!("Tenant {:?} has no data", id))),
+        Err(error) => Err(error_response(error))
+    }
+}
+
+pub fn allow(request: &Request, methods: &[&str]) -> Result<(), Response> {
+    if methods.contains(&request.method.as_str()) {
+        return Ok(());
+    }
+    Err(Response::error(405, &format!("{} is not allowed here", request.method)).with_header("Allow", &methods.join(", ")))
+}
+
+/// The status a failed database call is reported with.
+fn status_for(error: &Error) -> u16 {
+    match error {
+        Error::Decode(_) | Error::Invalid(_) => 400,
+        Error::NotFound(_) => 404,
+        Error::LimitExceeded { limit: Limit::Timeout, .. } | Error::Cancelled => 503,
+        Error::LimitExceeded { .. } => 422,
+        Error::Rejected { code: RejectCode::RateLimited, .. } => 429,
+        Error::Rejected { code, .. } if code.is_transient() => 503,
+        Error::Rejected { .. } => 400,
+        Error::Io(_) | Error::Corruption { .. } | Error::ArenaFull { .. } => 500
+    }
+}
+
+/// A 503 for writes the ingest pipeline had no room for, or a 429 for a tenant over its
+/// ingest rate. Either way none of the samples were stored.
+pub fn turned_away(result: &BatchResult) -> Option<Response> {
+    let error = result.errors.iter().find(|error| matches!(error.code, RejectCode::Overloaded | RejectCode::RateLimited))?;
+    let status = if error.code == RejectCode::RateLimited { 429 } else { 503 };
+    Some(Response::error(status, &error.reason).with_header("Retry-After", "1"))
+}
+
+pub fn error_response(error: Error) -> Response {
+    Response::error(status_for(&error), &error.to_string())
+}
+
+pub fn read_db(db: &Arc<RwLock<MetricsDb>>) -> Result<RwLockReadGuard<'_, MetricsDb>, Response> {
+    db.read().map_err(|_| Response::error(500, "Database lock is poisoned"))
+}
+
+pub fn write_db(db: &Arc<RwLock<MetricsDb>>) -> Result<RwLockWriteGuard<'_, MetricsDb>, Response> {
+    db.write().map_err(|_| Response::error(500, "Database lock is poisoned"))
+}
+
+fn accept(request: &Request, offered: &[&'static str]) -> Result<&'static str, Response> {
+    negotiate(request.header("accept"), offered)
+        .ok_or_else(|| Response::error(406, &format!("Can only respond with {}", offered.join(" or "))))
+}
+
+/// Query string parameters, followed by the form body of a urlencoded POST.
+pub fn params(request: &Request) -> Result<Vec<(String, String)>, Response> {
+    let mut params = request.query.clone();
+    if request.method == "POST" && request.content_type().as_deref() == Some(FORM) {
+        let form = std::str::from_utf8(&request.body).ok().and_then(parse_form)
+            .ok_or_else(|| Response::error(400, "Malformed form body"))?;
+        params.extend(form);
+    }
+    Ok(params)
+}
+
+pub fn param<'a>(params: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    params.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+}
+
+pub fn millis_param(params: &[(String, String)], name: &str) -> Result<Option<u64>, Response> {
+    param(params, name)
+        .map(|value| value.parse().map_err(|_| Response::error(400, &format!("{} must be a whole number of milliseconds", name))))
+        .transpose()
+}
+
+fn required_millis(params: &[(String, String)], name: &str) -> Result<u64, Response> {
+    millis_param(params, name)?.ok_or_else(|| Response::error(400, &format!("Missing parameter {}", name)))
+}
+
+pub fn selector_param(params: &[(String, String)], required: bool) -> Result<Selector, Response> {
+    match param(params, "selector") {
+        Some(selector) => Selector::parse(selector).map_err(error_response),
+        None if required => Err(Response::error(400, "Missing parameter selector")),
+        None => Ok(Selector::all())
+    }
+}
+
+fn matrix_response(matrix: Matrix, media: &str) -> Response {
+    if media == BINARY {
+        Response::new(200, BINARY, matrix.serialize())
+    } else {
+        Response::json(200, matrix.to_json())
+    }
+}
+
+fn string_list(values: &[String]) -> String {
+    let mut out = String::from("[");
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        json::write_str(&mut out, value);
+    }
+    out.push(']');
+    out
+}
+
+/// Takes a JSON array of samples or a binary `WriteBatch`. Samples that can't be read are
+/// rejected individually. The status is 400 only when nothing was stored.
+fn write(request: &Request, tenant: &Arc<Tenant>, pipeline: &Pipeline) -> Result<Response, Response> {
+    let media = accept(request, &[JSON, BINARY])?;
+    let decoded = match request.content_type().as_deref() {
+        Some(JSON) | None => decode_json_samples(&request.body)?,
+        Some(BINARY) => WriteBatch::decode_lenient(&request.body, &mut 0).map_err(error_response)?,
+        Some(other) => return Err(Response::error(415, &format!("Can't read {}, send {} or {}", other, JSON, BINARY)))
+    };
+    let result = pipeline.ingest(tenant, decoded);
+    if let Some(response) = turned_away(&result) {
+        return Err(response);
+    }
+
+    let status = if result.accepted == 0 && result.rejected > 0 { 400 } else { 200 };
+    Ok(if media == BINARY {
+        Response::new(status, BINARY, result.serialize())
+    } else {
+        Response::json(status, result.to_json())
+    })
+}
+
+fn decode_json_samples(body: &[u8]) -> Result<DecodedBatch, Response> {
+    let text = std::str::from_utf8(body).map_err(|_| Response::error(400, "Body is not valid utf-8"))?;
+    let document = json::parse(text).map_err(error_response)?;
+    let samples = document.as_array().ok_or_else(|| Response::error(400, "Expected an array of samples"))?;
+
+    let now = now_ms();
+    let mut metrics = Vec::with_capacity(samples.len());
+    let mut errors = Vec::new();
+    for (index, sample) in samples.iter().enumerate() {
+        match json_sample(sample, now) {
+            Ok(metric) => metrics.push((index, metric)),
+            Err(reason) => errors.push(SampleError::new(index, reason))
+        }
+    }
+    Ok((metrics, errors))
+}
+
+/// `{"name": "cpu", "labels": {"host": "a"}, "timestamp": 1700000000000, "value": 0.5}`.
+/// Labels are optional and the timestamp defaults to now.
+fn json_sample(sample: &JsonValue, now: u64) -> Result<Metric, &'static str> {
+    let name = sample.get("name").and_then(JsonValue::as_str).ok_or("Sample has no name")?;
+    let value = sample.get("value").and_then(json::parse_value).ok_or("Sample has no numeric value")?;
+    let timestamp = match sample.get("timestamp") {
+        Some(timestamp) => timestamp.as_u64().ok_or("Timestamp must be a whole number of milliseconds")?,
+        None => now
+    };
+    let labels = match sample.get("labels") {
+        Some(labels) => labels.as_object().ok_or("Labels must be an object")?
+            .iter()
+            .map(|(key, value)| value.as_str().map(|value| (key.clone(), value.to_string())).ok_or("Label values must be strings"))
+            .collect::<Result<Vec<_>, _>>()?,
+        None => Vec::new()
+    };
+    Ok(Metric { timestamp, value, name: name.to_string(), labels })
+}
+
+fn query(request: &Request, db: &Arc<RwLock<MetricsDb>>, token: &CancellationToken) -> Result<Response, Response> {
+    let media = accept(request, &[JSON, BINARY])?;
+    let params = params(request)?;
+    let selector = selector_param(&params, true)?;
+    let time = millis_param(&params, "time")?.unwrap_or_else(now_ms);
+
+    let db = read_db(db)?;
+    let mut ctx = QueryContext::with_token(db.query_limits(), token.clone());
+    let matrix = db.query_instant(&selector, time, &mut ctx).map_err(error_response)?;
+    Ok(matrix_response(matrix, media))
+}
+
+fn query_range(request: &Request, db: &Arc<RwLock<MetricsDb>>, token: &CancellationToken) -> Result<Response, Response> {
+    let media = accept(request, &[JSON, BINARY])?;
+    let params = params(request)?;
+    let selector = selector_param(&params, true)?;
+    let mut range = RangeQuery::new(required_millis(&params, "start")?, required_millis(&params, "end")?, required_millis(&params, "step")?)
+        .map_err(error_response)?;
+    if let Some(lookback) = millis_param(&params, "lookback")? {
+        range.lookback = lookback;
+    }
+
+    let db = read_db(db)?;
+    let mut ctx = QueryContext::with_token(db.query_limits(), token.clone());
+    let matrix = db.query_range(&selector, &range, &mut ctx).map_err(error_response)?;
+    Ok(matrix_response(matrix, media))
+}
+
+fn series(request: &Request, db: &Arc<RwLock<MetricsDb>>) -> Result<Response, Response> {
+    accept(request, &[JSON])?;
+    let selector = selector_param(&params(request)?, false)?;
+
+    let mut out = String::from("[");
+    for (i, (name, labels)) in read_db(db)?.series(&selector).iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str("{\"name\":");
+        json::write_str(&mut out, name);
+        out.push_str(",\"labels\":");
+        json::write_labels(&mut out, labels);
+        out.push('}');
+    }
+    out.push(']');
+    Ok(Response::json(200, out))
+}
+
+fn label_names(request: &Request, db: &Arc<RwLock<MetricsDb>>) -> Result<Response, Response> {
+    accept(request, &[JSON])?;
+    let selector = selector_param(&params(request)?, false)?;
+    Ok(Response::json(200, string_list(&read_db(db)?.label_names(&selector))))
+}
+
+fn label_values(label: &str, request: &Request, db: &Arc<RwLock<MetricsDb>>) -> Result<Response, Response> {
+    accept(request, &[JSON])?;
+    let selector = selector_param(&params(request)?, false)?;
+    Ok(Response::json(200, string_list(&read_db(db)?.label_values(label, &selector))))
+}
+
+/// The server's own metrics, for Prometheus to scrape.
+fn own_metrics(tenants: &Tenants) -> Response {
+    Response::new(200, EXPOSITION, telemetry::render(tenants).into_bytes())
+}
+
+/// The relabeling rules in the order they run, with how many samples each has matched.
+fn relabel_rules(relabeler: &Relabeler) -> Response {
+    let mut out = String::from("[");
+    for (i, rule) in relabeler.rules().iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let config = rule.config();
+        out.push_str("{\"action\":");
+        json::write_str(&mut out, config.action.as_str());
+        out.push_str(",\"source_labels\":");
+        out.push_str(&string_list(&config.source_labels));
+        out.push_str(",\"target_label\":");
+        json::write_str(&mut out, &config.target_label);
+        out.push_str(",\"regex\":");
+        json::write_str(&mut out, &config.regex);
+        out.push_str(&format!(",\"hits\":{}}}", rule.hits()));
+    }
+    out.push(']');
+    Response::json(200, out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib::{ingest::{pipeline::PipelineOptions, relabel::{Action, RelabelConfig}}, storage::StorageOptions, tenant::{TenantLimits, TenantSettings}, testing::TempDir};
+
+    fn test_tenants(test: &str, settings: TenantSettings) -> (TempDir, Tenants) {
+        let dir = TempDir::new(&format!("http_{}", test));
+        let options = StorageOptions { data_dir: dir.to_path_buf(), ..StorageOptions::default() };
+        (dir, Tenants::new(options.clone(), MetricsDb::open_with(&options).unwrap(), settings))
+    }
+
+    fn request(method: &str, target: &str, headers: &[(&str, &str)], body: &str) -> Request {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: parse_form(query).unwrap(),
+            headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            body: body.as_bytes().to_vec(),
+            keep_alive: true
+        }
+    }
+
+    fn body(response: &Response) -> &str {
+        std::str::from_utf8(&response.body).unwrap()
+    }
+
+    #[test]
+    fn writes_and_queries() {
+        let (_dir, tenants) = test_tenants("write", TenantSettings::default());
+        let pipeline = Pipeline::start(Arc::new(Relabeler::default()), PipelineOptions::default()).unwrap();
+        let samples = r#"[
+            {"name": "cpu", "labels": {"host": "a"}, "timestamp": 1000, "value": 1.5},
+            {"name": "cpu", "labels": {"host": "b"}, "timestamp": 1000, "value": "+Inf"},
+            {"labels": {}, "value": 1},
+            {"name": "mem", "timestamp": 2000, "value": 3}
+        ]"#;
+        let response = handle(&request("POST", "/api/write", &[("content-type", "application/json")], samples), &tenants, &pipeline, &CancellationToken::new());
+        assert_eq!(response.status, 200);
+        assert_eq!(body(&response), r#"{"accepted":3,"rejected":1,"errors":[{"index":2,"code":"decode","reason":"Sample has no name"}]}"#);
+
+        let response = handle(&request("GET", "/api/query?selector=cpu%7Bhost%3D%22a%22%7D&time=1500", &[], ""), &tenants, &pipeline, &CancellationToken::new());
+        assert_eq!(response.status, 200);
+        assert_eq!(body(&response), r#"[{"name":"cpu","labels":{"host":"a"},"points":[[1500,"1.5"]]}]"#);
+
+        let response = handle(&request("GET", "/api/query_range?selector=mem&start=1000&end=3000&step=1000", &[("accept", "application/octet-stream")], ""), &tenants, &pipeline, &CancellationToken::new());
+        assert_eq!(response.headers[0].1, BINARY);
+        let matrix = Matrix::deserialize(&response.body, &mut 0).unwrap();
+        assert_eq!(matrix.series[0].points, vec![(2000, 3.0), (3000, 3.0)]);
+
+        let response = handle(&request("GET", "/api/series?selector=cpu", &[], ""), &tenants, &pipeline, &CancellationToken::new());
+        assert_eq!(body(&response), r#"[{"name":"cpu","labels":{"host":"a"}},{"name":"cpu","labels":{"host":"b"}}]"#);
+        let response = handle(&request("GET", "/api/labels/host/values", &[], ""), &tenants, &pipeline, &CancellationToken::new());
+        assert_eq!(body(&response), r#"["a","b"]"#);
+        let response = handle(&request("GET", "/api/labels/__name__/values", &[], ""), &tenants, &pipeline, &CancellationToken::new());
+        assert_eq!(body(&response), r#"["cpu","mem"]"#);
+    }
+
+    #[test]
+    fn reports_errors_with_status_codes() {
+        let (_dir, tenants) = test_tenants("errors", TenantSettings::default());
+        let pipeline = Pipeline::start(Arc::new(Relabeler::default()), PipelineOptions { capacity: 1, ..PipelineOptions::default() }).unwrap();
+        let status = |method, target, headers: &[(&str, &str)], body| handle(&request(method, target, headers, body), &tenants, &pipeline, &CancellationToken::new()).status;
+
+        assert_eq!(status("GET", "/nope", &[], ""), 404);
+        assert_eq!(status("GET", "/api/write", &[], ""), 405);
+        assert_eq!(status("POST", "/api/write", &[], "{"), 400);
+        assert_eq!(status("POST", "/api/write", &[("content-type", "text/plain")], "[]"), 415);
+        assert_eq!(status("POST", "/api/write", &[], r#"[{"name": "cpu"}]"#), 400);
+        assert_eq!(status("GET", "/api/query", &[], ""), 400);
+        assert_eq!(status("GET", "/api/query?selector=cpu{", &[], ""), 400);
+        assert_eq!(status("GET", "/api/query_range?selector=cpu&start=10&end=0&step=1", &[], ""), 400);
+        assert_eq!(status("GET", "/api/series", &[("accept", "application/octet-stream")], ""), 406);
+        assert_eq!(status("POST", "/api/query", &[("content-type", FORM)], "selector=cpu&time=1"), 200);
+
+        let response = handle(&request("PUT", "/api/query", &[], ""), &tenants, &pipeline, &CancellationToken::new());
+        assert!(response.headers.contains(&(String::from("Allow"), String::from("GET, POST"))));
+
+        // A write waiting on the lock fills the queue, so the next one is turned away.
+        let tenant = tenants.default_tenant();
+        let guard = tenant.db().write().unwrap();
+        pipeline.submit(&tenant, (vec![(0, Metric { timestamp: 1, value: 1.0, name: String::from("up"), labels: Vec::new() })], Vec::new()), |_| {});
+        let response = handle(&request("POST", "/api/write", &[], r#"[{"name": "up", "value": 1}]"#), &tenants, &pipeline, &CancellationToken::new());
+        assert_eq!(response.status, 503);
+        assert!(response.headers.contains(&(String::from("Retry-After"), String::from("1"))));
+        drop(guard);
+    }
+
+    #[test]
+    fn relabels_writes() {
+        let (_dir, tenants) = test_tenants("relabel", TenantSettings::default());
+        let relabeler = Relabeler::new(vec![RelabelConfig {
+            source_labels: vec![String::from("host")],
+            regex: String::from("b"),
+            action: Action::Drop,
+            ..RelabelConfig::default()
+        }]).unwrap();
+        let pipeline = Pipeline::start(Arc::new(relabeler), PipelineOptions::default()).unwrap();
+        let samples = r#"[{"name": "cpu", "labels": {"host": "a"}, "value": 1}, {"name": "cpu", "labels": {"host": "b"}, "value": 2}]"#;
+        handle(&request("POST", "/api/write", &[], samples), &tenants, &pipeline, &CancellationToken::new());
+        let response = handle(&request("GET", "/api/labels/host/values", &[], ""), &tenants, &pipeline, &CancellationToken::new());
+        assert_eq!(body(&response), r#"["a"]"#);
+
+        let response = handle(&request("GET", "/api/relabel", &[], ""), &tenants, &pipeline, &CancellationToken::new());
+        assert_eq!(body(&response), r#"[{"action":"drop","source_labels":["host"],"target_label":"","regex":"b","hits":1}]"#);
+    }
+
+    #[test]
+    fn keeps_tenants_apart() {
+        let limits = TenantLimits { ingest_rate: Some(1), ..TenantLimits::default() };
+        let (_dir, tenants) = test_tenants("tenants", TenantSettings { overrides: vec![(String::from("slow"), limits)], ..TenantSettings::default() });
+        let pipeline = Pipeline::start(Arc::new(Relabeler::default()), PipelineOptions::default()).unwrap();
+        let send = |method, target, tenant: &str, body| {
+            let headers = [(TENANT_HEADER, tenant)];
+            let response = handle(&request(method, target, if tenant.is_empty() { &[] } else { &headers }, body), &tenants, &pipeline, &CancellationToken::new());
+            (response.status, String::from_utf8(response.body).unwrap())
+        };
+
+        assert_eq!(send("POST", "/api/write", "team-a", r#"[{"name": "cpu", "timestamp": 1000, "value": 1}]"#).0, 200);
+        assert_eq!(send("GET", "/api/series", "team-a", ""), (200, String::from(r#"[{"name":"cpu","labels":{}}]"#)));
+        assert_eq!(send("GET", "/api/series", "", ""), (200, String::from("[]")));
+        assert_eq!(send("GET", "/api/v1/query?query=cpu&time=1", "", "").1, r#"{"status":"success","data":{"resultType":"vector","result":[]}}"#);
+        assert_eq!(send("GET", "/api/series", "../a", "").0, 400);
+        // Reading a tenant that has never written doesn't create it.
+        assert_eq!(send("GET", "/api/series", "team-b", "").0, 404);
+        assert_eq!(send("GET", "/api/v1/query?query=cpu&time=1", "team-b", "").0, 404);
+        assert!(tenants.find("team-b").unwrap().is_none());
+
+        assert_eq!(send("POST", "/api/write", "slow", r#"[{"name": "up", "value": 1}]"#).0, 200);
+        assert_eq!(send("POST", "/api/write", "slow", r#"[{"name": "up", "value": 1}]"#).0, 429);
+    }
+}
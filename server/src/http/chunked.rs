@@ -0,0 +1,101 @@
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+/// Budget for a chunk size line, and separately for all the trailers together.
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Why a chunked body couldn't be read. The server and the scrape client each turn these
+/// into their own errors.
+#[derive(Debug)]
+pub enum ChunkedError {
+    Io(std::io::Error),
+    /// The chunks add up to more than the limit.
+    TooLarge,
+    Invalid(&'static str)
+}
+
+impl From<std::io::Error> for ChunkedError {
+    fn from(e: std::io::Error) -> Self {
+        ChunkedError::Io(e)
+    }
+}
+
+impl From<LineError> for ChunkedError {
+    fn from(e: LineError) -> Self {
+        match e {
+            LineError::Io(e) => ChunkedError::Io(e),
+            LineError::TooLong => ChunkedError::Invalid("Chunk line is too long"),
+            LineError::Closed => ChunkedError::Invalid("Connection closed mid line"),
+            LineError::NotUtf8 => ChunkedError::Invalid("Chunk line is not valid utf-8")
+        }
+    }
+}
+
+/// Why `read_line` couldn't read a line.
+#[derive(Debug)]
+pub enum LineError {
+    Io(std::io::Error),
+    /// The budget ran out before the line did.
+    TooLong,
+    Closed,
+    NotUtf8
+}
+
+impl From<std::io::Error> for LineError {
+    fn from(e: std::io::Error) -> Self {
+        LineError::Io(e)
+    }
+}
+
+/// Reads a `Transfer-Encoding: chunked` body of at most `max_len` bytes. Trailers are read
+/// and dropped.
+pub async fn read_chunked(reader: &mut (impl AsyncBufRead + Unpin), max_len: usize) -> Result<Vec<u8>, ChunkedError> {
+    let mut body = Vec::new();
+    loop {
+        // Each size line gets its own budget, so many small chunks aren't mistaken for a long line.
+        let line = read_line(reader, &mut 0, MAX_LINE_LEN).await?.ok_or(ChunkedError::Invalid("Connection closed inside a chunk"))?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| ChunkedError::Invalid("Invalid chunk size"))?;
+        if size == 0 {
+            break;
+        }
+        // The sender picks the size, so compare without adding to it.
+        if size > max_len - body.len() {
+            return Err(ChunkedError::TooLarge);
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).await?;
+        let mut crlf = [0u8; 2];
+        reader.read_exact(&mut crlf).await?;
+        if &crlf != b"\r\n" {
+            return Err(ChunkedError::Invalid("Chunk is not followed by CRLF"));
+        }
+    }
+    let mut trailers_len = 0;
+    while read_line(reader, &mut trailers_len, MAX_LINE_LEN).await?.is_some_and(|line| !line.is_empty()) {}
+    Ok(body)
+}
+
+/// One CRLF (or bare LF) terminated line without its terminator. `used` is what earlier lines
+/// took out of `budget`, and goes up by this one. `Ok(None)` means the connection closed
+/// before the line started.
+pub async fn read_line(reader: &mut (impl AsyncBufRead + Unpin), used: &mut usize, budget: usize) -> Result<Option<String>, LineError> {
+    // Reading nothing would look like the connection closing.
+    if *used >= budget {
+        return Err(LineError::TooLong);
+    }
+    let mut line = Vec::new();
+    let read = (&mut *reader).take((budget - *used) as u64).read_until(b'\n', &mut line).await?;
+    *used += read;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(if *used >= budget { LineError::TooLong } else { LineError::Closed });
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map(Some).map_err(|_| LineError::NotUtf8)
+}
@@ -0,0 +1,76 @@
+use std::sync::Arc;
+
+use lib::{ingest::{influx::{self, Precision}, pipeline::Pipeline}, models::RejectCode, tenant::Tenant, time::now_ms};
+
+use crate::http::{api::{param, turned_away}, request::Request, response::Response};
+use crate::influx::describe_errors;
+
+/// InfluxDB 1.x style `/write?precision=s`. Influx clients mostly go by the status: 204 when
+/// every line was stored, 400 naming the failing lines otherwise. The lines that did parse
+/// are stored either way, like an Influx partial write.
+pub fn handle(request: &Request, tenant: &Arc<Tenant>, pipeline: &Pipeline) -> Response {
+    if request.method != "POST" {
+        return Response::error(405, &format!("{} is not allowed here", request.method)).with_header("Allow", "POST");
+    }
+    if let Some(encoding) = request.header("content-encoding") && !encoding.trim().eq_ignore_ascii_case("identity") {
+        return Response::error(415, &format!("Can't read {} encoded bodies", encoding));
+    }
+    let precision = match param(&request.query, "precision") {
+        Some(value) => match Precision::parse(value) {
+            Some(precision) => precision,
+            None => return Response::error(400, &format!("Unknown precision {:?}", value))
+        },
+        None => Precision::Nanoseconds
+    };
+    let Ok(text) = std::str::from_utf8(&request.body) else {
+        return Response::error(400, "Body is not valid utf-8");
+    };
+
+    let decoded = influx::parse(text, precision, now_ms());
+    let result = pipeline.ingest(tenant, decoded);
+    if let Some(response) = turned_away(&result) {
+        return response;
+    }
+
+    if result.errors.iter().any(|error| error.code == RejectCode::WalAppendFailed) {
+        return Response::error(500, "Failed to persist samples, retry later");
+    }
+    if result.errors.is_empty() {
+        Response::new(204, "text/plain", Vec::new())
+    } else {
+        Response::error(400, &format!("partial write: {}", describe_errors(&result.errors)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib::{db::MetricsDb, ingest::{pipeline::PipelineOptions, relabel::Relabeler}, storage::StorageOptions, tenant::{TenantSettings, Tenants}, testing::TempDir};
+
+    #[test]
+    fn writes_line_protocol() {
+        let dir = TempDir::new("influx_write");
+        let options = StorageOptions { data_dir: dir.to_path_buf(), ..StorageOptions::default() };
+        let tenants = Tenants::new(options.clone(), MetricsDb::open_with(&options).unwrap(), TenantSettings::default());
+        let tenant = tenants.default_tenant();
+        let pipeline = Pipeline::start(Arc::new(Relabeler::default()), PipelineOptions::default()).unwrap();
+        let request = |query: &str, body: &str| Request {
+            method: String::from("POST"),
+            path: String::from("/write"),
+            query: crate::http::request::parse_form(query).unwrap(),
+            headers: Vec::new(),
+            body: body.as_bytes().to_vec(),
+            keep_alive: true
+        };
+
+        assert_eq!(handle(&request("precision=s", "cpu,host=a usage=0.5 1700000000"), &tenant, &pipeline).status, 204);
+        let stored = tenant.db().read().unwrap().query("cpu_usage").unwrap().clone();
+        assert_eq!(stored[0].timestamp, 1_700_000_000_000);
+
+        let response = handle(&request("", "cpu usage=1 1700000000000000000\ncpu usage=x"), &tenant, &pipeline);
+        assert_eq!(response.status, 400);
+        assert_eq!(response.body, br#"{"error":"partial write: line 2: Invalid value for field usage"}"#);
+        assert_eq!(tenant.db().read().unwrap().query("cpu_usage").unwrap().len(), 2);
+        assert_eq!(handle(&request("precision=x", ""), &tenant, &pipeline).status, 400);
+    }
+}
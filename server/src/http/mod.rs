@@ -0,0 +1,100 @@
+pub mod admin;
+pub mod api;
+pub mod chunked;
+pub mod influx;
+pub mod otlp;
+pub mod prometheus;
+pub mod remote_write;
+pub mod request;
+pub mod response;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use lib::{debug, error, ingest::pipeline::Pipeline, query::CancellationToken, tenant::Tenants, warn};
+use tokio::io::{BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::connection::cancel_on_close;
+use crate::http::{admin::Admin, request::{read_request, ReadError}, response::Response};
+use crate::telemetry::Connection;
+
+/// Accepts HTTP connections until the listener fails, one task per connection. Until `admin`
+/// is ready, only the `/-/` endpoints are served.
+pub async fn serve(listener: TcpListener, tenants: Arc<Tenants>, pipeline: Arc<Pipeline>, admin: Arc<Admin>) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!(protocol = "http", error = e; "Failed to accept connection");
+                continue;
+            }
+        };
+        tokio::spawn({
+            let tenants = tenants.clone();
+            let pipeline = pipeline.clone();
+            let admin = admin.clone();
+            async move {
+                let _connection = Connection::open("http");
+                if let Err(e) = handle_connection(socket, addr, &tenants, &pipeline, &admin).await {
+                    warn!(protocol = "http", peer = addr, error = e; "Connection closed with error");
+                }
+            }
+        });
+    }
+}
+
+/// Serves requests one after another until either side asks to close the connection. Every
+/// request is logged at debug level, and those that fail on our side at warn.
+async fn handle_connection(stream: TcpStream, peer: SocketAddr, tenants: &Arc<Tenants>, pipeline: &Arc<Pipeline>, admin: &Arc<Admin>) -> std::io::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    loop {
+        let request = match read_request(&mut reader).await {
+            Ok(Some(request)) => Arc::new(request),
+            Ok(None) => return Ok(()),
+            Err(ReadError::Io(e)) => return Err(e),
+            Err(ReadError::Http(response)) => {
+                debug!(protocol = "http", peer = peer, status = response.status; "Malformed request");
+                return response.write_to(&mut writer, false).await;
+            }
+        };
+
+        let started = Instant::now();
+        let response = if request.path.starts_with("/-/") {
+            admin::handle(&request, admin, tenants).await
+        } else if !admin.is_ready() {
+            // The default tenant is locked for the replay, and queries would miss what's left of it.
+            Response::error(503, "Not ready, the WAL is being replayed")
+        } else {
+            // Handlers take database locks, wait on the ingest pipeline and evaluate queries, so
+            // they run on the blocking pool rather than hold up the connections sharing this worker.
+            // A query is cancelled if the client goes away, and then there's no one to answer.
+            let (task_request, tenants, pipeline, token) = (request.clone(), tenants.clone(), pipeline.clone(), CancellationToken::new());
+            let task = tokio::task::spawn_blocking({
+                let token = token.clone();
+                move || api::handle(&task_request, &tenants, &pipeline, &token)
+            });
+            let response = cancel_on_close(task, &mut reader, &token).await
+                .unwrap_or_else(|_| Response::error(500, "Request handler failed unexpectedly"));
+            if token.is_cancelled() {
+                debug!(protocol = "http", peer = peer, method = request.method, path = request.path; "Client closed the connection before the response");
+                return Ok(());
+            }
+            response
+        };
+        let elapsed_ms = started.elapsed().as_millis();
+        if response.status >= 500 {
+            warn!(protocol = "http", peer = peer, method = request.method, path = request.path, status = response.status, elapsed_ms = elapsed_ms; "Request failed");
+        } else {
+            debug!(protocol = "http", peer = peer, method = request.method, path = request.path, status = response.status, elapsed_ms = elapsed_ms; "Request");
+        }
+        response.write_to(&mut writer, request.keep_alive).await?;
+        if !request.keep_alive {
+            return Ok(());
+        }
+    }
+}
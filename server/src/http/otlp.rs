@@ -0,0 +1,120 @@
+use std::sync::Arc;
+
+use lib::{ingest::{otlp, pipeline::Pipeline}, models::RejectCode, tenant::Tenant};
+
+use crate::http::{request::Request, response::Response};
+
+const PROTOBUF: &str = "application/x-protobuf";
+// gRPC status codes, which OTLP/HTTP reports failures with.
+const INVALID_ARGUMENT: u64 = 3;
+const UNAVAILABLE: u64 = 14;
+
+/// Receives OTLP/HTTP metrics in binary protobuf. Exporters retry on 429, 502, 503 and 504 and
+/// drop the batch on anything else, so only a failure to persist or a full ingest pipeline is a
+/// 503, and going over the tenant's ingest rate a 429. Points that can't
+/// be stored are reported in a partial success, as the spec asks.
+pub fn handle(request: &Request, tenant: &Arc<Tenant>, pipeline: &Pipeline) -> Response {
+    if request.method != "POST" {
+        return Response::error(405, &format!("{} is not allowed here", request.method)).with_header("Allow", "POST");
+    }
+    if request.content_type().as_deref() != Some(PROTOBUF) {
+        return status(415, INVALID_ARGUMENT, &format!("Only {} is supported", PROTOBUF));
+    }
+    if let Some(encoding) = request.header("content-encoding") && !encoding.trim().eq_ignore_ascii_case("identity") {
+        return status(415, INVALID_ARGUMENT, &format!("Content-Encoding {} is not supported", encoding));
+    }
+
+    let export = match otlp::decode(&request.body) {
+        Ok(export) => export,
+        Err(e) => return status(400, INVALID_ARGUMENT, &e.to_string())
+    };
+    let result = pipeline.ingest_export(tenant, export);
+
+    if let Some(error) = result.errors.iter().find(|error| matches!(error.code, RejectCode::Overloaded | RejectCode::RateLimited)) {
+        let code = if error.code == RejectCode::RateLimited { 429 } else { 503 };
+        return status(code, UNAVAILABLE, &error.reason).with_header("Retry-After", "1");
+    }
+    if result.errors.iter().any(|error| error.code == RejectCode::WalAppendFailed) {
+        return status(503, UNAVAILABLE, "Failed to persist data points, retry later");
+    }
+    let mut points: Vec<u32> = result.errors.iter().map(|error| error.index).collect();
+    points.dedup();
+    let message = result.errors.first()
+        .map(|first| format!("The first rejected data point, at index {}: {}", first.index, first.reason))
+        .unwrap_or_default();
+    Response::new(200, PROTOBUF, otlp::encode_response(points.len(), &message))
+}
+
+fn status(code: u16, grpc_code: u64, message: &str) -> Response {
+    Response::new(code, PROTOBUF, otlp::encode_status(grpc_code, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib::{db::MetricsDb, ingest::{pipeline::PipelineOptions, protobuf::{write_bytes_field, write_double_field, write_varint}, relabel::Relabeler}, models::MetricKind, storage::StorageOptions, tenant::{TenantLimits, TenantSettings, Tenants}, testing::TempDir};
+
+    fn test_tenants(test: &str, settings: TenantSettings) -> (TempDir, Tenants) {
+        let dir = TempDir::new(&format!("otlp_{}", test));
+        let options = StorageOptions { data_dir: dir.to_path_buf(), ..StorageOptions::default() };
+        (dir, Tenants::new(options.clone(), MetricsDb::open_with(&options).unwrap(), settings))
+    }
+
+    fn request(headers: &[(&str, &str)], body: Vec<u8>) -> Request {
+        Request {
+            method: String::from("POST"),
+            path: String::from("/v1/metrics"),
+            query: Vec::new(),
+            headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            body,
+            keep_alive: true
+        }
+    }
+
+    /// A request with one gauge `temperature` holding a point at `time_ns`.
+    fn export(time_ns: u64) -> Vec<u8> {
+        let mut point = Vec::new();
+        write_varint(&mut point, (3 << 3) | 1);
+        point.extend(time_ns.to_le_bytes());
+        write_double_field(&mut point, 4, 21.5);
+        let mut gauge = Vec::new();
+        write_bytes_field(&mut gauge, 1, &point);
+        let mut metric = Vec::new();
+        write_bytes_field(&mut metric, 1, b"temperature");
+        write_bytes_field(&mut metric, 5, &gauge);
+        let mut scope_metrics = Vec::new();
+        write_bytes_field(&mut scope_metrics, 2, &metric);
+        let mut resource_metrics = Vec::new();
+        write_bytes_field(&mut resource_metrics, 2, &scope_metrics);
+        let mut request = Vec::new();
+        write_bytes_field(&mut request, 1, &resource_metrics);
+        request
+    }
+
+    #[test]
+    fn stores_exported_metrics() {
+        let limits = TenantLimits { ingest_rate: Some(1), ..TenantLimits::default() };
+        let (_dir, tenants) = test_tenants("stores", TenantSettings { overrides: vec![(String::from("slow"), limits)], ..TenantSettings::default() });
+        let tenant = tenants.default_tenant();
+        let pipeline = Pipeline::start(Arc::new(Relabeler::default()), PipelineOptions::default()).unwrap();
+        let headers = [("content-type", PROTOBUF)];
+
+        let response = handle(&request(&headers, export(1_700_000_000_000_000_000)), &tenant, &pipeline);
+        assert_eq!((response.status, response.body.len()), (200, 0));
+        assert_eq!(tenant.db().read().unwrap().query("temperature").unwrap()[0].timestamp, 1_700_000_000_000);
+        assert_eq!(tenant.db().read().unwrap().kind("temperature"), MetricKind::Gauge);
+
+        // A point without a time is a partial success, which still comes back as 200.
+        let response = handle(&request(&headers, export(0)), &tenant, &pipeline);
+        assert_eq!(response.status, 200);
+        assert!(!response.body.is_empty());
+
+        assert_eq!(handle(&request(&headers, b"garbage".to_vec()), &tenant, &pipeline).status, 400);
+        assert_eq!(handle(&request(&[("content-type", "application/json")], b"{}".to_vec()), &tenant, &pipeline).status, 415);
+        assert_eq!(handle(&request(&[("content-type", PROTOBUF), ("content-encoding", "gzip")], Vec::new()), &tenant, &pipeline).status, 415);
+
+        let slow = tenants.get("slow").unwrap();
+        assert_eq!(handle(&request(&headers, export(1_700_000_000_000_000_000)), &slow, &pipeline).status, 200);
+        assert_eq!(handle(&request(&headers, export(1_700_000_000_000_000_000)), &slow, &pipeline).status, 429);
+    }
+}
@@ -0,0 +1,433 @@
+use std::sync::{Arc, RwLock};
+
+use lib::{db::MetricsDb, query::{promql::{self, Expr, Value}, CancellationToken, Limit, Matrix, QueryContext, QueryLimits, RangeQuery, Selector}, time::{days_from_civil, now_ms}, traits::json, Error};
+
+use crate::http::{api::{param, params, read_db}, request::Request, response::Response};
+use crate::telemetry::time_query;
+
+/// Serves the subset of the Prometheus HTTP API Grafana needs, under `/api/v1/`. Everything
+/// is answered in the Prometheus envelope, including errors, so its data source can read it.
+pub fn handle(request: &Request, db: &Arc<RwLock<MetricsDb>>, token: &CancellationToken) -> Response {
+    let path = request.path.trim_end_matches('/');
+    let result = match path {
+        "/api/v1/query" => allow(request).and_then(|_| time_query("prometheus", "query", || query(request, db, token))),
+        "/api/v1/query_range" => allow(request).and_then(|_| time_query("prometheus", "query_range", || query_range(request, db, token))),
+        "/api/v1/series" => allow(request).and_then(|_| series(request, db)),
+        "/api/v1/labels" => allow(request).and_then(|_| label_names(request, db)),
+        "/api/v1/metadata" => allow(request).and_then(|_| metadata(request, db)),
+        _ => match path.strip_prefix("/api/v1/label/").and_then(|rest| rest.strip_suffix("/values")) {
+            Some(label) if !label.is_empty() && !label.contains('/') => {
+                allow(request).and_then(|_| label_values(label, request, db))
+            },
+            _ => Err(error(404, "not_found", &format!("No endpoint at {}", request.path)))
+        }
+    };
+    result.unwrap_or_else(|response| response)
+}
+
+fn allow(request: &Request) -> Result<(), Response> {
+    if request.method == "GET" || request.method == "POST" {
+        return Ok(());
+    }
+    Err(error(405, "bad_data", &format!("{} is not allowed here", request.method)).with_header("Allow", "GET, POST"))
+}
+
+fn success(data: &str) -> Response {
+    Response::json(200, format!("{{\"status\":\"success\",\"data\":{}}}", data))
+}
+
+fn error(status: u16, kind: &str, message: &str) -> Response {
+    let mut body = format!("{{\"status\":\"error\",\"errorType\":\"{}\",\"error\":", kind);
+    json::write_str(&mut body, message);
+    body.push('}');
+    Response::json(status, body)
+}
+
+fn bad_data(message: &str) -> Response {
+    error(400, "bad_data", message)
+}
+
+/// How an error raised while evaluating a query is reported. Parse errors are turned into
+/// `bad_data` before evaluation starts.
+fn execution_error(e: Error) -> Response {
+    let message = e.to_string();
+    match e {
+        Error::LimitExceeded { limit: Limit::Timeout, .. } => error(503, "timeout", &message),
+        Error::Cancelled => error(503, "canceled", &message),
+        Error::Decode(_) | Error::Invalid(_) | Error::NotFound(_) | Error::LimitExceeded { .. } | Error::Rejected { .. } => error(422, "execution", &message),
+        Error::Io(_) | Error::Corruption { .. } | Error::ArenaFull { .. } => error(500, "internal", &message)
+    }
+}
+
+/// A timestamp as Unix seconds with an optional fraction, or RFC 3339. Returns milliseconds.
+fn parse_time(value: &str) -> Option<u64> {
+    if let Ok(seconds) = value.parse::<f64>() {
+        return (seconds.is_finite() && seconds >= 0.0).then(|| (seconds * 1000.0).round() as u64);
+    }
+    parse_rfc3339(value)
+}
+
+/// `2024-01-02T03:04:05.678Z` or with a `+hh:mm` offset.
+fn parse_rfc3339(value: &str) -> Option<u64> {
+    let bytes = value.as_bytes();
+    if bytes.len() < 20 || bytes[4] != b'-' || bytes[7] != b'-' || !matches!(bytes[10], b'T' | b't' | b' ') || bytes[13] != b':' || bytes[16] != b':' {
+        return None;
+    }
+    let number = |range: std::ops::Range<usize>| -> Option<i64> {
+        let digits = value.get(range)?;
+        digits.bytes().all(|b| b.is_ascii_digit()).then(|| digits.parse().ok())?
+    };
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    let (hour, minute, second) = (number(11..13)?, number(14..16)?, number(17..19)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let mut rest = &value[19..];
+    let mut millis = 0;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let digits = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        if digits == 0 {
+            return None;
+        }
+        // Anything past milliseconds is truncated.
+        let padded = format!("{:0<3}", &fraction[..digits.min(3)]);
+        millis = padded.parse::<i64>().ok()?;
+        rest = &fraction[digits..];
+    }
+
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = match rest.as_bytes().first()? {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None
+            };
+            if rest.len() != 6 || rest.as_bytes()[3] != b':' {
+                return None;
+            }
+            sign * (number(value.len() - 5..value.len() - 3)? * 60 + number(value.len() - 2..value.len())?)
+        }
+    };
+
+    let seconds = days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second - offset * 60;
+    u64::try_from(seconds * 1000 + millis).ok()
+}
+
+/// A duration like `30s`, or a number of seconds.
+fn parse_step(value: &str) -> Option<u64> {
+    if let Ok(seconds) = value.parse::<f64>() {
+        return (seconds.is_finite() && seconds > 0.0).then(|| (seconds * 1000.0).round() as u64);
+    }
+    promql::parse_duration(value).ok()
+}
+
+fn time_param(params: &[(String, String)], name: &str) -> Result<Option<u64>, Response> {
+    param(params, name)
+        .map(|value| parse_time(value).ok_or_else(|| bad_data(&format!("Invalid {} {:?}", name, value))))
+        .transpose()
+}
+
+fn parse_query(params: &[(String, String)]) -> Result<Expr, Response> {
+    let query = param(params, "query").ok_or_else(|| bad_data("Missing parameter query"))?;
+    promql::parse(query).map_err(|e| bad_data(&e.to_string()))
+}
+
+/// The server wide limits, with the timeout lowered when the request asks for less.
+fn query_context(params: &[(String, String)], mut limits: QueryLimits, token: &CancellationToken) -> Result<QueryContext, Response> {
+    if let Some(timeout) = param(params, "timeout") {
+        let millis = parse_step(timeout).ok_or_else(|| bad_data(&format!("Invalid timeout {:?}", timeout)))?;
+        limits.timeout = limits.timeout.min(std::time::Duration::from_millis(millis));
+    }
+    Ok(QueryContext::with_token(limits, token.clone()))
+}
+
+/// The `match[]` selectors, which must be plain series selectors.
+fn match_params(params: &[(String, String)]) -> Result<Vec<Selector>, Response> {
+    params.iter()
+        .filter(|(key, _)| key == "match[]")
+        .map(|(_, value)| match promql::parse(value) {
+            Ok(Expr::Selector { selector, range: None, offset: 0 }) => Ok(selector),
+            Ok(_) => Err(bad_data(&format!("match[] must be a series selector, got {:?}", value))),
+            Err(e) => Err(bad_data(&e.to_string()))
+        })
+        .collect()
+}
+
+fn write_time(out: &mut String, millis: u64) {
+    out.push_str(&(millis as f64 / 1000.0).to_string());
+}
+
+fn write_metric(out: &mut String, name: &str, labels: &[(String, String)]) {
+    let mut all = Vec::with_capacity(labels.len() + 1);
+    if !name.is_empty() {
+        all.push((String::from(lib::query::selector::NAME_LABEL), name.to_string()));
+    }
+    all.extend(labels.iter().cloned());
+    json::write_labels(out, &all);
+}
+
+fn write_point(out: &mut String, millis: u64, value: &str) {
+    out.push('[');
+    write_time(out, millis);
+    out.push(',');
+    json::write_str(out, value);
+    out.push(']');
+}
+
+fn write_matrix(out: &mut String, matrix: &Matrix) {
+    out.push('[');
+    for (i, series) in matrix.series.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str("{\"metric\":");
+        write_metric(out, &series.name, &series.labels);
+        out.push_str(",\"values\":[");
+        for (j, (timestamp, value)) in series.points.iter().enumerate() {
+            if j > 0 {
+                out.push(',');
+            }
+            write_point(out, *timestamp, &json::format_value(*value));
+        }
+        out.push_str("]}");
+    }
+    out.push(']');
+}
+
+fn result_json(value: &Value, time: u64) -> String {
+    let mut out = format!("{{\"resultType\":\"{}\",\"result\":", value.type_name());
+    match value {
+        Value::Scalar(scalar) => write_point(&mut out, time, &json::format_value(*scalar)),
+        Value::String(string) => write_point(&mut out, time, string),
+        Value::Vector(samples) => {
+            out.push('[');
+            for (i, sample) in samples.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str("{\"metric\":");
+                write_metric(&mut out, &sample.name, &sample.labels);
+                out.push_str(",\"value\":");
+                write_point(&mut out, time, &json::format_value(sample.value));
+                out.push('}');
+            }
+            out.push(']');
+        },
+        Value::Matrix(matrix) => write_matrix(&mut out, matrix)
+    }
+    out.push('}');
+    out
+}
+
+fn string_list(values: &[String]) -> String {
+    let parts: Vec<String> = values.iter().map(|value| {
+        let mut out = String::new();
+        json::write_str(&mut out, value);
+        out
+    }).collect();
+    format!("[{}]", parts.join(","))
+}
+
+fn query(request: &Request, db: &Arc<RwLock<MetricsDb>>, token: &CancellationToken) -> Result<Response, Response> {
+    let params = params(request)?;
+    let expr = parse_query(&params)?;
+    let time = time_param(&params, "time")?.unwrap_or_else(now_ms);
+    let db = read_db(db)?;
+    let mut ctx = query_context(&params, db.query_limits(), token)?;
+    let value = promql::instant_query(&db, &expr, time, &mut ctx).map_err(execution_error)?;
+    Ok(success(&result_json(&value, time)))
+}
+
+fn query_range(request: &Request, db: &Arc<RwLock<MetricsDb>>, token: &CancellationToken) -> Result<Response, Response> {
+    let params = params(request)?;
+    let expr = parse_query(&params)?;
+    let start = time_param(&params, "start")?.ok_or_else(|| bad_data("Missing parameter start"))?;
+    let end = time_param(&params, "end")?.ok_or_else(|| bad_data("Missing parameter end"))?;
+    let step = param(&params, "step").ok_or_else(|| bad_data("Missing parameter step"))?;
+    let step = parse_step(step).ok_or_else(|| bad_data(&format!("Invalid step {:?}", step)))?;
+    let range = RangeQuery::new(start, end, step).map_err(|e| bad_data(&e.to_string()))?;
+    let db = read_db(db)?;
+    let mut ctx = query_context(&params, db.query_limits(), token)?;
+    let matrix = promql::range_query(&db, &expr, &range, &mut ctx).map_err(execution_error)?;
+    let mut out = String::from("{\"resultType\":\"matrix\",\"result\":");
+    write_matrix(&mut out, &matrix);
+    out.push('}');
+    Ok(success(&out))
+}
+
+fn series(request: &Request, db: &Arc<RwLock<MetricsDb>>) -> Result<Response, Response> {
+    let selectors = match_params(&params(request)?)?;
+    if selectors.is_empty() {
+        return Err(bad_data("At least one match[] is required"));
+    }
+
+    let db = read_db(db)?;
+    let mut series: Vec<_> = selectors.iter().flat_map(|selector| db.series(selector)).collect();
+    series.sort();
+    series.dedup();
+
+    let mut out = String::from("[");
+    for (i, (name, labels)) in series.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_metric(&mut out, name, labels);
+    }
+    out.push(']');
+    Ok(success(&out))
+}
+
+/// Runs `collect` once per `match[]`, or over every series when there is none, and merges
+/// the sorted results.
+fn collect_strings(request: &Request, db: &Arc<RwLock<MetricsDb>>, collect: impl Fn(&MetricsDb, &Selector) -> Vec<String>) -> Result<Response, Response> {
+    let mut selectors = match_params(&params(request)?)?;
+    if selectors.is_empty() {
+        selectors.push(Selector::all());
+    }
+
+    let db = read_db(db)?;
+    let mut values: Vec<String> = selectors.iter().flat_map(|selector| collect(&db, selector)).collect();
+    values.sort();
+    values.dedup();
+    Ok(success(&string_list(&values)))
+}
+
+fn label_names(request: &Request, db: &Arc<RwLock<MetricsDb>>) -> Result<Response, Response> {
+    collect_strings(request, db, |db, selector| {
+        let mut names = db.label_names(selector);
+        if !db.series(selector).is_empty() {
+            names.push(String::from(lib::query::selector::NAME_LABEL));
+        }
+        names
+    })
+}
+
+fn label_values(label: &str, request: &Request, db: &Arc<RwLock<MetricsDb>>) -> Result<Response, Response> {
+    collect_strings(request, db, |db, selector| db.label_values(label, selector))
+}
+
+/// The kind of each metric whose writer said what it is, or only `metric`'s. Help texts and
+/// units aren't kept, so they're always empty.
+fn metadata(request: &Request, db: &Arc<RwLock<MetricsDb>>) -> Result<Response, Response> {
+    let params = params(request)?;
+    let only = param(&params, "metric");
+    let mut out = String::from("{");
+    for (name, kind) in read_db(db)?.kinds() {
+        if only.is_some_and(|only| only != name) {
+            continue;
+        }
+        if out.len() > 1 {
+            out.push(',');
+        }
+        json::write_str(&mut out, &name);
+        out.push_str(&format!(":[{{\"type\":\"{}\",\"help\":\"\",\"unit\":\"\"}}]", kind.as_str()));
+    }
+    out.push('}');
+    Ok(success(&out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::request::parse_form;
+    use lib::{models::{Metric, MetricKind}, testing::TempDir};
+
+    fn test_db(test: &str) -> (TempDir, Arc<RwLock<MetricsDb>>) {
+        let dir = TempDir::new(&format!("prometheus_{}", test));
+        let mut db = MetricsDb::open(&dir).unwrap();
+        let mut samples = Vec::new();
+        for i in 1..=6u64 {
+            for host in ["a", "b"] {
+                samples.push(Metric {
+                    timestamp: i * 10_000,
+                    value: i as f64,
+                    name: String::from("requests_total"),
+                    labels: vec![(String::from("host"), host.to_string())]
+                });
+            }
+        }
+        db.ingest_batch(samples);
+        db.set_kind("requests_total", MetricKind::Counter);
+        (dir, Arc::new(RwLock::new(db)))
+    }
+
+    fn get(db: &Arc<RwLock<MetricsDb>>, target: &str) -> (u16, String) {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let request = Request {
+            method: String::from("GET"),
+            path: path.to_string(),
+            query: parse_form(query).unwrap(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            keep_alive: true
+        };
+        let response = handle(&request, db, &CancellationToken::new());
+        (response.status, String::from_utf8(response.body).unwrap())
+    }
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(parse_time("1700000000.5"), Some(1_700_000_000_500));
+        assert_eq!(parse_time("2023-11-14T22:13:20Z"), Some(1_700_000_000_000));
+        assert_eq!(parse_time("2023-11-14T23:13:20.25+01:00"), Some(1_700_000_000_250));
+        assert_eq!(parse_time("2000-02-29T00:00:00Z"), Some(951_782_400_000));
+        assert_eq!(parse_time("2023-13-01T00:00:00Z"), None);
+        assert_eq!(parse_time("-1"), None);
+        assert_eq!(parse_step("15s"), Some(15_000));
+        assert_eq!(parse_step("0.5"), Some(500));
+        assert_eq!(parse_step("0"), None);
+    }
+
+    #[test]
+    fn answers_in_prometheus_shapes() {
+        let (_dir, db) = test_db("shapes");
+
+        let (status, body) = get(&db, "/api/v1/query?query=requests_total%7Bhost%3D%22a%22%7D&time=60");
+        assert_eq!(status, 200);
+        assert_eq!(body, r#"{"status":"success","data":{"resultType":"vector","result":[{"metric":{"__name__":"requests_total","host":"a"},"value":[60,"6"]}]}}"#);
+
+        let (_, body) = get(&db, "/api/v1/query?query=sum(requests_total)%20*%202&time=60");
+        assert_eq!(body, r#"{"status":"success","data":{"resultType":"vector","result":[{"metric":{},"value":[60,"24"]}]}}"#);
+
+        let (_, body) = get(&db, "/api/v1/query?query=1%2B1&time=1.5");
+        assert_eq!(body, r#"{"status":"success","data":{"resultType":"scalar","result":[1.5,"2"]}}"#);
+
+        let (status, body) = get(&db, "/api/v1/query_range?query=max(requests_total)&start=20&end=40&step=10s");
+        assert_eq!(status, 200);
+        assert_eq!(body, r#"{"status":"success","data":{"resultType":"matrix","result":[{"metric":{},"values":[[20,"2"],[30,"3"],[40,"4"]]}]}}"#);
+
+        let (_, body) = get(&db, "/api/v1/series?match[]=requests_total%7Bhost%3D%22b%22%7D");
+        assert_eq!(body, r#"{"status":"success","data":[{"__name__":"requests_total","host":"b"}]}"#);
+        let (_, body) = get(&db, "/api/v1/labels");
+        assert_eq!(body, r#"{"status":"success","data":["__name__","host"]}"#);
+        let (_, body) = get(&db, "/api/v1/label/host/values?match[]=requests_total");
+        assert_eq!(body, r#"{"status":"success","data":["a","b"]}"#);
+        let (_, body) = get(&db, "/api/v1/label/__name__/values");
+        assert_eq!(body, r#"{"status":"success","data":["requests_total"]}"#);
+        let (_, body) = get(&db, "/api/v1/metadata");
+        assert_eq!(body, r#"{"status":"success","data":{"requests_total":[{"type":"counter","help":"","unit":""}]}}"#);
+        let (_, body) = get(&db, "/api/v1/metadata?metric=up");
+        assert_eq!(body, r#"{"status":"success","data":{}}"#);
+    }
+
+    #[test]
+    fn reports_errors_in_prometheus_envelope() {
+        let (_dir, db) = test_db("errors");
+
+        let (status, body) = get(&db, "/api/v1/query?query=sum(");
+        assert_eq!(status, 400);
+        assert!(body.starts_with(r#"{"status":"error","errorType":"bad_data","error":"#));
+        assert_eq!(get(&db, "/api/v1/query").0, 400);
+        assert_eq!(get(&db, "/api/v1/query?query=up&time=yesterday").0, 400);
+        assert_eq!(get(&db, "/api/v1/query_range?query=up&start=10&end=0&step=1").0, 400);
+        assert_eq!(get(&db, "/api/v1/series").0, 400);
+        assert_eq!(get(&db, "/api/v1/series?match[]=rate(up[5m])").0, 400);
+
+        let (status, body) = get(&db, "/api/v1/query_range?query=requests_total[1m]&start=10&end=20&step=10");
+        assert_eq!(status, 422);
+        assert!(body.contains(r#""errorType":"execution""#));
+        assert_eq!(get(&db, "/api/v1/nope").0, 404);
+    }
+}
@@ -0,0 +1,102 @@
+use std::sync::Arc;
+
+use lib::{ingest::{pipeline::Pipeline, remote_write}, models::RejectCode, tenant::Tenant};
+
+use crate::http::{api::turned_away, request::Request, response::Response};
+
+const PROTOBUF: &str = "application/x-protobuf";
+
+/// Receives Prometheus remote write 1.0. Prometheus retries on 5xx and 429 and drops the
+/// batch on any other 4xx, so only failures that could go away on their own are 5xx.
+pub fn handle(request: &Request, tenant: &Arc<Tenant>, pipeline: &Pipeline) -> Response {
+    if request.method != "POST" {
+        return Response::error(405, &format!("{} is not allowed here", request.method)).with_header("Allow", "POST");
+    }
+    if let Some(content_type) = request.content_type() && content_type != PROTOBUF {
+        return Response::error(415, &format!("Can't read {}, send {}", content_type, PROTOBUF));
+    }
+    if !request.header("content-encoding").is_some_and(|encoding| encoding.trim().eq_ignore_ascii_case("snappy")) {
+        return Response::error(415, "The body must be snappy compressed");
+    }
+    if let Some(version) = request.header("x-prometheus-remote-write-version") && !version.starts_with("0.1") {
+        return Response::error(415, &format!("Remote write version {} is not supported, use 0.1.0", version));
+    }
+
+    let decoded = match remote_write::decode(&request.body) {
+        Ok(decoded) => decoded,
+        Err(e) => return Response::error(400, &e.to_string())
+    };
+    let result = pipeline.ingest(tenant, decoded);
+    if let Some(response) = turned_away(&result) {
+        return response;
+    }
+
+    if result.errors.iter().any(|error| error.code == RejectCode::WalAppendFailed) {
+        return Response::error(500, "Failed to persist samples, retry later");
+    }
+    match result.errors.first() {
+        None => Response::new(204, "text/plain", Vec::new()),
+        Some(first) => Response::error(400, &format!("Rejected {} of {} samples, the first at index {}: {}",
+            result.rejected, result.accepted + result.rejected, first.index, first.reason))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib::{db::MetricsDb, ingest::{pipeline::PipelineOptions, protobuf::{write_bytes_field, write_double_field, write_varint_field}, relabel::Relabeler, snappy}, models::Metric, storage::StorageOptions, tenant::{TenantSettings, Tenants}, testing::TempDir};
+
+    fn test_tenants(test: &str) -> (TempDir, Tenants) {
+        let dir = TempDir::new(&format!("remote_write_{}", test));
+        let options = StorageOptions { data_dir: dir.to_path_buf(), ..StorageOptions::default() };
+        (dir, Tenants::new(options.clone(), MetricsDb::open_with(&options).unwrap(), TenantSettings::default()))
+    }
+
+    fn request(headers: &[(&str, &str)], body: Vec<u8>) -> Request {
+        Request {
+            method: String::from("POST"),
+            path: String::from("/api/v1/write"),
+            query: Vec::new(),
+            headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            body,
+            keep_alive: true
+        }
+    }
+
+    /// A `WriteRequest` with one series holding one sample at timestamp 1000.
+    fn write_request(labels: &[(&str, &str)]) -> Vec<u8> {
+        let mut series = Vec::new();
+        for (name, value) in labels {
+            let mut label = Vec::new();
+            write_bytes_field(&mut label, 1, name.as_bytes());
+            write_bytes_field(&mut label, 2, value.as_bytes());
+            write_bytes_field(&mut series, 1, &label);
+        }
+        let mut sample = Vec::new();
+        write_double_field(&mut sample, 1, 2.5);
+        write_varint_field(&mut sample, 2, 1000);
+        write_bytes_field(&mut series, 2, &sample);
+
+        let mut request = Vec::new();
+        write_bytes_field(&mut request, 1, &series);
+        snappy::compress(&request)
+    }
+
+    #[test]
+    fn stores_remote_writes() {
+        let (_dir, tenants) = test_tenants("stores");
+        let tenant = tenants.default_tenant();
+        let pipeline = Pipeline::start(Arc::new(Relabeler::default()), PipelineOptions::default()).unwrap();
+        let headers = [("content-type", PROTOBUF), ("content-encoding", "snappy"), ("x-prometheus-remote-write-version", "0.1.0")];
+
+        let response = handle(&request(&headers, write_request(&[("__name__", "up"), ("job", "node")])), &tenant, &pipeline);
+        assert_eq!(response.status, 204);
+        let stored = tenant.db().read().unwrap().query("up").unwrap().clone();
+        assert_eq!(stored, vec![Metric { timestamp: 1000, value: 2.5, name: String::from("up"), labels: vec![(String::from("job"), String::from("node"))] }]);
+
+        assert_eq!(handle(&request(&headers, write_request(&[("job", "node")])), &tenant, &pipeline).status, 400);
+        assert_eq!(handle(&request(&headers, b"garbage".to_vec()), &tenant, &pipeline).status, 400);
+        assert_eq!(handle(&request(&[("content-type", PROTOBUF)], write_request(&[("__name__", "up")])), &tenant, &pipeline).status, 415);
+        assert_eq!(handle(&request(&[("content-encoding", "snappy"), ("x-prometheus-remote-write-version", "2.0.0")], Vec::new()), &tenant, &pipeline).status, 415);
+    }
+}
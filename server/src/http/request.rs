@@ -0,0 +1,225 @@
+use tokio::io::{AsyncBufRead, AsyncReadExt};
+
+use crate::http::{chunked::{read_chunked, read_line, ChunkedError, LineError}, response::Response};
+
+/// Request line plus headers, which is plenty for anything we serve.
+const MAX_HEAD_LEN: usize = 64 * 1024;
+
+/// Same cap as a TCP frame payload.
+pub const MAX_BODY_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Decoded query string parameters, in order. Names can repeat.
+    pub query: Vec<(String, String)>,
+    /// Header names are lowercased.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Whether the client expects the connection to stay open after the response.
+    pub keep_alive: bool
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    /// The media type of the body without its parameters, lowercased.
+    pub fn content_type(&self) -> Option<String> {
+        self.header("content-type").map(|value| value.split(';').next().unwrap_or("").trim().to_ascii_lowercase())
+    }
+}
+
+/// Anything that stops a request from being read. The response is sent before the
+/// connection is closed, since the stream can't be trusted to be at a request boundary.
+#[derive(Debug)]
+pub enum ReadError {
+    Io(std::io::Error),
+    Http(Response)
+}
+
+impl From<std::io::Error> for ReadError {
+    fn from(e: std::io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+impl From<LineError> for ReadError {
+    fn from(e: LineError) -> Self {
+        match e {
+            LineError::Io(e) => ReadError::Io(e),
+            LineError::TooLong => ReadError::Http(Response::error(431, "Request head is too large")),
+            LineError::Closed => bad_request("Connection closed mid line"),
+            LineError::NotUtf8 => bad_request("Request head is not valid utf-8")
+        }
+    }
+}
+
+fn bad_request(message: &str) -> ReadError {
+    ReadError::Http(Response::error(400, message))
+}
+
+/// Reads the next request. `Ok(None)` means the client closed the connection between requests.
+pub async fn read_request(reader: &mut (impl AsyncBufRead + Unpin)) -> Result<Option<Request>, ReadError> {
+    let mut head_len = 0;
+    // Every line of the head counts against `MAX_HEAD_LEN`.
+    let request_line = match read_line(reader, &mut head_len, MAX_HEAD_LEN).await? {
+        Some(line) => line,
+        None => return Ok(None)
+    };
+
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) if !method.is_empty() => (method, target, version),
+        _ => return Err(bad_request("Malformed request line"))
+    };
+    let mut keep_alive = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return Err(ReadError::Http(Response::error(505, "Only HTTP/1.0 and HTTP/1.1 are supported")))
+    };
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, parse_form(query).ok_or_else(|| bad_request("Malformed query string"))?),
+        None => (target, Vec::new())
+    };
+    let path = percent_decode(path, false).ok_or_else(|| bad_request("Malformed request path"))?;
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader, &mut head_len, MAX_HEAD_LEN).await?
+            .ok_or_else(|| bad_request("Connection closed inside the request head"))?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| bad_request("Malformed header"))?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+    }
+
+    let mut request = Request { method: method.to_string(), path, query, headers, body: Vec::new(), keep_alive };
+    if let Some(connection) = request.header("connection") {
+        let connection = connection.to_ascii_lowercase();
+        if connection.contains("close") {
+            keep_alive = false;
+        } else if connection.contains("keep-alive") {
+            keep_alive = true;
+        }
+    }
+    request.keep_alive = keep_alive;
+
+    if let Some(encoding) = request.header("transfer-encoding") {
+        if !encoding.eq_ignore_ascii_case("chunked") {
+            return Err(ReadError::Http(Response::error(501, "Only chunked transfer encoding is supported")));
+        }
+        request.body = read_chunked(reader, MAX_BODY_LEN).await.map_err(|e| match e {
+            ChunkedError::Io(e) => ReadError::Io(e),
+            ChunkedError::TooLarge => ReadError::Http(Response::error(413, &format!("Body is larger than {} bytes", MAX_BODY_LEN))),
+            ChunkedError::Invalid(message) => bad_request(message)
+        })?;
+    } else if let Some(length) = request.header("content-length") {
+        let length: usize = length.parse().map_err(|_| bad_request("Invalid Content-Length"))?;
+        if length > MAX_BODY_LEN {
+            return Err(ReadError::Http(Response::error(413, &format!("Body is larger than {} bytes", MAX_BODY_LEN))));
+        }
+        request.body = vec![0; length];
+        reader.read_exact(&mut request.body).await?;
+    }
+
+    Ok(Some(request))
+}
+
+/// `name=value&...` pairs, as found in query strings and urlencoded form bodies.
+pub fn parse_form(input: &str) -> Option<Vec<(String, String)>> {
+    input.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Some((percent_decode(name, true)?, percent_decode(value, true)?))
+        })
+        .collect()
+}
+
+/// Decodes `%XX` escapes, and `+` as a space when `plus_as_space` is set (query strings and
+/// form bodies). Returns `None` for a broken escape or bytes that aren't utf-8.
+pub fn percent_decode(input: &str, plus_as_space: bool) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                out.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            },
+            b'+' if plus_as_space => {
+                out.push(b' ');
+                i += 1;
+            },
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(raw: &[u8]) -> Result<Option<Request>, ReadError> {
+        read_request(&mut tokio::io::BufReader::new(raw)).await
+    }
+
+    #[tokio::test]
+    async fn parses_requests() {
+        let raw = b"POST /api/query?selector=cpu%7Bhost%3D%22a%22%7D&time=10&x HTTP/1.1\r\nHost: x\r\nContent-Type: application/json; charset=utf-8\r\nContent-Length: 2\r\n\r\n[]";
+        let request = read(raw).await.unwrap().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/query");
+        assert_eq!(request.query, vec![
+            (String::from("selector"), String::from("cpu{host=\"a\"}")),
+            (String::from("time"), String::from("10")),
+            (String::from("x"), String::new())
+        ]);
+        assert_eq!(request.content_type().as_deref(), Some("application/json"));
+        assert_eq!(request.body, b"[]");
+        assert!(request.keep_alive);
+
+        assert!(read(b"").await.unwrap().is_none());
+        let request = read(b"GET / HTTP/1.0\r\n\r\n").await.unwrap().unwrap();
+        assert!(!request.keep_alive);
+    }
+
+    #[tokio::test]
+    async fn reads_chunked_bodies() {
+        let raw = b"POST /api/write HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2;ext=1\r\nde\r\n0\r\n\r\n";
+        let request = read(raw).await.unwrap().unwrap();
+        assert_eq!(request.body, b"abcde");
+
+        // A size near usize::MAX must not wrap around the body limit.
+        let raw = b"POST /api/write HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\nffffffffffffffff\r\n";
+        assert!(matches!(read(raw).await, Err(ReadError::Http(response)) if response.status == 413));
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_requests() {
+        let status = |result: Result<Option<Request>, ReadError>| match result {
+            Err(ReadError::Http(response)) => response.status,
+            other => panic!("expected an http error, got {:?}", other)
+        };
+        assert_eq!(status(read(b"GET /\r\n\r\n").await), 400);
+        assert_eq!(status(read(b"GET / HTTP/2.0\r\n\r\n").await), 505);
+        assert_eq!(status(read(b"GET / HTTP/1.1\r\nbroken\r\n\r\n").await), 400);
+        assert_eq!(status(read(b"POST / HTTP/1.1\r\nContent-Length: 99999999999\r\n\r\n").await), 413);
+        assert_eq!(status(read(&[b"GET /".as_slice(), &[b'a'; MAX_HEAD_LEN], b" HTTP/1.1\r\n\r\n"].concat()).await), 431);
+        // A head that uses up the budget exactly, with the blank line still to come.
+        let header = [b"X: ".as_slice(), &vec![b'a'; MAX_HEAD_LEN - 21], b"\r\n"].concat();
+        assert_eq!(status(read(&[b"GET / HTTP/1.1\r\n".as_slice(), &header, b"\r\n"].concat()).await), 431);
+        assert_eq!(status(read(b"GET /?a=%zz HTTP/1.1\r\n\r\n").await), 400);
+    }
+}
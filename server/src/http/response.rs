@@ -0,0 +1,150 @@
+use lib::traits::json;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+pub const JSON: &str = "application/json";
+pub const BINARY: &str = "application/octet-stream";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> Self {
+        Response { status, headers: vec![(String::from("Content-Type"), content_type.to_string())], body }
+    }
+
+    pub fn json(status: u16, body: String) -> Self {
+        Self::new(status, JSON, body.into_bytes())
+    }
+
+    /// `{"error": message}` with the given status.
+    pub fn error(status: u16, message: &str) -> Self {
+        let mut body = String::from("{\"error\":");
+        json::write_str(&mut body, message);
+        body.push('}');
+        Self::json(status, body)
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub async fn write_to(&self, writer: &mut (impl AsyncWrite + Unpin), keep_alive: bool) -> std::io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        if !keep_alive {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+
+        writer.write_all(head.as_bytes()).await?;
+        writer.write_all(&self.body).await?;
+        writer.flush().await
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Content",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => ""
+    }
+}
+
+/// Picks the type in `offered` the `Accept` header likes best, preferring earlier entries on
+/// a tie. A missing header accepts anything. `None` means nothing offered is acceptable.
+pub fn negotiate(accept: Option<&str>, offered: &[&'static str]) -> Option<&'static str> {
+    let Some(accept) = accept else {
+        return offered.first().copied();
+    };
+
+    let ranges: Vec<(String, f32)> = accept.split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let media = params.next()?.trim().to_ascii_lowercase();
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (!media.is_empty()).then_some((media, q))
+        })
+        .collect();
+
+    let mut best: Option<(&'static str, f32)> = None;
+    for media in offered {
+        let (kind, _) = media.split_once('/').unwrap_or((media, ""));
+        // The most specific matching range decides the quality.
+        let quality = ranges.iter()
+            .filter_map(|(range, q)| {
+                let specificity = if range == media {
+                    2
+                } else if range.strip_suffix("/*") == Some(kind) {
+                    1
+                } else if range == "*/*" {
+                    0
+                } else {
+                    return None;
+                };
+                Some((specificity, *q))
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, q)| q);
+
+        if let Some(q) = quality
+            && q > 0.0
+            && best.is_none_or(|(_, best_q)| q > best_q)
+        {
+            best = Some((media, q));
+        }
+    }
+    best.map(|(media, _)| media)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_content_types() {
+        let offered = [JSON, BINARY];
+        assert_eq!(negotiate(None, &offered), Some(JSON));
+        assert_eq!(negotiate(Some("*/*"), &offered), Some(JSON));
+        assert_eq!(negotiate(Some("application/octet-stream"), &offered), Some(BINARY));
+        assert_eq!(negotiate(Some("application/json;q=0.5, application/*;q=0.8"), &offered), Some(BINARY));
+        assert_eq!(negotiate(Some("text/html, */*;q=0.1"), &offered), Some(JSON));
+        assert_eq!(negotiate(Some("*/*, application/json;q=0"), &offered), Some(BINARY));
+        assert_eq!(negotiate(Some("text/html"), &offered), None);
+    }
+
+    #[tokio::test]
+    async fn writes_responses() {
+        let mut out = Vec::new();
+        Response::error(404, "no \"such\" thing").write_to(&mut out, false).await.unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+            "HTTP/1.1 404 Not Found\r\nContent-Type: application/json\r\nContent-Length: 29\r\nConnection: close\r\n\r\n{\"error\":\"no \\\"such\\\" thing\"}");
+    }
+}
@@ -0,0 +1,87 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use lib::{error, warn, ingest::{influx::{self, Precision}, pipeline::Pipeline}, models::SampleError, tenant::Tenant, time::now_ms};
+use tokio::net::{TcpListener, UdpSocket};
+
+use crate::lines::read_lines;
+use crate::telemetry::Connection;
+
+/// Largest UDP payload there is.
+const MAX_DATAGRAM_LEN: usize = 65_535;
+/// How many failing lines an error message names before it just counts the rest.
+const MAX_REPORTED_LINES: usize = 10;
+
+/// Accepts line protocol over TCP, one task per connection. Writers get no reply, so
+/// failing lines are only logged.
+pub async fn serve_tcp(listener: TcpListener, tenant: Arc<Tenant>, pipeline: Arc<Pipeline>) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!(protocol = "influx", error = e; "Failed to accept connection");
+                continue;
+            }
+        };
+        tokio::spawn({
+            let tenant = tenant.clone();
+            let pipeline = pipeline.clone();
+            async move {
+                let _connection = Connection::open("influx");
+                if let Err(e) = read_lines(socket, |lines| ingest(&tenant, &pipeline, lines, "tcp", addr)).await {
+                    warn!(protocol = "influx", peer = addr, error = e; "Connection closed with error");
+                }
+            }
+        });
+    }
+}
+
+/// Accepts line protocol over UDP. Each datagram holds whole lines.
+pub async fn serve_udp(socket: UdpSocket, tenant: Arc<Tenant>, pipeline: Arc<Pipeline>) {
+    let mut buf = vec![0; MAX_DATAGRAM_LEN];
+    loop {
+        match socket.recv_from(&mut buf).await {
+            Ok((len, addr)) => ingest(&tenant, &pipeline, &buf[..len], "udp", addr),
+            Err(e) => error!(protocol = "influx", error = e; "Failed to receive datagram")
+        }
+    }
+}
+
+/// Hands the lines to the pipeline without waiting, as there's no one to tell how it went.
+fn ingest(tenant: &Arc<Tenant>, pipeline: &Pipeline, data: &[u8], transport: &'static str, peer: SocketAddr) {
+    let Ok(text) = std::str::from_utf8(data) else {
+        warn!(protocol = "influx", transport = transport, peer = peer; "Dropped line protocol that is not valid utf-8");
+        return;
+    };
+    pipeline.submit(tenant, influx::parse(text, Precision::Nanoseconds, now_ms()), move |result| {
+        if !result.errors.is_empty() {
+            warn!(protocol = "influx", transport = transport, peer = peer, rejected = result.rejected; "Rejected line protocol: {}", describe_errors(&result.errors));
+        }
+    });
+}
+
+/// `line 2: reason; line 5: reason`, for errors indexed by line number. A line with several
+/// failing fields is named once.
+pub fn describe_errors(errors: &[SampleError]) -> String {
+    let mut lines: Vec<&SampleError> = errors.iter().collect();
+    lines.dedup_by_key(|error| error.index);
+    let mut parts: Vec<String> = lines.iter().take(MAX_REPORTED_LINES).map(|error| format!("line {}: {}", error.index, error.reason)).collect();
+    if lines.len() > MAX_REPORTED_LINES {
+        parts.push(format!("and {} more lines", lines.len() - MAX_REPORTED_LINES));
+    }
+    parts.join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_errors_by_line() {
+        let errors = [SampleError::new(2, "bad"), SampleError::new(2, "worse"), SampleError::new(7, "Invalid tag")];
+        assert_eq!(describe_errors(&errors), "line 2: bad; line 7: Invalid tag");
+
+        let errors: Vec<SampleError> = (1..=12).map(|line| SampleError::new(line, "bad")).collect();
+        assert!(describe_errors(&errors).ends_with("line 10: bad; and 2 more lines"));
+    }
+}
@@ -0,0 +1,35 @@
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+/// Longest line accepted before the connection is dropped.
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Reads newline separated text until the peer closes, handing each run of complete lines
+/// to `on_lines` as soon as it arrives, so a busy connection is stored one read at a time.
+/// Whatever follows the last newline is handed over at the end.
+pub async fn read_lines(mut stream: TcpStream, mut on_lines: impl FnMut(&[u8])) -> std::io::Result<()> {
+    let mut pending = Vec::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            if !pending.is_empty() {
+                on_lines(&pending);
+            }
+            return Ok(());
+        }
+        pending.extend_from_slice(&buf[..read]);
+
+        match pending.iter().rposition(|b| *b == b'\n') {
+            Some(end) => {
+                let rest = pending.split_off(end + 1);
+                on_lines(&pending);
+                pending = rest;
+            },
+            None if pending.len() > MAX_LINE_LEN => {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Line is longer than {} bytes", MAX_LINE_LEN)));
+            },
+            None => {}
+        }
+    }
+}
@@ -1,73 +1,169 @@
-use std::sync::{Arc, RwLock};
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
-use lib::{db::MetricsDb, models::Metric, traits::serializable::BinarySerializable};
+mod config;
+mod connection;
+mod graphite;
+mod http;
+mod influx;
+mod lines;
+mod scrape;
+mod statsd;
+mod telemetry;
 
-const BIND_ADDRESS: &str = "127.0.0.1:1227";
+use std::sync::Arc;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::mpsc;
+use lib::{db::MetricsDb, debug, error, info, ingest::{graphite::Template, pipeline::Pipeline, relabel::Relabeler}, log, tenant::Tenants, warn, Error};
 
-async fn handle_client(stream: TcpStream, db: &Arc<RwLock<MetricsDb>>) -> tokio::io::Result<()> {
+use config::{reload::Reloader, Args, Config};
+use http::admin::Admin;
+use telemetry::Connection;
 
-    stream.readable().await?;
-    let mut buf_reader = BufReader::new(stream);
-    let data = buf_reader.fill_buf().await?;
-
-    let control_byte = data[0];
-    match control_byte {
-        0 => handle_read(data, db).await,
-        1 => handle_write(data, db).await,
-        _ => {
-            eprintln!("Unknown control byte: {}", control_byte);
-            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Unknown control byte"));
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) if args.help => {
+            println!("{}", config::USAGE);
+            return Ok(());
+        },
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, config::USAGE);
+            return Err(std::io::Error::other(e));
         }
-    }?;
+    };
+    let config = match Config::load(&args) {
+        Ok(config) if args.print_config => {
+            print!("{}", config.to_toml());
+            return Ok(());
+        },
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Err(std::io::Error::other(e));
+        }
+    };
 
-    Ok(())
-}
+    log::configure(config.log.level, config.log.format);
 
-async fn handle_read(data: &[u8], db: &Arc<RwLock<MetricsDb>>) -> tokio::io::Result<()> {
-    let content = &data[1..];
-    let len = u32::from_le_bytes(content[0..4].try_into().unwrap()) as usize;
-    let name = String::from_utf8(content[4..4 + len].to_vec()).unwrap();
+    let listener = if config.binary.enabled {
+        Some(TcpListener::bind(&config.binary.listen).await.map_err(|e| bind_failed("binary", "tcp", &config.binary.listen, e))?)
+    } else {
+        None
+    };
 
-    let guard = db.read().unwrap();
-    let _metrics = guard.query(&name);
+    // The WAL is replayed once HTTP is up, so the probes can say how that's going.
+    let tenants = match MetricsDb::open_unrecovered(&config.storage) {
+        Ok(db) => Arc::new(Tenants::new(config.storage.clone(), db, config.tenant_settings())),
+        Err(e) => {
+            error!(data_dir = config.storage.data_dir.display(), error = e; "Failed to open database");
+            return Err(std::io::Error::other(e));
+        }
+    };
+    // Validated with the rest of the config, so this can't fail.
+    let relabeler = Arc::new(Relabeler::new(config.relabel.clone()).map_err(std::io::Error::other)?);
+    // Its writers only lock a database once samples come in, which waits for the replay.
+    let pipeline = match Pipeline::start(relabeler, config.ingest) {
+        Ok(pipeline) => Arc::new(pipeline),
+        Err(e) => {
+            error!(error = e; "Failed to start the ingest pipeline");
+            return Err(std::io::Error::other(e));
+        }
+    };
+    let (reloads, reload_requests) = mpsc::channel(1);
+    let token = config.admin.token().map_err(|e| {
+        error!(error = e; "Failed to read the admin token");
+        std::io::Error::other(e)
+    })?;
+    let admin = Arc::new(Admin::new(token, reloads));
 
-    //TODO: I guess return the results back to the client??
-    Ok(())
-}
+    if config.http.enabled {
+        let http_listener = TcpListener::bind(&config.http.listen).await.map_err(|e| bind_failed("http", "tcp", &config.http.listen, e))?;
+        info!(protocol = "http", address = config.http.listen; "Listening");
+        tokio::spawn(http::serve(http_listener, tenants.clone(), pipeline.clone(), admin.clone()));
+    }
 
-async fn handle_write(data: &[u8], db: &Arc<RwLock<MetricsDb>>) -> std::io::Result<()> {
-    let content = &data[1..];
-    let mut byte_offset: usize = 0;
-    let metric = Metric::deserialize(content, &mut byte_offset).unwrap();
+    // Nothing else reads or writes until the replay is done. The other tenants are opened
+    // with it, so retention gets to their data even if they don't write again.
+    let replay = tokio::task::spawn_blocking({
+        let tenants = tenants.clone();
+        move || {
+            match tenants.default_tenant().db().write() {
+                Ok(mut db) => db.recover()?,
+                Err(_) => return Err(Error::Invalid(String::from("Database lock is poisoned")))
+            }
+            tenants.open_existing()
+        }
+    });
+    match replay.await.map_err(std::io::Error::other)? {
+        Ok(opened) => {
+            info!(tenants = opened + 1; "Replayed the WAL, ready");
+            admin.set_ready();
+        },
+        Err(e) => {
+            error!(data_dir = config.storage.data_dir.display(), error = e; "Failed to replay the WAL");
+            return Err(std::io::Error::other(e));
+        }
+    }
 
-    let mut guard = db.write().unwrap();
-    guard.ingest(metric);
+    // These protocols have nowhere to name a tenant, so they write to the default one.
+    if config.influx.enabled {
+        let influx_listener = TcpListener::bind(&config.influx.listen).await.map_err(|e| bind_failed("influx", "tcp", &config.influx.listen, e))?;
+        let influx_socket = UdpSocket::bind(&config.influx.listen).await.map_err(|e| bind_failed("influx", "udp", &config.influx.listen, e))?;
+        info!(protocol = "influx", address = config.influx.listen; "Listening on TCP and UDP");
+        tokio::spawn(influx::serve_tcp(influx_listener, tenants.default_tenant(), pipeline.clone()));
+        tokio::spawn(influx::serve_udp(influx_socket, tenants.default_tenant(), pipeline.clone()));
+    }
 
-    Ok(())
-}
+    if config.graphite.enabled {
+        let templates = Arc::new(config.graphite.templates.iter().map(|spec| Template::parse(spec)).collect::<Result<Vec<_>, _>>()
+            .map_err(std::io::Error::other)?);
+        let graphite_listener = TcpListener::bind(&config.graphite.listen).await.map_err(|e| bind_failed("graphite", "tcp", &config.graphite.listen, e))?;
+        let pickle_listener = TcpListener::bind(&config.graphite.pickle_listen).await.map_err(|e| bind_failed("graphite", "tcp", &config.graphite.pickle_listen, e))?;
+        info!(protocol = "graphite", address = config.graphite.listen, pickle_address = config.graphite.pickle_listen; "Listening");
+        tokio::spawn(graphite::serve_plaintext(graphite_listener, tenants.default_tenant(), pipeline.clone(), templates.clone()));
+        tokio::spawn(graphite::serve_pickle(pickle_listener, tenants.default_tenant(), pipeline.clone(), templates));
+    }
 
-#[tokio::main]
-async fn main() -> std::io::Result<()> {
-    let listener = TcpListener::bind(BIND_ADDRESS).await
-        .expect(&format!("Failed to bind to address {}", BIND_ADDRESS)); 
+    if config.statsd.enabled {
+        let statsd_socket = UdpSocket::bind(&config.statsd.listen).await.map_err(|e| bind_failed("statsd", "udp", &config.statsd.listen, e))?;
+        info!(protocol = "statsd", address = config.statsd.listen, flush_interval = config::format_duration(config.statsd.flush_interval); "Listening");
+        tokio::spawn(statsd::serve(statsd_socket, tenants.default_tenant(), pipeline.clone(), config.statsd.flush_interval, config.statsd.percentiles.clone()));
+    }
 
-    //let mut wal_writer = WalWriter::new();    
-    let db = Arc::new(RwLock::new(MetricsDb::new()));
-    //let arena = Arena::new(1024 * 1024); // 1MB capacity
-    println!("Server is listening on {}", BIND_ADDRESS);
+    let binary = config.binary.clone();
+    // Scrape jobs and retention run under the reloader, which takes over the config.
+    let reloader = Reloader::start(args, config, tenants.clone(), pipeline.clone());
+    tokio::spawn(reloader.run(reload_requests));
 
+    let Some(listener) = listener else {
+        // Everything else runs in its own task.
+        std::future::pending::<()>().await;
+        return Ok(());
+    };
+    info!(protocol = "binary", address = binary.listen; "Listening");
     loop {
-        let (socket, addr) = listener.accept().await?;
-        println!("New connection from {}", addr);
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!(protocol = "binary", error = e; "Failed to accept connection");
+                continue;
+            }
+        };
+        debug!(protocol = "binary", peer = addr; "New connection");
         tokio::spawn({
-            let db = db.clone();
+            let tenants = tenants.clone();
+            let pipeline = pipeline.clone();
             async move {
-                handle_client(socket, &db).await.unwrap();
+                let _connection = Connection::open("binary");
+                if let Err(e) = connection::handle_client(socket, addr, &tenants, &pipeline).await {
+                    warn!(protocol = "binary", peer = addr, error = e; "Connection closed with error");
+                }
             }
         });
     }
+}
 
-    Ok(())
+/// Logs a listener that couldn't be bound, which stops startup like any other failure there.
+fn bind_failed(protocol: &str, transport: &str, address: &str, e: std::io::Error) -> std::io::Error {
+    error!(protocol = protocol, transport = transport, address = address, error = e; "Failed to bind");
+    e
 }
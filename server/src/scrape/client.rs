@@ -0,0 +1,138 @@
+use std::io::{Error, ErrorKind};
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::http::chunked::{read_chunked, ChunkedError};
+
+/// Largest response body a scrape may return.
+pub const MAX_BODY_LEN: usize = 64 * 1024 * 1024;
+/// Status line plus headers.
+const MAX_HEAD_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// Sends `GET path` to `address` over plain HTTP/1.1 and reads the whole response. The
+/// connection is closed afterwards. Compressed bodies aren't asked for, since we can't read them.
+pub async fn get(address: &str, path: &str, headers: &[(&str, &str)]) -> std::io::Result<Response> {
+    let mut stream = TcpStream::connect(address).await?;
+    let mut head = format!("GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: MetricHouse\r\nAccept-Encoding: identity\r\nConnection: close\r\n", path, address);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+
+    let mut reader = BufReader::new(stream);
+    let mut head_len = 0;
+    let status_line = read_line(&mut reader, &mut head_len).await?;
+    let status = match status_line.split(' ').collect::<Vec<_>>().as_slice() {
+        [version, status, ..] if version.starts_with("HTTP/1.") => status.parse().map_err(|_| invalid("Invalid status code"))?,
+        _ => return Err(invalid("Malformed status line"))
+    };
+
+    let mut content_type = None;
+    let mut content_length = None;
+    let mut chunked = false;
+    loop {
+        let line = read_line(&mut reader, &mut head_len).await?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid("Malformed header"))?;
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-type" => content_type = Some(value.to_string()),
+            "content-length" => content_length = Some(value.parse::<usize>().map_err(|_| invalid("Invalid Content-Length"))?),
+            "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+            "content-encoding" if !value.eq_ignore_ascii_case("identity") => {
+                return Err(invalid(&format!("Content-Encoding {} is not supported", value)));
+            },
+            _ => {}
+        }
+    }
+
+    let body = if chunked {
+        read_chunked(&mut reader, MAX_BODY_LEN).await.map_err(|e| match e {
+            ChunkedError::Io(e) => e,
+            ChunkedError::TooLarge => invalid(&format!("Body is larger than {} bytes", MAX_BODY_LEN)),
+            ChunkedError::Invalid(message) => invalid(message)
+        })?
+    } else if let Some(length) = content_length {
+        if length > MAX_BODY_LEN {
+            return Err(invalid(&format!("Body is larger than {} bytes", MAX_BODY_LEN)));
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).await?;
+        body
+    } else {
+        let mut body = Vec::new();
+        (&mut reader).take(MAX_BODY_LEN as u64 + 1).read_to_end(&mut body).await?;
+        if body.len() > MAX_BODY_LEN {
+            return Err(invalid(&format!("Body is larger than {} bytes", MAX_BODY_LEN)));
+        }
+        body
+    };
+    Ok(Response { status, content_type, body })
+}
+
+async fn read_line(reader: &mut (impl AsyncBufRead + Unpin), head_len: &mut usize) -> std::io::Result<String> {
+    let mut line = Vec::new();
+    let read = (&mut *reader).take((MAX_HEAD_LEN - *head_len) as u64).read_until(b'\n', &mut line).await?;
+    *head_len += read;
+    if line.last() != Some(&b'\n') {
+        return Err(invalid(if *head_len >= MAX_HEAD_LEN { "Response head is too large" } else { "Connection closed mid line" }));
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| invalid("Response head is not valid utf-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Answers one connection with `response`, and hands back the request it got.
+    async fn serve_once(response: &'static [u8]) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let len = socket.read(&mut request).await.unwrap();
+            socket.write_all(response).await.unwrap();
+            String::from_utf8(request[..len].to_vec()).unwrap()
+        });
+        (address, handle)
+    }
+
+    #[tokio::test]
+    async fn reads_responses() {
+        let (address, handle) = serve_once(b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nup \r\n2\r\n1\n\r\n0\r\n\r\n").await;
+        let response = get(&address, "/metrics", &[("Accept", "text/plain")]).await.unwrap();
+        assert_eq!(response, Response { status: 200, content_type: Some(String::from("text/plain")), body: b"up 1\n".to_vec() });
+        assert!(handle.await.unwrap().starts_with("GET /metrics HTTP/1.1\r\n"));
+
+        let (address, _) = serve_once(b"HTTP/1.0 404 Not Found\r\n\r\ngone").await;
+        let response = get(&address, "/metrics", &[]).await.unwrap();
+        assert_eq!((response.status, response.body), (404, b"gone".to_vec()));
+
+        let (address, _) = serve_once(b"HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\n\r\n").await;
+        assert!(get(&address, "/metrics", &[]).await.is_err());
+
+        let (address, _) = serve_once(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n1\r\nu\r\nffffffffffffffff\r\n").await;
+        assert!(get(&address, "/metrics", &[]).await.unwrap_err().to_string().starts_with("Body is larger"));
+    }
+}
@@ -0,0 +1,114 @@
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use lib::{traits::json::{self, JsonValue}, warn, Error};
+
+/// Targets that share labels, as listed in a file SD file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TargetGroup {
+    /// `host:port` addresses.
+    pub targets: Vec<String>,
+    pub labels: Vec<(String, String)>
+}
+
+/// Parses a file SD file: a JSON list of `{"targets": ["host:port", ...], "labels": {...}}`,
+/// the format Prometheus reads. `labels` may be left out.
+pub fn parse_file(input: &str) -> Result<Vec<TargetGroup>, Error> {
+    let invalid = |detail: &str| Error::Invalid(format!("File SD: {}", detail));
+    let groups = json::parse(input)?;
+    let groups = groups.as_array().ok_or_else(|| invalid("expected a list of target groups"))?;
+    groups.iter().map(|group| {
+        let targets = group.get("targets").and_then(JsonValue::as_array).ok_or_else(|| invalid("a group has no \"targets\" list"))?
+            .iter()
+            .map(|target| match target.as_str() {
+                Some(target) if valid_address(target) => Ok(target.to_string()),
+                _ => Err(invalid(&format!("{:?} is not a host:port address", target)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let labels = match group.get("labels") {
+            None => Vec::new(),
+            Some(labels) => labels.as_object().ok_or_else(|| invalid("\"labels\" must be an object"))?
+                .iter()
+                .map(|(key, value)| value.as_str().map(|value| (key.clone(), value.to_string()))
+                    .ok_or_else(|| invalid(&format!("label {} must be a string", key))))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        Ok(TargetGroup { targets, labels })
+    }).collect()
+}
+
+/// Only plain `host:port` is scraped; schemes and paths come from the job.
+pub fn valid_address(address: &str) -> bool {
+    address.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && !host.contains('/') && port.parse::<u16>().is_ok())
+}
+
+/// Watches file SD files by their modification time.
+pub struct FileDiscovery {
+    files: Vec<PathBuf>,
+    /// Per file: when it was last read, and the groups it held then.
+    state: Vec<(Option<SystemTime>, Vec<TargetGroup>)>,
+    started: bool
+}
+
+impl FileDiscovery {
+    pub fn new(files: Vec<PathBuf>) -> Self {
+        let state = vec![(None, Vec::new()); files.len()];
+        FileDiscovery { files, state, started: false }
+    }
+
+    /// Every group across the files, if this is the first call or any file changed since the
+    /// last. A file that is missing has no targets; one that fails to parse keeps the groups
+    /// it last had, so a half-written file doesn't drop every target.
+    pub fn refresh(&mut self) -> Option<Vec<TargetGroup>> {
+        let mut changed = !self.started;
+        self.started = true;
+        for (file, (seen, groups)) in self.files.iter().zip(&mut self.state) {
+            let modified = std::fs::metadata(file).and_then(|metadata| metadata.modified()).ok();
+            if modified == *seen {
+                continue;
+            }
+            *seen = modified;
+            changed = true;
+            if modified.is_none() {
+                groups.clear();
+                continue;
+            }
+            match std::fs::read_to_string(file).map_err(Error::from).and_then(|input| parse_file(&input)) {
+                Ok(parsed) => *groups = parsed,
+                Err(e) => warn!(file = file.display(), error = e; "Failed to read targets, keeping the last ones")
+            }
+        }
+        changed.then(|| self.state.iter().flat_map(|(_, groups)| groups.clone()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib::testing::TempDir;
+
+    #[test]
+    fn parses_and_watches_files() {
+        let groups = parse_file(r#"[{"targets": ["a:9100", "b:9100"], "labels": {"env": "prod"}}, {"targets": []}]"#).unwrap();
+        assert_eq!(groups[0], TargetGroup {
+            targets: vec![String::from("a:9100"), String::from("b:9100")],
+            labels: vec![(String::from("env"), String::from("prod"))]
+        });
+        assert!(groups[1].targets.is_empty());
+        assert!(parse_file(r#"[{"targets": ["http://a:9100/metrics"]}]"#).is_err());
+        assert!(parse_file(r#"{"targets": []}"#).is_err());
+
+        let dir = TempDir::new("file_sd");
+        let file = dir.join("targets.json");
+        let mut discovery = FileDiscovery::new(vec![file.clone()]);
+        assert_eq!(discovery.refresh(), Some(Vec::new()));
+        assert_eq!(discovery.refresh(), None);
+
+        std::fs::write(&file, r#"[{"targets": ["a:9100"]}]"#).unwrap();
+        assert_eq!(discovery.refresh().unwrap()[0].targets, vec![String::from("a:9100")]);
+        assert_eq!(discovery.refresh(), None);
+
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(discovery.refresh(), Some(Vec::new()));
+    }
+}
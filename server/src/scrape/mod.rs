@@ -0,0 +1,331 @@
+pub mod client;
+pub mod discovery;
+
+use std::collections::{hash_map::Entry, BTreeMap, HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use lib::{ingest::{exposition::{self, Exposition, Format}, pipeline::Pipeline}, models::{Metric, MetricKind}, query::range::stale_marker, tenant::{validate_id, Tenant, Tenants, DEFAULT_TENANT}, time::now_ms, error, info, warn, Error};
+use tokio::sync::oneshot;
+use tokio::time::{Instant, MissedTickBehavior};
+
+use crate::influx::describe_errors;
+use crate::scrape::discovery::{valid_address, FileDiscovery};
+
+/// How often file SD files are checked for changes.
+const FILE_SD_REFRESH: Duration = Duration::from_secs(5);
+const ACCEPT: &str = "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5,*/*;q=0.1";
+/// Series written about every scrape, besides what the target exposes.
+const UP: &str = "up";
+const SCRAPE_DURATION: &str = "scrape_duration_seconds";
+const SAMPLES_SCRAPED: &str = "scrape_samples_scraped";
+
+/// Targets scraped the same way.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    /// The `job` label of everything scraped.
+    pub name: String,
+    /// `host:port` addresses, scraped along with those listed in `file_sd`.
+    pub targets: Vec<String>,
+    /// JSON files listing more targets, reread when they change.
+    pub file_sd: Vec<PathBuf>,
+    pub interval: Duration,
+    /// How long a scrape may take, at most `interval`.
+    pub timeout: Duration,
+    pub metrics_path: String,
+    /// Whose data the samples become.
+    pub tenant: String
+}
+
+impl Job {
+    /// A job without targets that scrapes `/metrics` every 15 seconds, with a 10 second timeout,
+    /// for the default tenant.
+    pub fn new(name: &str) -> Self {
+        Job {
+            name: name.to_string(),
+            targets: Vec::new(),
+            file_sd: Vec::new(),
+            interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(10),
+            metrics_path: String::from("/metrics"),
+            tenant: String::from(DEFAULT_TENANT)
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |detail: String| Err(Error::Invalid(format!("Scrape job {:?}: {}", self.name, detail)));
+        if self.name.is_empty() {
+            return invalid(String::from("the name is empty"));
+        }
+        if self.interval.is_zero() || self.timeout.is_zero() || self.timeout > self.interval {
+            return invalid(format!("the timeout {:?} must be positive and at most the interval {:?}", self.timeout, self.interval));
+        }
+        if !self.metrics_path.starts_with('/') {
+            return invalid(format!("the metrics path {:?} must start with /", self.metrics_path));
+        }
+        if let Err(e) = validate_id(&self.tenant) {
+            return invalid(e.to_string());
+        }
+        match self.targets.iter().find(|target| !valid_address(target)) {
+            Some(target) => invalid(format!("{:?} is not a host:port address", target)),
+            None => Ok(())
+        }
+    }
+}
+
+/// One endpoint of a job.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Target {
+    pub address: String,
+    /// `job`, `instance` and the labels of its target group, sorted. Every series scraped from
+    /// the target gets them.
+    pub labels: Vec<(String, String)>
+}
+
+impl Target {
+    /// Labels from the target group override `job` and `instance`.
+    fn new(job: &Job, address: &str, group_labels: &[(String, String)]) -> Self {
+        let mut labels = BTreeMap::from([
+            (String::from("job"), job.name.clone()),
+            (String::from("instance"), address.to_string())
+        ]);
+        labels.extend(group_labels.iter().cloned());
+        Target { address: address.to_string(), labels: labels.into_iter().collect() }
+    }
+}
+
+/// Scrapes every target of `job`, starting and stopping targets as its file SD files change.
+pub async fn run(job: Job, tenants: Arc<Tenants>, pipeline: Arc<Pipeline>) {
+    let tenant = match tenants.get(&job.tenant) {
+        Ok(tenant) => tenant,
+        Err(e) => {
+            error!(job = job.name, tenant = job.tenant, error = e; "Failed to open the tenant, the job isn't scraped");
+            return;
+        }
+    };
+    let job = Arc::new(job);
+    let mut discovery = FileDiscovery::new(job.file_sd.clone());
+    // Dropping a target's sender stops it.
+    let mut running: HashMap<Target, oneshot::Sender<()>> = HashMap::new();
+    let mut refresh = tokio::time::interval(FILE_SD_REFRESH);
+    loop {
+        refresh.tick().await;
+        let Some(groups) = discovery.refresh() else {
+            continue;
+        };
+        let mut targets: HashSet<Target> = job.targets.iter().map(|address| Target::new(&job, address, &[])).collect();
+        for group in &groups {
+            targets.extend(group.targets.iter().map(|address| Target::new(&job, address, &group.labels)));
+        }
+
+        running.retain(|target, _| targets.contains(target));
+        for target in targets {
+            if let Entry::Vacant(entry) = running.entry(target) {
+                let (stop, stopped) = oneshot::channel();
+                tokio::spawn(scrape_loop(Scraper::new(job.clone(), entry.key().clone(), tenant.clone(), pipeline.clone()), stopped));
+                entry.insert(stop);
+            }
+        }
+    }
+}
+
+async fn scrape_loop(mut scraper: Scraper, mut stopped: oneshot::Receiver<()>) {
+    // Targets start at different points of the interval so they aren't all scraped at once.
+    let mut hasher = DefaultHasher::new();
+    scraper.target.hash(&mut hasher);
+    let interval = scraper.job.interval;
+    let offset = Duration::from_millis(hasher.finish() % interval.as_millis().max(1) as u64);
+    let mut ticker = tokio::time::interval_at(Instant::now() + offset, interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => scraper.scrape().await,
+            _ = &mut stopped => {
+                scraper.stop();
+                return;
+            }
+        }
+    }
+}
+
+type SeriesId = (String, Vec<(String, String)>);
+
+/// Scrapes one target and remembers what it exposed, so series that go away get a stale marker.
+struct Scraper {
+    job: Arc<Job>,
+    target: Target,
+    tenant: Arc<Tenant>,
+    pipeline: Arc<Pipeline>,
+    previous: HashSet<SeriesId>,
+    last_error: Option<String>
+}
+
+impl Scraper {
+    fn new(job: Arc<Job>, target: Target, tenant: Arc<Tenant>, pipeline: Arc<Pipeline>) -> Self {
+        Scraper { job, target, tenant, pipeline, previous: HashSet::new(), last_error: None }
+    }
+
+    async fn scrape(&mut self) {
+        let timestamp = now_ms();
+        let started = Instant::now();
+        let result = match tokio::time::timeout(self.job.timeout, self.fetch(timestamp)).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("Timed out after {:?}", self.job.timeout))
+        };
+        let duration = started.elapsed().as_secs_f64();
+
+        match &result {
+            Err(e) if self.last_error.as_ref() != Some(e) => {
+                warn!(job = self.job.name, target = self.target.address, error = e; "Scrape failed");
+            },
+            Ok(_) if self.last_error.is_some() => info!(job = self.job.name, target = self.target.address; "Scrape is back up"),
+            _ => {}
+        }
+        self.last_error = result.as_ref().err().cloned();
+
+        let (up, exposition) = match result {
+            Ok(exposition) => (1.0, exposition),
+            Err(_) => (0.0, Exposition::default())
+        };
+        let scraped = exposition.samples.0.len();
+        let mut metrics: Vec<Metric> = exposition.samples.0.into_iter().map(|(_, metric)| self.attach_labels(metric)).collect();
+        let current: HashSet<SeriesId> = metrics.iter().map(|metric| (metric.name.clone(), metric.labels.clone())).collect();
+        metrics.extend(self.previous.difference(&current).map(|(name, labels)| {
+            Metric { timestamp, value: stale_marker(), name: name.clone(), labels: labels.clone() }
+        }));
+        self.previous = current;
+        for (name, value) in [(UP, up), (SCRAPE_DURATION, duration), (SAMPLES_SCRAPED, scraped as f64)] {
+            metrics.push(Metric { timestamp, value, name: name.to_string(), labels: self.target.labels.clone() });
+        }
+        self.store(metrics, &exposition.kinds);
+    }
+
+    async fn fetch(&self, timestamp: u64) -> Result<Exposition, String> {
+        let timeout = self.job.timeout.as_secs_f64().to_string();
+        let headers = [("Accept", ACCEPT), ("X-Prometheus-Scrape-Timeout-Seconds", timeout.as_str())];
+        let response = client::get(&self.target.address, &self.job.metrics_path, &headers).await.map_err(|e| e.to_string())?;
+        if response.status != 200 {
+            return Err(format!("Server returned HTTP status {}", response.status));
+        }
+        let body = std::str::from_utf8(&response.body).map_err(|_| String::from("Body is not valid utf-8"))?;
+        let format = Format::from_content_type(response.content_type.as_deref().unwrap_or(""));
+        let exposition = exposition::parse(body, format, timestamp);
+        // As in Prometheus, one bad line fails the whole scrape.
+        if !exposition.samples.1.is_empty() {
+            return Err(describe_errors(&exposition.samples.1));
+        }
+        Ok(exposition)
+    }
+
+    /// The target's labels win over the scraped ones, which are kept as `exported_<name>`.
+    fn attach_labels(&self, mut metric: Metric) -> Metric {
+        for (key, _) in &mut metric.labels {
+            if self.target.labels.iter().any(|(target_key, _)| target_key == key) {
+                *key = format!("exported_{}", key);
+            }
+        }
+        metric.labels.extend(self.target.labels.iter().cloned());
+        metric.labels.sort();
+        metric
+    }
+
+    /// Marks everything the target exposed stale, for when it's no longer scraped.
+    fn stop(&mut self) {
+        let timestamp = now_ms();
+        let mut metrics: Vec<Metric> = self.previous.drain()
+            .map(|(name, labels)| Metric { timestamp, value: stale_marker(), name, labels })
+            .collect();
+        for name in [UP, SCRAPE_DURATION, SAMPLES_SCRAPED] {
+            metrics.push(Metric { timestamp, value: stale_marker(), name: name.to_string(), labels: self.target.labels.clone() });
+        }
+        self.store(metrics, &[]);
+    }
+
+    /// Series are tracked as scraped, before the pipeline relabels them, which maps a stale
+    /// marker to the same series as the samples before it. Kinds are set straight away, so
+    /// they're known by the time the samples are stored.
+    fn store(&self, metrics: Vec<Metric>, kinds: &[(String, MetricKind)]) {
+        {
+            let Ok(mut db) = self.tenant.db().write() else {
+                error!(job = self.job.name, target = self.target.address; "Dropped scrape: database lock is poisoned");
+                return;
+            };
+            for (name, kind) in kinds.iter().map(|(name, kind)| (name.as_str(), *kind))
+                .chain([(UP, MetricKind::Gauge), (SCRAPE_DURATION, MetricKind::Gauge), (SAMPLES_SCRAPED, MetricKind::Gauge)]) {
+                db.set_kind(name, kind);
+            }
+        }
+        let (job, target) = (self.job.name.clone(), self.target.address.clone());
+        self.pipeline.submit_metrics(&self.tenant, metrics, move |result| {
+            if let Some(first) = result.errors.first() {
+                warn!(job = job, target = target, rejected = result.rejected, code = first.code; "Rejected scraped samples, the first: {}", first.reason);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib::{db::MetricsDb, ingest::{pipeline::PipelineOptions, relabel::Relabeler}, storage::StorageOptions, tenant::TenantSettings, testing::TempDir};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn test_tenants(test: &str) -> (TempDir, Tenants) {
+        let dir = TempDir::new(&format!("scrape_{}", test));
+        let options = StorageOptions { data_dir: dir.to_path_buf(), ..StorageOptions::default() };
+        (dir, Tenants::new(options.clone(), MetricsDb::open_with(&options).unwrap(), TenantSettings::default()))
+    }
+
+    fn value(tenant: &Tenant, name: &str) -> Vec<f64> {
+        tenant.db().read().unwrap().query(name).unwrap().iter().map(|metric| metric.value).collect()
+    }
+
+    #[test]
+    fn validates_jobs() {
+        assert!(Job::new("node").validate().is_ok());
+        assert!(Job { timeout: Duration::from_secs(20), ..Job::new("node") }.validate().is_err());
+        assert!(Job { targets: vec![String::from("localhost")], ..Job::new("node") }.validate().is_err());
+        assert!(Job { metrics_path: String::from("metrics"), ..Job::new("node") }.validate().is_err());
+        assert!(Job { tenant: String::new(), ..Job::new("node") }.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn scrapes_targets() {
+        let (_dir, tenants) = test_tenants("targets");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let body = "# TYPE requests_total counter\nrequests_total{job=\"app\",path=\"/\"} 7\n";
+            let response = format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+            // Answers once, then closes so the next scrape fails.
+            let (mut socket, _) = listener.accept().await.unwrap();
+            assert!(socket.read(&mut [0; 4096]).await.unwrap() > 0);
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let job = Job { targets: vec![address.clone()], tenant: String::from("team-a"), ..Job::new("node") };
+        let target = Target::new(&job, &address, &[(String::from("env"), String::from("test"))]);
+        let tenant = tenants.get(&job.tenant).unwrap();
+        let pipeline = Arc::new(Pipeline::start(Arc::new(Relabeler::default()), PipelineOptions::default()).unwrap());
+        let mut scraper = Scraper::new(Arc::new(job), target, tenant.clone(), pipeline.clone());
+        scraper.scrape().await;
+        pipeline.wait();
+
+        let stored = tenant.db().read().unwrap().query("requests_total").unwrap()[0].clone();
+        let label = |k: &str, v: &str| (k.to_string(), v.to_string());
+        assert_eq!(stored.labels, vec![label("env", "test"), label("exported_job", "app"), label("instance", &address), label("job", "node"), label("path", "/")]);
+        assert_eq!(stored.value, 7.0);
+        assert_eq!(tenant.db().read().unwrap().kind("requests_total"), MetricKind::Counter);
+        assert!(tenants.default_tenant().db().read().unwrap().query("up").is_err());
+        assert_eq!(value(&tenant, "up"), vec![1.0]);
+        assert_eq!(value(&tenant, "scrape_samples_scraped"), vec![1.0]);
+
+        scraper.scrape().await;
+        pipeline.wait();
+        assert_eq!(value(&tenant, "up"), vec![1.0, 0.0]);
+        assert!(value(&tenant, "requests_total")[1].is_nan());
+    }
+}
@@ -0,0 +1,52 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use lib::{error, warn, ingest::{pipeline::Pipeline, statsd::{self, Aggregator}}, tenant::Tenant, time::now_ms};
+use tokio::net::UdpSocket;
+use tokio::time::{interval, MissedTickBehavior};
+
+
+const MAX_DATAGRAM_LEN: usize = 65_535;
+
+/// Receives StatsD over UDP and stores what was aggregated every `flush_interval`. Samples
+/// only live in memory until the flush, so a crash loses at most one interval.
+pub async fn serve(socket: UdpSocket, tenant: Arc<Tenant>, pipeline: Arc<Pipeline>, flush_interval: Duration, percentiles: Vec<f64>) {
+    let mut aggregator = Aggregator::new(percentiles);
+    let mut buf = vec![0; MAX_DATAGRAM_LEN];
+    let mut ticker = interval(flush_interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick is immediate and there is nothing to flush yet.
+    ticker.tick().await;
+
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => match received {
+                Ok((len, addr)) => {
+                    let Ok(packet) = std::str::from_utf8(&buf[..len]) else {
+                        warn!(protocol = "statsd", peer = addr; "Dropped packet that is not valid utf-8");
+                        continue;
+                    };
+                    let (samples, errors) = statsd::parse(packet);
+                    for (line, reason) in errors {
+                        warn!(protocol = "statsd", peer = addr, line = line; "Rejected line: {}", reason);
+                    }
+                    samples.into_iter().for_each(|sample| aggregator.add(sample));
+                },
+                Err(e) => error!(protocol = "statsd", error = e; "Failed to receive packet")
+            },
+            _ = ticker.tick() => flush(&mut aggregator, &tenant, &pipeline, flush_interval)
+        }
+    }
+}
+
+fn flush(aggregator: &mut Aggregator, tenant: &Arc<Tenant>, pipeline: &Pipeline, flush_interval: Duration) {
+    let metrics = aggregator.flush(now_ms(), flush_interval.as_millis() as u64);
+    if metrics.is_empty() {
+        return;
+    }
+    pipeline.submit_metrics(tenant, metrics, |result| {
+        if let Some(error) = result.errors.first() {
+            warn!(protocol = "statsd", rejected = result.rejected, code = error.code; "Rejected flushed series, the first: {}", error.reason);
+        }
+    });
+}
@@ -0,0 +1,102 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use lib::{error, ingest::pipeline::Pipeline, telemetry::{self, Gauge, DURATION_BUCKETS}, tenant::Tenants, time::now_ms, warn};
+
+
+/// Counts a connection as open until it's dropped.
+pub struct Connection(Arc<Gauge>);
+
+impl Connection {
+    pub fn open(protocol: &str) -> Self {
+        let registry = telemetry::global();
+        registry.counter("metrichouse_connections_total", "Connections accepted, by protocol.", &[("protocol", protocol)]).inc();
+        let open = registry.gauge("metrichouse_connections_open", "Connections currently open, by protocol.", &[("protocol", protocol)]);
+        open.inc();
+        Connection(open)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Runs `query` and records how long it took under the API and endpoint it was made on.
+pub fn time_query<T>(api: &str, endpoint: &str, query: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let result = query();
+    telemetry::global().histogram("metrichouse_query_duration_seconds", "Query latency, by API and endpoint.", &[("api", api), ("endpoint", endpoint)], DURATION_BUCKETS)
+        .observe_duration(started.elapsed());
+    result
+}
+
+/// The global registry in the Prometheus text format, with the head gauges brought up to date.
+pub fn render(tenants: &Tenants) -> String {
+    tenants.record_stats();
+    telemetry::global().render()
+}
+
+/// Submits what the global registry holds every `interval` as the default tenant's, so the
+/// server's own metrics can be queried like any others. See `Pipeline::submit_own`.
+pub async fn run(tenants: Arc<Tenants>, pipeline: Arc<Pipeline>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let (tenants, pipeline) = (tenants.clone(), pipeline.clone());
+        // Bringing the head gauges up to date takes every tenant's read lock.
+        if let Err(e) = tokio::task::spawn_blocking(move || write(&tenants, &pipeline)).await {
+            error!(error = e; "Failed to write self-monitoring metrics");
+        }
+    }
+}
+
+fn write(tenants: &Tenants, pipeline: &Pipeline) {
+    tenants.record_stats();
+    let (mut metrics, mut kinds) = (Vec::new(), Vec::new());
+    for (name, _, kind, samples) in telemetry::global().gather(now_ms()) {
+        kinds.push((name, kind));
+        metrics.extend(samples);
+    }
+    pipeline.submit_own(&tenants.default_tenant(), metrics, kinds, |result| {
+        if let Some(first) = result.errors.first() {
+            warn!(rejected = result.rejected, code = first.code; "Rejected self-monitoring samples, the first: {}", first.reason);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib::{db::MetricsDb, ingest::relabel::Relabeler, storage::StorageOptions, tenant::{TenantLimits, TenantSettings}, testing::TempDir};
+
+    #[test]
+    fn writes_own_metrics() {
+        let dir = TempDir::new("telemetry_writes");
+        let options = StorageOptions { data_dir: dir.to_path_buf(), ..StorageOptions::default() };
+        // Neither limit applies to the server's own metrics.
+        let limits = TenantLimits { max_series: Some(1), ingest_rate: Some(1), ..TenantLimits::default() };
+        let tenants = Tenants::new(options.clone(), MetricsDb::open_with(&options).unwrap(), TenantSettings { limits, ..TenantSettings::default() });
+        tenants.get("team-a").unwrap();
+        let pipeline = Pipeline::start(Arc::new(Relabeler::default()), Default::default()).unwrap();
+
+        let connection = Connection::open("test");
+        assert_eq!(time_query("test", "query", || 42), 42);
+        write(&tenants, &pipeline);
+        pipeline.wait();
+        drop(connection);
+        let rendered = render(&tenants);
+        assert!(rendered.contains("# TYPE metrichouse_connections_open gauge\n"));
+        assert!(rendered.contains("metrichouse_tenant_series{tenant=\"team-a\"} 0\n"), "{}", rendered);
+
+        let tenant = tenants.default_tenant();
+        let db = tenant.db().read().unwrap();
+        let open = db.query("metrichouse_connections_open").unwrap().iter()
+            .find(|metric| metric.labels == [(String::from("protocol"), String::from("test"))])
+            .map(|metric| metric.value);
+        assert_eq!(open, Some(1.0));
+        assert!(db.query("metrichouse_query_duration_seconds_bucket").is_ok());
+        assert!(db.query("metrichouse_head_series").is_ok());
+    }
+}